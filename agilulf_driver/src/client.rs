@@ -1,8 +1,9 @@
 use std::net::SocketAddr;
 
 use agilulf_protocol::{
-    AsyncReadBuffer, AsyncWriteBuffer, Command, DeleteCommand, GetCommand, ProtocolError,
-    PutCommand, Reply, ScanCommand, Slice,
+    ApproximateSizeCommand, AsyncReadBuffer, AsyncWriteBuffer, Command, DeleteCommand,
+    DeleteRangeCommand, GetCommand, MergeCommand, ProtocolError, PutCommand, PutExpireCommand,
+    Reply, ScanCommand, SetRateLimitCommand, Slice,
 };
 use romio::TcpStream;
 
@@ -13,6 +14,16 @@ use futures::lock::Mutex;
 use futures::{SinkExt, StreamExt};
 use std::hash::{Hash, Hasher};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Expiration time of a key which lives for `ttl` from now, in milliseconds since UNIX epoch. It's
+/// calculated with the clock of client.
+fn expire_at_after(ttl: Duration) -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (now + ttl).as_millis() as u64
+}
 
 /// A simple single-thread client
 #[derive(Clone)]
@@ -49,6 +60,26 @@ impl AgilulfClient {
         self.send(Command::PUT(PutCommand { key, value })).await
     }
 
+    /// PUT a key which expires at `expire_at`, in milliseconds since UNIX epoch.
+    pub async fn put_expire(&self, key: Slice, value: Slice, expire_at: u64) -> Result<Reply> {
+        self.send(Command::PUT_EXPIRE(PutExpireCommand {
+            key,
+            value,
+            expire_at,
+        }))
+        .await
+    }
+
+    pub async fn put_with_ttl(&self, key: Slice, value: Slice, ttl: Duration) -> Result<Reply> {
+        self.put_expire(key, value, expire_at_after(ttl)).await
+    }
+
+    /// Apply `operand` on the value of `key` with the merge operator of server.
+    pub async fn merge(&self, key: Slice, operand: Slice) -> Result<Reply> {
+        self.send(Command::MERGE(MergeCommand { key, operand }))
+            .await
+    }
+
     pub async fn get(&self, key: Slice) -> Result<Reply> {
         self.send(Command::GET(GetCommand { key })).await
     }
@@ -61,6 +92,28 @@ impl AgilulfClient {
         self.send(Command::SCAN(ScanCommand { start, end })).await
     }
 
+    pub async fn delete_range(&self, start: Slice, end: Slice) -> Result<Reply> {
+        self.send(Command::DELETE_RANGE(DeleteRangeCommand { start, end }))
+            .await
+    }
+
+    /// Estimated size and number of keys in `[start, end)`, replied as two decimal slices.
+    pub async fn approximate_size(&self, start: Slice, end: Slice) -> Result<Reply> {
+        self.send(Command::APPROXIMATE_SIZE(ApproximateSizeCommand {
+            start,
+            end,
+        }))
+        .await
+    }
+
+    /// Limit background writes of server to `bytes_per_second`, or remove the limit with `0`.
+    pub async fn set_rate_limit(&self, bytes_per_second: u64) -> Result<Reply> {
+        self.send(Command::SET_RATE_LIMIT(SetRateLimitCommand {
+            bytes_per_second,
+        }))
+        .await
+    }
+
     pub async fn send(&self, command: Command) -> Result<Reply> {
         let message: Vec<u8> = command.into();
 
@@ -131,11 +184,20 @@ impl MultiAgilulfClient {
     pub fn allocate_task(&self, command: &Command) -> usize {
         match command {
             Command::PUT(command) => Self::hash_key(&command.key) % self.knights.len(),
+            Command::PUT_EXPIRE(command) => Self::hash_key(&command.key) % self.knights.len(),
+            Command::MERGE(command) => Self::hash_key(&command.key) % self.knights.len(),
             Command::DELETE(command) => Self::hash_key(&command.key) % self.knights.len(),
             Command::GET(command) => Self::hash_key(&command.key) % self.knights.len(),
             Command::SCAN(command) => {
                 Self::hash_key(&command.start) % self.knights.len() // TODO: Add Barrier here
             }
+            Command::DELETE_RANGE(command) => {
+                Self::hash_key(&command.start) % self.knights.len() // TODO: Add Barrier here
+            }
+            Command::APPROXIMATE_SIZE(command) => {
+                Self::hash_key(&command.start) % self.knights.len()
+            }
+            Command::SET_RATE_LIMIT(_) => 0,
         }
     }
 
@@ -143,6 +205,26 @@ impl MultiAgilulfClient {
         self.send(Command::PUT(PutCommand { key, value })).await
     }
 
+    /// PUT a key which expires at `expire_at`, in milliseconds since UNIX epoch.
+    pub async fn put_expire(&self, key: Slice, value: Slice, expire_at: u64) -> Result<Reply> {
+        self.send(Command::PUT_EXPIRE(PutExpireCommand {
+            key,
+            value,
+            expire_at,
+        }))
+        .await
+    }
+
+    pub async fn put_with_ttl(&self, key: Slice, value: Slice, ttl: Duration) -> Result<Reply> {
+        self.put_expire(key, value, expire_at_after(ttl)).await
+    }
+
+    /// Apply `operand` on the value of `key` with the merge operator of server.
+    pub async fn merge(&self, key: Slice, operand: Slice) -> Result<Reply> {
+        self.send(Command::MERGE(MergeCommand { key, operand }))
+            .await
+    }
+
     pub async fn get(&self, key: Slice) -> Result<Reply> {
         self.send(Command::GET(GetCommand { key })).await
     }
@@ -155,6 +237,29 @@ impl MultiAgilulfClient {
         self.send(Command::SCAN(ScanCommand { start, end })).await // TODO: need barrier for safety of scan.
     }
 
+    pub async fn delete_range(&self, start: Slice, end: Slice) -> Result<Reply> {
+        // TODO: like scan, need barrier for safety of delete range.
+        self.send(Command::DELETE_RANGE(DeleteRangeCommand { start, end }))
+            .await
+    }
+
+    /// Estimated size and number of keys in `[start, end)`, replied as two decimal slices.
+    pub async fn approximate_size(&self, start: Slice, end: Slice) -> Result<Reply> {
+        self.send(Command::APPROXIMATE_SIZE(ApproximateSizeCommand {
+            start,
+            end,
+        }))
+        .await
+    }
+
+    /// Limit background writes of server to `bytes_per_second`, or remove the limit with `0`.
+    pub async fn set_rate_limit(&self, bytes_per_second: u64) -> Result<Reply> {
+        self.send(Command::SET_RATE_LIMIT(SetRateLimitCommand {
+            bytes_per_second,
+        }))
+        .await
+    }
+
     pub async fn send(&self, command: Command) -> Result<Reply> {
         let knight_id = self.allocate_task(&command);
         self.knights[knight_id].send(command).await
@@ -391,6 +496,44 @@ mod tests {
         });
     }
 
+    #[test]
+    fn delete_range_test() {
+        run_test(async move |port, _| {
+            let client = connect(port).await;
+            for i in 0..100 {
+                let ans = client
+                    .put(
+                        Slice(format!("key{:03}", i).into_bytes()),
+                        Slice(format!("value{}", i).into_bytes()),
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(ans, Reply::StatusReply(Status::OK));
+            }
+
+            let ans = client
+                .delete_range(Slice(b"key010".to_vec()), Slice(b"key020".to_vec()))
+                .await
+                .unwrap();
+            assert_eq!(ans, Reply::StatusReply(Status::OK));
+
+            for i in 0..100 {
+                let ans = client
+                    .get(Slice(format!("key{:03}", i).into_bytes()))
+                    .await
+                    .unwrap();
+                if (10..20).contains(&i) {
+                    assert_eq!(ans, Reply::ErrorReply(String::from("KeyNotFound\r\n")));
+                } else {
+                    assert_eq!(
+                        ans,
+                        Reply::SliceReply(Slice(format!("value{}", i).into_bytes()))
+                    );
+                }
+            }
+        });
+    }
+
     #[test]
     fn override_test() {
         run_test(async move |port, _| {
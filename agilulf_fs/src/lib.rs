@@ -32,24 +32,53 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 
 static AIO_SIGNAL_HANDLER: Once = Once::new();
 lazy_static! {
-    static ref WAKER_LIST: RwLock<Vec<Waker>> = RwLock::new(Vec::new());
+    static ref WAKER_LIST: RwLock<Vec<Option<Waker>>> = RwLock::new(Vec::new());
+}
+
+/// Run `f` with the write lock of `WAKER_LIST`.
+///
+/// SIGIO is blocked on this thread while it holds the write lock. Otherwise `handle_sig_io` may run on
+/// this thread and wait for the lock held by itself forever, which happens easily once files are
+/// written by several threads at the same time.
+fn with_waker_list<T>(f: impl FnOnce(&mut Vec<Option<Waker>>) -> T) -> T {
+    let mut sigio = signal::SigSet::empty();
+    sigio.add(Signal::SIGIO);
+    let old_mask = sigio.thread_swap_mask(signal::SigmaskHow::SIG_BLOCK);
+
+    let result = f(&mut WAKER_LIST.write().unwrap());
+
+    if let Ok(old_mask) = old_mask {
+        let _ = old_mask.thread_set_mask();
+    }
+    result
 }
 
 fn add_to_waker_list(waker: Waker) -> usize {
-    let mut waker_list = WAKER_LIST.write().unwrap();
-    waker_list.push(waker);
-    return waker_list.len() - 1;
+    with_waker_list(
+        |waker_list| match waker_list.iter().position(Option::is_none) {
+            Some(index) => {
+                waker_list[index] = Some(waker);
+                index
+            }
+            None => {
+                waker_list.push(Some(waker));
+                waker_list.len() - 1
+            }
+        },
+    )
 }
 
-extern "C" fn handle_sig_io(_: i32, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
-    let index: usize = unsafe { std::mem::transmute((*info).si_value()) };
-    WAKER_LIST
-        .read()
-        .unwrap()
-        .get(index)
-        .unwrap()
-        .clone()
-        .wake();
+fn remove_from_waker_list(index: usize) {
+    with_waker_list(|waker_list| waker_list[index] = None);
+}
+
+/// SIGIO is a standard signal, so it's not queued: if several requests finish while a SIGIO is
+/// pending, only one of them is delivered. So every waiting task is woken here, and each of them
+/// checks whether its own request has finished.
+extern "C" fn handle_sig_io(_: i32, _: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    for waker in WAKER_LIST.read().unwrap().iter().flatten() {
+        waker.wake_by_ref();
+    }
 }
 
 /// A struct contains only one fd.
@@ -66,9 +95,9 @@ extern "C" fn handle_sig_io(_: i32, info: *mut libc::siginfo_t, _: *mut libc::c_
 ///
 /// 1. This struct will close the fd automatically when it's dropped.
 ///
-/// 2. Open file with this struct will take over the SIGIO signal. AIO will send a SIGIO signal with
-/// request id to this process and then this library will lookup `WAKER_LIST` and find the waker
-/// corresponding to this id. Then it will wake that task.
+/// 2. Open file with this struct will take over the SIGIO signal. AIO will send a SIGIO signal to
+/// this process when a request finishes and then this library will wake every task waiting in
+/// `WAKER_LIST`.
 ///
 pub struct File {
     fd: RawFd,
@@ -127,6 +156,7 @@ impl File {
         WriteFile {
             aio_cb,
             register: AtomicUsize::new(0),
+            waker_index: 0,
         }
     }
 
@@ -143,10 +173,12 @@ impl File {
 ///
 /// It's the return value of the write method of a File. When it's polled the first time, it will
 /// clone the waker and add the waker to `WAKER_LIST` and set `aio_cb.sigev_notify` with SIGIO signal
-/// and set `aio_cb.sigev_notify.si_value` with the index of its waker in `WAKER_LIST`.
+/// and set `aio_cb.sigev_notify.si_value` with the index of its waker in `WAKER_LIST`. The waker is
+/// removed from `WAKER_LIST` once the request finishes.
 pub struct WriteFile<'a> {
     aio_cb: AioCb<'a>,
     register: AtomicUsize,
+    waker_index: usize,
 }
 
 impl<'a> Future for WriteFile<'a> {
@@ -156,21 +188,26 @@ impl<'a> Future for WriteFile<'a> {
         let this = self.get_mut();
         if this.register.fetch_add(1, Ordering::SeqCst) == 0 {
             let waker = cx.waker().clone();
+            this.waker_index = add_to_waker_list(waker);
 
             this.aio_cb.set_sigev_notify(SigevNotify::SigevSignal {
                 signal: Signal::SIGIO,
-                si_value: add_to_waker_list(waker) as isize,
+                si_value: this.waker_index as isize,
             });
 
             match this.aio_cb.write() {
                 Ok(()) => {}
-                Err(err) => return Poll::Ready(Err(err.into())),
+                Err(err) => {
+                    remove_from_waker_list(this.waker_index);
+                    return Poll::Ready(Err(err.into()));
+                }
             }
         }
 
         if let Err(_) = this.aio_cb.error() {
             return Poll::Pending; // TODO: handle other error here
         } else {
+            remove_from_waker_list(this.waker_index);
             match this.aio_cb.aio_return() {
                 Ok(_status) => return Poll::Ready(Ok(())),
                 Err(err) => return Poll::Ready(Err(err.into())),
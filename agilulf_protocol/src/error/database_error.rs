@@ -2,6 +2,9 @@ quick_error! {
     #[derive(Debug)]
     pub enum DatabaseError {
         KeyNotFound
+        MergeOperatorNotSet
+        ColumnFamilyNotFound
+        NotSupported
         InternalError(err: String)
     }
 }
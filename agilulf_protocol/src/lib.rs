@@ -28,7 +28,10 @@ mod slice;
 pub use slice::Slice;
 
 pub use reply::{Reply, Status};
-pub use request::{Command, DeleteCommand, GetCommand, PutCommand, ScanCommand};
+pub use request::{
+    ApproximateSizeCommand, Command, DeleteCommand, DeleteRangeCommand, GetCommand, MergeCommand,
+    PutCommand, PutExpireCommand, ScanCommand, SetRateLimitCommand,
+};
 
 pub use error::database_error::{DatabaseError, Result as DatabaseResult};
 pub use error::protocol_error::{ProtocolError, Result};
@@ -63,6 +63,15 @@ impl From<Vec<(Slice, Slice)>> for Reply {
     }
 }
 
+impl From<DatabaseResult<Vec<(Slice, Slice)>>> for Reply {
+    fn from(result: DatabaseResult<Vec<(Slice, Slice)>>) -> Self {
+        match result {
+            Ok(pairs) => pairs.into(),
+            Err(err) => Reply::ErrorReply(err.description().to_string()),
+        }
+    }
+}
+
 impl Into<Vec<u8>> for Reply {
     fn into(self) -> Vec<u8> {
         let mut reply: Vec<u8> = Vec::new();
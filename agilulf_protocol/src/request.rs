@@ -26,6 +26,21 @@ pub struct PutCommand {
     pub value: Slice,
 }
 
+/// PUT a key which expires at `expire_at`, in milliseconds since UNIX epoch.
+#[derive(Clone)]
+pub struct PutExpireCommand {
+    pub key: Slice,
+    pub value: Slice,
+    pub expire_at: u64,
+}
+
+/// Apply `operand` on the value of `key` with the merge operator of database.
+#[derive(Clone)]
+pub struct MergeCommand {
+    pub key: Slice,
+    pub operand: Slice,
+}
+
 #[derive(Clone)]
 pub struct GetCommand {
     pub key: Slice,
@@ -42,12 +57,42 @@ pub struct DeleteCommand {
     pub key: Slice,
 }
 
+/// Delete every key in `[start, end)`.
+#[derive(Clone)]
+pub struct DeleteRangeCommand {
+    pub start: Slice,
+    pub end: Slice,
+}
+
+/// Estimate size and number of keys in `[start, end)` without scanning them. It's answered with two
+/// slices: bytes and count, both in decimal.
+#[derive(Clone)]
+pub struct ApproximateSizeCommand {
+    pub start: Slice,
+    pub end: Slice,
+}
+
+/// Change the limit of background writes of the database, in bytes per second. `0` means no limit.
+#[derive(Clone)]
+pub struct SetRateLimitCommand {
+    pub bytes_per_second: u64,
+}
+
 #[derive(Clone)]
 pub enum Command {
     PUT(PutCommand),
     GET(GetCommand),
     DELETE(DeleteCommand),
     SCAN(ScanCommand),
+    #[allow(non_camel_case_types)]
+    DELETE_RANGE(DeleteRangeCommand),
+    #[allow(non_camel_case_types)]
+    PUT_EXPIRE(PutExpireCommand),
+    MERGE(MergeCommand),
+    #[allow(non_camel_case_types)]
+    APPROXIMATE_SIZE(ApproximateSizeCommand),
+    #[allow(non_camel_case_types)]
+    SET_RATE_LIMIT(SetRateLimitCommand),
 }
 
 impl Command {
@@ -96,6 +141,70 @@ impl Command {
                     ))
                 }
             }
+            "DELETE_RANGE" => {
+                if message.len() == 3 {
+                    let end = Slice(message.remove(2));
+                    let start = Slice(message.remove(1));
+                    Ok(Command::DELETE_RANGE(DeleteRangeCommand { start, end }))
+                } else {
+                    Err(ProtocolError::GrammarCheckFailed(
+                        "DELETE_RANGE should have two arguments",
+                    ))
+                }
+            }
+            "PUT_EXPIRE" => {
+                if message.len() == 4 {
+                    let expire_at = std::str::from_utf8(&message[3])?.parse()?;
+                    let value = Slice(message.remove(2));
+                    let key = Slice(message.remove(1));
+                    Ok(Command::PUT_EXPIRE(PutExpireCommand {
+                        key,
+                        value,
+                        expire_at,
+                    }))
+                } else {
+                    Err(ProtocolError::GrammarCheckFailed(
+                        "PUT_EXPIRE should have three arguments",
+                    ))
+                }
+            }
+            "MERGE" => {
+                if message.len() == 3 {
+                    let operand = Slice(message.remove(2));
+                    let key = Slice(message.remove(1));
+                    Ok(Command::MERGE(MergeCommand { key, operand }))
+                } else {
+                    Err(ProtocolError::GrammarCheckFailed(
+                        "MERGE should have two arguments",
+                    ))
+                }
+            }
+            "APPROXIMATE_SIZE" => {
+                if message.len() == 3 {
+                    let end = Slice(message.remove(2));
+                    let start = Slice(message.remove(1));
+                    Ok(Command::APPROXIMATE_SIZE(ApproximateSizeCommand {
+                        start,
+                        end,
+                    }))
+                } else {
+                    Err(ProtocolError::GrammarCheckFailed(
+                        "APPROXIMATE_SIZE should have two arguments",
+                    ))
+                }
+            }
+            "SET_RATE_LIMIT" => {
+                if message.len() == 2 {
+                    let bytes_per_second = std::str::from_utf8(&message[1])?.parse()?;
+                    Ok(Command::SET_RATE_LIMIT(SetRateLimitCommand {
+                        bytes_per_second,
+                    }))
+                } else {
+                    Err(ProtocolError::GrammarCheckFailed(
+                        "SET_RATE_LIMIT should have one argument",
+                    ))
+                }
+            }
             _ => Err(ProtocolError::CommandNotSupport(command)),
         }
     }
@@ -149,6 +258,41 @@ impl Into<Vec<u8>> for Command {
                 message.append_part(command.start.0.as_slice());
                 message.append_part(command.end.0.as_slice());
             }
+            Command::DELETE_RANGE(command) => {
+                message.extend_from_slice((MessageHead { count: 3 }).into_bytes().as_slice());
+
+                message.append_part(b"DELETE_RANGE");
+                message.append_part(command.start.0.as_slice());
+                message.append_part(command.end.0.as_slice());
+            }
+            Command::PUT_EXPIRE(command) => {
+                message.extend_from_slice((MessageHead { count: 4 }).into_bytes().as_slice());
+
+                message.append_part(b"PUT_EXPIRE");
+                message.append_part(command.key.0.as_slice());
+                message.append_part(command.value.0.as_slice());
+                message.append_part(command.expire_at.to_string().as_bytes());
+            }
+            Command::MERGE(command) => {
+                message.extend_from_slice((MessageHead { count: 3 }).into_bytes().as_slice());
+
+                message.append_part(b"MERGE");
+                message.append_part(command.key.0.as_slice());
+                message.append_part(command.operand.0.as_slice());
+            }
+            Command::APPROXIMATE_SIZE(command) => {
+                message.extend_from_slice((MessageHead { count: 3 }).into_bytes().as_slice());
+
+                message.append_part(b"APPROXIMATE_SIZE");
+                message.append_part(command.start.0.as_slice());
+                message.append_part(command.end.0.as_slice());
+            }
+            Command::SET_RATE_LIMIT(command) => {
+                message.extend_from_slice((MessageHead { count: 2 }).into_bytes().as_slice());
+
+                message.append_part(b"SET_RATE_LIMIT");
+                message.append_part(command.bytes_per_second.to_string().as_bytes());
+            }
         }
 
         message
@@ -178,6 +178,20 @@ impl<T: std::cmp::PartialOrd + Clone + NonStandard> SkipList<T> {
         ret
     }
 
+    /// Number of keys from `start` to `end`, like `scan(start, end).len()` without collecting them.
+    pub fn count(&self, start: &T, end: &T) -> usize {
+        let (_, mut next) = self.find_key(start);
+        let mut count = 0;
+
+        unsafe {
+            while (*next).get_key() < end {
+                count += 1;
+                next = (*next).get_succ().load(Ordering::SeqCst);
+            }
+        }
+        count
+    }
+
     pub fn insert(&self, key: &T) {
         let seek_result = self.seek(key);
         let level = min(generate_level(), SKIPLIST_MAX_LEVEL);
@@ -144,7 +144,7 @@ impl<T: Default + Clone> SkipMap<T> {
         }
     }
 
-    pub fn scan<R>(&self, range: R) -> Vec<(Slice, T)>
+    fn bounds<R>(range: R) -> (Item<T>, Item<T>)
     where
         R: RangeBounds<Slice>,
     {
@@ -176,6 +176,25 @@ impl<T: Default + Clone> SkipMap<T> {
             },
             Bound::Unbounded => Item::max(),
         };
+
+        (start_item, end_item)
+    }
+
+    /// Number of items in range, including overwritten ones. Items are walked without being collected or
+    /// cloned, so it's cheaper than `scan`, but it still takes time linear in the number of items.
+    pub fn count<R>(&self, range: R) -> u64
+    where
+        R: RangeBounds<Slice>,
+    {
+        let (start_item, end_item) = Self::bounds(range);
+        self.skiplist.count(&start_item, &end_item) as u64
+    }
+
+    pub fn scan<R>(&self, range: R) -> Vec<(Slice, T)>
+    where
+        R: RangeBounds<Slice>,
+    {
+        let (start_item, end_item) = Self::bounds(range);
         let data = self
             .skiplist
             .scan(&start_item, &end_item)
@@ -356,6 +375,10 @@ mod tests {
             &Slice(b"key1".to_vec()),
             &Slice(b"modified_value1".to_vec()),
         );
+        assert_eq!(
+            map.count(Slice(b"key1".to_vec())..Slice(b"key3".to_vec())),
+            3
+        );
         let scan_result = map.scan(Slice(b"key1".to_vec())..Slice(b"key3".to_vec()));
         assert_eq!(
             scan_result,
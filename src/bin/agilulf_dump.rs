@@ -0,0 +1,562 @@
+extern crate agilulf;
+extern crate agilulf_protocol;
+extern crate clap;
+extern crate env_logger;
+#[macro_use]
+extern crate serde_json;
+
+use agilulf::inspect::{self, ManifestRecord, Value};
+use agilulf::{ChaCha20Poly1305Encryptor, Encryptor};
+use agilulf_protocol::{Command, Slice};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use serde_json::Value as Json;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Keys and values are padded with zero on disk. Trailing zeros are not printed.
+fn trim(slice: &Slice) -> &[u8] {
+    let len = slice
+        .0
+        .iter()
+        .rposition(|byte| *byte != 0)
+        .map_or(0, |index| index + 1);
+    &slice.0[0..len]
+}
+
+fn text(slice: &Slice) -> String {
+    let mut ret = String::new();
+    for byte in trim(slice) {
+        match *byte {
+            b'\\' => ret.push_str("\\\\"),
+            0x20..=0x7e => ret.push(*byte as char),
+            byte => ret.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    ret
+}
+
+/// Every byte is taken as a character from U+0000 to U+00FF, so binary keys and values are kept.
+fn json(slice: &Slice) -> Json {
+    Json::String(trim(slice).iter().map(|byte| *byte as char).collect())
+}
+
+fn dump_table(
+    path: &Path,
+    encryptor: Option<&Arc<dyn Encryptor>>,
+    as_json: bool,
+) -> Result<(), agilulf::StorageError> {
+    let inspect::TableContents {
+        records,
+        range_tombstones,
+    } = inspect::read_table(path, encryptor)?;
+
+    if as_json {
+        let records: Vec<Json> = records
+            .iter()
+            .map(|(key, value)| match value {
+                Value::Slice(value) => json!({"key": json(key), "value": json(value)}),
+                Value::Expiring(value, expire_at) => json!({
+                    "key": json(key),
+                    "value": json(value),
+                    "expire_at": expire_at,
+                }),
+                Value::NotExist => json!({"key": json(key), "deleted": true}),
+                Value::Merge(operands) => json!({
+                    "key": json(key),
+                    "operands": operands.iter().map(json).collect::<Vec<Json>>(),
+                }),
+                Value::Blob(index, expire_at) => json!({
+                    "key": json(key),
+                    "blob": {"file": index.file, "offset": index.offset, "length": index.length},
+                    "expire_at": expire_at,
+                }),
+            })
+            .collect();
+        let range_tombstones: Vec<Json> = range_tombstones
+            .iter()
+            .map(|tombstone| json!({"start": json(&tombstone.start), "end": json(&tombstone.end)}))
+            .collect();
+        println!(
+            "{}",
+            json!({
+                "count": records.len(),
+                "records": records,
+                "range_tombstones": range_tombstones,
+            })
+        );
+    } else {
+        for (key, value) in records.iter() {
+            match value {
+                Value::Slice(value) => println!("{}\t{}", text(key), text(value)),
+                Value::Expiring(value, expire_at) => {
+                    println!("{}\t{}\tEXPIRE_AT {}", text(key), text(value), expire_at)
+                }
+                Value::NotExist => println!("{}\tDELETED", text(key)),
+                Value::Merge(operands) => {
+                    println!("{}\tMERGE {} operands", text(key), operands.len())
+                }
+                Value::Blob(index, expire_at) => println!(
+                    "{}\tBLOB {} {} {}\tEXPIRE_AT {}",
+                    text(key),
+                    index.file,
+                    index.offset,
+                    index.length,
+                    expire_at
+                ),
+            }
+        }
+        for tombstone in range_tombstones.iter() {
+            println!(
+                "DELETE_RANGE\t[{}, {})",
+                text(&tombstone.start),
+                text(&tombstone.end)
+            );
+        }
+        match (records.first(), records.last()) {
+            (Some((smallest, _)), Some((largest, _))) => println!(
+                "{} records, keys in [{}, {}], {} range tombstones",
+                records.len(),
+                text(smallest),
+                text(largest),
+                range_tombstones.len()
+            ),
+            _ => println!("0 records, {} range tombstones", range_tombstones.len()),
+        }
+    }
+
+    Ok(())
+}
+
+fn log_json(record: &inspect::LogRecord) -> Option<Json> {
+    let (sequence, timestamp, family) = (record.sequence, record.timestamp, record.family);
+    if record.is_ingest() {
+        return Some(json!({"op": "INGEST", "sequence": sequence, "timestamp": timestamp}));
+    }
+    let mut json = match record.blob_index() {
+        Some((key, index, expire_at)) => json!({
+            "op": "PUT_BLOB",
+            "key": json(&key),
+            "blob": {"file": index.file, "offset": index.offset, "length": index.length},
+            "expire_at": expire_at,
+        }),
+        None => match &record.command {
+            Command::PUT(command) => json!({
+                "op": "PUT",
+                "key": json(&command.key),
+                "value": json(&command.value),
+            }),
+            Command::PUT_EXPIRE(command) => json!({
+                "op": "PUT_EXPIRE",
+                "key": json(&command.key),
+                "value": json(&command.value),
+                "expire_at": command.expire_at,
+            }),
+            Command::MERGE(command) => json!({
+                "op": "MERGE",
+                "key": json(&command.key),
+                "operand": json(&command.operand),
+            }),
+            Command::DELETE(command) => json!({"op": "DELETE", "key": json(&command.key)}),
+            Command::DELETE_RANGE(command) => json!({
+                "op": "DELETE_RANGE",
+                "start": json(&command.start),
+                "end": json(&command.end),
+            }),
+            _ => return None,
+        },
+    };
+    json["sequence"] = json!(sequence);
+    json["timestamp"] = json!(timestamp);
+    json["family"] = json!(family);
+    Some(json)
+}
+
+fn log_text(record: &inspect::LogRecord) -> Option<String> {
+    let (sequence, timestamp, family) = (record.sequence, record.timestamp, record.family);
+    if record.is_ingest() {
+        return Some(format!("{}\t{}\t-\tINGEST", sequence, timestamp));
+    }
+    if let Some((key, index, expire_at)) = record.blob_index() {
+        return Some(format!(
+            "{}\t{}\t{}\tPUT_BLOB\t{}\t{} {} {}\t{}",
+            sequence,
+            timestamp,
+            family,
+            text(&key),
+            index.file,
+            index.offset,
+            index.length,
+            expire_at
+        ));
+    }
+    match &record.command {
+        Command::PUT(command) => Some(format!(
+            "{}\t{}\t{}\tPUT\t{}\t{}",
+            sequence,
+            timestamp,
+            family,
+            text(&command.key),
+            text(&command.value)
+        )),
+        Command::PUT_EXPIRE(command) => Some(format!(
+            "{}\t{}\t{}\tPUT_EXPIRE\t{}\t{}\t{}",
+            sequence,
+            timestamp,
+            family,
+            text(&command.key),
+            text(&command.value),
+            command.expire_at
+        )),
+        Command::MERGE(command) => Some(format!(
+            "{}\t{}\t{}\tMERGE\t{}\t{}",
+            sequence,
+            timestamp,
+            family,
+            text(&command.key),
+            text(&command.operand)
+        )),
+        Command::DELETE(command) => Some(format!(
+            "{}\t{}\t{}\tDELETE\t{}",
+            sequence,
+            timestamp,
+            family,
+            text(&command.key)
+        )),
+        Command::DELETE_RANGE(command) => Some(format!(
+            "{}\t{}\t{}\tDELETE_RANGE\t{}\t{}",
+            sequence,
+            timestamp,
+            family,
+            text(&command.start),
+            text(&command.end)
+        )),
+        _ => None,
+    }
+}
+
+fn dump_log(
+    path: &Path,
+    encryptor: Option<&Arc<dyn Encryptor>>,
+    as_json: bool,
+) -> Result<(), agilulf::StorageError> {
+    let records = inspect::read_log(path, encryptor)?;
+
+    if as_json {
+        let records: Vec<Json> = records.iter().filter_map(log_json).collect();
+        println!("{}", json!({"count": records.len(), "records": records}));
+    } else {
+        let lines: Vec<String> = records.iter().filter_map(log_text).collect();
+        for line in lines.iter() {
+            println!("{}", line);
+        }
+        println!("{} records", lines.len());
+    }
+
+    Ok(())
+}
+
+fn manifest_json(record: &ManifestRecord) -> Json {
+    match record {
+        ManifestRecord::AddTable {
+            level,
+            id,
+            smallest,
+            largest,
+        } => json!({
+            "type": "add_table",
+            "level": level,
+            "id": id,
+            "smallest": json(smallest),
+            "largest": json(largest),
+        }),
+        ManifestRecord::RemoveTable { level, id } => {
+            json!({"type": "remove_table", "level": level, "id": id})
+        }
+        ManifestRecord::TablePath(path_id) => json!({"type": "table_path", "path_id": path_id}),
+        ManifestRecord::LogNumber(number) => json!({"type": "log_number", "log_number": number}),
+        ManifestRecord::LastSequence(sequence) => {
+            json!({"type": "last_sequence", "last_sequence": sequence})
+        }
+        ManifestRecord::Unknown(kind) => json!({"type": "unknown", "kind": kind}),
+        ManifestRecord::EditEnd => json!({"type": "edit_end"}),
+        ManifestRecord::EditAbort => json!({"type": "edit_abort"}),
+    }
+}
+
+fn manifest_text(record: &ManifestRecord) -> String {
+    match record {
+        ManifestRecord::AddTable {
+            level,
+            id,
+            smallest,
+            largest,
+        } => format!(
+            "  ADD sstable_{}_{} [{}, {}]",
+            level,
+            id,
+            text(smallest),
+            text(largest)
+        ),
+        ManifestRecord::RemoveTable { level, id } => format!("  REMOVE sstable_{}_{}", level, id),
+        ManifestRecord::TablePath(path_id) => format!("  TABLE_PATH {}", path_id),
+        ManifestRecord::LogNumber(number) => format!("  LOG_NUMBER {}", number),
+        ManifestRecord::LastSequence(sequence) => format!("  LAST_SEQUENCE {}", sequence),
+        ManifestRecord::Unknown(kind) => format!("  UNKNOWN {}", kind),
+        ManifestRecord::EditEnd => "  EDIT_END".to_string(),
+        ManifestRecord::EditAbort => "  EDIT_ABORT".to_string(),
+    }
+}
+
+fn dump_manifest(
+    path: &Path,
+    encryptor: Option<&Arc<dyn Encryptor>>,
+    as_json: bool,
+) -> Result<(), agilulf::StorageError> {
+    let records = inspect::read_manifest(path, encryptor)?;
+
+    // Records are grouped into edits, which end with EDIT_END or EDIT_ABORT.
+    let mut edits: Vec<(&[ManifestRecord], &str)> = Vec::new();
+    let mut start = 0;
+    for (index, record) in records.iter().enumerate() {
+        let status = match record {
+            ManifestRecord::EditEnd => "committed",
+            ManifestRecord::EditAbort => "aborted",
+            _ => continue,
+        };
+        edits.push((&records[start..index], status));
+        start = index + 1;
+    }
+    if start < records.len() {
+        edits.push((&records[start..], "incomplete"));
+    }
+
+    if as_json {
+        let edits: Vec<Json> = edits
+            .iter()
+            .map(|(records, status)| {
+                json!({
+                    "status": status,
+                    "records": records.iter().map(manifest_json).collect::<Vec<Json>>(),
+                })
+            })
+            .collect();
+        println!("{}", json!({ "edits": edits }));
+    } else {
+        for (index, (records, status)) in edits.iter().enumerate() {
+            println!("Edit #{} ({})", index, status);
+            for record in records.iter() {
+                println!("{}", manifest_text(record));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn summary(
+    base_dir: &str,
+    encryptor: Option<&Arc<dyn Encryptor>>,
+    as_json: bool,
+) -> Result<(), agilulf::StorageError> {
+    let summaries = inspect::table_summaries(base_dir, encryptor)?;
+
+    if as_json {
+        let tables: Vec<Json> = summaries
+            .iter()
+            .map(|summary| {
+                let (smallest, largest) = match &summary.key_range {
+                    Some((smallest, largest)) => (json(smallest), json(largest)),
+                    None => (Json::Null, Json::Null),
+                };
+                json!({
+                    "family": summary.family,
+                    "level": summary.level,
+                    "id": summary.id,
+                    "file_size": summary.file_size,
+                    "records": summary.records,
+                    "range_tombstones": summary.range_tombstones,
+                    "smallest": smallest,
+                    "largest": largest,
+                    "corrupted": summary.corrupted,
+                })
+            })
+            .collect();
+        println!("{}", json!({ "tables": tables }));
+        return Ok(());
+    }
+
+    let mut group = None;
+    for summary in summaries.iter() {
+        if group.map(|(family, _)| family) != Some(summary.family) && summary.family != 0 {
+            println!("Column family {}:", summary.family);
+        }
+        if group != Some((summary.family, summary.level)) {
+            group = Some((summary.family, summary.level));
+            let tables = summaries
+                .iter()
+                .filter(|table| (table.family, table.level) == (summary.family, summary.level));
+            let (count, records, size) = tables.fold((0, 0, 0), |(count, records, size), table| {
+                (count + 1, records + table.records, size + table.file_size)
+            });
+            println!(
+                "Level {}: {} tables, {} records, {} bytes",
+                summary.level, count, records, size
+            );
+        }
+
+        let range = match &summary.key_range {
+            Some((smallest, largest)) => format!("[{}, {}]", text(smallest), text(largest)),
+            None if summary.corrupted => "corrupted".to_string(),
+            None => "empty".to_string(),
+        };
+        println!(
+            "  sstable_{}_{}\t{} records\t{} bytes\t{}",
+            summary.level, summary.id, summary.records, summary.file_size, range
+        );
+    }
+
+    Ok(())
+}
+
+fn verify(
+    base_dir: &str,
+    encryptor: Option<&Arc<dyn Encryptor>>,
+    as_json: bool,
+) -> Result<bool, agilulf::StorageError> {
+    let problems = inspect::verify(base_dir, encryptor)?;
+
+    if as_json {
+        let problems: Vec<Json> = problems
+            .iter()
+            .map(|problem| {
+                json!({
+                    "path": problem.path.to_string_lossy(),
+                    "message": problem.message,
+                })
+            })
+            .collect();
+        println!(
+            "{}",
+            json!({"ok": problems.is_empty(), "problems": problems})
+        );
+    } else {
+        for problem in problems.iter() {
+            println!("{}: {}", problem.path.display(), problem.message);
+        }
+        if problems.is_empty() {
+            println!("OK");
+        } else {
+            println!("{} problems found", problems.len());
+        }
+    }
+
+    Ok(problems.is_empty())
+}
+
+fn run(matches: &ArgMatches) -> Result<bool, agilulf::StorageError> {
+    let as_json = matches.is_present("json");
+    let encryptor: Option<Arc<dyn Encryptor>> = match matches.value_of("key_file") {
+        Some(path) => Some(Arc::new(ChaCha20Poly1305Encryptor::from_key_file(path)?)),
+        None => None,
+    };
+    let encryptor = encryptor.as_ref();
+
+    match matches.subcommand() {
+        ("table", Some(matches)) => dump_table(
+            Path::new(matches.value_of("FILE").unwrap_or_default()),
+            encryptor,
+            as_json,
+        )?,
+        ("log", Some(matches)) => dump_log(
+            Path::new(matches.value_of("FILE").unwrap_or_default()),
+            encryptor,
+            as_json,
+        )?,
+        ("manifest", Some(matches)) => dump_manifest(
+            Path::new(matches.value_of("FILE").unwrap_or_default()),
+            encryptor,
+            as_json,
+        )?,
+        ("summary", Some(matches)) => summary(
+            matches.value_of("BASE_DIR").unwrap_or_default(),
+            encryptor,
+            as_json,
+        )?,
+        ("verify", Some(matches)) => {
+            return verify(
+                matches.value_of("BASE_DIR").unwrap_or_default(),
+                encryptor,
+                as_json,
+            )
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(true)
+}
+
+fn main() {
+    env_logger::init();
+
+    let file_arg = Arg::with_name("FILE")
+        .required(true)
+        .help("Path of the file");
+    let base_dir_arg = Arg::with_name("BASE_DIR")
+        .default_value("/var/tmp/agilulf")
+        .help("Base directory of database");
+
+    let matches = App::new("Agilulf Dump")
+        .version("0.1.0")
+        .author("Yang Keao <keao.yang@yahoo.com>")
+        .about("Print SSTables, logs and MANIFEST of an Agilulf database")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .global(true)
+                .help("Print as JSON"),
+        )
+        .arg(
+            Arg::with_name("key_file")
+                .long("key_file")
+                .value_name("KEY_FILE")
+                .global(true)
+                .help("Read files of an encrypted database with the key in this file")
+                .takes_value(true),
+        )
+        .subcommand(
+            SubCommand::with_name("table")
+                .about("Print every record in an SSTable")
+                .arg(file_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("log")
+                .about("Print every record in a log")
+                .arg(file_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("manifest")
+                .about("Print edit history in MANIFEST")
+                .arg(file_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("summary")
+                .about("Print summary of tables in every level")
+                .arg(base_dir_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Check checksums and ordering of tables, records of logs and tables in MANIFEST")
+                .arg(base_dir_arg),
+        )
+        .get_matches();
+
+    match run(&matches) {
+        Ok(true) => {}
+        Ok(false) => std::process::exit(1),
+        Err(err) => {
+            println!("Error: {:?}", err);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,73 @@
+extern crate agilulf;
+extern crate clap;
+extern crate env_logger;
+extern crate log;
+
+use agilulf::{ChaCha20Poly1305Encryptor, Database, Encryptor};
+use clap::{App, Arg};
+use std::sync::Arc;
+
+fn main() {
+    env_logger::init();
+
+    let matches = App::new("Agilulf Repair")
+        .version("0.1.0")
+        .author("Yang Keao <keao.yang@yahoo.com>")
+        .about("Rebuild the MANIFEST of an Agilulf database from files on disk")
+        .arg(
+            Arg::with_name("base_dir")
+                .long("base_dir")
+                .value_name("BASE_DIR")
+                .default_value("/var/tmp/agilulf")
+                .help("Set the base directory of database")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("key_file")
+                .long("key_file")
+                .value_name("KEY_FILE")
+                .help("Repair an encrypted database with the key in this file")
+                .takes_value(true),
+        )
+        .get_matches();
+
+    let base_dir = matches.value_of("base_dir").unwrap_or("/var/tmp/agilulf");
+    let encryptor: Option<Arc<dyn Encryptor>> = match matches.value_of("key_file") {
+        Some(path) => match ChaCha20Poly1305Encryptor::from_key_file(path) {
+            Ok(encryptor) => Some(Arc::new(encryptor)),
+            Err(err) => {
+                println!("Error occurred during reading key file: {:?}", err);
+                return;
+            }
+        },
+        None => None,
+    };
+
+    log::info!("Repairing database in {}", base_dir);
+    let report = match Database::repair(base_dir, encryptor) {
+        Ok(report) => report,
+        Err(err) => {
+            println!("Error occurred during repairing database: {:?}", err);
+            return;
+        }
+    };
+
+    for (level, id) in report.tables.iter() {
+        println!("Recovered table sstable_{}_{}", level, id);
+    }
+    for (family, level, id) in report.family_tables.iter() {
+        println!("Recovered table family_{}/sstable_{}_{}", family, level, id);
+    }
+    for id in report.logs.iter() {
+        println!("Kept frozen log log.{}", id);
+    }
+    for path in report.lost.iter() {
+        println!("Moved unreadable file to {}", path.display());
+    }
+    println!(
+        "Repaired: {} tables, {} frozen logs, {} lost files",
+        report.tables.len() + report.family_tables.len(),
+        report.logs.len(),
+        report.lost.len()
+    );
+}
@@ -32,6 +32,16 @@ mod server;
 mod storage;
 
 pub use server::Server;
+pub use storage::error::StorageError;
+pub use storage::inspect;
 pub use storage::mem_database::MemDatabase;
+pub use storage::{AppendOperator, MaxOperator, MergeOperator, U64AddOperator};
 pub use storage::{AsyncDatabase, SyncDatabase};
-pub use storage::{Database, DatabaseBuilder};
+pub use storage::{BlobGcReport, CheckpointReport, CompactionReport, RepairReport};
+pub use storage::{ChaCha20Poly1305Encryptor, Encryptor};
+pub use storage::{Change, ChangeStream, ColumnFamily, ColumnFamilyOptions};
+pub use storage::{CompactionFilter, Compression, DatabaseStats, FilterDecision};
+pub use storage::{CompactionStrategy, LeveledCompaction, UniversalCompaction};
+pub use storage::{
+    Database, DatabaseBuilder, RateLimiter, RestorePoint, SSTableWriter, WriteBatch,
+};
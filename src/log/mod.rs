@@ -1,7 +1,11 @@
-use memmap::{MmapMut, MmapOptions};
+use memmap::{Mmap, MmapMut, MmapOptions};
+use std::io::Read;
 use std::marker::PhantomData;
 use std::mem::size_of;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::storage::Encryptor;
 
 quick_error! {
     #[derive(Debug)]
@@ -12,6 +16,7 @@ quick_error! {
         IOError(err: std::io::Error) {
             from()
         }
+        IncompatibleFormat
     }
 }
 pub type Result<T> = std::result::Result<T, LogError>;
@@ -21,53 +26,146 @@ pub trait JudgeReal {
     fn set_real(&mut self, real: bool);
 }
 
+/// Every log starts with a header of `HEADER_LENGTH` bytes: the magic of its kind of entries, the
+/// version of their format and a random id of the file (both in little endian). A log written in
+/// another format, or a log of another kind, is rejected with `LogError::IncompatibleFormat` instead
+/// of being read as entries. The version should be increased whenever the layout of entries changes.
+///
+/// Every entry is followed by a CRC32 of it (in little endian), or encrypted with an authentication
+/// tag if the log is encrypted, so an entry with a flipped bit is found instead of being read.
+pub trait LogFormat {
+    const MAGIC: [u8; 8];
+    const VERSION: u32;
+}
+
+pub const HEADER_LENGTH: usize = 8 + 4 + 8;
+const FORMAT_LENGTH: usize = 8 + 4;
+pub const CHECKSUM_LENGTH: usize = 4;
+
+/// Bytes added to every entry: the authentication tag and nonce if it's encrypted, otherwise its
+/// checksum.
+fn overhead(encryptor: Option<&dyn Encryptor>) -> usize {
+    encryptor.map_or(CHECKSUM_LENGTH, |encryptor| encryptor.overhead())
+}
+
+/// A random id for a new file. It's kept when the file is renamed or copied.
+pub fn random_id() -> std::io::Result<u64> {
+    let mut id = [0u8; 8];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut id)?;
+    Ok(u64::from_le_bytes(id))
+}
+
+fn format<T: LogFormat>() -> [u8; FORMAT_LENGTH] {
+    let mut format = [0u8; FORMAT_LENGTH];
+    format[0..8].copy_from_slice(&T::MAGIC);
+    format[8..12].copy_from_slice(&T::VERSION.to_le_bytes());
+    format
+}
+
+/// Data authenticated with an encrypted entry: the header of its log and its index, so an entry moved
+/// to another slot or copied from another log cannot be decrypted.
+fn entry_aad(header: &[u8], index: usize) -> Vec<u8> {
+    let mut aad = header.to_vec();
+    aad.extend_from_slice(&(index as u64).to_le_bytes());
+    aad
+}
+
 pub struct LogIterator<'a, T> {
-    inner_mmap: &'a MmapMut,
+    data: &'a [u8],
     index: usize,
+    entry_length: usize,
+    encryptor: Option<&'a dyn Encryptor>,
     phantom: PhantomData<T>,
 }
 
+/// Read the entry of `index` from its slot in log. `None` if it cannot be decrypted or doesn't match
+/// its checksum, e.g. the slot is empty or half written.
+fn read_entry<T: Clone>(
+    slot: &[u8],
+    header: &[u8],
+    index: usize,
+    encryptor: Option<&dyn Encryptor>,
+) -> Option<T> {
+    match encryptor {
+        Some(encryptor) => {
+            let data = encryptor.decrypt(slot, &entry_aad(header, index))?;
+            unsafe { Some(std::ptr::read_unaligned(data.as_ptr() as *const T)) }
+        }
+        None => {
+            let (data, checksum) = slot.split_at(size_of::<T>());
+            if crc32fast::hash(data).to_le_bytes() != checksum {
+                return None;
+            }
+            unsafe { Some(std::ptr::read_unaligned(data.as_ptr() as *const T)) }
+        }
+    }
+}
+
 impl<'a, T: Clone + JudgeReal> Iterator for LogIterator<'a, T> {
     type Item = T;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let record =
-            self.inner_mmap[(self.index * size_of::<T>())..].as_ref() as *const [u8] as *mut T;
-        unsafe {
-            if (*record).is_real() {
+        let start = HEADER_LENGTH + self.index * self.entry_length;
+        if start + self.entry_length > self.data.len() {
+            return None;
+        }
+
+        let slot = &self.data[start..start + self.entry_length];
+        let header = &self.data[0..HEADER_LENGTH];
+        match read_entry::<T>(slot, header, self.index, self.encryptor) {
+            Some(record) if record.is_real() => {
                 self.index += 1;
-                return Some((*record).clone());
-            } else {
-                return None;
+                Some(record)
             }
+            _ => None,
         }
     }
 }
 
-pub struct LogManager<T: JudgeReal + Clone> {
+pub struct LogManager<T: JudgeReal + LogFormat + Clone> {
     inner_mmap: MmapMut,
+    file: std::fs::File,
     index: AtomicUsize,
+    length: usize,
     phantom: PhantomData<T>,
     path: String,
+    encryptor: Option<Arc<dyn Encryptor>>,
 }
 
-impl<T: JudgeReal + Clone> LogManager<T> {
-    pub fn create_new(path: &str, length: usize) -> Result<LogManager<T>> {
+impl<T: JudgeReal + LogFormat + Clone> LogManager<T> {
+    pub fn create_new(
+        path: &str,
+        length: usize,
+        encryptor: Option<&Arc<dyn Encryptor>>,
+    ) -> Result<LogManager<T>> {
         std::fs::OpenOptions::new()
             .create(true)
             .write(true)
             .truncate(true)
             .open(path)?;
 
-        LogManager::open(path, length)
+        LogManager::open(path, length, encryptor)
     }
 
-    pub fn open(path: &str, length: usize) -> Result<LogManager<T>> {
+    /// Open a log holding `length` entries, or more if the file is already larger. If `encryptor` is
+    /// given, every entry is encrypted separately, and takes `overhead` more bytes (instead of its
+    /// checksum). The header is written into a new (empty) log, and checked in an existing one.
+    pub fn open(
+        path: &str,
+        length: usize,
+        encryptor: Option<&Arc<dyn Encryptor>>,
+    ) -> Result<LogManager<T>> {
         log::info!("Opening log from {:#?}", path);
 
+        let entry_length = size_of::<T>() + overhead(encryptor.map(Arc::as_ref));
+        let file_length = std::fs::metadata(path).map_or(0, |metadata| metadata.len() as usize);
+        let length = std::cmp::max(
+            length,
+            file_length.saturating_sub(HEADER_LENGTH) / entry_length,
+        );
         {
             let file = agilulf_fs::File::open(path)?;
-            file.fallocate(0, (size_of::<T>() * length) as i64)?;
+            file.fallocate(0, (HEADER_LENGTH + entry_length * length) as i64)?;
         }
 
         let file = std::fs::OpenOptions::new()
@@ -76,45 +174,119 @@ impl<T: JudgeReal + Clone> LogManager<T> {
             .create(true)
             .open(path)?;
 
-        let mmap = unsafe { MmapOptions::new().map_mut(&file)? };
-        let records = mmap.as_ref() as *const [u8] as *mut [T];
+        let mut mmap = unsafe { MmapOptions::new().map_mut(&file)? };
+        let format = format::<T>();
+        if mmap[0..HEADER_LENGTH].iter().all(|byte| *byte == 0) {
+            mmap[0..FORMAT_LENGTH].copy_from_slice(&format);
+            mmap[FORMAT_LENGTH..HEADER_LENGTH].copy_from_slice(&random_id()?.to_le_bytes());
+        } else if mmap[0..FORMAT_LENGTH] != format {
+            log::error!(
+                "{} is not a log of this format (version {})",
+                path,
+                T::VERSION
+            );
+            return Err(LogError::IncompatibleFormat);
+        }
 
         let mut index = 0;
-        unsafe {
-            let records = &(*records);
-            loop {
-                if records[index].is_real() {
-                    index += 1;
-                } else {
+        while index < length {
+            let start = HEADER_LENGTH + index * entry_length;
+            let slot = &mmap[start..start + entry_length];
+            let header = &mmap[0..HEADER_LENGTH];
+            match read_entry::<T>(slot, header, index, encryptor.map(Arc::as_ref)) {
+                Some(record) if record.is_real() => index += 1,
+                None if slot.iter().any(|byte| *byte != 0) => {
+                    log::warn!("Entry {} of {} is corrupted", index, path);
                     break;
                 }
+                _ => break,
             }
         }
 
         Ok(Self {
             inner_mmap: mmap,
+            file,
             index: AtomicUsize::new(index),
+            length,
             phantom: PhantomData,
             path: path.to_string(),
+            encryptor: encryptor.cloned(),
         })
     }
 
     pub fn iter(&self) -> LogIterator<T> {
         LogIterator {
-            inner_mmap: &self.inner_mmap,
+            data: &self.inner_mmap,
             index: 0,
+            entry_length: self.entry_length(),
+            encryptor: self.encryptor.as_ref().map(Arc::as_ref),
             phantom: PhantomData,
         }
     }
 
+    /// How many entries have been written into this log.
+    pub fn len(&self) -> usize {
+        self.index.load(Ordering::SeqCst)
+    }
+
+    /// How many entries this log can hold.
+    pub fn capacity(&self) -> usize {
+        self.length
+    }
+
+    /// Bytes added to every entry by encryption or its checksum.
+    pub fn overhead(&self) -> usize {
+        overhead(self.encryptor.as_ref().map(Arc::as_ref))
+    }
+
+    /// Bytes taken by every entry on disk.
+    pub fn entry_length(&self) -> usize {
+        size_of::<T>() + self.overhead()
+    }
+
     pub fn add_entry(&self, data: T) {
         let index = self.index.fetch_add(1, Ordering::SeqCst);
+        let start = HEADER_LENGTH + index * self.entry_length();
 
         unsafe {
-            let record =
-                self.inner_mmap[(index * size_of::<T>())..].as_ref() as *const [u8] as *mut T;
-            (*record).clone_from(&data);
+            match &self.encryptor {
+                Some(encryptor) => {
+                    let data =
+                        std::slice::from_raw_parts(&data as *const T as *const u8, size_of::<T>());
+                    let aad = entry_aad(&self.inner_mmap[0..HEADER_LENGTH], index);
+                    let data = encryptor.encrypt(data, &aad);
+                    let slot = self.inner_mmap[start..start + data.len()].as_ref() as *const [u8]
+                        as *mut [u8];
+                    (*slot).copy_from_slice(&data);
+                }
+                None => {
+                    let record = self.inner_mmap[start..].as_ref() as *const [u8] as *mut T;
+                    (*record).clone_from(&data);
+                    let end = start + size_of::<T>();
+                    let checksum = crc32fast::hash(&self.inner_mmap[start..end]).to_le_bytes();
+                    let slot = self.inner_mmap[end..end + CHECKSUM_LENGTH].as_ref() as *const [u8]
+                        as *mut [u8];
+                    (*slot).copy_from_slice(&checksum);
+                }
+            }
+        }
+    }
+
+    /// Erase entries from `index`, so the next entry is added there.
+    pub fn truncate(&mut self, index: usize) {
+        let start = HEADER_LENGTH + index * self.entry_length();
+        let end = HEADER_LENGTH + self.len() * self.entry_length();
+        for byte in self.inner_mmap[start..end].iter_mut() {
+            *byte = 0;
         }
+        self.index.store(index, Ordering::SeqCst);
+    }
+
+    /// Write entries added before to disk. It can be called after the log is renamed.
+    pub fn sync(&self) -> Result<()> {
+        self.inner_mmap.flush()?;
+        self.file.sync_all()?;
+        Ok(())
     }
 
     pub fn rename(&self, new_path: &str) -> Result<()> {
@@ -122,3 +294,63 @@ impl<T: JudgeReal + Clone> LogManager<T> {
         Ok(())
     }
 }
+
+/// A log mapped read-only, e.g. by a tool reading files of a database. Unlike `LogManager::open`, the
+/// file is never created, extended or written, and an empty file is rejected.
+pub struct LogReader<T: JudgeReal + LogFormat + Clone> {
+    inner_mmap: Mmap,
+    phantom: PhantomData<T>,
+    encryptor: Option<Arc<dyn Encryptor>>,
+}
+
+impl<T: JudgeReal + LogFormat + Clone> LogReader<T> {
+    pub fn open(path: &str, encryptor: Option<&Arc<dyn Encryptor>>) -> Result<LogReader<T>> {
+        let file = std::fs::File::open(path)?;
+        if (file.metadata()?.len() as usize) < HEADER_LENGTH {
+            return Err(LogError::IncompatibleFormat);
+        }
+
+        let mmap = unsafe { MmapOptions::new().map(&file)? };
+        if mmap[0..FORMAT_LENGTH] != format::<T>() {
+            log::error!(
+                "{} is not a log of this format (version {})",
+                path,
+                T::VERSION
+            );
+            return Err(LogError::IncompatibleFormat);
+        }
+
+        Ok(Self {
+            inner_mmap: mmap,
+            phantom: PhantomData,
+            encryptor: encryptor.cloned(),
+        })
+    }
+
+    pub fn iter(&self) -> LogIterator<T> {
+        LogIterator {
+            data: &self.inner_mmap,
+            index: 0,
+            entry_length: self.entry_length(),
+            encryptor: self.encryptor.as_ref().map(Arc::as_ref),
+            phantom: PhantomData,
+        }
+    }
+
+    fn entry_length(&self) -> usize {
+        size_of::<T>() + overhead(self.encryptor.as_ref().map(Arc::as_ref))
+    }
+
+    /// Index of the first entry which is written but cannot be read, e.g. a bit of it is flipped, or it
+    /// was being written when the process crashed. Entries after it are never read.
+    pub fn corrupted_entry(&self) -> Option<usize> {
+        let index = self.iter().count();
+        let start = HEADER_LENGTH + index * self.entry_length();
+        let slot = self.inner_mmap.get(start..start + self.entry_length())?;
+        if slot.iter().any(|byte| *byte != 0) {
+            Some(index)
+        } else {
+            None
+        }
+    }
+}
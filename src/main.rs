@@ -5,8 +5,13 @@ extern crate clap;
 extern crate env_logger;
 extern crate log;
 
-use agilulf::{DatabaseBuilder, MemDatabase, Server};
+use agilulf::{
+    AppendOperator, ChaCha20Poly1305Encryptor, DatabaseBuilder, MaxOperator, MemDatabase,
+    MergeOperator, RateLimiter, Server, U64AddOperator, UniversalCompaction,
+};
 use clap::{App, Arg};
+use std::sync::Arc;
+use std::time::Duration;
 
 fn main() {
     env_logger::init();
@@ -20,6 +25,22 @@ fn main() {
                 .long("mem")
                 .help("Run memory only database"),
         )
+        .arg(
+            Arg::with_name("sweep_interval")
+                .long("sweep_interval")
+                .value_name("SECONDS")
+                .default_value("60")
+                .help("Set how often expired keys are freed in memory only database")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("merge_operator")
+                .long("merge_operator")
+                .value_name("MERGE_OPERATOR")
+                .possible_values(&["add", "append", "max"])
+                .help("Set how MERGE requests are applied on values")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("base_dir")
                 .long("base_dir")
@@ -28,11 +49,82 @@ fn main() {
                 .help("Set the base directory of database")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("wal_dir")
+                .long("wal_dir")
+                .value_name("WAL_DIR")
+                .help("Write log into this directory instead of the base directory")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("data_path")
+                .long("data_path")
+                .value_name("PATH:TARGET_SIZE")
+                .help("Put SSTables into this directory up to the target size (can be repeated)")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
         .arg(
             Arg::with_name("forget")
                 .long("forget")
                 .help("Ignore the existed log and sstables"),
         )
+        .arg(
+            Arg::with_name("max_open_files")
+                .long("max_open_files")
+                .value_name("MAX_OPEN_FILES")
+                .default_value("1000")
+                .help("Set how many SSTables can be opened at the same time")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("flush_threads")
+                .long("flush_threads")
+                .value_name("FLUSH_THREADS")
+                .default_value("1")
+                .help("Set how many threads only flush memtables")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("compaction_threads")
+                .long("compaction_threads")
+                .value_name("COMPACTION_THREADS")
+                .default_value("2")
+                .help("Set how many threads compact SSTables (and flush memtables first)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rate_limit")
+                .long("rate_limit")
+                .value_name("BYTES_PER_SECOND")
+                .default_value("0")
+                .help("Limit how fast flushes and compactions write (0 for no limit)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("wal_archive_dir")
+                .long("wal_archive_dir")
+                .value_name("WAL_ARCHIVE_DIR")
+                .help("Keep logs in this directory after they are written into SSTables")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("compaction_strategy")
+                .long("compaction_strategy")
+                .value_name("COMPACTION_STRATEGY")
+                .possible_values(&["leveled", "universal"])
+                .default_value("leveled")
+                .help("Set how SSTables are compacted")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("key_file")
+                .long("key_file")
+                .value_name("KEY_FILE")
+                .help("Encrypt files of database with the key in this file")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("addr")
                 .long("addr")
@@ -44,13 +136,80 @@ fn main() {
         .get_matches();
 
     let address = matches.value_of("addr").unwrap_or("127.0.0.1:3421");
+    let merge_operator: Option<Arc<dyn MergeOperator>> = match matches.value_of("merge_operator") {
+        Some("add") => Some(Arc::new(U64AddOperator)),
+        Some("append") => Some(Arc::new(AppendOperator)),
+        Some("max") => Some(Arc::new(MaxOperator)),
+        _ => None,
+    };
     let server = match matches.is_present("mem") {
         true => {
             log::info!("Build memory database");
-            Server::new(address, MemDatabase::default())
+            let sweep_interval = matches.value_of("sweep_interval").unwrap_or("60");
+            let sweep_interval = match sweep_interval.parse() {
+                Ok(sweep_interval) => Duration::from_secs(sweep_interval),
+                Err(err) => {
+                    println!("Invalid sweep_interval: {:?}", err);
+                    return;
+                }
+            };
+
+            let database = Arc::new(MemDatabase::new(merge_operator));
+            MemDatabase::spawn_sweeper(&database, sweep_interval);
+            Server::new(address, database)
         }
         false => {
             log::info!("Build database");
+            let max_open_files = matches.value_of("max_open_files").unwrap_or("1000");
+            let max_open_files = match max_open_files.parse() {
+                Ok(max_open_files) => max_open_files,
+                Err(err) => {
+                    println!("Invalid max_open_files: {:?}", err);
+                    return;
+                }
+            };
+            let flush_threads = matches.value_of("flush_threads").unwrap_or("1");
+            let flush_threads = match flush_threads.parse() {
+                Ok(flush_threads) => flush_threads,
+                Err(err) => {
+                    println!("Invalid flush_threads: {:?}", err);
+                    return;
+                }
+            };
+            let compaction_threads = matches.value_of("compaction_threads").unwrap_or("2");
+            let compaction_threads = match compaction_threads.parse() {
+                Ok(compaction_threads) => compaction_threads,
+                Err(err) => {
+                    println!("Invalid compaction_threads: {:?}", err);
+                    return;
+                }
+            };
+
+            let rate_limit = matches.value_of("rate_limit").unwrap_or("0");
+            let rate_limiter = RateLimiter::unlimited();
+            match rate_limit.parse() {
+                Ok(rate_limit) => rate_limiter.set_bytes_per_second(rate_limit),
+                Err(err) => {
+                    println!("Invalid rate_limit: {:?}", err);
+                    return;
+                }
+            };
+
+            let mut data_paths = Vec::new();
+            for data_path in matches.values_of("data_path").into_iter().flatten() {
+                let mut parts = data_path.rsplitn(2, ':');
+                let (target_size, path) = (parts.next(), parts.next());
+                match (path, target_size.map(str::parse::<u64>)) {
+                    (Some(path), Some(Ok(target_size))) => {
+                        data_paths.push((path.to_string(), target_size))
+                    }
+                    _ => {
+                        println!("Invalid data_path: {:?}", data_path);
+                        return;
+                    }
+                }
+            }
+
             let mut builder = DatabaseBuilder::default();
             builder
                 .base_dir(
@@ -59,7 +218,34 @@ fn main() {
                         .unwrap_or("/var/tmp/agilulf")
                         .to_string(),
                 )
-                .restore(!matches.is_present("forget"));
+                .restore(!matches.is_present("forget"))
+                .max_open_files(max_open_files)
+                .background_threads(flush_threads, compaction_threads)
+                .rate_limiter(Arc::new(rate_limiter));
+            if let Some(wal_dir) = matches.value_of("wal_dir") {
+                builder.wal_dir(wal_dir.to_string());
+            }
+            for (path, target_size) in data_paths {
+                builder.data_path(path, target_size);
+            }
+            if let Some(wal_archive_dir) = matches.value_of("wal_archive_dir") {
+                builder.wal_archive_dir(wal_archive_dir.to_string());
+            }
+            if let Some(merge_operator) = merge_operator {
+                builder.merge_operator(merge_operator);
+            }
+            if matches.value_of("compaction_strategy") == Some("universal") {
+                builder.compaction_strategy(Arc::new(UniversalCompaction::default()));
+            }
+            if let Some(key_file) = matches.value_of("key_file") {
+                match ChaCha20Poly1305Encryptor::from_key_file(key_file) {
+                    Ok(encryptor) => builder.encryptor(Arc::new(encryptor)),
+                    Err(err) => {
+                        println!("Error occurred during reading key file: {:?}", err);
+                        return;
+                    }
+                };
+            }
 
             match builder.build() {
                 Ok(db) => Server::new(address, db),
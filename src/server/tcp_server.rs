@@ -14,7 +14,7 @@ use agilulf_protocol::{AsyncReadBuffer, AsyncWriteBuffer};
 use agilulf_protocol::{ProtocolError, Result as ProtocolResult};
 
 use crate::storage::AsyncDatabase;
-use agilulf_protocol::Command;
+use agilulf_protocol::{Command, Slice};
 use std::sync::Arc;
 
 /// A simple TCP server constructed by a foreign database with the help of `agilulf_protocol`
@@ -98,6 +98,39 @@ async fn handle_stream(stream: TcpStream, database: Arc<dyn AsyncDatabase>) -> R
                     Command::DELETE(command) => {
                         ProtocolResult::Ok(database.delete(command.key).await.into())
                     }
+                    Command::PUT_EXPIRE(command) => ProtocolResult::Ok(
+                        database
+                            .put_expire(command.key, command.value, command.expire_at)
+                            .await
+                            .into(),
+                    ),
+                    Command::MERGE(command) => ProtocolResult::Ok(
+                        database.merge(command.key, command.operand).await.into(),
+                    ),
+                    Command::DELETE_RANGE(command) => ProtocolResult::Ok(
+                        database
+                            .delete_range(command.start, command.end)
+                            .await
+                            .into(),
+                    ),
+                    Command::APPROXIMATE_SIZE(command) => ProtocolResult::Ok(
+                        database
+                            .approximate(command.start, command.end)
+                            .await
+                            .map(|(bytes, count)| {
+                                vec![
+                                    Slice(bytes.to_string().into_bytes()),
+                                    Slice(count.to_string().into_bytes()),
+                                ]
+                            })
+                            .into(),
+                    ),
+                    Command::SET_RATE_LIMIT(command) => ProtocolResult::Ok(
+                        database
+                            .set_rate_limit(command.bytes_per_second)
+                            .await
+                            .into(),
+                    ),
                 },
                 Err(err) => ProtocolResult::Ok(err.into()),
             }
@@ -0,0 +1,241 @@
+use super::error::StorageResult;
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Priority of a job in `BackgroundPool`. Flushes are `High`, so they are never queued behind
+/// compactions, which are `Low`.
+///
+/// Priority only orders queued jobs. A running compaction is never interrupted for a flush, which runs
+/// on a flush thread instead (see `BackgroundPool`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Priority {
+    High,
+    Low,
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+struct Queues {
+    high: VecDeque<Job>,
+    low: VecDeque<Job>,
+    /// Jobs queued by `Scheduler::schedule_after`, with the time they're due.
+    delayed: Vec<(Instant, Priority, Job)>,
+    shutdown: bool,
+}
+
+impl Queues {
+    fn push(&mut self, priority: Priority, job: Job) {
+        match priority {
+            Priority::High => self.high.push_back(job),
+            Priority::Low => self.low.push_back(job),
+        }
+    }
+
+    /// Queue delayed jobs which are due, and return when the next one is.
+    fn queue_due_jobs(&mut self) -> Option<Instant> {
+        let now = Instant::now();
+        let mut index = 0;
+        while index < self.delayed.len() {
+            if self.delayed[index].0 <= now {
+                let (_, priority, job) = self.delayed.remove(index);
+                self.push(priority, job);
+            } else {
+                index += 1;
+            }
+        }
+
+        self.delayed.iter().map(|(due, _, _)| *due).min()
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    queues: Mutex<Queues>,
+    condvar: Condvar,
+}
+
+impl Shared {
+    /// Wait for the next job a thread can run. `None` means the pool is dropped.
+    fn next_job(&self, low: bool) -> Option<Job> {
+        let mut queues = self.queues.lock().unwrap();
+        loop {
+            if queues.shutdown {
+                return None;
+            }
+            let next_due = queues.queue_due_jobs();
+            if let Some(job) = queues.high.pop_front() {
+                return Some(job);
+            }
+            if low {
+                if let Some(job) = queues.low.pop_front() {
+                    return Some(job);
+                }
+            }
+            queues = match next_due {
+                Some(due) => {
+                    let now = Instant::now();
+                    let timeout = if due > now { due - now } else { Duration::from_millis(0) };
+                    self.condvar.wait_timeout(queues, timeout).unwrap().0
+                }
+                None => self.condvar.wait(queues).unwrap(),
+            };
+        }
+    }
+}
+
+/// A handle to put jobs into `BackgroundPool`. It's held by background workers of every column
+/// family, so a job can schedule more jobs (e.g. compactions after a flush).
+#[derive(Clone)]
+pub struct Scheduler {
+    shared: Arc<Shared>,
+}
+
+impl Scheduler {
+    /// Queue a job. It's dropped without running if the pool is already dropped.
+    pub fn schedule<F: FnOnce() + Send + 'static>(&self, priority: Priority, job: F) {
+        let mut queues = self.shared.queues.lock().unwrap();
+        if queues.shutdown {
+            return;
+        }
+        queues.push(priority, box job);
+        self.shared.condvar.notify_all();
+    }
+
+    /// Queue a job after `delay`, e.g. to retry a failed one later.
+    pub fn schedule_after<F: FnOnce() + Send + 'static>(
+        &self,
+        priority: Priority,
+        delay: Duration,
+        job: F,
+    ) {
+        let mut queues = self.shared.queues.lock().unwrap();
+        if queues.shutdown {
+            return;
+        }
+        queues
+            .delayed
+            .push((Instant::now() + delay, priority, box job));
+        self.shared.condvar.notify_all();
+    }
+}
+
+/// Threads running flushes and compactions of every column family.
+///
+/// `flush_threads` threads only run `High` jobs, so a flush can start while every other thread is
+/// compacting. `compaction_threads` threads run `Low` jobs, but take a `High` job first if there is
+/// one. Dropping the pool waits for running jobs, and drops jobs which have not started.
+pub struct BackgroundPool {
+    scheduler: Scheduler,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl BackgroundPool {
+    pub fn new(flush_threads: usize, compaction_threads: usize) -> StorageResult<BackgroundPool> {
+        let shared = Arc::new(Shared::default());
+
+        let mut threads = Vec::new();
+        let kinds = [("flush", false, flush_threads), ("compaction", true, compaction_threads)];
+        for &(name, low, count) in kinds.iter() {
+            for _ in 0..std::cmp::max(count, 1) {
+                let shared = shared.clone();
+                threads.push(
+                    std::thread::Builder::new()
+                        .name(format!("background_{}_{}", name, threads.len()))
+                        .spawn(move || {
+                            while let Some(job) = shared.next_job(low) {
+                                job();
+                            }
+                        })?,
+                );
+            }
+        }
+
+        Ok(BackgroundPool {
+            scheduler: Scheduler { shared },
+            threads,
+        })
+    }
+
+    pub fn scheduler(&self) -> Scheduler {
+        self.scheduler.clone()
+    }
+}
+
+impl Drop for BackgroundPool {
+    fn drop(&mut self) {
+        {
+            let mut queues = self.scheduler.shared.queues.lock().unwrap();
+            queues.shutdown = true;
+            queues.high.clear();
+            queues.low.clear();
+            queues.delayed.clear();
+            self.scheduler.shared.condvar.notify_all();
+        }
+
+        for thread in self.threads.drain(..) {
+            if thread.join().is_err() {
+                log::error!("Background thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::sync::Barrier;
+
+    #[test]
+    fn flush_preempts_compaction() {
+        let pool = BackgroundPool::new(1, 2).unwrap();
+        let scheduler = pool.scheduler();
+
+        // Both compactions run at the same time, or neither of them passes the barrier.
+        let barrier = Arc::new(Barrier::new(3));
+        let (release, released) = channel::<()>();
+        let released = Arc::new(Mutex::new(released));
+        for _ in 0..2 {
+            let barrier = barrier.clone();
+            let released = released.clone();
+            scheduler.schedule(Priority::Low, move || {
+                barrier.wait();
+                let _ = released.lock().unwrap().recv();
+            });
+        }
+        barrier.wait();
+
+        // Every compaction thread is busy, but a flush still runs.
+        let (done, finished) = channel();
+        scheduler.schedule(Priority::High, move || done.send(()).unwrap());
+        finished.recv_timeout(Duration::from_secs(10)).unwrap();
+
+        // Another compaction waits for a free compaction thread.
+        let (done, finished) = channel();
+        scheduler.schedule(Priority::Low, move || done.send(()).unwrap());
+        assert!(finished.recv_timeout(Duration::from_millis(100)).is_err());
+        release.send(()).unwrap();
+        finished.recv_timeout(Duration::from_secs(10)).unwrap();
+
+        release.send(()).unwrap();
+        drop(pool);
+    }
+
+    #[test]
+    fn delayed_job() {
+        let pool = BackgroundPool::new(1, 1).unwrap();
+        let scheduler = pool.scheduler();
+
+        let (done, finished) = channel();
+        let started = Instant::now();
+        scheduler.schedule_after(Priority::Low, Duration::from_millis(200), move || {
+            done.send(()).unwrap()
+        });
+        finished.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,375 @@
+use super::encryption::Encryptor;
+use super::error::{StorageError, StorageResult};
+use super::mem_database::Value;
+use super::sstable::{key_to_array, KEY_LENGTH, VALUE_LENGTH};
+
+use agilulf_protocol::Slice;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// A new blob file is started once the active one is larger than this.
+pub const BLOB_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Every record in a blob file is the column family (in little endian), the length of key, the key
+/// (padded with zero), the length of value (in little endian) and then the value.
+///
+/// In an encrypted database, the header and the value are encrypted separately, and the length in
+/// header is the length of the encrypted value. Both are bound to the id of the file and their offset
+/// in it (see `position_aad`).
+const HEADER_LENGTH: u64 = 4 + 1 + KEY_LENGTH as u64 + 8;
+
+/// Bytes of an encoded `BlobIndex`, which is stored in log and tables instead of the value.
+pub const BLOB_INDEX_LENGTH: usize = 24;
+
+/// Where a value is in blob files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlobIndex {
+    pub file: u64,
+    /// Offset of the value (not the record) in the file.
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Data authenticated with an encrypted header or value at `offset` of a blob file.
+fn position_aad(file: u64, offset: u64) -> [u8; 16] {
+    let mut aad = [0u8; 16];
+    aad[0..8].copy_from_slice(&file.to_le_bytes());
+    aad[8..16].copy_from_slice(&offset.to_le_bytes());
+    aad
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut array = [0u8; 8];
+    array.copy_from_slice(&bytes[0..8]);
+    u64::from_le_bytes(array)
+}
+
+impl BlobIndex {
+    pub fn encode(&self) -> Slice {
+        let mut bytes = Vec::with_capacity(BLOB_INDEX_LENGTH);
+        bytes.extend_from_slice(&self.file.to_le_bytes());
+        bytes.extend_from_slice(&self.offset.to_le_bytes());
+        bytes.extend_from_slice(&self.length.to_le_bytes());
+        Slice(bytes)
+    }
+
+    /// Read an index from the front of `bytes`, which may be padded.
+    pub fn decode(bytes: &[u8]) -> Option<BlobIndex> {
+        if bytes.len() < BLOB_INDEX_LENGTH {
+            return None;
+        }
+        Some(BlobIndex {
+            file: read_u64(&bytes[0..8]),
+            offset: read_u64(&bytes[8..16]),
+            length: read_u64(&bytes[16..24]),
+        })
+    }
+}
+
+/// What `Database::collect_blob_garbage` has done.
+#[derive(Debug, Default)]
+pub struct BlobGcReport {
+    /// Blob files which are rewritten and removed.
+    pub removed_files: usize,
+    /// Live values moved from removed files into the active one.
+    pub rewritten: usize,
+    /// Total size of removed files.
+    pub removed_bytes: u64,
+}
+
+/// A record read from a blob file by `BlobStore::records`.
+pub struct BlobRecord {
+    pub family: u32,
+    pub key: Slice,
+    pub index: BlobIndex,
+}
+
+pub fn blob_path(dir: &str, id: u64) -> PathBuf {
+    Path::new(dir).join(format!("blob.{}", id))
+}
+
+pub fn parse_blob_name(name: &str) -> Option<u64> {
+    if name.starts_with("blob.") {
+        name["blob.".len()..].parse().ok()
+    } else {
+        None
+    }
+}
+
+/// Ids of every blob file in `dir`, in ascending order.
+pub fn blob_file_ids(dir: &str) -> StorageResult<Vec<u64>> {
+    let mut ids = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        if let Some(id) = name.to_str().and_then(parse_blob_name) {
+            ids.push(id);
+        }
+    }
+    ids.sort();
+
+    Ok(ids)
+}
+
+struct ActiveFile {
+    id: u64,
+    file: Option<File>,
+    size: u64,
+}
+
+/// Append only files holding large values, so they are not rewritten by flush and compaction. Files
+/// written before opening are only read (and removed by garbage collection), and new values go to a
+/// new file, which is created on the first append.
+pub struct BlobStore {
+    dir: String,
+    threshold: Option<usize>,
+    encryptor: Option<Arc<dyn Encryptor>>,
+    /// Id of the first file created after opening.
+    first_new_file: u64,
+    active: Mutex<ActiveFile>,
+}
+
+impl BlobStore {
+    /// Values longer than `threshold` are put into blob files. If it's `None`, values are never separated,
+    /// but values separated before can still be read.
+    pub fn open(
+        dir: &str,
+        threshold: Option<usize>,
+        encryptor: Option<Arc<dyn Encryptor>>,
+    ) -> StorageResult<BlobStore> {
+        let next_id = blob_file_ids(dir)?.last().map_or(0, |id| id + 1);
+
+        Ok(BlobStore {
+            dir: dir.to_string(),
+            threshold,
+            encryptor,
+            first_new_file: next_id,
+            active: Mutex::new(ActiveFile {
+                id: next_id,
+                file: None,
+                size: 0,
+            }),
+        })
+    }
+
+    /// Whether a blob file was written before opening. Keys of its values are restored from log or
+    /// tables, so they are padded, while keys of values written after opening are kept as they are
+    /// until they are flushed.
+    pub fn written_before_open(&self, id: u64) -> bool {
+        id < self.first_new_file
+    }
+
+    /// Write values appended to the active file to disk.
+    pub fn sync(&self) -> StorageResult<()> {
+        if let Some(file) = self.active.lock().unwrap().file.as_ref() {
+            file.sync_all()?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether a value should be put into blob files.
+    pub fn separates(&self, value: &Slice) -> bool {
+        match self.threshold {
+            Some(threshold) => value.0.len() > threshold,
+            None => false,
+        }
+    }
+
+    /// Put a value of `family` into blob files if it should be separated, or it's too long for a table.
+    /// Other values are returned as they are.
+    pub fn separate(&self, family: u32, key: &Slice, value: Value) -> StorageResult<Value> {
+        let (slice, expire_at) = match &value {
+            Value::Slice(slice) => (slice, 0),
+            Value::Expiring(slice, expire_at) => (slice, *expire_at),
+            _ => return Ok(value),
+        };
+        if !self.separates(slice) && slice.0.len() <= VALUE_LENGTH {
+            return Ok(value);
+        }
+
+        Ok(Value::Blob(self.append(family, key, slice)?, expire_at))
+    }
+
+    /// Bytes of the header of every record, including the overhead of encryption.
+    pub fn header_length(&self) -> u64 {
+        let overhead = self
+            .encryptor
+            .as_ref()
+            .map(|encryptor| encryptor.overhead());
+        HEADER_LENGTH + overhead.unwrap_or(0) as u64
+    }
+
+    /// Append a value to the active file. It's written before the log record pointing to it.
+    pub fn append(&self, family: u32, key: &Slice, value: &Slice) -> StorageResult<BlobIndex> {
+        let mut active = self.active.lock().unwrap();
+        if active.size >= BLOB_FILE_SIZE {
+            // A sealed file may be collected, which relies on values moved into it being on disk.
+            if let Some(file) = active.file.as_ref() {
+                file.sync_all()?;
+            }
+            active.id += 1;
+            active.file = None;
+            active.size = 0;
+        }
+        if active.file.is_none() {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(blob_path(&self.dir, active.id))?;
+            active.file = Some(file);
+        }
+
+        let record_offset = active.size;
+        let value_offset = record_offset + self.header_length();
+        let encrypted;
+        let value = match &self.encryptor {
+            Some(encryptor) => {
+                encrypted = encryptor.encrypt(&value.0, &position_aad(active.id, value_offset));
+                &encrypted
+            }
+            None => &value.0,
+        };
+        let mut header = Vec::with_capacity(HEADER_LENGTH as usize);
+        header.extend_from_slice(&family.to_le_bytes());
+        header.push(key.0.len() as u8);
+        header.extend_from_slice(&key_to_array(key)?);
+        header.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        if let Some(encryptor) = &self.encryptor {
+            header = encryptor.encrypt(&header, &position_aad(active.id, record_offset));
+        }
+
+        let mut record = header;
+        record.extend_from_slice(value);
+        let written = match active.file.as_mut() {
+            Some(file) => file
+                .write_all(&record)
+                .map_err(|err| (err, file.set_len(record_offset).is_ok())),
+            None => Ok(()),
+        };
+        if let Err((err, truncated)) = written {
+            // A part of the record may be written. It's cut off, so the next value is put at
+            // `active.size`. If it cannot be cut off, the next value goes to a new file, and the
+            // partial record is left at the end of this one, where `records` stops.
+            if !truncated {
+                active.id += 1;
+                active.file = None;
+                active.size = 0;
+            }
+            return Err(err.into());
+        }
+
+        let index = BlobIndex {
+            file: active.id,
+            offset: value_offset,
+            length: value.len() as u64,
+        };
+        active.size += record.len() as u64;
+
+        Ok(index)
+    }
+
+    pub fn read(&self, index: &BlobIndex) -> StorageResult<Slice> {
+        let file = File::open(blob_path(&self.dir, index.file))?;
+        let mut value = vec![0u8; index.length as usize];
+        file.read_exact_at(&mut value, index.offset)?;
+
+        match &self.encryptor {
+            Some(encryptor) => {
+                match encryptor.decrypt(&value, &position_aad(index.file, index.offset)) {
+                    Some(value) => Ok(Slice(value)),
+                    None => {
+                        log::error!(
+                            "Value at {} of blob.{} cannot be decrypted",
+                            index.offset,
+                            index.file
+                        );
+                        Err(StorageError::DecryptionFailed)
+                    }
+                }
+            }
+            None => Ok(Slice(value)),
+        }
+    }
+
+    /// Replace the index of a value in blob files with the value. Other values are returned as they are.
+    pub fn resolve(&self, value: Value) -> StorageResult<Value> {
+        match value {
+            Value::Blob(index, 0) => Ok(Value::Slice(self.read(&index)?)),
+            Value::Blob(index, expire_at) => Ok(Value::Expiring(self.read(&index)?, expire_at)),
+            value => Ok(value),
+        }
+    }
+
+    /// Every record in a blob file, with the size of the file.
+    pub fn records(&self, id: u64) -> StorageResult<(Vec<BlobRecord>, u64)> {
+        let content = std::fs::read(blob_path(&self.dir, id))?;
+
+        let header_length = self.header_length() as usize;
+        let mut records = Vec::new();
+        let mut offset = 0usize;
+        while offset + header_length <= content.len() {
+            let header = &content[offset..offset + header_length];
+            let decrypted;
+            let header = match &self.encryptor {
+                Some(encryptor) => {
+                    match encryptor.decrypt(header, &position_aad(id, offset as u64)) {
+                        Some(header) => {
+                            decrypted = header;
+                            decrypted.as_slice()
+                        }
+                        None => {
+                            log::warn!("Record at {} of blob.{} cannot be decrypted", offset, id);
+                            break;
+                        }
+                    }
+                }
+                None => header,
+            };
+            let mut family = [0u8; 4];
+            family.copy_from_slice(&header[0..4]);
+            let key_length = std::cmp::min(header[4] as usize, KEY_LENGTH);
+            let length = read_u64(&header[5 + KEY_LENGTH..]);
+
+            let value_offset = (offset + header_length) as u64;
+            if value_offset + length > content.len() as u64 {
+                // The last record may be half written before crash, and no log record points to it.
+                log::warn!("blob.{} is truncated at {}", id, offset);
+                break;
+            }
+            records.push(BlobRecord {
+                family: u32::from_le_bytes(family),
+                key: Slice(header[5..5 + key_length].to_vec()),
+                index: BlobIndex {
+                    file: id,
+                    offset: value_offset,
+                    length,
+                },
+            });
+            offset = (value_offset + length) as usize;
+        }
+
+        Ok((records, content.len() as u64))
+    }
+
+    /// Blob files which are not appended any more, so they can be collected.
+    pub fn sealed_files(&self) -> StorageResult<Vec<u64>> {
+        let active = self.active.lock().unwrap().id;
+        Ok(blob_file_ids(&self.dir)?
+            .into_iter()
+            .filter(|id| *id != active)
+            .collect())
+    }
+
+    pub fn remove(&self, id: u64) -> StorageResult<()> {
+        if id == self.active.lock().unwrap().id {
+            log::error!("Cannot remove blob.{} which is being written", id);
+            return Err(StorageError::BlobFileInUse);
+        }
+        std::fs::remove_file(blob_path(&self.dir, id))?;
+
+        Ok(())
+    }
+}
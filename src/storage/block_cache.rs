@@ -0,0 +1,140 @@
+use super::mem_database::Value;
+
+use agilulf_protocol::Slice;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Records of a block, decrypted and decompressed.
+pub type Block = Vec<(Slice, Value)>;
+
+struct CachedBlock {
+    block: Arc<Block>,
+    charge: usize,
+    last_used: u64,
+}
+
+struct BlockCacheInner {
+    blocks: HashMap<(u64, usize), CachedBlock>,
+    lru: BTreeMap<u64, (u64, usize)>,
+    tick: u64,
+    usage: usize,
+}
+
+impl BlockCacheInner {
+    fn remove(&mut self, key: (u64, usize)) {
+        if let Some(cached) = self.blocks.remove(&key) {
+            self.lru.remove(&cached.last_used);
+            self.usage -= cached.charge;
+        }
+    }
+}
+
+/// An LRU cache of decoded blocks of compressed or encrypted tables, shared by every table of a
+/// database.
+///
+/// A block is found by the id given to its table when the table is opened and its index in the table,
+/// so blocks of a reopened table are never confused with old ones. Every block is charged by the length
+/// of its records, and the least recently used blocks are dropped when the total charge is larger than
+/// `capacity`. Blocks of a table which has been closed are never used again, and leave the cache in
+/// the same way.
+pub struct BlockCache {
+    capacity: usize,
+    next_id: AtomicU64,
+    inner: Mutex<BlockCacheInner>,
+}
+
+impl BlockCache {
+    /// A cache of `capacity` bytes. Nothing is cached if it's `0`.
+    pub fn new(capacity: usize) -> BlockCache {
+        BlockCache {
+            capacity,
+            next_id: AtomicU64::new(0),
+            inner: Mutex::new(BlockCacheInner {
+                blocks: HashMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+                usage: 0,
+            }),
+        }
+    }
+
+    /// A new id for an opened table.
+    pub fn new_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    pub fn get(&self, table: u64, index: usize) -> Option<Arc<Block>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        let cached = inner.blocks.get_mut(&(table, index))?;
+        let last_used = std::mem::replace(&mut cached.last_used, tick);
+        let block = cached.block.clone();
+        inner.lru.remove(&last_used);
+        inner.lru.insert(tick, (table, index));
+
+        Some(block)
+    }
+
+    pub fn insert(&self, table: u64, index: usize, block: Arc<Block>, charge: usize) {
+        if charge > self.capacity {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove((table, index));
+        while inner.usage + charge > self.capacity {
+            let oldest = match inner.lru.iter().next() {
+                Some((_, key)) => *key,
+                None => break,
+            };
+            inner.remove(oldest);
+        }
+
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.usage += charge;
+        inner.blocks.insert(
+            (table, index),
+            CachedBlock {
+                block,
+                charge,
+                last_used: tick,
+            },
+        );
+        inner.lru.insert(tick, (table, index));
+    }
+
+    /// Bytes of blocks in cache.
+    pub fn usage(&self) -> usize {
+        self.inner.lock().unwrap().usage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evict_by_charge() {
+        let cache = BlockCache::new(100);
+        let table = cache.new_id();
+        for index in 0..3 {
+            cache.insert(table, index, Arc::new(Vec::new()), 40);
+        }
+        assert_eq!(cache.usage(), 80);
+        assert!(cache.get(table, 0).is_none());
+        assert!(cache.get(table, 1).is_some());
+
+        // The second block was used more recently than the third one.
+        cache.insert(table, 3, Arc::new(Vec::new()), 40);
+        assert!(cache.get(table, 1).is_some());
+        assert!(cache.get(table, 2).is_none());
+
+        cache.insert(table, 4, Arc::new(Vec::new()), 200);
+        assert!(cache.get(table, 4).is_none());
+        assert!(cache.get(cache.new_id(), 1).is_none());
+    }
+}
@@ -0,0 +1,233 @@
+use super::blob::BlobStore;
+use super::column_family::ColumnFamily;
+use super::database::resolve_blob;
+use super::database_log::{DatabaseLog, DatabaseLogReader, LogRecord};
+use super::encryption::Encryptor;
+use super::error::{StorageError, StorageResult};
+use super::wal_archive::open_archived_log;
+
+use agilulf_protocol::Command;
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use futures::Stream;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// A write sent to subscribers: its sequence number, column family and command.
+pub type Change = (u64, ColumnFamily, Command);
+
+/// How many new writes can wait in the channel of a subscriber. A subscriber falling further behind is
+/// dropped, so a slow one never holds writes in memory without limit or blocks writers.
+pub const CHANGE_BUFFER: usize = 4096;
+
+/// The side of a subscription kept by `Database`, which new writes are sent to while committing.
+pub struct Subscriber {
+    sender: Sender<Change>,
+    lagged: Arc<AtomicBool>,
+}
+
+impl Subscriber {
+    /// Send a change without waiting. `false` if the subscriber is gone or has fallen behind, and
+    /// should be removed. A subscriber which has fallen behind sees `StorageError::ChangesLagged`.
+    pub fn send(&mut self, change: Change) -> bool {
+        match self.sender.try_send(change) {
+            Ok(()) => true,
+            Err(err) => {
+                if err.is_full() {
+                    self.lagged.store(true, Ordering::SeqCst);
+                }
+                false
+            }
+        }
+    }
+}
+
+/// A log which writes before subscribing are read from.
+pub enum HistoryLog {
+    Current(Arc<DatabaseLog>),
+    Frozen(DatabaseLogReader),
+    /// Archived logs are opened only when they're read.
+    Archived(PathBuf),
+}
+
+/// Writes before subscribing, read from logs one by one in order of sequence number, so only
+/// records of one log are kept in memory. Values in blob files are read as writes are sent.
+pub struct History {
+    logs: VecDeque<HistoryLog>,
+    records: std::vec::IntoIter<LogRecord>,
+    next_sequence: u64,
+    last_sequence: u64,
+    families: Vec<ColumnFamily>,
+    blob_store: Arc<BlobStore>,
+    encryptor: Option<Arc<dyn Encryptor>>,
+}
+
+impl History {
+    /// Writes from `from_sequence` to `last_sequence` in `logs`, which are given with their first
+    /// sequence numbers. The same log may be given twice (e.g. it's found in archive after it's
+    /// opened as a frozen log), and only the first one is read. Writes of column families not in
+    /// `families` are skipped.
+    pub fn new(
+        mut logs: Vec<(u64, HistoryLog)>,
+        from_sequence: u64,
+        last_sequence: u64,
+        families: Vec<ColumnFamily>,
+        blob_store: Arc<BlobStore>,
+        encryptor: Option<Arc<dyn Encryptor>>,
+    ) -> History {
+        logs.sort_by_key(|(first_sequence, _)| *first_sequence);
+        logs.dedup_by_key(|(first_sequence, _)| *first_sequence);
+
+        History {
+            logs: logs.into_iter().map(|(_, log)| log).collect(),
+            records: Vec::new().into_iter(),
+            next_sequence: from_sequence,
+            last_sequence,
+            families,
+            blob_store,
+            encryptor,
+        }
+    }
+
+    /// The sequence number of the first record left, which may be a marker of ingested tables.
+    pub fn first_sequence(&mut self) -> StorageResult<Option<u64>> {
+        self.read_logs()?;
+        Ok(self
+            .records
+            .as_slice()
+            .first()
+            .map(|record| record.sequence))
+    }
+
+    /// Read logs until one of them has records left.
+    fn read_logs(&mut self) -> StorageResult<()> {
+        while self.records.as_slice().is_empty() {
+            let log = match self.logs.pop_front() {
+                Some(log) => log,
+                None => return Ok(()),
+            };
+            let (next_sequence, last_sequence) = (self.next_sequence, self.last_sequence);
+            let in_range = |record: &LogRecord| {
+                record.sequence >= next_sequence && record.sequence <= last_sequence
+            };
+            let mut records: Vec<LogRecord> = match log {
+                HistoryLog::Current(log) => log.records().filter(in_range).collect(),
+                HistoryLog::Frozen(log) => log.records().filter(in_range).collect(),
+                HistoryLog::Archived(path) => open_archived_log(&path, self.encryptor.as_ref())?
+                    .records()
+                    .filter(in_range)
+                    .collect(),
+            };
+            records.sort_by_key(|record| record.sequence);
+            self.records = records.into_iter();
+        }
+
+        Ok(())
+    }
+}
+
+impl Iterator for History {
+    type Item = StorageResult<Change>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Err(err) = self.read_logs() {
+                self.logs.clear();
+                return Some(Err(err));
+            }
+            let record = self.records.next()?;
+            self.next_sequence = record.sequence + 1;
+            let family = self
+                .families
+                .iter()
+                .find(|family| family.id() == record.family);
+            // Markers of ingested tables belong to no column family.
+            let family = match family {
+                Some(family) => family.clone(),
+                None => continue,
+            };
+            let sequence = record.sequence;
+            let command = resolve_blob(&self.blob_store, record);
+            return Some(command.map(|command| (sequence, family, command)));
+        }
+    }
+}
+
+/// Writes returned by `Database::subscribe_changes`: writes read from logs first, and then new writes
+/// in commit order.
+///
+/// If the subscriber falls more than `CHANGE_BUFFER` writes behind, the stream ends with
+/// `StorageError::ChangesLagged`. It can subscribe again from the sequence number after the last
+/// change it has received. If a write cannot be read from logs or blob files, the stream ends with
+/// the error.
+pub struct ChangeStream {
+    history: Option<History>,
+    receiver: Receiver<Change>,
+    lagged: Arc<AtomicBool>,
+    ended: bool,
+}
+
+impl ChangeStream {
+    /// Send `history` before new writes.
+    pub fn set_history(&mut self, history: History) {
+        self.history = Some(history);
+    }
+}
+
+impl Stream for ChangeStream {
+    type Item = StorageResult<Change>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if let Some(history) = &mut this.history {
+            match history.next() {
+                Some(Ok(change)) => return Poll::Ready(Some(Ok(change))),
+                Some(Err(err)) => {
+                    this.history = None;
+                    this.receiver.close();
+                    this.ended = true;
+                    return Poll::Ready(Some(Err(err)));
+                }
+                None => this.history = None,
+            }
+        }
+        // The receiver cannot be polled again after it ends.
+        if this.ended {
+            return Poll::Ready(None);
+        }
+
+        match Pin::new(&mut this.receiver).poll_next(cx) {
+            Poll::Ready(Some(change)) => Poll::Ready(Some(Ok(change))),
+            Poll::Ready(None) => {
+                this.ended = true;
+                if this.lagged.load(Ordering::SeqCst) {
+                    Poll::Ready(Some(Err(StorageError::ChangesLagged)))
+                } else {
+                    Poll::Ready(None)
+                }
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// A new subscription with an empty history.
+pub fn subscribe() -> (Subscriber, ChangeStream) {
+    let (sender, receiver) = channel(CHANGE_BUFFER);
+    let lagged = Arc::new(AtomicBool::new(false));
+    let subscriber = Subscriber {
+        sender,
+        lagged: lagged.clone(),
+    };
+    let stream = ChangeStream {
+        history: None,
+        receiver,
+        lagged,
+        ended: false,
+    };
+
+    (subscriber, stream)
+}
@@ -0,0 +1,202 @@
+use super::blob::{blob_file_ids, blob_path, parse_blob_name};
+use super::error::{StorageError, StorageResult};
+use super::sstable::table_file_id;
+use super::table_cache::{parse_table_name, table_path};
+use super::version::{Version, NUM_LEVELS};
+
+use std::collections::HashSet;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// What `Database::checkpoint` has done with tables and blob files.
+#[derive(Debug, Default)]
+pub struct CheckpointReport {
+    /// Tables hard linked into the target.
+    pub linked: usize,
+    /// Tables copied into the target, because hard link is not possible (e.g. on another file system).
+    pub copied: usize,
+    /// Tables which already exist in the target and are skipped in incremental mode.
+    pub skipped: usize,
+}
+
+fn link_or_copy(source: &Path, target: &Path, report: &mut CheckpointReport) -> StorageResult<()> {
+    match std::fs::hard_link(source, target) {
+        Ok(()) => report.linked += 1,
+        Err(err) => {
+            log::debug!("Cannot link {:#?}, copy it instead: {}", source, err);
+            std::fs::copy(source, target)?;
+            report.copied += 1;
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether `target` is the table `source`. A target which cannot be read as a table is not.
+fn same_table(source: &Path, target: &Path) -> StorageResult<bool> {
+    let source_id = table_file_id(source)?;
+    match table_file_id(target) {
+        Ok(target_id) => Ok(source_id == target_id),
+        Err(err) => {
+            log::warn!("Cannot read table {:#?} in checkpoint: {}", target, err);
+            Ok(false)
+        }
+    }
+}
+
+/// Put every table of a version into the target directory, from whichever data path holds it. Other
+/// tables in the target are removed.
+///
+/// Tables are never modified after written, so in incremental mode a table in the target with the same
+/// name and the same file id (see `table_file_id`) is skipped. A table left by another database, or
+/// written again with the same name after a restore, has another id and is replaced.
+pub fn checkpoint_tables(
+    target_dir: &str,
+    version: &Version,
+    incremental: bool,
+) -> StorageResult<CheckpointReport> {
+    let mut report = CheckpointReport::default();
+    let mut live = HashSet::new();
+
+    for level in 0..NUM_LEVELS {
+        for table in version.level(level) {
+            live.insert((level, table.id));
+
+            let source = table.path();
+            let target = table_path(target_dir, level, table.id);
+            if target.exists() {
+                if incremental && same_table(&source, &target)? {
+                    report.skipped += 1;
+                    continue;
+                }
+                std::fs::remove_file(&target)?;
+            }
+            link_or_copy(&source, &target, &mut report)?;
+        }
+    }
+
+    for entry in std::fs::read_dir(target_dir)? {
+        let path = entry?.path();
+        let table = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(parse_table_name);
+        match table {
+            Some(table) if !live.contains(&table) => std::fs::remove_file(&path)?,
+            _ => {}
+        }
+    }
+
+    Ok(report)
+}
+
+/// Put every blob file into the target. Other blob files in the target are removed.
+///
+/// `sealed` files are not appended any more, so in incremental mode a sealed file is skipped if the
+/// target is a hard link of it. Blob files have no id like tables, so a copied one is always copied
+/// again. The active file is always copied, as values appended to it later should not appear in the
+/// target.
+pub fn checkpoint_blobs(
+    base_dir: &str,
+    target_dir: &str,
+    sealed: &[u64],
+    incremental: bool,
+) -> StorageResult<CheckpointReport> {
+    let mut report = CheckpointReport::default();
+    let ids = blob_file_ids(base_dir)?;
+
+    for id in ids.iter() {
+        let source = blob_path(base_dir, *id);
+        let target = blob_path(target_dir, *id);
+        if let Ok(metadata) = std::fs::metadata(&target) {
+            let source_metadata = std::fs::metadata(&source)?;
+            if incremental
+                && sealed.contains(id)
+                && metadata.dev() == source_metadata.dev()
+                && metadata.ino() == source_metadata.ino()
+            {
+                report.skipped += 1;
+                continue;
+            }
+            std::fs::remove_file(&target)?;
+        }
+        if sealed.contains(id) {
+            link_or_copy(&source, &target, &mut report)?;
+        } else {
+            std::fs::copy(&source, &target)?;
+            report.copied += 1;
+        }
+    }
+
+    for id in blob_file_ids(target_dir)? {
+        if !ids.contains(&id) {
+            std::fs::remove_file(blob_path(target_dir, id))?;
+        }
+    }
+
+    Ok(report)
+}
+
+/// Copy logs of the database from `wal_dir` into the target. Logs which were in the target before are
+/// removed.
+pub fn checkpoint_logs(wal_dir: &str, target_dir: &str, log_ids: &[usize]) -> StorageResult<()> {
+    let (wal_path, target_path) = (Path::new(wal_dir), Path::new(target_dir));
+
+    for entry in std::fs::read_dir(target_path)? {
+        let path = entry?.path();
+        match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) if name == "log" || name.starts_with("log.") => std::fs::remove_file(&path)?,
+            _ => {}
+        }
+    }
+
+    std::fs::copy(wal_path.join("log"), target_path.join("log"))?;
+    for id in log_ids {
+        let name = format!("log.{}", id);
+        std::fs::copy(wal_path.join(&name), target_path.join(&name))?;
+    }
+
+    Ok(())
+}
+
+/// Copy every file of a checkpoint (or a stopped database) into another directory, so it can be opened
+/// without modifying the source. Files with the same name in the target are replaced. Directories of
+/// column families are copied in the same way, and logs are copied into `wal_dir`.
+pub fn copy_checkpoint(source_dir: &str, target_dir: &str, wal_dir: &str) -> StorageResult<()> {
+    std::fs::create_dir_all(target_dir)?;
+    std::fs::create_dir_all(wal_dir)?;
+    let mut report = CheckpointReport::default();
+
+    for entry in std::fs::read_dir(source_dir)? {
+        let path = entry?.path();
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let is_log = name == "log" || name.starts_with("log.");
+        let target = if is_log {
+            Path::new(wal_dir).join(&name)
+        } else {
+            Path::new(target_dir).join(&name)
+        };
+        if name.starts_with("family_") && path.is_dir() {
+            match (path.to_str(), target.to_str()) {
+                (Some(source), Some(target)) => copy_checkpoint(source, target, wal_dir)?,
+                _ => return Err(StorageError::UnicodeError),
+            }
+            continue;
+        }
+        // The target may be a hard link of the source. Copying onto it would truncate the source.
+        if target.exists() {
+            std::fs::remove_file(&target)?;
+        }
+
+        if parse_table_name(&name).is_some() || parse_blob_name(&name).is_some() {
+            link_or_copy(&path, &target, &mut report)?;
+        } else if name == "MANIFEST" || name == "FAMILIES" || name == "ENCRYPTION" || is_log {
+            std::fs::copy(&path, &target)?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,467 @@
+use super::background::Scheduler;
+use super::blob::{BlobIndex, BlobStore};
+use super::block_cache::BlockCache;
+use super::compaction_filter::CompactionFilter;
+use super::compaction_strategy::CompactionStrategy;
+use super::compression::Compression;
+use super::database_log::LogRecord;
+use super::encryption::Encryptor;
+use super::error::{StorageError, StorageResult};
+use super::manifest_manager::{BackgroundWorker, FrozenDatabases, ManifestManager};
+use super::mem_database::{MemDatabase, Value};
+use super::merge::merge_sources;
+use super::merge_operator::{fold, MergeOperator};
+use super::rate_limiter::RateLimiter;
+use super::statistics::{DatabaseStats, ReadSource, Statistics};
+use super::version::NUM_LEVELS;
+use super::wal_archive::PendingLogs;
+
+use agilulf_protocol::{Command, DatabaseError, DatabaseResult, Slice};
+use crossbeam::sync::ShardedLock;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Id of the default column family, which is used when no family is given.
+pub const DEFAULT_FAMILY: u32 = 0;
+pub const DEFAULT_FAMILY_NAME: &str = "default";
+
+/// A handle of a column family, returned by `Database::create_column_family` and
+/// `Database::column_family`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnFamily {
+    id: u32,
+    name: String,
+}
+
+impl ColumnFamily {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Options of a column family. Methods can be chained on it like `DatabaseBuilder`.
+///
+/// Like options of `DatabaseBuilder`, they are not persisted, and should be given every time the
+/// database is opened.
+#[derive(Clone, Default)]
+pub struct ColumnFamilyOptions {
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    compression: Vec<Compression>,
+}
+
+impl ColumnFamilyOptions {
+    pub fn merge_operator(&mut self, merge_operator: Arc<dyn MergeOperator>) -> &mut Self {
+        self.merge_operator = Some(merge_operator);
+        self
+    }
+    pub fn compaction_filter(&mut self, compaction_filter: Arc<dyn CompactionFilter>) -> &mut Self {
+        self.compaction_filter = Some(compaction_filter);
+        self
+    }
+    /// Compress blocks of new tables in `level` with `compression`. Tables are not compressed by default.
+    pub fn compression(&mut self, level: usize, compression: Compression) -> &mut Self {
+        if level >= NUM_LEVELS {
+            log::warn!("Level {} doesn't exist, compression is ignored", level);
+            return self;
+        }
+        if self.compression.len() <= level {
+            self.compression.resize(level + 1, Compression::None);
+        }
+        self.compression[level] = compression;
+        self
+    }
+}
+
+/// Names can't be empty or contain white space, so they can be written into `FAMILIES` line by line.
+pub fn valid_family_name(name: &str) -> bool {
+    !name.is_empty() && !name.chars().any(char::is_whitespace)
+}
+
+/// The directory of MANIFEST and tables of a column family. The default family is in base directory,
+/// and every other one is in `family_<id>` under it.
+pub fn family_dir(base_dir: &str, id: u32) -> String {
+    if id == DEFAULT_FAMILY {
+        return base_dir.to_string();
+    }
+    format!("{}/family_{}", base_dir, id)
+}
+
+/// Column families except the default one, recorded as `<id> <name>` lines in `FAMILIES` under base
+/// directory.
+pub fn read_families(base_dir: &str) -> StorageResult<Vec<(u32, String)>> {
+    let path = Path::new(base_dir).join("FAMILIES");
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut families = Vec::new();
+    for line in std::fs::read_to_string(&path)?.lines() {
+        let mut fields = line.split(' ');
+        match (fields.next().and_then(|id| id.parse().ok()), fields.next()) {
+            (Some(id), Some(name)) => families.push((id, name.to_string())),
+            _ => {
+                log::error!("Cannot understand {:?} in {:#?}", line, path);
+                return Err(StorageError::FamiliesFormatError);
+            }
+        }
+    }
+
+    Ok(families)
+}
+
+/// Replace `FAMILIES` with a temporary file, so it's never half written. Both the file and the
+/// directory are synced, so a registered family isn't forgotten after a crash.
+pub fn write_families(base_dir: &str, families: &[(u32, String)]) -> StorageResult<()> {
+    let path = Path::new(base_dir).join("FAMILIES");
+    let tmp_path = Path::new(base_dir).join("FAMILIES.tmp");
+
+    let content: String = families
+        .iter()
+        .map(|(id, name)| format!("{} {}\n", id, name))
+        .collect();
+    std::fs::write(&tmp_path, content)?;
+    std::fs::File::open(&tmp_path)?.sync_all()?;
+    std::fs::rename(&tmp_path, &path)?;
+    std::fs::File::open(base_dir)?.sync_all()?;
+
+    Ok(())
+}
+
+/// An independent key space of `Database`, with its own MemDatabase, frozen databases, tables and
+/// background worker. The log and threads of background work are shared with other families.
+pub struct Family {
+    pub handle: ColumnFamily,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    mem_database: ShardedLock<Arc<MemDatabase>>,
+    pub frozen_databases: Arc<FrozenDatabases>,
+    pub manifest_manager: ManifestManager,
+    pub background: BackgroundWorker,
+    statistics: Arc<Statistics>,
+    blob_store: Arc<BlobStore>,
+}
+
+impl Family {
+    /// Open (or create if `restore` is `false`) tables of a family in its directory and its directories
+    /// under `data_paths`, and create its background worker, whose jobs are run by `scheduler`.
+    /// `records` of log are replayed into its MemDatabase. Blob files are shared by every family, and so
+    /// are the block cache, the compaction strategy, the rate limiter of background writes and the
+    /// encryptor of tables and MANIFEST.
+    #[allow(clippy::too_many_arguments)]
+    pub fn open<I: Iterator<Item = LogRecord>>(
+        base_dir: &str,
+        data_paths: &[(String, u64)],
+        id: u32,
+        name: &str,
+        options: &ColumnFamilyOptions,
+        restore: bool,
+        records: I,
+        max_open_files: usize,
+        block_cache: Arc<BlockCache>,
+        pending_logs: Arc<PendingLogs>,
+        scheduler: Scheduler,
+        compaction_strategy: Arc<dyn CompactionStrategy>,
+        statistics: Arc<Statistics>,
+        rate_limiter: Arc<RateLimiter>,
+        blob_store: Arc<BlobStore>,
+        encryptor: Option<Arc<dyn Encryptor>>,
+    ) -> StorageResult<Family> {
+        let dir = family_dir(base_dir, id);
+        std::fs::create_dir_all(&dir)?;
+        let mut family_paths = Vec::new();
+        for (path, target_size) in data_paths.iter() {
+            let path = family_dir(path, id);
+            std::fs::create_dir_all(&path)?;
+            family_paths.push((path, *target_size));
+        }
+
+        let frozen_databases = Arc::new(ShardedLock::new(VecDeque::new()));
+        let manifest_manager = if restore {
+            ManifestManager::open(
+                &dir,
+                family_paths,
+                frozen_databases.clone(),
+                max_open_files,
+                block_cache,
+                encryptor,
+            )?
+        } else {
+            ManifestManager::create_new(
+                &dir,
+                family_paths,
+                frozen_databases.clone(),
+                max_open_files,
+                block_cache,
+                encryptor,
+            )?
+        };
+        manifest_manager.remove_obsolete_files()?;
+
+        let merge_operator = options.merge_operator.clone();
+        let mem_database = MemDatabase::restore_from_records(records, merge_operator.clone())?;
+        let background = manifest_manager.background_work(
+            scheduler,
+            id,
+            blob_store.clone(),
+            pending_logs,
+            compaction_strategy,
+            options.compaction_filter.clone(),
+            options.compression.clone(),
+            statistics.clone(),
+            rate_limiter,
+        );
+
+        Ok(Family {
+            handle: ColumnFamily {
+                id,
+                name: name.to_string(),
+            },
+            merge_operator,
+            mem_database: ShardedLock::new(Arc::new(mem_database)),
+            frozen_databases,
+            manifest_manager,
+            background,
+            statistics,
+            blob_store,
+        })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.handle.id
+    }
+
+    /// Every `log.<id>` with `id` less than it has been written into tables of this family.
+    pub fn log_number(&self) -> usize {
+        self.manifest_manager.current().log_number
+    }
+
+    pub fn merge_operator(&self) -> Option<&Arc<dyn MergeOperator>> {
+        self.merge_operator.as_ref()
+    }
+
+    pub fn mem_database(&self) -> Arc<MemDatabase> {
+        self.mem_database.read().unwrap().clone()
+    }
+
+    /// Replace MemDatabase with an empty one, and return the old one.
+    pub fn replace_mem_database(&self) -> Arc<MemDatabase> {
+        let new_database = Arc::new(MemDatabase::new(self.merge_operator.clone()));
+        std::mem::replace(&mut *self.mem_database.write().unwrap(), new_database)
+    }
+
+    /// Apply a PUT, PUT_EXPIRE, DELETE, DELETE_RANGE or MERGE command on MemDatabase.
+    pub fn apply(&self, command: Command) -> DatabaseResult<()> {
+        self.mem_database().apply(command)
+    }
+
+    /// Put the index of a value written into blob files on MemDatabase.
+    pub fn apply_blob(&self, key: Slice, index: BlobIndex, expire_at: u64) {
+        self.mem_database().put_blob(key, index, expire_at)
+    }
+
+    /// Same as `find`, and record where it stops in statistics.
+    pub fn get(&self, key: &Slice) -> DatabaseResult<Slice> {
+        let (value, source) = self.lookup(key)?;
+        self.statistics.record_get(source);
+        value.into_slice().ok_or(DatabaseError::KeyNotFound)
+    }
+
+    /// Find a key from the newest MemDatabase to SSTables. It stops at the first place which has the key
+    /// or deletes it, and operands of merge found on the way are applied on that value.
+    pub fn find(&self, key: &Slice) -> DatabaseResult<Slice> {
+        let (value, _) = self.lookup(key)?;
+        value.into_slice().ok_or(DatabaseError::KeyNotFound)
+    }
+
+    /// The value found by `find` (or `Value::NotExist`), and where searching stops. If operands of merge
+    /// are found, it's where the base value is found.
+    fn lookup(&self, key: &Slice) -> DatabaseResult<(Value, ReadSource)> {
+        let (base, operands, source) = self.search(key)?;
+        let base = self.read_blob(base)?;
+        let value = match &self.merge_operator {
+            Some(operator) if !operands.is_empty() => fold(operator.as_ref(), key, base, &operands),
+            _ => base,
+        };
+
+        Ok((value, source))
+    }
+
+    /// The newest value (or `Value::NotExist`) under operands of merge, the operands and where the value
+    /// is found. Values in blob files are not read.
+    fn search(&self, key: &Slice) -> DatabaseResult<(Value, Vec<Slice>, ReadSource)> {
+        let mut operands = Vec::new();
+        let mut base = None;
+        let mut visit = |value: Option<Value>, source: ReadSource| match value {
+            Some(Value::Merge(mut older)) => {
+                older.append(&mut operands);
+                operands = older;
+                false
+            }
+            Some(value) => {
+                base = Some((value, source));
+                true
+            }
+            None => false,
+        };
+
+        if !visit(self.mem_database().lookup(key), ReadSource::MemTable) {
+            for (_, db) in self.frozen_databases.read().unwrap().iter() {
+                if visit(db.lookup(key), ReadSource::Frozen) {
+                    break;
+                }
+            }
+        }
+
+        let (base, source) = match base {
+            Some(base) => base,
+            None => match self.manifest_manager.find_key(key) {
+                Ok(Some((level, value))) => (value, ReadSource::Level(level)),
+                Ok(None) => (Value::NotExist, ReadSource::Miss),
+                Err(err) => {
+                    return Err(DatabaseError::InternalError(err.description().to_string()))
+                }
+            },
+        };
+
+        Ok((base, operands, source))
+    }
+
+    /// Replace the index of a value in blob files with the value.
+    fn read_blob(&self, value: Value) -> DatabaseResult<Value> {
+        match self.blob_store.resolve(value) {
+            Ok(value) => Ok(value),
+            Err(err) => Err(DatabaseError::InternalError(err.description().to_string())),
+        }
+    }
+
+    /// Whether the current value of a key is based on the value at `index` of blob files. The value is
+    /// not read.
+    pub fn holds_blob(&self, key: &Slice, index: &BlobIndex) -> DatabaseResult<bool> {
+        match self.search(key)? {
+            (Value::Blob(found, _), _, _) => Ok(found == *index),
+            _ => Ok(false),
+        }
+    }
+
+    /// The current value of a key (with operands of merge above it applied), if it's based on the
+    /// value at `index` of blob files.
+    pub fn live_blob(&self, key: &Slice, index: &BlobIndex) -> DatabaseResult<Option<Value>> {
+        let (base, operands, _) = self.search(key)?;
+        match base {
+            Value::Blob(found, _) if found == *index => {
+                let base = self.read_blob(base)?;
+                match &self.merge_operator {
+                    Some(operator) if !operands.is_empty() => {
+                        Ok(Some(fold(operator.as_ref(), key, base, &operands)))
+                    }
+                    _ => Ok(Some(base)),
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// If a new operand of merge is written on a value in blob files in MemDatabase, which cannot keep
+    /// operands above such a value, the operand is applied on it at once and the new value is returned.
+    /// Nothing else is searched, and operands above a value in blob files elsewhere are kept, which are
+    /// applied when the key is read or flushed.
+    pub fn merge_blob(&self, key: &Slice, operand: Slice) -> DatabaseResult<Option<Value>> {
+        let operator = match &self.merge_operator {
+            Some(operator) => operator,
+            None => return Err(DatabaseError::MergeOperatorNotSet),
+        };
+        match self.mem_database().lookup(key) {
+            Some(base @ Value::Blob(..)) => {
+                let base = self.read_blob(base)?;
+                Ok(Some(fold(operator.as_ref(), key, base, &[operand])))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Merge every source from MemDatabase, frozen databases and SSTables. A key is taken from the newest
+    /// source, unless it's deleted there or by a newer range tombstone. Keys with operands of merge are
+    /// looked up again to find their base values, and values in blob files are read from there. If a
+    /// table or a blob file cannot be read, the scan fails instead of leaving keys out.
+    pub fn scan(&self, start: &Slice, end: &Slice) -> DatabaseResult<Vec<(Slice, Slice)>> {
+        let mut sources = Vec::new();
+        sources.push(self.mem_database().source(start, end));
+        for (_, db) in self.frozen_databases.read().unwrap().iter() {
+            sources.push(db.source(start, end));
+        }
+        match self.manifest_manager.sources(start, end) {
+            Ok(mut table_sources) => sources.append(&mut table_sources),
+            Err(err) => return Err(DatabaseError::InternalError(err.description().to_string())),
+        }
+
+        let mut pairs = Vec::new();
+        for (key, value) in merge_sources(sources, false) {
+            let value = match value {
+                Value::Merge(_) => match self.find(&key) {
+                    Ok(value) => Value::Slice(value),
+                    Err(DatabaseError::KeyNotFound) => continue,
+                    Err(err) => return Err(err),
+                },
+                Value::Blob(..) => self.read_blob(value)?,
+                value => value,
+            };
+            if let Some(value) = value.into_slice() {
+                pairs.push((key, value));
+            }
+        }
+
+        Ok(pairs)
+    }
+
+    /// Estimated size and number of records in `[start, end)`, summed over MemDatabase, frozen databases
+    /// and tables without reading any value. A key written several times is counted several times.
+    pub fn approximate_range(&self, start: &Slice, end: &Slice) -> (u64, u64) {
+        let (mut bytes, mut count) = self.mem_database().approximate_range(start, end);
+        for (_, db) in self.frozen_databases.read().unwrap().iter() {
+            let (db_bytes, db_count) = db.approximate_range(start, end);
+            bytes += db_bytes;
+            count += db_count;
+        }
+        let (table_bytes, table_count) = self.manifest_manager.approximate_range(start, end);
+
+        (bytes + table_bytes, count + table_count)
+    }
+
+    /// Add sizes of MemDatabase, frozen databases and tables of this family into `stats`.
+    pub fn collect_stats(&self, stats: &mut DatabaseStats) {
+        let memtable = self.mem_database().stats();
+        stats.memtable_bytes += memtable.memtable_bytes;
+        stats.memtable_entries += memtable.memtable_entries;
+        stats.frozen_memtables += self.frozen_databases.read().unwrap().len() as u64;
+
+        stats.level_tables.resize(NUM_LEVELS, 0);
+        stats.level_bytes.resize(NUM_LEVELS, 0);
+        for (level, (tables, bytes)) in self.manifest_manager.level_stats().into_iter().enumerate()
+        {
+            stats.level_tables[level] += tables;
+            stats.level_bytes[level] += bytes;
+        }
+    }
+
+    /// Whether MemDatabase or any frozen database has keys or range tombstones in `[start, end)`.
+    pub fn memory_overlaps(&self, start: &Slice, end: &Slice) -> bool {
+        let overlaps = |db: &MemDatabase| {
+            let source = db.source(start, end);
+            !source.entries.is_empty() || !source.range_tombstones.is_empty()
+        };
+
+        overlaps(&self.mem_database())
+            || self
+                .frozen_databases
+                .read()
+                .unwrap()
+                .iter()
+                .any(|(_, db)| overlaps(db))
+    }
+}
@@ -0,0 +1,401 @@
+use super::blob::BlobStore;
+use super::compaction_filter::{CompactionFilter, FilterDecision};
+use super::compression::Compression;
+use super::error::{StorageError, StorageResult};
+use super::manifest_manager::VersionSet;
+use super::mem_database::Value;
+use super::merge::{merge_sources, Source};
+use super::merge_operator::trim_zero;
+use super::range_tombstone::RangeTombstone;
+use super::rate_limiter::RateLimiter;
+use super::sstable::SSTable;
+use super::statistics::Statistics;
+use super::table_cache::{sync_parent, TableCache};
+use super::version::{TableMeta, Version, VersionEdit, NUM_LEVELS};
+
+use agilulf_protocol::Slice;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Tables written by compaction are split after this many records.
+pub const MAX_TABLE_RECORDS: usize = 4 * 1024;
+
+/// What a flush, compaction or ingestion has changed, returned by `Database::flush_memtable`,
+/// `Database::compact_range` and `Database::ingest`.
+#[derive(Debug, Default)]
+pub struct CompactionReport {
+    /// Tables written, as `(level, id)`.
+    pub added: Vec<(usize, usize)>,
+    /// Tables replaced by them, as `(level, id)`.
+    pub removed: Vec<(usize, usize)>,
+    /// Size of removed tables.
+    pub bytes_read: u64,
+    /// Size of added tables.
+    pub bytes_written: u64,
+}
+
+impl CompactionReport {
+    pub fn append(&mut self, mut other: CompactionReport) {
+        self.added.append(&mut other.added);
+        self.removed.append(&mut other.removed);
+        self.bytes_read += other.bytes_read;
+        self.bytes_written += other.bytes_written;
+    }
+}
+
+/// Tables which will be merged into `output_level`, with their levels. Newer tables are put in front:
+/// level 0 from the newest one, then deeper levels.
+pub struct Compaction {
+    pub inputs: Vec<(usize, Arc<TableMeta>)>,
+    pub output_level: usize,
+}
+
+impl Compaction {
+    /// Tables in `level` and overlapping tables in `level + 1`, which will be merged into `level + 1`.
+    pub fn with_next_level(
+        version: &Version,
+        level: usize,
+        tables: Vec<Arc<TableMeta>>,
+    ) -> Compaction {
+        let next_inputs = match key_range(&tables) {
+            Some((smallest, largest)) => overlapping(version.level(level + 1), &smallest, &largest),
+            None => Vec::new(),
+        };
+
+        let mut inputs: Vec<(usize, Arc<TableMeta>)> = tables
+            .into_iter()
+            .rev()
+            .map(|table| (level, table))
+            .collect();
+        inputs.extend(next_inputs.into_iter().map(|table| (level + 1, table)));
+        Compaction {
+            inputs,
+            output_level: level + 1,
+        }
+    }
+
+    /// The smallest and the largest key of every input.
+    pub fn key_range(&self) -> Option<(Slice, Slice)> {
+        let tables: Vec<Arc<TableMeta>> =
+            self.inputs.iter().map(|(_, table)| table.clone()).collect();
+        key_range(&tables)
+    }
+
+    /// Whether any input is being compacted by another compaction.
+    pub fn is_busy(&self) -> bool {
+        self.inputs
+            .iter()
+            .any(|(_, table)| table.is_being_compacted())
+    }
+
+    pub fn set_being_compacted(&self, being_compacted: bool) {
+        for (_, table) in self.inputs.iter() {
+            table.set_being_compacted(being_compacted);
+        }
+    }
+}
+
+pub fn key_range(tables: &[Arc<TableMeta>]) -> Option<(Slice, Slice)> {
+    let smallest = tables.iter().map(|table| &table.smallest).min()?;
+    let largest = tables.iter().map(|table| &table.largest).max()?;
+    Some((smallest.clone(), largest.clone()))
+}
+
+pub fn overlapping(
+    tables: &[Arc<TableMeta>],
+    smallest: &Slice,
+    largest: &Slice,
+) -> Vec<Arc<TableMeta>> {
+    tables
+        .iter()
+        .filter(|table| &table.smallest <= largest && &table.largest >= smallest)
+        .cloned()
+        .collect()
+}
+
+/// Size of a table file, or `0` if it cannot be read.
+pub fn table_bytes(table: &TableMeta) -> u64 {
+    std::fs::metadata(table.path()).map_or(0, |metadata| metadata.len())
+}
+
+pub fn level_bytes(version: &Version, level: usize) -> u64 {
+    version
+        .level(level)
+        .iter()
+        .map(|table| table_bytes(table))
+        .sum()
+}
+
+/// Split sorted records into tables of about `MAX_TABLE_RECORDS` records. A split never happens inside
+/// a range tombstone, so tables in the output level don't overlap with each other.
+fn split_tables(
+    records: Vec<(Slice, Value)>,
+    range_tombstones: Vec<RangeTombstone>,
+) -> Vec<SSTable> {
+    let new_batch = || Source {
+        entries: Vec::new(),
+        range_tombstones: Vec::new(),
+    };
+    let mut batches = vec![new_batch()];
+    for (key, value) in records {
+        let can_split = match batches.last().and_then(|batch| batch.entries.last()) {
+            Some((last, _)) => !range_tombstones
+                .iter()
+                .any(|tombstone| tombstone.start <= key && tombstone.end > *last),
+            None => false,
+        };
+        let full = batches.last().map_or(0, |batch| batch.entries.len()) >= MAX_TABLE_RECORDS;
+        if full && can_split {
+            batches.push(new_batch());
+        }
+        if let Some(batch) = batches.last_mut() {
+            batch.entries.push((key, value));
+        }
+    }
+
+    // A range tombstone belongs to the last table starting before it.
+    for tombstone in range_tombstones {
+        let index = batches
+            .iter()
+            .rposition(|batch| match batch.entries.first() {
+                Some((first, _)) => first <= &tombstone.start,
+                None => false,
+            })
+            .unwrap_or(0);
+        batches[index].range_tombstones.push(tombstone);
+    }
+
+    batches
+        .into_iter()
+        .filter(|batch| !batch.entries.is_empty() || !batch.range_tombstones.is_empty())
+        .map(|batch| SSTable::new(batch.entries, batch.range_tombstones))
+        .collect()
+}
+
+/// The compaction filter of a column family, with the blob files which its values may be kept in.
+#[derive(Clone, Copy)]
+pub struct FilterContext<'a> {
+    pub filter: &'a dyn CompactionFilter,
+    pub blob_store: &'a BlobStore,
+    pub family: u32,
+}
+
+/// Pass every live record to the compaction filter. A removed record becomes a tombstone, unless it's
+/// written into the bottommost level.
+///
+/// Values in blob files are read for the filter, and padding of values read from tables is trimmed.
+/// A changed value is put into blob files if it should be separated or it's too long for a table.
+fn filter_records(
+    records: Vec<(Slice, Value)>,
+    level: usize,
+    bottommost: bool,
+    context: FilterContext,
+    statistics: &Statistics,
+) -> StorageResult<Vec<(Slice, Value)>> {
+    let mut filtered = Vec::with_capacity(records.len());
+    for (key, value) in records {
+        let (slice, expire_at) = match &value {
+            Value::Slice(slice) => (Slice(trim_zero(&slice.0).to_vec()), 0),
+            Value::Expiring(slice, expire_at) => (Slice(trim_zero(&slice.0).to_vec()), *expire_at),
+            Value::Blob(index, expire_at) => (context.blob_store.read(index)?, *expire_at),
+            Value::NotExist | Value::Merge(_) => {
+                filtered.push((key, value));
+                continue;
+            }
+        };
+        let decision = context.filter.filter(level, &key, &slice);
+        statistics.record_filter_decision(&decision);
+
+        let value = match decision {
+            FilterDecision::Keep => value,
+            FilterDecision::Remove if bottommost => continue,
+            FilterDecision::Remove => Value::NotExist,
+            FilterDecision::Change(new_value) => {
+                let new_value = match expire_at {
+                    0 => Value::Slice(new_value),
+                    expire_at => Value::Expiring(new_value, expire_at),
+                };
+                context
+                    .blob_store
+                    .separate(context.family, &key, new_value)?
+            }
+        };
+        filtered.push((key, value));
+    }
+
+    Ok(filtered)
+}
+
+/// Merge input tables into the output level, and replace them in a single edit.
+///
+/// Keys covered by a newer tombstone or range tombstone are dropped. If no table in deeper levels
+/// overlaps the inputs, tombstones and range tombstones are dropped too, as there is nothing left for
+/// them to delete. Then the compaction filter (if any) decides what to do with remaining records. New
+/// tables are compressed with the codec of the output level in `compression`.
+pub async fn compact(
+    version_set: &VersionSet,
+    table_cache: &TableCache,
+    compaction: &Compaction,
+    filter: Option<FilterContext<'_>>,
+    compression: &[Compression],
+    statistics: &Statistics,
+    rate_limiter: &RateLimiter,
+) -> StorageResult<CompactionReport> {
+    let started = Instant::now();
+    let inputs = &compaction.inputs;
+    let output_level = compaction.output_level;
+    log::info!(
+        "Compacting {} tables in levels {:?} into level {}",
+        inputs.len(),
+        inputs
+            .iter()
+            .map(|(level, _)| *level)
+            .collect::<Vec<usize>>(),
+        output_level
+    );
+
+    let mut report = CompactionReport::default();
+    let mut sources = Vec::new();
+    let mut range_tombstones = Vec::new();
+    for (level, table) in inputs.iter() {
+        report.bytes_read += std::fs::metadata(table.path())?.len();
+        report.removed.push((*level, table.id));
+        let table = table_cache.get(table.path_id, *level, table.id)?;
+        range_tombstones.extend(table.range_tombstones().iter().cloned());
+        sources.push(Source {
+            entries: table.records()?,
+            range_tombstones: table.range_tombstones().to_vec(),
+        });
+    }
+
+    let version = version_set.current();
+    let all_inputs: Vec<Arc<TableMeta>> = inputs.iter().map(|(_, table)| table.clone()).collect();
+    let bottommost = match key_range(&all_inputs) {
+        Some((smallest, largest)) => (output_level + 1..NUM_LEVELS)
+            .all(|level| overlapping(version.level(level), &smallest, &largest).is_empty()),
+        None => true,
+    };
+    if bottommost {
+        range_tombstones.clear();
+    }
+
+    let mut records = merge_sources(sources, !bottommost);
+    if let Some(filter) = filter {
+        records = filter_records(records, output_level, bottommost, filter, statistics)?;
+    }
+    let mut edit = VersionEdit::default();
+    let mut outputs = Vec::new();
+    let path_id = version_set.path_for_level(output_level);
+    for sstable in split_tables(records, range_tombstones) {
+        let (smallest, largest) = match sstable.key_range() {
+            Some(range) => range,
+            None => continue,
+        };
+
+        let id = version_set.new_table_id(output_level);
+        let path = table_cache.table_path(path_id, output_level, id);
+        let path = match path.to_str() {
+            Some(str) => str,
+            None => {
+                log::error!("Table path is not UTF-8: {:#?}", path);
+                return Err(StorageError::UnicodeError);
+            }
+        };
+        sstable
+            .save(
+                path,
+                Compression::for_level(compression, output_level),
+                table_cache.encryptor(),
+                Some(rate_limiter),
+            )
+            .await?;
+        sync_parent(Path::new(path))?;
+        report.bytes_written += std::fs::metadata(path)?.len();
+        report.added.push((output_level, id));
+
+        edit.add_table(output_level, id, path_id, smallest, largest);
+        outputs.push((id, sstable));
+    }
+    for (level, table) in inputs.iter() {
+        edit.delete_table(*level, table.id);
+    }
+    version_set.log_and_apply(&edit)?;
+
+    for (id, sstable) in outputs {
+        table_cache.insert(output_level, id, sstable);
+    }
+    statistics.record_compaction(started.elapsed(), &report);
+
+    Ok(report)
+}
+
+/// Compact tables overlapping `[start, end)` level by level, down to the deepest level which has such
+/// tables (or level 1 if only level 0 has them).
+///
+/// In level 0, older tables overlapping the chosen ones are compacted with them, otherwise they would
+/// hide newer records moved into level 1.
+#[allow(clippy::too_many_arguments)]
+pub async fn compact_range(
+    version_set: &VersionSet,
+    table_cache: &TableCache,
+    start: &Slice,
+    end: &Slice,
+    filter: Option<FilterContext<'_>>,
+    compression: &[Compression],
+    statistics: &Statistics,
+    rate_limiter: &RateLimiter,
+) -> StorageResult<CompactionReport> {
+    let mut report = CompactionReport::default();
+
+    let in_range = |version: &Version, level: usize| -> Vec<Arc<TableMeta>> {
+        version
+            .level(level)
+            .iter()
+            .filter(|table| table.overlaps(start, end))
+            .cloned()
+            .collect()
+    };
+    let version = version_set.current();
+    let bottom = match (0..NUM_LEVELS)
+        .rev()
+        .find(|level| !in_range(&version, *level).is_empty())
+    {
+        Some(level) => std::cmp::max(level, 1),
+        None => return Ok(report),
+    };
+
+    for level in 0..bottom {
+        let version = version_set.current();
+        let mut inputs = in_range(&version, level);
+        if level == 0 {
+            while let Some((smallest, largest)) = key_range(&inputs) {
+                let expanded = overlapping(version.level(0), &smallest, &largest);
+                if expanded.len() == inputs.len() {
+                    break;
+                }
+                inputs = expanded;
+            }
+        }
+
+        if inputs.is_empty() {
+            continue;
+        }
+
+        let compaction = Compaction::with_next_level(&version, level, inputs);
+        report.append(
+            compact(
+                version_set,
+                table_cache,
+                &compaction,
+                filter,
+                compression,
+                statistics,
+                rate_limiter,
+            )
+            .await?,
+        );
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,27 @@
+use agilulf_protocol::Slice;
+
+/// What compaction does with a record, returned by `CompactionFilter::filter`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterDecision {
+    /// Write the record as it is.
+    Keep,
+    /// Drop the record. If there may be an older version of the key in deeper levels, a tombstone is
+    /// written instead, so the older version will not appear again.
+    Remove,
+    /// Replace the value. The expiration time of the record is kept. A value too long for a table is
+    /// put into blob files.
+    Change(Slice),
+}
+
+/// Decide whether records should be kept while compaction rewrites them, so obsolete records (e.g.
+/// with an old version embedded in value) can be removed without deleting them one by one.
+///
+/// `level` is the level which the record is written into. Only live values are passed: deleted and
+/// expired keys never reach the filter. Values kept in blob files are read for it, and zero bytes
+/// padded to values in tables are trimmed, so trailing zero bytes of a value are not passed either.
+///
+/// Records in MemDatabase and level 0 are not filtered until they are compacted, so reads may still
+/// return a record which the filter would remove.
+pub trait CompactionFilter: Send + Sync {
+    fn filter(&self, level: usize, key: &Slice, value: &Slice) -> FilterDecision;
+}
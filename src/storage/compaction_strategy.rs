@@ -0,0 +1,414 @@
+use super::compaction::{level_bytes, table_bytes, Compaction};
+use super::version::{TableMeta, Version, NUM_LEVELS};
+
+use std::sync::Arc;
+
+/// Decide which tables should be compacted after every flush. `Database` compacts until it returns
+/// `None`, so every compaction it picks should bring the tables closer to the shape it wants.
+///
+/// The output of a compaction is a sorted run in its output level, so a strategy must keep newer
+/// records in shallower levels: tables in level 0 are newer than every other level, and a level is
+/// newer than every deeper one. `compact_range` and ingestion keep this too, so they work with every
+/// strategy.
+///
+/// Compactions run in parallel if their key ranges don't overlap. A strategy should not pick tables
+/// which are being compacted (see `Compaction::is_busy`), and may pick other tables instead.
+pub trait CompactionStrategy: Send + Sync {
+    fn pick_compaction(&self, version: &Version) -> Option<Compaction>;
+}
+
+/// Compaction of LevelDB, which is the default one. Level 0 is merged into level 1 when it has
+/// `l0_compaction_trigger` tables, and a level larger than its limit is merged into the next level
+/// one table at a time. Level 1 can hold `max_bytes_for_level_base` bytes, and every next level can
+/// hold `level_size_ratio` times more.
+///
+/// Reads check few tables, but every record is rewritten about `level_size_ratio` times in every level.
+#[derive(Clone, Debug)]
+pub struct LeveledCompaction {
+    l0_compaction_trigger: usize,
+    max_bytes_for_level_base: u64,
+    level_size_ratio: u64,
+}
+
+impl Default for LeveledCompaction {
+    fn default() -> LeveledCompaction {
+        LeveledCompaction {
+            l0_compaction_trigger: 4,
+            max_bytes_for_level_base: 10 * 1024 * 1024,
+            level_size_ratio: 10,
+        }
+    }
+}
+
+impl LeveledCompaction {
+    pub fn l0_compaction_trigger(&mut self, l0_compaction_trigger: usize) -> &mut Self {
+        self.l0_compaction_trigger = std::cmp::max(l0_compaction_trigger, 1);
+        self
+    }
+    pub fn max_bytes_for_level_base(&mut self, max_bytes_for_level_base: u64) -> &mut Self {
+        self.max_bytes_for_level_base = max_bytes_for_level_base;
+        self
+    }
+    pub fn level_size_ratio(&mut self, level_size_ratio: u64) -> &mut Self {
+        self.level_size_ratio = std::cmp::max(level_size_ratio, 1);
+        self
+    }
+
+    fn max_bytes_for_level(&self, level: usize) -> u64 {
+        self.max_bytes_for_level_base * self.level_size_ratio.pow(level as u32 - 1)
+    }
+}
+
+impl CompactionStrategy for LeveledCompaction {
+    /// Level 0 when it has too many tables, or a level which is larger than its limit. In level 0
+    /// every table is compacted, as they may overlap with each other. In other levels, the oldest
+    /// table which is not being compacted (with tables it overlaps in the next level) is compacted.
+    fn pick_compaction(&self, version: &Version) -> Option<Compaction> {
+        if version.level(0).len() >= self.l0_compaction_trigger {
+            let compaction = Compaction::with_next_level(version, 0, version.level(0).to_vec());
+            if !compaction.is_busy() {
+                return Some(compaction);
+            }
+        }
+
+        (1..NUM_LEVELS - 1)
+            .filter(|level| level_bytes(version, *level) > self.max_bytes_for_level(*level))
+            .find_map(|level| {
+                let mut tables = version.level(level).to_vec();
+                tables.sort_by_key(|table| table.id);
+                tables
+                    .into_iter()
+                    .map(|table| Compaction::with_next_level(version, level, vec![table]))
+                    .find(|compaction| !compaction.is_busy())
+            })
+    }
+}
+
+/// Size-tiered compaction like the universal compaction of RocksDB, for workloads which write much
+/// more than they read. Every table in level 0 and every other level is a sorted run. Once there are
+/// `max_sorted_runs` runs, adjacent runs of similar size are merged: starting from the newest run, the
+/// next older run is added while it's at most `size_ratio` percent larger than the runs picked before
+/// it, and they are merged if at least `min_merge_width` runs are picked. If no such runs are found,
+/// the newest runs are merged until there are fewer than `max_sorted_runs` runs.
+///
+/// A record is rewritten far fewer times than with `LeveledCompaction`, but a read may check every
+/// sorted run, and space of overwritten records is reclaimed only when the largest runs are merged.
+#[derive(Clone, Debug)]
+pub struct UniversalCompaction {
+    max_sorted_runs: usize,
+    size_ratio: u64,
+    min_merge_width: usize,
+}
+
+impl Default for UniversalCompaction {
+    fn default() -> UniversalCompaction {
+        UniversalCompaction {
+            max_sorted_runs: 4,
+            size_ratio: 1,
+            min_merge_width: 2,
+        }
+    }
+}
+
+impl UniversalCompaction {
+    pub fn max_sorted_runs(&mut self, max_sorted_runs: usize) -> &mut Self {
+        self.max_sorted_runs = std::cmp::max(max_sorted_runs, 2);
+        self
+    }
+    pub fn size_ratio(&mut self, size_ratio: u64) -> &mut Self {
+        self.size_ratio = size_ratio;
+        self
+    }
+    pub fn min_merge_width(&mut self, min_merge_width: usize) -> &mut Self {
+        self.min_merge_width = std::cmp::max(min_merge_width, 2);
+        self
+    }
+}
+
+/// A table in level 0, or every table in another level.
+struct SortedRun {
+    level: usize,
+    tables: Vec<Arc<TableMeta>>,
+    bytes: u64,
+}
+
+/// Sorted runs from the newest one.
+fn sorted_runs(version: &Version) -> Vec<SortedRun> {
+    let mut runs: Vec<SortedRun> = version
+        .level(0)
+        .iter()
+        .rev()
+        .map(|table| SortedRun {
+            level: 0,
+            tables: vec![table.clone()],
+            bytes: table_bytes(table),
+        })
+        .collect();
+    for level in 1..NUM_LEVELS {
+        if !version.level(level).is_empty() {
+            runs.push(SortedRun {
+                level,
+                tables: version.level(level).to_vec(),
+                bytes: level_bytes(version, level),
+            });
+        }
+    }
+    runs
+}
+
+/// Extend picked runs `[start, end)` until their output can be put into a level without breaking the
+/// order of runs, and return the new `end` with the output level.
+///
+/// Runs ending with a level are merged into it. Runs of level 0 must take every older table in level
+/// 0, then they are written into the empty level right above the next run (or the bottommost level).
+/// If the next run is level 1, it's merged too.
+fn place(runs: &[SortedRun], mut end: usize) -> (usize, usize) {
+    loop {
+        let last = &runs[end - 1];
+        if last.level != 0 {
+            return (end, last.level);
+        }
+        let next_level = runs.get(end).map_or(NUM_LEVELS, |run| run.level);
+        if next_level > 1 {
+            return (end, next_level - 1);
+        }
+        end += 1;
+    }
+}
+
+impl CompactionStrategy for UniversalCompaction {
+    fn pick_compaction(&self, version: &Version) -> Option<Compaction> {
+        let runs = sorted_runs(version);
+        if runs.len() < self.max_sorted_runs {
+            return None;
+        }
+
+        let similar = (0..runs.len()).find_map(|start| {
+            let mut bytes = runs[start].bytes;
+            let mut end = start + 1;
+            while end < runs.len() && runs[end].bytes * 100 <= bytes * (100 + self.size_ratio) {
+                bytes += runs[end].bytes;
+                end += 1;
+            }
+            if end - start >= self.min_merge_width {
+                Some((start, end))
+            } else {
+                None
+            }
+        });
+        let (start, end) = similar.unwrap_or_else(|| {
+            let width = std::cmp::max(runs.len() + 1 - self.max_sorted_runs, self.min_merge_width);
+            (0, std::cmp::min(width, runs.len()))
+        });
+
+        let (end, output_level) = place(&runs, end);
+        let inputs = runs[start..end]
+            .iter()
+            .flat_map(|run| {
+                run.tables
+                    .iter()
+                    .map(move |table| (run.level, table.clone()))
+            })
+            .collect();
+        let compaction = Compaction {
+            inputs,
+            output_level,
+        };
+        // A run which is being merged is not picked again until that compaction finishes.
+        if compaction.is_busy() {
+            return None;
+        }
+        Some(compaction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::block_cache::BlockCache;
+    use crate::storage::manifest_manager::VersionSet;
+    use crate::storage::table_cache::{table_path, TableCache};
+    use crate::storage::version::VersionEdit;
+    use agilulf_protocol::Slice;
+
+    struct Tables {
+        base_dir: &'static str,
+        version_set: VersionSet,
+    }
+
+    impl Tables {
+        fn open(base_dir: &'static str) -> Tables {
+            let _ = std::fs::remove_dir_all(base_dir);
+            std::fs::create_dir_all(base_dir).unwrap();
+            let table_cache = Arc::new(TableCache::new(
+                base_dir,
+                Vec::new(),
+                16,
+                Arc::new(BlockCache::new(0)),
+                None,
+            ));
+            let version_set = VersionSet::create_new(base_dir, table_cache).unwrap();
+            Tables {
+                base_dir,
+                version_set,
+            }
+        }
+
+        /// Tables are written as files of their size only, as strategies never read them.
+        fn add_table(&self, edit: &mut VersionEdit, level: usize, bytes: u64) {
+            let id = self.version_set.new_table_id(level);
+            let path = table_path(self.base_dir, level, id);
+            std::fs::write(&path, vec![0u8; bytes as usize]).unwrap();
+            edit.add_table(
+                level,
+                id,
+                0,
+                Slice(b"AAAAAAAA".to_vec()),
+                Slice(b"ZZZZZZZZ".to_vec()),
+            );
+        }
+
+        fn write(&self, level: usize, bytes: u64) {
+            let mut edit = VersionEdit::default();
+            self.add_table(&mut edit, level, bytes);
+            self.version_set.log_and_apply(&edit).unwrap();
+        }
+
+        /// Replace inputs with a table of their total size, like a compaction which drops nothing.
+        fn compact(&self, strategy: &dyn CompactionStrategy) -> usize {
+            let mut compactions = 0;
+            while let Some(compaction) = strategy.pick_compaction(&self.version_set.current()) {
+                let mut edit = VersionEdit::default();
+                let mut bytes = 0;
+                for (level, table) in compaction.inputs.iter() {
+                    bytes += table_bytes(table);
+                    edit.delete_table(*level, table.id);
+                }
+                self.add_table(&mut edit, compaction.output_level, bytes);
+                self.version_set.log_and_apply(&edit).unwrap();
+                compactions += 1;
+            }
+            compactions
+        }
+
+        /// Size of every level.
+        fn shape(&self) -> Vec<u64> {
+            let version = self.version_set.current();
+            (0..NUM_LEVELS)
+                .map(|level| level_bytes(&version, level))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn leveled_compaction() {
+        let tables = Tables::open("/var/tmp/agilulf_leveled_strategy_test");
+        let mut strategy = LeveledCompaction::default();
+        strategy
+            .l0_compaction_trigger(2)
+            .max_bytes_for_level_base(250)
+            .level_size_ratio(4);
+
+        tables.write(0, 100);
+        assert_eq!(tables.compact(&strategy), 0);
+        tables.write(0, 100);
+        assert_eq!(tables.compact(&strategy), 1);
+        assert_eq!(tables.shape(), vec![0, 200, 0, 0, 0, 0]);
+
+        // Level 1 grows over its limit, and is moved into level 2.
+        tables.write(0, 100);
+        tables.write(0, 100);
+        assert_eq!(tables.compact(&strategy), 2);
+        assert_eq!(tables.shape(), vec![0, 0, 400, 0, 0, 0]);
+
+        for _ in 0..12 {
+            tables.write(0, 100);
+            tables.compact(&strategy);
+        }
+        assert_eq!(tables.shape(), vec![0, 0, 400, 1200, 0, 0]);
+    }
+
+    #[test]
+    fn leveled_compaction_skips_busy_tables() {
+        let tables = Tables::open("/var/tmp/agilulf_leveled_busy_strategy_test");
+        let mut strategy = LeveledCompaction::default();
+        strategy.max_bytes_for_level_base(250);
+
+        tables.write(1, 200);
+        tables.write(1, 200);
+        let version = tables.version_set.current();
+
+        // The oldest table is being compacted, so the other one is picked.
+        let oldest = strategy.pick_compaction(&version).unwrap();
+        oldest.set_being_compacted(true);
+        let other = strategy.pick_compaction(&version).unwrap();
+        assert_ne!(oldest.inputs[0].1.id, other.inputs[0].1.id);
+
+        other.set_being_compacted(true);
+        assert!(strategy.pick_compaction(&version).is_none());
+        oldest.set_being_compacted(false);
+        let again = strategy.pick_compaction(&version).unwrap();
+        assert_eq!(oldest.inputs[0].1.id, again.inputs[0].1.id);
+    }
+
+    #[test]
+    fn universal_compaction() {
+        let tables = Tables::open("/var/tmp/agilulf_universal_strategy_test");
+        let mut strategy = UniversalCompaction::default();
+        strategy
+            .max_sorted_runs(4)
+            .size_ratio(10)
+            .min_merge_width(2);
+
+        // Runs of similar size are merged into the bottommost level.
+        for _ in 0..3 {
+            tables.write(0, 100);
+        }
+        assert_eq!(tables.compact(&strategy), 0);
+        tables.write(0, 100);
+        assert_eq!(tables.compact(&strategy), 1);
+        assert_eq!(tables.shape(), vec![0, 0, 0, 0, 0, 400]);
+
+        // The bottommost level is much larger, so similar runs are merged right above it. The newest
+        // table is too small to be merged with them, and it's kept in level 0.
+        tables.write(0, 100);
+        tables.write(0, 105);
+        tables.write(0, 10);
+        assert_eq!(tables.compact(&strategy), 1);
+        assert_eq!(tables.shape(), vec![10, 0, 0, 0, 205, 400]);
+
+        // Once new runs are as large as older ones, they are all merged.
+        tables.write(0, 100);
+        tables.write(0, 100);
+        assert_eq!(tables.compact(&strategy), 1);
+        assert_eq!(tables.shape(), vec![0, 0, 0, 0, 0, 815]);
+    }
+
+    #[test]
+    fn universal_compaction_without_similar_runs() {
+        let tables = Tables::open("/var/tmp/agilulf_universal_fallback_test");
+        let mut strategy = UniversalCompaction::default();
+        strategy.max_sorted_runs(3).size_ratio(0).min_merge_width(2);
+
+        // Every run is larger than the newer ones, so the newest runs are merged. Older tables in
+        // level 0 are merged with them, as the output cannot be put in front of them.
+        tables.write(0, 400);
+        tables.write(0, 200);
+        tables.write(0, 100);
+        assert_eq!(tables.compact(&strategy), 1);
+        assert_eq!(tables.shape(), vec![0, 0, 0, 0, 0, 700]);
+
+        tables.write(0, 300);
+        tables.write(0, 200);
+        assert_eq!(tables.compact(&strategy), 1);
+        assert_eq!(tables.shape(), vec![0, 0, 0, 0, 500, 700]);
+
+        // There is no empty level between level 0 and level 1, so level 1 is merged too.
+        let tables = Tables::open("/var/tmp/agilulf_universal_level_1_test");
+        tables.write(1, 1000);
+        tables.write(0, 100);
+        tables.write(0, 200);
+        assert_eq!(tables.compact(&strategy), 1);
+        assert_eq!(tables.shape(), vec![0, 1300, 0, 0, 0, 0]);
+    }
+}
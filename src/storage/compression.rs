@@ -0,0 +1,245 @@
+/// Codec of a block in SSTable. It's recorded in the header of every block, so tables (and blocks)
+/// written with different codecs can be read together.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// The block format of LZ4, which is fast and good at the zero padding of keys and values.
+    Lz4,
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::None
+    }
+}
+
+const MIN_MATCH: usize = 4;
+/// The last 5 bytes are always literals, and the last match starts at least 12 bytes before the end.
+const LAST_LITERALS: usize = 5;
+const MF_LIMIT: usize = 12;
+const HASH_BITS: u32 = 12;
+const MAX_OFFSET: usize = 65535;
+
+impl Compression {
+    /// The codec of `level` in a list of codecs for every level. Levels not in it are not compressed.
+    pub fn for_level(compression: &[Compression], level: usize) -> Compression {
+        compression.get(level).cloned().unwrap_or_default()
+    }
+
+    pub fn code(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Compression> {
+        match code {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+
+    pub fn compress(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::None => data.to_vec(),
+            Compression::Lz4 => lz4_compress(data),
+        }
+    }
+
+    /// `None` if `data` is not a valid block of `raw_length` bytes.
+    pub fn decompress(self, data: &[u8], raw_length: usize) -> Option<Vec<u8>> {
+        match self {
+            Compression::None if data.len() == raw_length => Some(data.to_vec()),
+            Compression::None => None,
+            Compression::Lz4 => lz4_decompress(data, raw_length),
+        }
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut array = [0u8; 4];
+    array.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(array)
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+fn write_length(output: &mut Vec<u8>, mut length: usize) {
+    while length >= 255 {
+        output.push(255);
+        length -= 255;
+    }
+    output.push(length as u8);
+}
+
+/// Write a sequence of literals followed by a match. A match of length `0` ends the block.
+fn write_sequence(output: &mut Vec<u8>, literals: &[u8], offset: usize, match_length: usize) {
+    let literal_token = std::cmp::min(literals.len(), 15) as u8;
+    let match_token = if match_length == 0 {
+        0
+    } else {
+        std::cmp::min(match_length - MIN_MATCH, 15) as u8
+    };
+    output.push(literal_token << 4 | match_token);
+    if literals.len() >= 15 {
+        write_length(output, literals.len() - 15);
+    }
+    output.extend_from_slice(literals);
+
+    if match_length != 0 {
+        output.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_length - MIN_MATCH >= 15 {
+            write_length(output, match_length - MIN_MATCH - 15);
+        }
+    }
+}
+
+/// Find matches greedily with a hash table of the last position of every 4 bytes.
+fn lz4_compress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len() / 2 + 16);
+    let mut table = vec![0usize; 1 << HASH_BITS];
+    let (mut anchor, mut position) = (0, 0);
+
+    while position + MF_LIMIT <= data.len() {
+        let sequence = read_u32(data, position);
+        let slot = hash(sequence);
+        // Positions are stored plus one, so `0` means empty.
+        let candidate = table[slot];
+        table[slot] = position + 1;
+
+        if candidate != 0 {
+            let candidate = candidate - 1;
+            if position - candidate <= MAX_OFFSET && read_u32(data, candidate) == sequence {
+                let limit = data.len() - LAST_LITERALS;
+                let mut length = MIN_MATCH;
+                while position + length < limit
+                    && data[candidate + length] == data[position + length]
+                {
+                    length += 1;
+                }
+
+                write_sequence(
+                    &mut output,
+                    &data[anchor..position],
+                    position - candidate,
+                    length,
+                );
+                position += length;
+                anchor = position;
+                continue;
+            }
+        }
+        position += 1;
+    }
+    write_sequence(&mut output, &data[anchor..], 0, 0);
+
+    output
+}
+
+fn read_length(data: &[u8], position: &mut usize, mut length: usize) -> Option<usize> {
+    loop {
+        let byte = *data.get(*position)?;
+        *position += 1;
+        length += byte as usize;
+        if byte != 255 {
+            return Some(length);
+        }
+    }
+}
+
+fn lz4_decompress(data: &[u8], raw_length: usize) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(raw_length);
+    let mut position = 0;
+
+    loop {
+        let token = *data.get(position)?;
+        position += 1;
+
+        let mut literal_length = (token >> 4) as usize;
+        if literal_length == 15 {
+            literal_length = read_length(data, &mut position, literal_length)?;
+        }
+        let literal_end = position.checked_add(literal_length)?;
+        if output.len() + literal_length > raw_length {
+            return None;
+        }
+        output.extend_from_slice(data.get(position..literal_end)?);
+        position = literal_end;
+        if position == data.len() {
+            break;
+        }
+
+        let offset = data.get(position..position + 2)?;
+        let offset = u16::from_le_bytes([offset[0], offset[1]]) as usize;
+        position += 2;
+        if offset == 0 || offset > output.len() {
+            return None;
+        }
+        let mut match_length = (token & 15) as usize;
+        if match_length == 15 {
+            match_length = read_length(data, &mut position, match_length)?;
+        }
+        match_length += MIN_MATCH;
+        if output.len() + match_length > raw_length {
+            return None;
+        }
+
+        // The match may overlap with bytes it's writing, so it's copied byte by byte.
+        let start = output.len() - offset;
+        for index in start..start + match_length {
+            let byte = output[index];
+            output.push(byte);
+        }
+    }
+
+    if output.len() == raw_length {
+        Some(output)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(data: &[u8]) -> usize {
+        let compressed = Compression::Lz4.compress(data);
+        assert_eq!(
+            Compression::Lz4.decompress(&compressed, data.len()),
+            Some(data.to_vec())
+        );
+        compressed.len()
+    }
+
+    #[test]
+    fn lz4_round_trip() {
+        assert_eq!(round_trip(b""), 1);
+        round_trip(b"HELLO");
+        round_trip(b"HELLO WORLD HELLO WORLD HELLO WORLD");
+
+        let mut padded = b"KEY00001".to_vec();
+        padded.extend_from_slice(b"VALUE");
+        padded.resize(4096, 0);
+        assert!(round_trip(&padded) < 64);
+
+        let text: Vec<u8> = (0..20000u32).map(|index| (index * 7 % 251) as u8).collect();
+        round_trip(&text);
+    }
+
+    #[test]
+    fn reject_corrupted_block() {
+        let data = vec![1u8; 1000];
+        let compressed = Compression::Lz4.compress(&data);
+        assert_eq!(Compression::Lz4.decompress(&compressed, 999), None);
+        assert_eq!(
+            Compression::Lz4.decompress(&compressed[0..compressed.len() - 1], 1000),
+            None
+        );
+        assert_eq!(Compression::None.decompress(&data, 999), None);
+    }
+}
@@ -1,34 +1,126 @@
-use super::database_log::DatabaseLog;
+use super::background::BackgroundPool;
+use super::blob::{blob_file_ids, blob_path, BlobGcReport, BlobIndex, BlobStore};
+use super::block_cache::BlockCache;
+use super::change_stream::{subscribe, ChangeStream, History, HistoryLog, Subscriber};
+use super::checkpoint::{
+    checkpoint_blobs, checkpoint_logs, checkpoint_tables, copy_checkpoint, CheckpointReport,
+};
+use super::column_family::{
+    family_dir, read_families, valid_family_name, write_families, ColumnFamily,
+    ColumnFamilyOptions, Family, DEFAULT_FAMILY, DEFAULT_FAMILY_NAME,
+};
+use super::compaction::CompactionReport;
+use super::compaction_filter::CompactionFilter;
+use super::compaction_strategy::{CompactionStrategy, LeveledCompaction};
+use super::compression::Compression;
+use super::database_log::LogRecord;
+use super::database_log::{DatabaseLog, DatabaseLogReader};
+use super::encryption::{check_key, Encryptor};
 use super::error::{StorageError, StorageResult};
-use super::manifest_manager::ManifestManager;
-use super::mem_database::MemDatabase;
-use super::merge::merge_iter;
-use super::{AsyncDatabase, SyncDatabase};
+use super::file_lock::FileLock;
+use super::ingest::IngestFile;
+use super::manifest_manager::write_manifest;
+use super::mem_database::{MemDatabase, Value};
+use super::merge_operator::{fold, MergeOperator};
+use super::rate_limiter::RateLimiter;
+use super::sstable::{KEY_LENGTH, VALUE_LENGTH};
+use super::statistics::{DatabaseStats, Statistics};
+use super::wal_archive::{
+    archived_logs, discard_log, for_each_archived_record, PendingLogs, RestorePoint,
+};
+use super::write_batch::WriteBatch;
+use super::AsyncDatabase;
 
-use agilulf_protocol::Slice;
+use agilulf_protocol::{
+    Command, DeleteCommand, DeleteRangeCommand, MergeCommand, PutCommand, PutExpireCommand, Slice,
+};
 use agilulf_protocol::{DatabaseError, DatabaseResult};
 
 use crossbeam::sync::ShardedLock;
-use futures::channel::mpsc::UnboundedSender;
+use futures::channel::oneshot;
 use futures::Future;
-use std::collections::VecDeque;
 use std::error::Error;
 use std::path::Path;
 use std::pin::Pin;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
 
 /// Database factory, which can be used in order to configure the properties of a new database.
 ///
 /// Methods can be chained on it in order to configure it.
 ///
-/// The two configurations available are:
+/// The configurations available are:
 ///
 /// * [restore](#method.restore): chooese whether restore from previous existing log. The default value
 /// is `true`.
 ///
 /// * [base_dir](#method.base_dir): choose where the base directory is. Base directory is used to store
-/// log, MANIFEST and SSTables. The default value of base_dir is `/var/tmp/agilulf`.
+/// log, MANIFEST and SSTables. It will be created if it doesn't exist. The default value of base_dir is
+/// `/var/tmp/agilulf`.
+///
+/// * [wal_dir](#method.wal_dir): where the log is written instead of base directory, e.g. on a faster
+/// disk. It's recorded in `PATHS` under base directory, and opening the database with another
+/// `wal_dir` fails with `StorageError::PathsChanged`, as logs in the old one would not be restored. By
+/// default it's base directory.
+///
+/// * [data_path](#method.data_path): add a directory for SSTables with its target size. Upper levels
+/// are put into the first data paths, and a level goes into the next one when the data paths before
+/// it are full, e.g. recent levels on a small SSD and the bottom level on large disks. The last data
+/// path takes what the others can't hold. The data path of every table is recorded in MANIFEST, and
+/// data paths are recorded in `PATHS`, so opening the database with other data paths (or in another
+/// order) fails with `StorageError::PathsChanged`. Target sizes can be changed. By default SSTables are
+/// in base directory.
+///
+/// * [max_open_files](#method.max_open_files): how many SSTables can be opened at the same time. Other
+/// tables will be opened on demand. The default value is `1000`.
+///
+/// * [block_cache_size](#method.block_cache_size): how many bytes of decoded blocks of compressed or
+/// encrypted tables are cached, shared by every column family. The default value is 8 MiB.
+///
+/// * [wal_archive_dir](#method.wal_archive_dir): where to keep logs after they are written into tables.
+/// They are needed by [restore_point_in_time](#method.restore_point_in_time). By default logs are
+/// removed.
+///
+/// * [merge_operator](#method.merge_operator): how operands written by `merge` are applied on values.
+/// `merge` fails if it's not set. The same operator should be set every time the database is opened,
+/// as operands in logs are applied again while restoring.
+///
+/// * [background_threads](#method.background_threads): how many threads flush MemDatabases and
+/// how many threads compact tables. Flushes never wait for compactions, and compactions of
+/// non-overlapping key ranges run in parallel. The default value is `1` and `2`.
+///
+/// * [rate_limiter](#method.rate_limiter): limits how fast flushes and compactions write tables, so
+/// they leave bandwidth of the disk to reads. It can be shared by several databases, and its limit
+/// can be changed while they are running. By default background writes are not limited.
+///
+/// * [compaction_strategy](#method.compaction_strategy): which tables are compacted after a flush, for
+/// every column family. [UniversalCompaction](struct.UniversalCompaction.html) rewrites records fewer
+/// times than the default [LeveledCompaction](struct.LeveledCompaction.html), at the cost of slower
+/// reads and more space. Tables written by either strategy can be read with the other one.
+///
+/// * [compaction_filter](#method.compaction_filter): called for every record rewritten by compaction,
+/// to keep, remove or change it. By default every record is kept.
+///
+/// * [compression](#method.compression): the codec of blocks in new tables of a level. Tables written
+/// with any codec can be read, so it can be changed when the database is opened again. By default
+/// tables are not compressed.
+///
+/// * [column_family](#method.column_family): open a column family with its options, and create it if
+/// it doesn't exist. Families created before are opened with default options if they are not given.
+/// `merge_operator`, `compaction_filter` and `compression` above are options of the default family.
+///
+/// * [blob_threshold](#method.blob_threshold): values longer than it are written into append only blob
+/// files, and only their positions are kept in log and tables, so they are not rewritten by flush and
+/// compaction. Space of overwritten values is reclaimed by
+/// [collect_blob_garbage](struct.Database.html#method.collect_blob_garbage). By default every value is
+/// kept in log and tables. It cannot be larger than the length of values in log and tables (256), so
+/// longer values always go to blob files.
+///
+/// * [encryptor](#method.encryptor): encrypt log, MANIFEST, tables and blob files of a new database
+/// (e.g. with a [ChaCha20Poly1305Encryptor](struct.ChaCha20Poly1305Encryptor.html) read from a key
+/// file). An encrypted database must be opened with the same key, and a database created without it
+/// cannot be encrypted later. By default nothing is encrypted.
 ///
 /// # Example
 ///
@@ -36,9 +128,25 @@ use std::sync::Arc;
 /// # use crate::agilulf::DatabaseBuilder;
 /// let database = DatabaseBuilder::default().restore(false).build().unwrap();
 /// ```
+#[derive(Clone)]
 pub struct DatabaseBuilder {
     base_dir: String,
     restore: bool,
+    max_open_files: usize,
+    block_cache_size: usize,
+    flush_threads: usize,
+    compaction_threads: usize,
+    rate_limiter: Arc<RateLimiter>,
+    wal_dir: Option<String>,
+    data_paths: Vec<(String, u64)>,
+    wal_archive_dir: Option<String>,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    compaction_strategy: Arc<dyn CompactionStrategy>,
+    compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    compression: Vec<(usize, Compression)>,
+    column_families: Vec<(String, ColumnFamilyOptions)>,
+    blob_threshold: Option<usize>,
+    encryptor: Option<Arc<dyn Encryptor>>,
 }
 
 impl Default for DatabaseBuilder {
@@ -46,6 +154,21 @@ impl Default for DatabaseBuilder {
         DatabaseBuilder {
             base_dir: "/var/tmp/agilulf".to_string(),
             restore: true,
+            max_open_files: 1000,
+            block_cache_size: 8 * 1024 * 1024,
+            flush_threads: 1,
+            compaction_threads: 2,
+            rate_limiter: Arc::new(RateLimiter::unlimited()),
+            wal_dir: None,
+            data_paths: Vec::new(),
+            wal_archive_dir: None,
+            merge_operator: None,
+            compaction_strategy: Arc::new(LeveledCompaction::default()),
+            compaction_filter: None,
+            compression: Vec::new(),
+            column_families: Vec::new(),
+            blob_threshold: None,
+            encryptor: None,
         }
     }
 }
@@ -59,10 +182,98 @@ impl DatabaseBuilder {
         self.base_dir = base_dir;
         self
     }
+    pub fn max_open_files(&mut self, max_open_files: usize) -> &mut Self {
+        self.max_open_files = max_open_files;
+        self
+    }
+    pub fn block_cache_size(&mut self, block_cache_size: usize) -> &mut Self {
+        self.block_cache_size = block_cache_size;
+        self
+    }
+    pub fn background_threads(
+        &mut self,
+        flush_threads: usize,
+        compaction_threads: usize,
+    ) -> &mut Self {
+        self.flush_threads = flush_threads;
+        self.compaction_threads = compaction_threads;
+        self
+    }
+    pub fn rate_limiter(&mut self, rate_limiter: Arc<RateLimiter>) -> &mut Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+    pub fn wal_dir(&mut self, wal_dir: String) -> &mut Self {
+        self.wal_dir = Some(wal_dir);
+        self
+    }
+    pub fn data_path(&mut self, path: String, target_size: u64) -> &mut Self {
+        self.data_paths.push((path, target_size));
+        self
+    }
+    pub fn wal_archive_dir(&mut self, wal_archive_dir: String) -> &mut Self {
+        self.wal_archive_dir = Some(wal_archive_dir);
+        self
+    }
+    pub fn merge_operator(&mut self, merge_operator: Arc<dyn MergeOperator>) -> &mut Self {
+        self.merge_operator = Some(merge_operator);
+        self
+    }
+    pub fn compaction_strategy(
+        &mut self,
+        compaction_strategy: Arc<dyn CompactionStrategy>,
+    ) -> &mut Self {
+        self.compaction_strategy = compaction_strategy;
+        self
+    }
+    pub fn compaction_filter(&mut self, compaction_filter: Arc<dyn CompactionFilter>) -> &mut Self {
+        self.compaction_filter = Some(compaction_filter);
+        self
+    }
+    pub fn compression(&mut self, level: usize, compression: Compression) -> &mut Self {
+        self.compression.push((level, compression));
+        self
+    }
+    pub fn column_family(&mut self, name: String, options: ColumnFamilyOptions) -> &mut Self {
+        self.column_families.retain(|(family, _)| family != &name);
+        self.column_families.push((name, options));
+        self
+    }
+    pub fn blob_threshold(&mut self, blob_threshold: usize) -> &mut Self {
+        self.blob_threshold = Some(blob_threshold);
+        self
+    }
+    pub fn encryptor(&mut self, encryptor: Arc<dyn Encryptor>) -> &mut Self {
+        self.encryptor = Some(encryptor);
+        self
+    }
+    /// Open the database. The base directory is locked until the returned `Database` is dropped.
+    ///
+    /// Files which are not needed any more (tables not in MANIFEST and logs already written into tables)
+    /// are removed. If `restore` is `false`, every old file is removed. Logs which were frozen but not
+    /// written into tables yet are restored as frozen databases and flushed again.
+    ///
+    /// Opening an encrypted database with another key (or without a key) fails with
+    /// `StorageError::WrongEncryptionKey` (or `StorageError::EncryptionKeyRequired`) before anything is
+    /// read.
     pub fn build(&self) -> StorageResult<Database> {
+        if let Some(blob_threshold) = self.blob_threshold {
+            if blob_threshold > VALUE_LENGTH {
+                log::error!("Values longer than {} must go to blob files", VALUE_LENGTH);
+                return Err(StorageError::InvalidBlobThreshold);
+            }
+        }
+        std::fs::create_dir_all(&self.base_dir)?;
+        let file_lock = FileLock::lock(&self.base_dir)?;
+        check_key(&self.base_dir, self.encryptor.as_ref(), self.restore)?;
+        let old_data_paths = self.check_paths()?;
+
         let base_path = Path::new(&self.base_dir);
+        let wal_dir = self.log_dir();
+        std::fs::create_dir_all(&wal_dir)?;
+        let wal_path = Path::new(&wal_dir);
 
-        let log_path = base_path.join("log");
+        let log_path = wal_path.join("log");
         let log_path = match log_path.to_str() {
             Some(str) => str,
             None => {
@@ -74,344 +285,3226 @@ impl DatabaseBuilder {
         let log_length = 4 * 1024 * 2;
 
         let database_log = match self.restore {
-            true => DatabaseLog::open(log_path, log_length)?,
-            false => DatabaseLog::create_new(log_path, log_length)?,
-        };
-
-        let mem_database = if self.restore {
-            MemDatabase::restore_from_iterator(database_log.iter())?
-        } else {
-            MemDatabase::default()
-        };
-
-        let frozen_databases_queue = Arc::new(ShardedLock::new(VecDeque::new()));
-
-        let manifest_manager = if self.restore {
-            ManifestManager::open(self.base_dir.as_str(), frozen_databases_queue.clone())?
-        } else {
-            ManifestManager::create_new(self.base_dir.as_str(), frozen_databases_queue.clone())?
+            true => DatabaseLog::open(log_path, log_length, self.encryptor.as_ref())?,
+            false => DatabaseLog::create_new(log_path, log_length, self.encryptor.as_ref())?,
         };
-        let freeze_notifier = manifest_manager.background_work()?;
-
-        Ok(Database {
-            frozen_databases: frozen_databases_queue,
-            mem_database: ShardedLock::new(Arc::new(mem_database)),
-            database_log: ShardedLock::new(Arc::new(database_log)),
-            base_dir: self.base_dir.to_string(),
-            log_counter: AtomicUsize::new(0),
-            manifest_manager,
-            freeze_notifier,
-        })
-    }
-}
 
-/// A Database with LevelDB algorithm. (Though the compaction of sstable is not implemented yet)
-///
-/// Now it will freeze exceeded MemDatabase into frozen_databases list. Then a background thread will
-/// write the frozen database into disk and modify the MANIFEST.
-pub struct Database {
-    frozen_databases: Arc<ShardedLock<VecDeque<Arc<MemDatabase>>>>,
-    mem_database: ShardedLock<Arc<MemDatabase>>,
-    database_log: ShardedLock<Arc<DatabaseLog>>,
-    base_dir: String,
-    log_counter: AtomicUsize,
-    manifest_manager: ManifestManager,
-    freeze_notifier: UnboundedSender<usize>,
-}
+        if !self.restore {
+            remove_families(base_path)?;
+            for path in old_data_paths.iter() {
+                if Path::new(path).exists() {
+                    remove_families(Path::new(path))?;
+                }
+            }
+            for id in blob_file_ids(&self.base_dir)? {
+                std::fs::remove_file(blob_path(&self.base_dir, id))?;
+            }
+        }
+        let blob_store = Arc::new(BlobStore::open(
+            &self.base_dir,
+            self.blob_threshold,
+            self.encryptor.clone(),
+        )?);
+        let mut registry = read_families(&self.base_dir)?;
+        let mut created = Vec::new();
+        for (name, _) in self.column_families.iter() {
+            if !valid_family_name(name) || name == DEFAULT_FAMILY_NAME {
+                log::error!("{:?} cannot be used as name of column family", name);
+                return Err(StorageError::InvalidColumnFamilyName);
+            }
+            if !registry.iter().any(|(_, family)| family == name) {
+                let id = registry
+                    .iter()
+                    .map(|(id, _)| *id)
+                    .max()
+                    .unwrap_or(DEFAULT_FAMILY)
+                    + 1;
+                registry.push((id, name.clone()));
+                created.push(id);
+            }
+        }
 
-impl Database {
-    fn check_mem_database(&self) -> StorageResult<()> {
-        if self.mem_database.read().unwrap().large_enough() {
-            let base_path = Path::new(&self.base_dir);
+        let mut default_options = ColumnFamilyOptions::default();
+        if let Some(merge_operator) = &self.merge_operator {
+            default_options.merge_operator(merge_operator.clone());
+        }
+        if let Some(compaction_filter) = &self.compaction_filter {
+            default_options.compaction_filter(compaction_filter.clone());
+        }
+        for (level, compression) in self.compression.iter() {
+            default_options.compression(*level, *compression);
+        }
 
-            let old_database = self.mem_database.read().unwrap().clone();
-            let mut frozen_queue = self.frozen_databases.write().unwrap();
+        let statistics = Arc::new(Statistics::default());
+        let block_cache = Arc::new(BlockCache::new(self.block_cache_size));
+        let background_pool = BackgroundPool::new(self.flush_threads, self.compaction_threads)?;
+        let pending_logs = Arc::new(PendingLogs::new(
+            &wal_dir,
+            self.wal_archive_dir.clone(),
+            self.encryptor.clone(),
+        ));
+        let mut families = Vec::new();
+        let default_family = (DEFAULT_FAMILY, DEFAULT_FAMILY_NAME.to_string());
+        for (id, name) in std::iter::once(default_family).chain(registry.iter().cloned()) {
+            let options = match self
+                .column_families
+                .iter()
+                .find(|(family, _)| family == &name)
+            {
+                Some((_, options)) => options.clone(),
+                None if id == DEFAULT_FAMILY => default_options.clone(),
+                None => ColumnFamilyOptions::default(),
+            };
+            families.push(Arc::new(Family::open(
+                &self.base_dir,
+                &self.data_paths,
+                id,
+                &name,
+                &options,
+                self.restore && !created.contains(&id),
+                database_log.iter(id),
+                self.max_open_files,
+                block_cache.clone(),
+                pending_logs.clone(),
+                background_pool.scheduler(),
+                self.compaction_strategy.clone(),
+                statistics.clone(),
+                self.rate_limiter.clone(),
+                blob_store.clone(),
+                self.encryptor.clone(),
+            )?));
+        }
+        // New families are registered after their directories are created, so a registered family
+        // always has its MANIFEST.
+        if !created.is_empty() {
+            write_families(&self.base_dir, &registry)?;
+        }
 
-            let new_database = MemDatabase::default();
-            self.mem_database
-                .write()
-                .unwrap()
-                .clone_from(&Arc::new(new_database));
+        // Every family has written logs before its log number, so only logs after the smallest one are
+        // needed.
+        let min_log_number = families.iter().map(|family| family.log_number()).min();
+        let min_log_number = min_log_number.unwrap_or(0);
+        let log_counter = families.iter().map(|family| family.log_number()).max();
+        let mut log_counter = log_counter.unwrap_or(0);
+        let mut last_sequence = families
+            .iter()
+            .map(|family| family.manifest_manager.current().last_sequence)
+            .fold(database_log.last_sequence(), std::cmp::max);
+        let mut flushes = Vec::new();
+        for log_id in frozen_log_ids(wal_path)? {
+            let frozen_log_path = wal_path.join(format!("log.{}", log_id));
+            if !self.restore {
+                log::info!("Removing old log {:#?}", frozen_log_path);
+                std::fs::remove_file(&frozen_log_path)?;
+                continue;
+            }
+            if log_id < min_log_number {
+                log::info!("Removing obsolete log {:#?}", frozen_log_path);
+                discard_log(
+                    &frozen_log_path,
+                    self.wal_archive_dir.as_ref().map(String::as_str),
+                    self.encryptor.as_ref(),
+                )?;
+                continue;
+            }
 
-            let log_id = self.log_counter.fetch_add(1, Ordering::SeqCst);
-            let new_log_path = base_path.join(format!("log.{}", log_id));
-            let new_log_path = match new_log_path.to_str() {
+            let frozen_log_path = match frozen_log_path.to_str() {
                 Some(str) => str,
                 None => {
-                    log::error!("log path {:#?} is not UTF-8", new_log_path);
+                    log::error!("log path {:#?} is not UTF-8", frozen_log_path);
                     return Err(StorageError::UnicodeError);
                 }
             };
-            self.database_log.read().unwrap().rename(new_log_path)?;
+            log::info!("Restoring frozen log {}", frozen_log_path);
+            let frozen_log =
+                DatabaseLog::open(frozen_log_path, log_length, self.encryptor.as_ref())?;
+            last_sequence = std::cmp::max(last_sequence, frozen_log.last_sequence());
 
-            let log_path = base_path.join("log");
-            let log_path = match log_path.to_str() {
-                Some(str) => str,
-                None => {
-                    log::error!("log path {:#?} is not UTF-8", log_path);
-                    return Err(StorageError::UnicodeError);
+            let mut pending = 0;
+            for family in families.iter() {
+                if log_id < family.log_number() {
+                    continue;
                 }
-            };
-            let new_log = DatabaseLog::create_new(log_path, 4 * 1024 * 2)?;
-            self.database_log
-                .write()
-                .unwrap()
-                .clone_from(&Arc::new(new_log));
+                let frozen_database = MemDatabase::restore_from_records(
+                    frozen_log.iter(family.id()),
+                    family.merge_operator().cloned(),
+                )?;
+                family
+                    .frozen_databases
+                    .write()
+                    .unwrap()
+                    .push_front((log_id, Arc::new(frozen_database)));
+                flushes.push((family.clone(), log_id));
+                pending += 1;
+            }
+            pending_logs.add(log_id, pending)?;
 
-            frozen_queue.push_front(old_database);
-            self.freeze_notifier.clone().unbounded_send(log_id)?;
-        };
+            log_counter = std::cmp::max(log_counter, log_id + 1);
+        }
 
-        Ok(())
-    }
-}
+        for (family, log_id) in flushes {
+            family.background.flush(log_id, None);
+        }
 
-impl AsyncDatabase for Database {
-    /// GET request for the database will firstly read from MemDatabase. And then read from frozen database
-    /// . Then will find in SSTable. If they are all not found, error will be returned.
-    fn get(&self, key: Slice) -> Pin<Box<dyn Future<Output = DatabaseResult<Slice>> + Send + '_>> {
-        Box::pin(async move {
-            let ret = self.mem_database.read().unwrap().get_sync(key.clone());
+        Ok(Database {
+            families: ShardedLock::new(families),
+            database_log: ShardedLock::new(Arc::new(database_log)),
+            base_dir: self.base_dir.to_string(),
+            wal_dir,
+            data_paths: self.data_paths.clone(),
+            max_open_files: self.max_open_files,
+            block_cache,
+            compaction_strategy: self.compaction_strategy.clone(),
+            log_counter: AtomicUsize::new(log_counter),
+            last_sequence: AtomicU64::new(last_sequence),
+            change_subscribers: Mutex::new(Vec::new()),
+            wal_archive_dir: self.wal_archive_dir.clone(),
+            statistics,
+            rate_limiter: self.rate_limiter.clone(),
+            pending_logs,
+            blob_store,
+            encryptor: self.encryptor.clone(),
+            background_pool,
+            _file_lock: file_lock,
+        })
+    }
 
-            if ret.is_err() {
-                for db in self.frozen_databases.read().unwrap().iter() {
-                    let try_ret = db.get_sync(key.clone());
-                    if try_ret.is_ok() {
-                        return try_ret;
-                    }
-                }
+    /// Build a database in `base_dir` from a checkpoint created by `Database::checkpoint`, and replay
+    /// logs archived in `archive_dir` up to `restore_point`. It can be used to get the state just before
+    /// a bad write.
+    ///
+    /// Files of the checkpoint are copied, so the checkpoint can be used again. `archive_dir` should not
+    /// be the `wal_archive_dir` of the restored database, otherwise logs after `restore_point` will be
+    /// mixed with new writes. Column families created after the checkpoint should be given by
+    /// `column_family`, or their records cannot be replayed. Values in blob files are read from the
+    /// checkpoint, so blob files written after it should be copied into `base_dir` too. Logs of the
+    /// checkpoint are copied into `wal_dir`, and tables into `base_dir`.
+    pub fn restore_point_in_time(
+        &self,
+        checkpoint_dir: &str,
+        archive_dir: &str,
+        restore_point: RestorePoint,
+    ) -> StorageResult<Database> {
+        copy_checkpoint(checkpoint_dir, &self.base_dir, &self.log_dir())?;
+        let database = self.clone().restore(true).build()?;
 
-                match self.manifest_manager.find_key(key.clone()) {
-                    Some(value) => return Ok(value),
-                    None => return Err(DatabaseError::KeyNotFound),
-                };
+        if let RestorePoint::Sequence(sequence) = restore_point {
+            if sequence < database.last_sequence() {
+                log::error!(
+                    "Checkpoint contains writes up to {}, which is after {}",
+                    database.last_sequence(),
+                    sequence
+                );
+                return Err(StorageError::RestorePointTooEarly);
             }
+        }
 
-            ret
-        })
+        // Timestamps are read from the clock while records are appended, so they're not always in order
+        // of sequence number (writers append at the same time, and the clock can be set back). Every
+        // record is checked instead of stopping at the first one after `restore_point`.
+        let mut replayed = 0;
+        for_each_archived_record(
+            archive_dir,
+            database.last_sequence(),
+            self.encryptor.as_ref(),
+            |record| {
+                // Markers of ingested tables write nothing, and belong to no column family.
+                if !restore_point.includes(&record) || record.is_ingest() {
+                    return Ok(());
+                }
+                let family = record.family;
+                let command = resolve_blob(&database.blob_store, record)?;
+                database.write(family, command)?;
+                replayed += 1;
+                Ok(())
+            },
+        )?;
+        log::info!("Replayed {} archived records", replayed);
+
+        Ok(database)
     }
 
-    /// PUT request to this database will simply run PUT command on MemDatabase and check
-    /// whether MemDatabase is so big that needs to freeze.
-    fn put(
-        &self,
-        key: Slice,
-        value: Slice,
-    ) -> Pin<Box<dyn Future<Output = DatabaseResult<()>> + Send + '_>> {
-        Box::pin(async move {
-            match self
-                .database_log
-                .read()
-                .unwrap()
-                .put_sync(key.clone(), value.clone())
-            {
-                Ok(()) => {}
-                Err(err) => {
-                    return Err(DatabaseError::InternalError(err.description().to_string()))
-                }
-            };
-            let ret = self.mem_database.read().unwrap().put_sync(key, value);
+    /// Check `wal_dir` and data paths against the ones recorded in `PATHS`, and record them if the
+    /// database is new (or was created before they were recorded). Returns data paths used before, whose
+    /// column families are removed if `restore` is `false`.
+    ///
+    /// Without `PATHS`, the database is only rejected if `wal_dir` is set while logs are still in base
+    /// directory.
+    fn check_paths(&self) -> StorageResult<Vec<String>> {
+        let base_path = Path::new(&self.base_dir);
+        let data_paths: Vec<String> = self
+            .data_paths
+            .iter()
+            .map(|(path, _)| path.clone())
+            .collect();
+        let recorded = read_paths(&self.base_dir)?;
 
-            match self.check_mem_database() {
-                Ok(()) => {}
-                Err(err) => {
-                    return Err(DatabaseError::InternalError(err.description().to_string()))
+        if self.restore {
+            match &recorded {
+                Some((wal_dir, old_data_paths)) => {
+                    let same_wal_dir =
+                        wal_dir.as_ref().map(Path::new) == self.wal_dir.as_ref().map(Path::new);
+                    let same_data_paths = old_data_paths.len() == data_paths.len()
+                        && old_data_paths
+                            .iter()
+                            .zip(data_paths.iter())
+                            .all(|(old, new)| Path::new(old) == Path::new(new));
+                    if !same_wal_dir || !same_data_paths {
+                        log::error!(
+                            "Database in {} was created with wal_dir {:?} and data paths {:?}",
+                            self.base_dir,
+                            wal_dir,
+                            old_data_paths
+                        );
+                        return Err(StorageError::PathsChanged);
+                    }
+                    return Ok(data_paths);
                 }
-            };
+                None if self.wal_dir.is_some()
+                    && (base_path.join("log").exists()
+                        || !frozen_log_ids(base_path)?.is_empty()) =>
+                {
+                    log::error!(
+                        "Logs in {} would not be restored from wal_dir",
+                        self.base_dir
+                    );
+                    return Err(StorageError::PathsChanged);
+                }
+                None => {}
+            }
+        }
 
-            ret
-        })
+        write_paths(&self.base_dir, self.wal_dir.as_deref(), &data_paths)?;
+        Ok(recorded.map_or(data_paths, |(_, old_data_paths)| old_data_paths))
     }
 
-    /// SCAN operation will merge every iterator from MemDatabase and FrozenDatabase and SStable together
-    /// and return.
-    fn scan(
-        &self,
-        start: Slice,
-        end: Slice,
-    ) -> Pin<Box<dyn Future<Output = Vec<(Slice, Slice)>> + Send + '_>> {
-        Box::pin(async move {
-            let mut merge_vec: Vec<Box<dyn Iterator<Item = (Slice, Slice)>>> = Vec::new();
-            merge_vec.push(Box::new(
-                self.mem_database
-                    .read()
-                    .unwrap()
-                    .scan_sync(start.clone(), end.clone())
-                    .into_iter(),
-            ));
-            for db in self.frozen_databases.read().unwrap().iter() {
-                merge_vec.push(Box::new(
-                    db.scan_sync(start.clone(), end.clone()).into_iter(),
-                ));
-            }
-            merge_vec.push(Box::new(
-                self.manifest_manager.scan(start.clone(), end.clone()),
-            ));
-
-            merge_iter(merge_vec).collect()
+    /// `wal_dir` if it's set, otherwise base directory.
+    fn log_dir(&self) -> String {
+        self.wal_dir
+            .clone()
+            .unwrap_or_else(|| self.base_dir.clone())
+    }
+}
+
+/// A PUT, or a PUT_EXPIRE if `expire_at` is not `0`.
+fn put_command(key: Slice, value: Slice, expire_at: u64) -> Command {
+    if expire_at == 0 {
+        Command::PUT(PutCommand { key, value })
+    } else {
+        Command::PUT_EXPIRE(PutExpireCommand {
+            key,
+            value,
+            expire_at,
         })
     }
+}
 
-    // Like PUT, delete will also check the MemDatabase size and may trigger a freeze.
-    fn delete(&self, key: Slice) -> Pin<Box<dyn Future<Output = DatabaseResult<()>> + Send + '_>> {
-        Box::pin(async move {
-            match self.database_log.read().unwrap().delete_sync(key.clone()) {
-                Ok(()) => {}
-                Err(err) => {
-                    return Err(DatabaseError::InternalError(err.description().to_string()))
-                }
-            };
-            let ret = self.mem_database.read().unwrap().delete_sync(key);
+/// The command of a log record, with its value read from blob files if it's kept there.
+pub fn resolve_blob(blob_store: &BlobStore, record: LogRecord) -> StorageResult<Command> {
+    let (key, index, expire_at) = match record.blob_index() {
+        Some(blob) => blob,
+        None => return Ok(record.command),
+    };
+    let value = blob_store.read(&index)?;
 
-            match self.check_mem_database() {
-                Ok(()) => {}
-                Err(err) => {
-                    return Err(DatabaseError::InternalError(err.description().to_string()))
-                }
-            };
+    Ok(put_command(key, value, expire_at))
+}
 
-            ret
-        })
+/// A write of a batch ready to be appended into log, with the key, blob index and expiration time of its
+/// value if the value has been written into blob files.
+type PreparedWrite = (Arc<Family>, Command, Option<(Slice, BlobIndex, u64)>);
+
+/// Whether a command writes on a key.
+fn touches(command: &Command, key: &Slice) -> bool {
+    match command {
+        Command::PUT(command) => command.key == *key,
+        Command::PUT_EXPIRE(command) => command.key == *key,
+        Command::DELETE(command) => command.key == *key,
+        Command::DELETE_RANGE(command) => command.start <= *key && *key < command.end,
+        Command::MERGE(command) => command.key == *key,
+        _ => false,
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use agilulf_protocol::Command;
-    use rand::distributions::Standard;
-    use rand::{thread_rng, Rng};
+/// A MERGE on a value in blob files, which cannot keep operands above it in MemDatabase, is applied at
+/// once and turned into a PUT of the new value (or a DELETE). The value is the last one written on the
+/// key by `earlier` writes of the same batch, or the one in MemDatabase if there isn't any.
+fn merge_on_blob(
+    family: &Family,
+    command: Command,
+    earlier: &[PreparedWrite],
+) -> DatabaseResult<Command> {
+    let merge = match &command {
+        Command::MERGE(merge) => merge,
+        _ => return Ok(command),
+    };
+    let earlier = earlier
+        .iter()
+        .rev()
+        .find(|(other, command, _)| other.id() == family.id() && touches(command, &merge.key));
+    let base = match earlier {
+        Some((_, Command::PUT(put), Some(_))) => Some(Value::Slice(put.value.clone())),
+        Some((_, Command::PUT_EXPIRE(put), Some(_))) => {
+            Some(Value::Expiring(put.value.clone(), put.expire_at))
+        }
+        _ => None,
+    };
+    let merged = match (earlier, base, family.merge_operator()) {
+        (Some(_), Some(base), Some(operator)) => Some(fold(
+            operator.as_ref(),
+            &merge.key,
+            base,
+            std::slice::from_ref(&merge.operand),
+        )),
+        (Some(_), _, _) => None,
+        (None, _, _) => family.merge_blob(&merge.key, merge.operand.clone())?,
+    };
 
-    fn generate_keys(num: usize) -> Vec<Vec<u8>> {
-        (0..num)
-            .map(|_| thread_rng().sample_iter(&Standard).take(8).collect())
-            .collect()
-    }
+    Ok(match merged {
+        Some(Value::Slice(value)) => put_command(merge.key.clone(), value, 0),
+        Some(Value::Expiring(value, expire_at)) => put_command(merge.key.clone(), value, expire_at),
+        Some(_) => Command::DELETE(DeleteCommand {
+            key: merge.key.clone(),
+        }),
+        None => command,
+    })
+}
 
-    fn generate_values(num: usize) -> Vec<Vec<u8>> {
-        (0..num)
-            .map(|_| thread_rng().sample_iter(&Standard).take(256).collect())
-            .collect()
+/// Remove directories of every column family except the default one, and the registry of them.
+fn remove_families(base_path: &Path) -> StorageResult<()> {
+    for entry in std::fs::read_dir(base_path)? {
+        let path = entry?.path();
+        match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) if name.starts_with("family_") && path.is_dir() => {
+                log::info!("Removing old column family {:#?}", path);
+                std::fs::remove_dir_all(&path)?;
+            }
+            Some("FAMILIES") => std::fs::remove_file(&path)?,
+            _ => {}
+        }
     }
 
-    #[test]
-    fn log_test() {
-        let database = DatabaseBuilder::default().restore(false).build().unwrap();
-        futures::executor::block_on(async move {
-            database
-                .put(Slice(b"HELLO".to_vec()), Slice(b"WORLD".to_vec()))
-                .await
-                .unwrap();
-        });
+    Ok(())
+}
 
-        let log_manager = DatabaseLog::open("/var/tmp/agilulf/log", 4 * 1024 * 2).unwrap();
-        for command in log_manager.iter() {
-            match command {
-                Command::PUT(command) => {
-                    assert_eq!(&command.key.0[0..5], b"HELLO");
-                    assert_eq!(&command.value.0[0..5], b"WORLD");
-                }
-                _ => unreachable!(),
+/// `wal_dir` (if it's set) and data paths of a database, recorded as `wal <dir>` and `data <dir>` lines
+/// in `PATHS` under base directory. `None` if they are not recorded.
+fn read_paths(base_dir: &str) -> StorageResult<Option<(Option<String>, Vec<String>)>> {
+    let path = Path::new(base_dir).join("PATHS");
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let mut wal_dir = None;
+    let mut data_paths = Vec::new();
+    for line in std::fs::read_to_string(&path)?.lines() {
+        let mut fields = line.splitn(2, ' ');
+        match (fields.next(), fields.next()) {
+            (Some("wal"), Some(dir)) => wal_dir = Some(dir.to_string()),
+            (Some("data"), Some(dir)) => data_paths.push(dir.to_string()),
+            _ => {
+                log::error!("Cannot understand {:?} in {:#?}", line, path);
+                return Err(StorageError::PathsFormatError);
             }
         }
+    }
 
-        let database = DatabaseBuilder::default().restore(true).build().unwrap();
-        futures::executor::block_on(async move {
-            let value = database.get(Slice(b"HELLO\0\0\0".to_vec())).await.unwrap();
-            assert_eq!(&value.0[0..5], b"WORLD");
-            database
-                .put(Slice(b"HELLO2".to_vec()), Slice(b"WORLD".to_vec()))
-                .await
-                .unwrap();
-        });
+    Ok(Some((wal_dir, data_paths)))
+}
 
-        let database = DatabaseBuilder::default().restore(true).build().unwrap();
-        futures::executor::block_on(async move {
-            let value = database.get(Slice(b"HELLO2\0\0".to_vec())).await.unwrap();
-            assert_eq!(&value.0[0..5], b"WORLD");
-        });
-    }
+/// Replace `PATHS` with a temporary file, which is written to disk first, so it's never half written.
+fn write_paths(base_dir: &str, wal_dir: Option<&str>, data_paths: &[String]) -> StorageResult<()> {
+    let path = Path::new(base_dir).join("PATHS");
+    let tmp_path = Path::new(base_dir).join("PATHS.tmp");
 
-    #[test]
-    fn frozen_test() {
-        let keys = generate_keys(10 * 1024);
-        let values = generate_values(10 * 1024);
+    let mut content: String = wal_dir.map_or(String::new(), |dir| format!("wal {}\n", dir));
+    for dir in data_paths {
+        content.push_str(&format!("data {}\n", dir));
+    }
+    std::fs::write(&tmp_path, content)?;
+    std::fs::File::open(&tmp_path)?.sync_all()?;
+    std::fs::rename(&tmp_path, &path)?;
+    std::fs::File::open(base_dir)?.sync_all()?;
 
-        let database = DatabaseBuilder::default().restore(false).build().unwrap();
+    Ok(())
+}
 
-        futures::executor::block_on(async move {
-            for index in 0..(5 * 1024) {
-                database
-                    .put(Slice(keys[index].clone()), Slice(values[index].clone()))
-                    .await
-                    .unwrap();
+/// Ids of every frozen log `log.<id>` in a directory, in ascending order.
+pub fn frozen_log_ids(base_path: &Path) -> StorageResult<Vec<usize>> {
+    let mut ids = Vec::new();
+    for entry in std::fs::read_dir(base_path)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+        if name.starts_with("log.") {
+            if let Ok(id) = name["log.".len()..].parse::<usize>() {
+                ids.push(id);
             }
+        }
+    }
+    ids.sort();
 
-            for index in 0..(5 * 1024) {
-                let value = database.get(Slice(keys[index].clone())).await.unwrap();
-                assert_eq!(value, Slice(values[index].clone()))
-            }
-        })
+    Ok(ids)
+}
+
+/// A Database with LevelDB algorithm.
+///
+/// Now it will freeze exceeded MemDatabase into frozen_databases list. Then a background thread will
+/// write the frozen database into disk and modify the MANIFEST, and compact tables into higher levels.
+///
+/// Keys are kept in column families. Every family has its own MemDatabase, tables and options, and
+/// they share one log, so a write is logged once whichever family it goes to. MemDatabases of every
+/// family are frozen together with the log.
+pub struct Database {
+    families: ShardedLock<Vec<Arc<Family>>>,
+    database_log: ShardedLock<Arc<DatabaseLog>>,
+    base_dir: String,
+    wal_dir: String,
+    data_paths: Vec<(String, u64)>,
+    max_open_files: usize,
+    block_cache: Arc<BlockCache>,
+    compaction_strategy: Arc<dyn CompactionStrategy>,
+    log_counter: AtomicUsize,
+    last_sequence: AtomicU64,
+    /// Writes are committed one by one while holding this lock, so they reach log, MemDatabase and
+    /// subscribers in the order of sequence numbers.
+    change_subscribers: Mutex<Vec<Subscriber>>,
+    wal_archive_dir: Option<String>,
+    statistics: Arc<Statistics>,
+    rate_limiter: Arc<RateLimiter>,
+    pending_logs: Arc<PendingLogs>,
+    blob_store: Arc<BlobStore>,
+    encryptor: Option<Arc<dyn Encryptor>>,
+    /// It's dropped after families, and waits for running background jobs before the lock is released.
+    background_pool: BackgroundPool,
+    _file_lock: FileLock,
+}
+
+impl Database {
+    /// Create a copy of this database in `target_dir` without stopping it. The copy can be opened by
+    /// `DatabaseBuilder` with `restore(true)`, and contains exactly the data written before calling it.
+    ///
+    /// Writing and freezing are blocked while logs and blob files are copied. Tables are hard linked (or
+    /// copied if linking is not possible) after that, as they are immutable and kept by the pinned
+    /// version.
+    pub fn checkpoint(&self, target_dir: &str) -> StorageResult<CheckpointReport> {
+        self.create_checkpoint(target_dir, false)
     }
 
-    #[test]
-    fn scan_test() {
-        let key = Slice(b"HELLO".to_vec());
-        let key = &key;
+    /// Same as `checkpoint`, but tables which already exist in `target_dir` (written by an earlier
+    /// checkpoint of this database) are skipped. Tables which are not needed any more are removed.
+    pub fn incremental_checkpoint(&self, target_dir: &str) -> StorageResult<CheckpointReport> {
+        self.create_checkpoint(target_dir, true)
+    }
 
-        let database = DatabaseBuilder::default().restore(false).build().unwrap();
+    fn create_checkpoint(
+        &self,
+        target_dir: &str,
+        incremental: bool,
+    ) -> StorageResult<CheckpointReport> {
+        std::fs::create_dir_all(target_dir)?;
+        let _target_lock = FileLock::lock(target_dir)?;
 
-        futures::executor::block_on(async move {
-            for index in 0..(5 * 1024) {
-                let value = Slice(format!("WORLD{}", index).into_bytes());
+        let families = self.families.read().unwrap().clone();
+        let (versions, mut report) = {
+            // Blob files are removed by garbage collection while holding it.
+            let _change_subscribers = self.change_subscribers.lock().unwrap();
+            // Freezing and flushing need to write frozen queues, and writing needs to read log.
+            let _frozen_databases: Vec<_> = families
+                .iter()
+                .map(|family| family.frozen_databases.read().unwrap())
+                .collect();
+            let _database_log = self.database_log.write().unwrap();
 
-                database.put(key.clone(), value).await.unwrap();
+            let versions: Vec<_> = families
+                .iter()
+                .map(|family| (family.id(), family.manifest_manager.current()))
+                .collect();
+            let log_number = versions.iter().map(|(_, version)| version.log_number).min();
+            let log_ids: Vec<usize> = frozen_log_ids(Path::new(&self.wal_dir))?
+                .into_iter()
+                .filter(|id| *id >= log_number.unwrap_or(0))
+                .collect();
+            checkpoint_logs(&self.wal_dir, target_dir, &log_ids)?;
+
+            for name in ["FAMILIES", "ENCRYPTION"].iter() {
+                let path = Path::new(&self.base_dir).join(name);
+                if path.exists() {
+                    std::fs::copy(&path, Path::new(target_dir).join(name))?;
+                }
             }
+            let report = checkpoint_blobs(
+                &self.base_dir,
+                target_dir,
+                &self.blob_store.sealed_files()?,
+                incremental,
+            )?;
 
-            let ret = database
-                .scan(Slice(b"HELL\0".to_vec()), Slice(b"HELLP".to_vec()))
-                .await;
-            assert_eq!(ret.len(), 1);
-            let value = Slice(format!("WORLD{}", (5 * 1024 - 1)).into_bytes());
-            assert_eq!(ret[0], (key.clone(), value));
-        })
+            (versions, report)
+        };
+
+        for (id, version) in versions {
+            let family_target = family_dir(target_dir, id);
+            std::fs::create_dir_all(&family_target)?;
+            let family_report = checkpoint_tables(&family_target, &version, incremental)?;
+            write_manifest(&family_target, &version, false, self.encryptor.as_ref())?;
+
+            report.linked += family_report.linked;
+            report.copied += family_report.copied;
+            report.skipped += family_report.skipped;
+        }
+
+        Ok(report)
+    }
+
+    /// The sequence number of the last write.
+    pub fn last_sequence(&self) -> u64 {
+        self.last_sequence.load(Ordering::SeqCst)
+    }
+
+    /// Create a column family with its options. Its name should not contain white space. Options are
+    /// not persisted, so they should be given by `DatabaseBuilder::column_family` when the database is
+    /// opened again.
+    pub fn create_column_family(
+        &self,
+        name: &str,
+        options: ColumnFamilyOptions,
+    ) -> StorageResult<ColumnFamily> {
+        if !valid_family_name(name) {
+            log::error!("{:?} cannot be used as name of column family", name);
+            return Err(StorageError::InvalidColumnFamilyName);
+        }
+
+        // A family must not be added while a write or freeze is running.
+        let _change_subscribers = self.change_subscribers.lock().unwrap();
+        let mut families = self.families.write().unwrap();
+        if families.iter().any(|family| family.handle.name() == name) {
+            return Err(StorageError::ColumnFamilyExists);
+        }
+
+        let id = families.iter().map(|family| family.id()).max().unwrap_or(0) + 1;
+        // The directory may be left by a family which was not registered before crash.
+        let dir = family_dir(&self.base_dir, id);
+        if Path::new(&dir).exists() {
+            std::fs::remove_dir_all(&dir)?;
+        }
+        let family = Family::open(
+            &self.base_dir,
+            &self.data_paths,
+            id,
+            name,
+            &options,
+            false,
+            std::iter::empty(),
+            self.max_open_files,
+            self.block_cache.clone(),
+            self.pending_logs.clone(),
+            self.background_pool.scheduler(),
+            self.compaction_strategy.clone(),
+            self.statistics.clone(),
+            self.rate_limiter.clone(),
+            self.blob_store.clone(),
+            self.encryptor.clone(),
+        )?;
+
+        let mut registry: Vec<(u32, String)> = families
+            .iter()
+            .filter(|family| family.id() != DEFAULT_FAMILY)
+            .map(|family| (family.id(), family.handle.name().to_string()))
+            .collect();
+        registry.push((id, name.to_string()));
+        write_families(&self.base_dir, &registry)?;
+
+        let handle = family.handle.clone();
+        families.push(Arc::new(family));
+
+        Ok(handle)
+    }
+
+    /// The handle of a column family. The default family is named `default`.
+    pub fn column_family(&self, name: &str) -> Option<ColumnFamily> {
+        self.families
+            .read()
+            .unwrap()
+            .iter()
+            .find(|family| family.handle.name() == name)
+            .map(|family| family.handle.clone())
+    }
+
+    /// Handles of every column family, ordered by id.
+    pub fn column_families(&self) -> Vec<ColumnFamily> {
+        self.families
+            .read()
+            .unwrap()
+            .iter()
+            .map(|family| family.handle.clone())
+            .collect()
+    }
+
+    /// Freeze MemDatabase and wait until it's written into a table. The returned report contains the new
+    /// tables of every column family, which are empty if there was nothing to write.
+    ///
+    /// Writes are only blocked while freezing, not while waiting. It blocks the calling thread, so
+    /// `flush_memtable_async` should be used in async code instead.
+    pub fn flush_memtable(&self) -> StorageResult<CompactionReport> {
+        futures::executor::block_on(self.flush_memtable_async())
+    }
+
+    /// Same as `flush_memtable`, but waits without blocking the thread.
+    pub async fn flush_memtable_async(&self) -> StorageResult<CompactionReport> {
+        let receivers = {
+            // Writes are committed while holding this lock, so none of them is half done.
+            let _change_subscribers = self.change_subscribers.lock().unwrap();
+            let started = Instant::now();
+            let receivers = self.freeze(true)?;
+            self.statistics.record_stall(started.elapsed());
+            receivers
+        };
+
+        let mut report = CompactionReport::default();
+        for receiver in receivers {
+            report.append(receiver.await??);
+        }
+
+        Ok(report)
+    }
+
+    /// Compact tables of a column family (the default one if `None`) overlapping `[start, end)` down to
+    /// the deepest level containing them, e.g. to drop keys removed by a large delete. It waits until
+    /// running background compactions and then this compaction finish. Records in MemDatabase and
+    /// frozen databases are not touched, so `flush_memtable` should be called first if they should be
+    /// compacted too.
+    pub fn compact_range(
+        &self,
+        family: Option<&ColumnFamily>,
+        start: Slice,
+        end: Slice,
+    ) -> StorageResult<CompactionReport> {
+        let family = self.family_of(family)?;
+        family.background.compact_range(&start, &end)
+    }
+
+    /// Load table files built by `SSTableWriter` into a column family (the default one if `None`). Files
+    /// are verified, and should not overlap with each other. They are moved into the directory of the
+    /// family and recorded in MANIFEST by a single edit, each into the deepest level which keeps it
+    /// above older records. The original files are removed only after MANIFEST is written to disk, and
+    /// they are kept if ingestion fails.
+    ///
+    /// Ingested records take one sequence number, and are newer than every write before.
+    /// MemDatabase is flushed first if it (or a frozen database) has keys in range of the files.
+    /// Writes are only blocked while the sequence number is taken and MANIFEST is written, not
+    /// while files are copied or while waiting for flushes and compactions. Ingested records are
+    /// not written into logs, so they are not sent to change subscribers. Only a marker taking up
+    /// the sequence number is appended into log. If MANIFEST cannot be written, the marker stays
+    /// and the sequence number is skipped.
+    pub fn ingest(
+        &self,
+        family: Option<&ColumnFamily>,
+        paths: &[&str],
+    ) -> StorageResult<CompactionReport> {
+        let family = self.family_of(family)?;
+        let mut files = Vec::new();
+        for path in paths {
+            if let Some(file) = IngestFile::open(path, self.encryptor.as_ref())? {
+                files.push(file);
+            }
+        }
+        files.sort_by(|a, b| a.smallest.cmp(&b.smallest));
+        for pair in files.windows(2) {
+            if pair[0].largest >= pair[1].smallest {
+                log::error!("{} overlaps with {}", pair[0].path, pair[1].path);
+                return Err(StorageError::IngestFilesOverlap);
+            }
+        }
+
+        let (start, mut end) = match (files.first(), files.last()) {
+            (Some(first), Some(last)) => (first.smallest.clone(), last.largest.clone()),
+            _ => return Ok(CompactionReport::default()),
+        };
+        // The largest key is included.
+        end.0.push(0);
+
+        // MemDatabase is flushed first, so tables are usually written only once. Keys may be
+        // written into the range again while tables are written, and then it's flushed again.
+        self.block_writes_outside(&family, &start, &end)?;
+        let (report, (_change_subscribers, started)) = family.background.ingest(files, || {
+            match self.block_writes_outside(&family, &start, &end)? {
+                Some(locked) => Ok(Some((locked, self.log_ingest()?))),
+                None => Ok(None),
+            }
+        })?;
+        self.statistics.record_stall(started.elapsed());
+
+        Ok(report)
+    }
+
+    /// Block writes if MemDatabase and frozen databases of a family have no key in `[start, end)`,
+    /// and return the guard with the time writes are blocked. Otherwise they are flushed without
+    /// blocking writes, and `None` is returned.
+    fn block_writes_outside(
+        &self,
+        family: &Family,
+        start: &Slice,
+        end: &Slice,
+    ) -> StorageResult<Option<(MutexGuard<Vec<Subscriber>>, Instant)>> {
+        let change_subscribers = self.change_subscribers.lock().unwrap();
+        let started = Instant::now();
+        if !family.memory_overlaps(start, end) {
+            return Ok(Some((change_subscribers, started)));
+        }
+        let receivers = self.freeze(true)?;
+        self.statistics.record_stall(started.elapsed());
+        drop(change_subscribers);
+
+        for receiver in receivers {
+            futures::executor::block_on(receiver)??;
+        }
+        Ok(None)
+    }
+
+    /// Take a sequence number for ingested tables, and append a marker of it into log, so
+    /// subscribers don't take the gap for a write which is lost. Writes should be blocked.
+    fn log_ingest(&self) -> StorageResult<u64> {
+        let full = {
+            let database_log = self.database_log.read().unwrap();
+            database_log.len() >= database_log.capacity()
+        };
+        if full {
+            self.freeze(false)?;
+        }
+
+        let sequence = self.last_sequence.fetch_add(1, Ordering::SeqCst) + 1;
+        self.database_log.read().unwrap().ingest(sequence)?;
+        Ok(sequence)
+    }
+
+    /// Estimated bytes taken by keys in `[start, end)` of the default column family, from the offsets of
+    /// the range in tables and the number of records in MemDatabases. Nothing is scanned, so deleted and
+    /// overwritten records which are not compacted yet are counted too.
+    pub fn approximate_size(&self, start: &Slice, end: &Slice) -> u64 {
+        self.approximate_range(None, start, end)
+            .map_or(0, |(bytes, _)| bytes)
+    }
+
+    /// Estimated number of keys in `[start, end)` of the default column family, counted like
+    /// `approximate_size`.
+    pub fn approximate_count(&self, start: &Slice, end: &Slice) -> u64 {
+        self.approximate_range(None, start, end)
+            .map_or(0, |(_, count)| count)
+    }
+
+    /// Estimated bytes and number of keys in `[start, end)` of a column family (the default one if
+    /// `None`), counted like `approximate_size`.
+    pub fn approximate_range(
+        &self,
+        family: Option<&ColumnFamily>,
+        start: &Slice,
+        end: &Slice,
+    ) -> StorageResult<(u64, u64)> {
+        Ok(self.family_of(family)?.approximate_range(start, end))
+    }
+
+    /// Rewrite blob files in which at least `dead_ratio` (from `0.0` to `1.0`) of the bytes belong to
+    /// values overwritten, deleted or expired. Live values in them are moved into the active blob file by
+    /// new writes, and then they are removed after moved values and the log are written to disk.
+    ///
+    /// Files are scanned without blocking writes. Writes are only blocked while a live value is checked
+    /// again and moved, so a value written after it's scanned is never replaced by the old one.
+    ///
+    /// Older log records pointing to removed files cannot be read any more, so they are not sent to
+    /// change subscribers or replayed by `restore_point_in_time`.
+    pub fn collect_blob_garbage(&self, dead_ratio: f64) -> StorageResult<BlobGcReport> {
+        let mut report = BlobGcReport::default();
+        for id in self.blob_store.sealed_files()? {
+            let (records, size) = self.blob_store.records(id)?;
+            let padded = self.blob_store.written_before_open(id);
+            let mut live = Vec::new();
+            let mut live_bytes = 0;
+            for record in records {
+                let family = match self.family(record.family) {
+                    Some(family) => family,
+                    None => continue,
+                };
+                let mut key = record.key;
+                if padded {
+                    key.0.resize(KEY_LENGTH, 0);
+                }
+                if family.holds_blob(&key, &record.index)? {
+                    live_bytes += self.blob_store.header_length() + record.index.length;
+                    live.push((family, key, record.index));
+                }
+            }
+            if size > 0 && ((size - live_bytes) as f64) < dead_ratio * size as f64 {
+                continue;
+            }
+
+            for (family, key, index) in live {
+                let mut change_subscribers = self.change_subscribers.lock().unwrap();
+                let started = Instant::now();
+                let (value, expire_at) = match family.live_blob(&key, &index)? {
+                    Some(Value::Slice(value)) => (value, 0),
+                    Some(Value::Expiring(value, expire_at)) => (value, expire_at),
+                    _ => continue,
+                };
+                let command = put_command(key, value, expire_at);
+                self.commit(&mut change_subscribers, vec![(family, command)], true)?;
+                self.statistics.record_stall(started.elapsed());
+                report.rewritten += 1;
+            }
+            self.blob_store.sync()?;
+            self.database_log.read().unwrap().sync()?;
+            self.blob_store.remove(id)?;
+            report.removed_files += 1;
+            report.removed_bytes += size;
+        }
+
+        Ok(report)
+    }
+
+    /// Statistics since the database is opened, with current sizes of MemDatabases and tables.
+    pub fn stats(&self) -> DatabaseStats {
+        let mut stats = self.statistics.snapshot();
+        for family in self.families.read().unwrap().iter() {
+            family.collect_stats(&mut stats);
+        }
+        stats
+    }
+
+    /// The limiter of flushes and compactions. Its limit can be changed at any time, e.g. lowered while
+    /// reads are slow.
+    pub fn rate_limiter(&self) -> &RateLimiter {
+        &self.rate_limiter
+    }
+
+    /// Subscribe every write committed with sequence number not less than `from_sequence`, in commit
+    /// order. Writes of every column family are sent with the family they go to. Sequence numbers
+    /// start from `1`, so `0` subscribes from the first write.
+    ///
+    /// New writes are sent as they commit, and writes before subscribing are read from logs (including
+    /// archived logs if `wal_archive_dir` is set) without blocking writers, one log at a time. If
+    /// some of the requested writes are not kept in any log, `StorageError::ChangesTruncated` is
+    /// returned. Values in blob files are read again as writes are sent, so the stream ends with an
+    /// error at a write whose blob file has been collected. See `ChangeStream` for a subscriber
+    /// which falls behind.
+    pub fn subscribe_changes(&self, from_sequence: u64) -> StorageResult<ChangeStream> {
+        let from_sequence = std::cmp::max(from_sequence, 1);
+        let (subscriber, mut stream) = subscribe();
+        let last_sequence = {
+            let mut change_subscribers = self.change_subscribers.lock().unwrap();
+            change_subscribers.push(subscriber);
+            self.last_sequence()
+        };
+        if from_sequence > last_sequence {
+            return Ok(stream);
+        }
+
+        // Writes after `last_sequence` are sent to the subscriber, and every write before has been
+        // appended into log.
+        let mut history = self.history(from_sequence, last_sequence)?;
+        if history.first_sequence()? != Some(from_sequence) {
+            log::error!("Changes from {} are not kept in logs", from_sequence);
+            return Err(StorageError::ChangesTruncated);
+        }
+        stream.set_history(history);
+
+        Ok(stream)
+    }
+
+    /// Writes in current log, frozen logs and archived logs with sequence number from
+    /// `from_sequence` to `to_sequence`, which are read as they're sent.
+    ///
+    /// Logs are moved from the current one to frozen ones and then into archive (or removed) while
+    /// they're found, so they're found in the same order, and a log moved away is found in the next
+    /// place. Current and frozen logs are opened at once, so they can still be read after they're
+    /// moved.
+    fn history(&self, from_sequence: u64, to_sequence: u64) -> StorageResult<History> {
+        let wal_path = Path::new(&self.wal_dir);
+        let mut logs = Vec::new();
+
+        let database_log = self.database_log.read().unwrap().clone();
+        if let Some(record) = database_log.records().next() {
+            logs.push((record.sequence, HistoryLog::Current(database_log)));
+        }
+        for log_id in frozen_log_ids(wal_path)? {
+            let frozen_log_path = wal_path.join(format!("log.{}", log_id));
+            let frozen_log = match frozen_log_path.to_str() {
+                Some(path) => DatabaseLogReader::open(path, self.encryptor.as_ref()),
+                None => continue,
+            };
+            let frozen_log = match frozen_log {
+                Ok(frozen_log) => frozen_log,
+                // The log is removed or archived by background worker at the same time.
+                Err(_) if !frozen_log_path.exists() => continue,
+                Err(err) => return Err(err.into()),
+            };
+            if let Some(record) = frozen_log.records().next() {
+                logs.push((record.sequence, HistoryLog::Frozen(frozen_log)));
+            }
+        }
+        if let Some(archive_dir) = &self.wal_archive_dir {
+            if Path::new(archive_dir).exists() {
+                let after = from_sequence.saturating_sub(1);
+                for (first_sequence, path) in archived_logs(archive_dir, after)? {
+                    logs.push((first_sequence, HistoryLog::Archived(path)));
+                }
+            }
+        }
+
+        let families = self.families.read().unwrap();
+        Ok(History::new(
+            logs,
+            from_sequence,
+            to_sequence,
+            families
+                .iter()
+                .map(|family| family.handle.clone())
+                .collect(),
+            self.blob_store.clone(),
+            self.encryptor.clone(),
+        ))
+    }
+
+    fn family(&self, id: u32) -> Option<Arc<Family>> {
+        self.families
+            .read()
+            .unwrap()
+            .iter()
+            .find(|family| family.id() == id)
+            .cloned()
+    }
+
+    /// The family of a handle, or the default family if it's `None`.
+    fn family_of(&self, family: Option<&ColumnFamily>) -> StorageResult<Arc<Family>> {
+        self.family(family.map_or(DEFAULT_FAMILY, ColumnFamily::id))
+            .ok_or(StorageError::ColumnFamilyNotFound)
+    }
+
+    /// Write a PUT, PUT_EXPIRE, DELETE, DELETE_RANGE or MERGE command into log and then MemDatabase of a
+    /// column family, and send it to subscribers. Every write gets a new sequence number.
+    fn write(&self, family: u32, command: Command) -> DatabaseResult<()> {
+        let mut batch = WriteBatch::new();
+        batch.push(family, command);
+        self.write_batch(batch)
+    }
+
+    /// Write every command of a batch, which may be in different column families, as one write. Records
+    /// of them are appended into log together, and a batch cut off by a crash is never replayed, so
+    /// either all of them or none of them are restored. Every command still gets its own sequence number.
+    ///
+    /// Commands are checked before anything is written, so a MERGE in a family without merge operator
+    /// fails the whole batch.
+    pub fn write_batch(&self, batch: WriteBatch) -> DatabaseResult<()> {
+        let mut writes = Vec::with_capacity(batch.len());
+        for (family, command) in batch.into_commands() {
+            let family = match self.family(family) {
+                Some(family) => family,
+                None => return Err(DatabaseError::ColumnFamilyNotFound),
+            };
+            if let (Command::MERGE(_), None) = (&command, family.merge_operator()) {
+                return Err(DatabaseError::MergeOperatorNotSet);
+            }
+            writes.push((family, command));
+        }
+        if writes.is_empty() {
+            return Ok(());
+        }
+        if writes.len() > self.database_log.read().unwrap().capacity() {
+            return Err(DatabaseError::InternalError(format!(
+                "batch of {} commands doesn't fit in a log",
+                writes.len()
+            )));
+        }
+
+        let mut change_subscribers = self.change_subscribers.lock().unwrap();
+        self.commit(&mut change_subscribers, writes, false)
+    }
+
+    /// Commit writes while holding the lock of subscribers. The value of a PUT is written into blob
+    /// files first if it's longer than `blob_threshold` or `to_blob` is `true`. A MERGE on a value in
+    /// blob files is applied at once, and committed as a PUT of the new value. Records are appended into
+    /// log as a batch, and writes are applied on MemDatabases only after all of them are appended.
+    fn commit(
+        &self,
+        change_subscribers: &mut Vec<Subscriber>,
+        writes: Vec<(Arc<Family>, Command)>,
+        to_blob: bool,
+    ) -> DatabaseResult<()> {
+        let mut prepared: Vec<PreparedWrite> = Vec::with_capacity(writes.len());
+        for (family, command) in writes {
+            let command = merge_on_blob(&family, command, &prepared)?;
+            let separated = match &command {
+                Command::PUT(command) if to_blob || self.blob_store.separates(&command.value) => {
+                    Some((&command.key, &command.value, 0))
+                }
+                Command::PUT_EXPIRE(command)
+                    if to_blob || self.blob_store.separates(&command.value) =>
+                {
+                    Some((&command.key, &command.value, command.expire_at))
+                }
+                _ => None,
+            };
+            // The value is written before the log record pointing to it.
+            let blob = match separated {
+                Some((key, value, expire_at)) => {
+                    match self.blob_store.append(family.id(), key, value) {
+                        Ok(index) => Some((key.clone(), index, expire_at)),
+                        Err(err) => {
+                            return Err(DatabaseError::InternalError(err.description().to_string()))
+                        }
+                    }
+                }
+                None => None,
+            };
+            prepared.push((family, command, blob));
+        }
+
+        // A batch is never split between two logs.
+        let full = {
+            let database_log = self.database_log.read().unwrap();
+            database_log.len() + prepared.len() > database_log.capacity()
+        };
+        if full {
+            if let Err(err) = self.freeze(false) {
+                return Err(DatabaseError::InternalError(err.description().to_string()));
+            }
+        }
+
+        let count = prepared.len() as u64;
+        let first_sequence = self.last_sequence.fetch_add(count, Ordering::SeqCst) + 1;
+        let database_log = self.database_log.read().unwrap();
+        for (index, (family, command, blob)) in prepared.iter().enumerate() {
+            let sequence = first_sequence + index as u64;
+            let remaining = (prepared.len() - 1 - index) as u32;
+            let log_result = match (command, blob) {
+                (_, Some((key, index, expire_at))) => {
+                    database_log.put_blob(family.id(), key, index, *expire_at, sequence, remaining)
+                }
+                (Command::PUT(command), None) => database_log.put(
+                    family.id(),
+                    &command.key,
+                    &command.value,
+                    sequence,
+                    remaining,
+                ),
+                (Command::PUT_EXPIRE(command), None) => database_log.put_expire(
+                    family.id(),
+                    &command.key,
+                    &command.value,
+                    command.expire_at,
+                    sequence,
+                    remaining,
+                ),
+                (Command::DELETE(command), None) => {
+                    database_log.delete(family.id(), &command.key, sequence, remaining)
+                }
+                (Command::DELETE_RANGE(command), None) => database_log.delete_range(
+                    family.id(),
+                    &command.start,
+                    &command.end,
+                    sequence,
+                    remaining,
+                ),
+                (Command::MERGE(command), None) => database_log.merge(
+                    family.id(),
+                    &command.key,
+                    &command.operand,
+                    sequence,
+                    remaining,
+                ),
+                _ => unreachable!(),
+            };
+            if let Err(err) = log_result {
+                return Err(DatabaseError::InternalError(err.description().to_string()));
+            }
+        }
+        let record_length = database_log.record_length();
+        drop(database_log);
+        for _ in 0..count {
+            self.statistics.record_write(record_length);
+        }
+
+        let mut ret = Ok(());
+        for (index, (family, command, blob)) in prepared.iter().enumerate() {
+            let result = match blob {
+                Some((key, index, expire_at)) => {
+                    family.apply_blob(key.clone(), *index, *expire_at);
+                    Ok(())
+                }
+                None => family.apply(command.clone()),
+            };
+            if ret.is_ok() {
+                ret = result;
+            }
+            if !change_subscribers.is_empty() {
+                let sequence = first_sequence + index as u64;
+                *change_subscribers = change_subscribers
+                    .drain(..)
+                    .filter_map(|mut subscriber| {
+                        let change = (sequence, family.handle.clone(), command.clone());
+                        if subscriber.send(change) {
+                            Some(subscriber)
+                        } else {
+                            None
+                        }
+                    })
+                    .collect();
+            }
+        }
+
+        for (family, _, _) in prepared.iter() {
+            if let Err(err) = self.check_mem_database(family) {
+                return Err(DatabaseError::InternalError(err.description().to_string()));
+            }
+        }
+
+        ret
+    }
+
+    fn check_mem_database(&self, family: &Family) -> StorageResult<()> {
+        if family.mem_database().large_enough() {
+            let started = Instant::now();
+            self.freeze(false)?;
+            self.statistics.record_stall(started.elapsed());
+        }
+
+        Ok(())
+    }
+
+    /// Replace MemDatabases of every column family and the log with new ones, and send the old ones to
+    /// background workers. If `wait` is `true`, results of writing them into tables can be received
+    /// from the returned receivers.
+    fn freeze(
+        &self,
+        wait: bool,
+    ) -> StorageResult<Vec<oneshot::Receiver<StorageResult<CompactionReport>>>> {
+        let wal_path = Path::new(&self.wal_dir);
+
+        let families = self.families.read().unwrap();
+        let mut frozen_queues: Vec<_> = families
+            .iter()
+            .map(|family| family.frozen_databases.write().unwrap())
+            .collect();
+        let old_databases: Vec<_> = families
+            .iter()
+            .map(|family| family.replace_mem_database())
+            .collect();
+
+        let log_id = self.log_counter.fetch_add(1, Ordering::SeqCst);
+        let new_log_path = wal_path.join(format!("log.{}", log_id));
+        let new_log_path = match new_log_path.to_str() {
+            Some(str) => str,
+            None => {
+                log::error!("log path {:#?} is not UTF-8", new_log_path);
+                return Err(StorageError::UnicodeError);
+            }
+        };
+        // Frozen logs are written to disk, so writes which must not be lost (e.g. values moved by
+        // `collect_blob_garbage`) only need the current log to be synced.
+        let database_log = self.database_log.read().unwrap();
+        database_log.sync()?;
+        database_log.rename(new_log_path)?;
+        drop(database_log);
+
+        let log_path = wal_path.join("log");
+        let log_path = match log_path.to_str() {
+            Some(str) => str,
+            None => {
+                log::error!("log path {:#?} is not UTF-8", log_path);
+                return Err(StorageError::UnicodeError);
+            }
+        };
+        let new_log = DatabaseLog::create_new(log_path, 4 * 1024 * 2, self.encryptor.as_ref())?;
+        self.database_log
+            .write()
+            .unwrap()
+            .clone_from(&Arc::new(new_log));
+
+        self.pending_logs.add(log_id, families.len())?;
+        let mut receivers = Vec::new();
+        for ((family, frozen_queue), old_database) in families
+            .iter()
+            .zip(frozen_queues.iter_mut())
+            .zip(old_databases)
+        {
+            frozen_queue.push_front((log_id, old_database));
+            let reply = if wait {
+                let (sender, receiver) = oneshot::channel();
+                receivers.push(receiver);
+                Some(sender)
+            } else {
+                None
+            };
+            family.background.flush(log_id, reply);
+        }
+
+        Ok(receivers)
+    }
+}
+
+impl AsyncDatabase for Database {
+    /// GET request for the database will firstly read from MemDatabase. And then read from frozen database
+    /// . Then will find in SSTable. It stops at the first place which has the key or deletes it. If the key
+    /// is not found or deleted, error will be returned.
+    fn get(&self, key: Slice) -> Pin<Box<dyn Future<Output = DatabaseResult<Slice>> + Send + '_>> {
+        self.get_cf(None, key)
+    }
+
+    /// PUT request to this database will simply run PUT command on MemDatabase and check
+    /// whether MemDatabase is so big that needs to freeze.
+    fn put(
+        &self,
+        key: Slice,
+        value: Slice,
+    ) -> Pin<Box<dyn Future<Output = DatabaseResult<()>> + Send + '_>> {
+        self.put_cf(None, key, value)
+    }
+
+    /// Expiration time is written into log and SSTables with the value. An expired key is hidden like a
+    /// deleted one, and is removed by compaction.
+    fn put_expire(
+        &self,
+        key: Slice,
+        value: Slice,
+        expire_at: u64,
+    ) -> Pin<Box<dyn Future<Output = DatabaseResult<()>> + Send + '_>> {
+        self.put_expire_cf(None, key, value, expire_at)
+    }
+
+    /// SCAN operation will merge every source from MemDatabase and FrozenDatabase and SStable together
+    /// and return. A key is taken from the newest source, unless it's deleted there or by a newer range
+    /// tombstone. Keys with operands of merge are looked up again to find their base values.
+    fn scan(
+        &self,
+        start: Slice,
+        end: Slice,
+    ) -> Pin<Box<dyn Future<Output = DatabaseResult<Vec<(Slice, Slice)>>> + Send + '_>> {
+        self.scan_cf(None, start, end)
+    }
+
+    // Like PUT, delete will also check the MemDatabase size and may trigger a freeze.
+    fn delete(&self, key: Slice) -> Pin<Box<dyn Future<Output = DatabaseResult<()>> + Send + '_>> {
+        self.delete_cf(None, key)
+    }
+
+    /// The range is written as a single range tombstone, which hides older keys in MemDatabases and
+    /// SSTables until compaction removes them.
+    fn delete_range(
+        &self,
+        start: Slice,
+        end: Slice,
+    ) -> Pin<Box<dyn Future<Output = DatabaseResult<()>> + Send + '_>> {
+        self.delete_range_cf(None, start, end)
+    }
+
+    /// Operands are written into log and MemDatabase. They are applied when the key is read, and are
+    /// folded into the value when MemDatabase is written into a table.
+    fn merge(
+        &self,
+        key: Slice,
+        operand: Slice,
+    ) -> Pin<Box<dyn Future<Output = DatabaseResult<()>> + Send + '_>> {
+        self.merge_cf(None, key, operand)
+    }
+
+    /// Estimated from tables and MemDatabases of the default column family, see `approximate_size`.
+    fn approximate(
+        &self,
+        start: Slice,
+        end: Slice,
+    ) -> Pin<Box<dyn Future<Output = DatabaseResult<(u64, u64)>> + Send + '_>> {
+        self.approximate_cf(None, start, end)
+    }
+
+    fn set_rate_limit(
+        &self,
+        bytes_per_second: u64,
+    ) -> Pin<Box<dyn Future<Output = DatabaseResult<()>> + Send + '_>> {
+        self.rate_limiter.set_bytes_per_second(bytes_per_second);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn get_cf<'a>(
+        &'a self,
+        family: Option<&'a ColumnFamily>,
+        key: Slice,
+    ) -> Pin<Box<dyn Future<Output = DatabaseResult<Slice>> + Send + 'a>> {
+        let family = family.map_or(DEFAULT_FAMILY, ColumnFamily::id);
+        Box::pin(async move {
+            match self.family(family) {
+                Some(family) => family.get(&key),
+                None => Err(DatabaseError::ColumnFamilyNotFound),
+            }
+        })
+    }
+
+    fn put_cf<'a>(
+        &'a self,
+        family: Option<&'a ColumnFamily>,
+        key: Slice,
+        value: Slice,
+    ) -> Pin<Box<dyn Future<Output = DatabaseResult<()>> + Send + 'a>> {
+        let family = family.map_or(DEFAULT_FAMILY, ColumnFamily::id);
+        Box::pin(async move { self.write(family, Command::PUT(PutCommand { key, value })) })
+    }
+
+    fn put_expire_cf<'a>(
+        &'a self,
+        family: Option<&'a ColumnFamily>,
+        key: Slice,
+        value: Slice,
+        expire_at: u64,
+    ) -> Pin<Box<dyn Future<Output = DatabaseResult<()>> + Send + 'a>> {
+        let family = family.map_or(DEFAULT_FAMILY, ColumnFamily::id);
+        Box::pin(async move {
+            self.write(
+                family,
+                Command::PUT_EXPIRE(PutExpireCommand {
+                    key,
+                    value,
+                    expire_at,
+                }),
+            )
+        })
+    }
+
+    fn scan_cf<'a>(
+        &'a self,
+        family: Option<&'a ColumnFamily>,
+        start: Slice,
+        end: Slice,
+    ) -> Pin<Box<dyn Future<Output = DatabaseResult<Vec<(Slice, Slice)>>> + Send + 'a>> {
+        let family = family.map_or(DEFAULT_FAMILY, ColumnFamily::id);
+        Box::pin(async move {
+            self.statistics.record_scan();
+            match self.family(family) {
+                Some(family) => family.scan(&start, &end),
+                None => Err(DatabaseError::ColumnFamilyNotFound),
+            }
+        })
+    }
+
+    fn delete_cf<'a>(
+        &'a self,
+        family: Option<&'a ColumnFamily>,
+        key: Slice,
+    ) -> Pin<Box<dyn Future<Output = DatabaseResult<()>> + Send + 'a>> {
+        let family = family.map_or(DEFAULT_FAMILY, ColumnFamily::id);
+        Box::pin(async move { self.write(family, Command::DELETE(DeleteCommand { key })) })
+    }
+
+    fn delete_range_cf<'a>(
+        &'a self,
+        family: Option<&'a ColumnFamily>,
+        start: Slice,
+        end: Slice,
+    ) -> Pin<Box<dyn Future<Output = DatabaseResult<()>> + Send + 'a>> {
+        let family = family.map_or(DEFAULT_FAMILY, ColumnFamily::id);
+        Box::pin(async move {
+            self.write(
+                family,
+                Command::DELETE_RANGE(DeleteRangeCommand { start, end }),
+            )
+        })
+    }
+
+    fn merge_cf<'a>(
+        &'a self,
+        family: Option<&'a ColumnFamily>,
+        key: Slice,
+        operand: Slice,
+    ) -> Pin<Box<dyn Future<Output = DatabaseResult<()>> + Send + 'a>> {
+        let family = family.map_or(DEFAULT_FAMILY, ColumnFamily::id);
+        Box::pin(async move { self.write(family, Command::MERGE(MergeCommand { key, operand })) })
+    }
+
+    fn approximate_cf<'a>(
+        &'a self,
+        family: Option<&'a ColumnFamily>,
+        start: Slice,
+        end: Slice,
+    ) -> Pin<Box<dyn Future<Output = DatabaseResult<(u64, u64)>> + Send + 'a>> {
+        let family = family.map_or(DEFAULT_FAMILY, ColumnFamily::id);
+        Box::pin(async move {
+            match self.family(family) {
+                Some(family) => Ok(family.approximate_range(&start, &end)),
+                None => Err(DatabaseError::ColumnFamilyNotFound),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::database_log::RECORD_LENGTH;
+    use super::super::mem_database::Value;
+    use super::*;
+    use agilulf_protocol::Command;
+    use rand::distributions::Standard;
+    use rand::{thread_rng, Rng};
+
+    fn generate_keys(num: usize) -> Vec<Vec<u8>> {
+        (0..num)
+            .map(|_| thread_rng().sample_iter(&Standard).take(8).collect())
+            .collect()
+    }
+
+    fn generate_values(num: usize) -> Vec<Vec<u8>> {
+        (0..num)
+            .map(|_| thread_rng().sample_iter(&Standard).take(256).collect())
+            .collect()
+    }
+
+    fn open_database(base_dir: &str, restore: bool) -> Database {
+        DatabaseBuilder::default()
+            .base_dir(base_dir.to_string())
+            .restore(restore)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn log_test() {
+        let base_dir = "/var/tmp/agilulf_log_test";
+        let database = open_database(base_dir, false);
+        futures::executor::block_on(async move {
+            database
+                .put(Slice(b"HELLO".to_vec()), Slice(b"WORLD".to_vec()))
+                .await
+                .unwrap();
+        });
+
+        let log_manager =
+            DatabaseLog::open("/var/tmp/agilulf_log_test/log", 4 * 1024 * 2, None).unwrap();
+        for record in log_manager.iter(DEFAULT_FAMILY) {
+            match record.command {
+                Command::PUT(command) => {
+                    assert_eq!(&command.key.0[0..5], b"HELLO");
+                    assert_eq!(&command.value.0[0..5], b"WORLD");
+                }
+                _ => unreachable!(),
+            }
+        }
+
+        let database = open_database(base_dir, true);
+        futures::executor::block_on(async move {
+            let value = database.get(Slice(b"HELLO\0\0\0".to_vec())).await.unwrap();
+            assert_eq!(&value.0[0..5], b"WORLD");
+            database
+                .put(Slice(b"HELLO2".to_vec()), Slice(b"WORLD".to_vec()))
+                .await
+                .unwrap();
+        });
+
+        let database = open_database(base_dir, true);
+        futures::executor::block_on(async move {
+            let value = database.get(Slice(b"HELLO2\0\0".to_vec())).await.unwrap();
+            assert_eq!(&value.0[0..5], b"WORLD");
+        });
+    }
+
+    #[test]
+    fn frozen_test() {
+        let keys = generate_keys(10 * 1024);
+        let values = generate_values(10 * 1024);
+
+        let database = open_database("/var/tmp/agilulf_frozen_test", false);
+
+        futures::executor::block_on(async move {
+            for index in 0..(5 * 1024) {
+                database
+                    .put(Slice(keys[index].clone()), Slice(values[index].clone()))
+                    .await
+                    .unwrap();
+            }
+
+            for index in 0..(5 * 1024) {
+                let value = database.get(Slice(keys[index].clone())).await.unwrap();
+                assert_eq!(value, Slice(values[index].clone()))
+            }
+        })
+    }
+
+    #[test]
+    fn scan_test() {
+        let key = Slice(b"HELLO".to_vec());
+        let key = &key;
+
+        let database = open_database("/var/tmp/agilulf_scan_test", false);
+
+        futures::executor::block_on(async move {
+            for index in 0..(5 * 1024) {
+                let value = Slice(format!("WORLD{}", index).into_bytes());
+
+                database.put(key.clone(), value).await.unwrap();
+            }
+
+            let ret = database
+                .scan(Slice(b"HELL\0".to_vec()), Slice(b"HELLP".to_vec()))
+                .await
+                .unwrap();
+            assert_eq!(ret.len(), 1);
+            let value = Slice(format!("WORLD{}", (5 * 1024 - 1)).into_bytes());
+            assert_eq!(ret[0], (key.clone(), value));
+        })
+    }
+
+    #[test]
+    fn restore_after_big_request_test() {
+        let keys = generate_keys(1024 * 16);
+        let values = generate_values(1024 * 16);
+
+        let keys = &keys;
+        let values = &values;
+
+        let base_dir = "/var/tmp/agilulf_restore_test";
+        let database = open_database(base_dir, false);
+        futures::executor::block_on(async move {
+            for index in 0..(1024 * 16) {
+                let key = Slice(keys[index].clone());
+                let value = Slice(values[index].clone());
+
+                database.put(key, value).await.unwrap();
+            }
+
+            for index in 0..(1024 * 16) {
+                let key = Slice(keys[index].clone());
+                let value = Slice(values[index].clone());
+
+                let ans = database.get(key).await.unwrap();
+                assert_eq!(ans, value);
+            }
+        });
+
+        let database = open_database(base_dir, true);
+        futures::executor::block_on(async move {
+            for index in 0..(1024 * 16) {
+                let key = Slice(keys[index].clone());
+                let value = Slice(values[index].clone());
+
+                let ans = database.get(key).await.unwrap();
+                assert_eq!(ans, value);
+            }
+        });
+    }
+
+    #[test]
+    fn overwrite_after_frozen_test() {
+        let keys = generate_keys(1024 * 5);
+        let keys = &keys;
+
+        let database = open_database("/var/tmp/agilulf_overwrite_test", false);
+        futures::executor::block_on(async move {
+            for round in 0..3 {
+                for key in keys.iter() {
+                    let value = Slice(format!("VALUE{}", round).into_bytes());
+                    database.put(Slice(key.clone()), value).await.unwrap();
+                }
+            }
+
+            for key in keys.iter() {
+                let value = database.get(Slice(key.clone())).await.unwrap();
+                assert_eq!(&value.0[0..6], b"VALUE2");
+            }
+        });
+    }
+
+    #[test]
+    fn lock_and_remove_obsolete_files_test() {
+        let base_dir = "/var/tmp/agilulf_obsolete_test";
+        let _ = std::fs::remove_dir_all(base_dir);
+
+        let database = open_database(base_dir, false);
+        match DatabaseBuilder::default()
+            .base_dir(base_dir.to_string())
+            .build()
+        {
+            Err(StorageError::DatabaseLocked) => {}
+            _ => panic!("base_dir should be locked"),
+        }
+        drop(database);
+
+        let stale_table = Path::new(base_dir).join("sstable_0_100");
+        let stale_log = Path::new(base_dir).join("log.100");
+        std::fs::write(&stale_table, b"").unwrap();
+        DatabaseLog::create_new(stale_log.to_str().unwrap(), 4 * 1024 * 2, None).unwrap();
+
+        let database = open_database(base_dir, false);
+        assert!(!stale_table.exists());
+        assert!(!stale_log.exists());
+        drop(database);
+    }
+
+    #[test]
+    fn restore_frozen_log_test() {
+        let base_dir = "/var/tmp/agilulf_frozen_log_test";
+        let _ = std::fs::remove_dir_all(base_dir);
+        drop(open_database(base_dir, false));
+
+        // A log which had been frozen but not written into a table before crash.
+        let frozen_log_path = Path::new(base_dir).join("log.0");
+        let frozen_log =
+            DatabaseLog::create_new(frozen_log_path.to_str().unwrap(), 4 * 1024 * 2, None).unwrap();
+        frozen_log
+            .put(
+                DEFAULT_FAMILY,
+                &Slice(b"FROZEN".to_vec()),
+                &Slice(b"VALUE".to_vec()),
+                1,
+                0,
+            )
+            .unwrap();
+        drop(frozen_log);
+
+        let database = open_database(base_dir, true);
+        futures::executor::block_on(async move {
+            let value = database.get(Slice(b"FROZEN\0\0".to_vec())).await.unwrap();
+            assert_eq!(&value.0[0..5], b"VALUE");
+        });
+    }
+
+    #[test]
+    fn checkpoint_test() {
+        let base_dir = "/var/tmp/agilulf_checkpoint_test";
+        let target_dir = "/var/tmp/agilulf_checkpoint_test_target";
+        let _ = std::fs::remove_dir_all(base_dir);
+        let _ = std::fs::remove_dir_all(target_dir);
+
+        let database = open_database(base_dir, false);
+        futures::executor::block_on(async {
+            for index in 0..(5 * 1024) {
+                let key = Slice(format!("{:08}", index).into_bytes());
+                database.put(key, Slice(b"OLD".to_vec())).await.unwrap();
+            }
+        });
+
+        // Wait for the frozen database to be written into a table.
+        while Path::new(base_dir).join("log.0").exists() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        let report = database.checkpoint(target_dir).unwrap();
+        assert_eq!(report.linked + report.copied, 1);
+        let report = database.incremental_checkpoint(target_dir).unwrap();
+        assert_eq!(report.skipped, 1);
+        assert_eq!(report.linked + report.copied, 0);
+
+        // A table of the same name and size, but written by another database.
+        let table = std::fs::read_dir(target_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| {
+                path.file_name()
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .starts_with("sstable_")
+            })
+            .unwrap();
+        let mut data = std::fs::read(&table).unwrap();
+        std::fs::remove_file(&table).unwrap();
+        data[25] = !data[25];
+        std::fs::write(&table, data).unwrap();
+        let report = database.incremental_checkpoint(target_dir).unwrap();
+        assert_eq!(report.skipped, 0);
+        assert_eq!(report.linked + report.copied, 1);
+
+        futures::executor::block_on(async {
+            for index in 0..(5 * 1024) {
+                let key = Slice(format!("{:08}", index).into_bytes());
+                database.put(key, Slice(b"NEW".to_vec())).await.unwrap();
+            }
+        });
+
+        let checkpoint = open_database(target_dir, true);
+        futures::executor::block_on(async {
+            for index in 0..(5 * 1024) {
+                let key = Slice(format!("{:08}", index).into_bytes());
+                let value = checkpoint.get(key).await.unwrap();
+                assert_eq!(&value.0[0..3], b"OLD");
+            }
+        });
+    }
+
+    #[test]
+    fn point_in_time_restore_test() {
+        let base_dir = "/var/tmp/agilulf_pitr_test";
+        let archive_dir = "/var/tmp/agilulf_pitr_test_archive";
+        let checkpoint_dir = "/var/tmp/agilulf_pitr_test_checkpoint";
+        let restore_dir = "/var/tmp/agilulf_pitr_test_restore";
+        for dir in [base_dir, archive_dir, checkpoint_dir, restore_dir].iter() {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+
+        let database = DatabaseBuilder::default()
+            .base_dir(base_dir.to_string())
+            .wal_archive_dir(archive_dir.to_string())
+            .restore(false)
+            .build()
+            .unwrap();
+        let write = |prefix: &str, count: usize, value: &[u8]| {
+            futures::executor::block_on(async {
+                for index in 0..count {
+                    let key = Slice(format!("{}{:07}", prefix, index).into_bytes());
+                    database.put(key, Slice(value.to_vec())).await.unwrap();
+                }
+            });
+        };
+
+        write("K", 10, b"GOOD");
+        database.checkpoint(checkpoint_dir).unwrap();
+        write("F", 5000, b"FILL");
+        let before_bad_write = database.last_sequence();
+        write("K", 10, b"BAD");
+        write("G", 5000, b"FILL");
+
+        // Wait for both frozen logs to be archived.
+        while Path::new(base_dir).join("log.0").exists()
+            || Path::new(base_dir).join("log.1").exists()
+        {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        let restored = DatabaseBuilder::default()
+            .base_dir(restore_dir.to_string())
+            .restore_point_in_time(
+                checkpoint_dir,
+                archive_dir,
+                RestorePoint::Sequence(before_bad_write),
+            )
+            .unwrap();
+        assert_eq!(restored.last_sequence(), before_bad_write);
+        futures::executor::block_on(async {
+            for index in 0..10 {
+                let key = Slice(format!("K{:07}", index).into_bytes());
+                assert_eq!(&restored.get(key).await.unwrap().0[0..4], b"GOOD");
+            }
+            for index in 0..5000 {
+                let key = Slice(format!("F{:07}", index).into_bytes());
+                assert_eq!(&restored.get(key).await.unwrap().0[0..4], b"FILL");
+            }
+            let key = Slice(format!("G{:07}", 0).into_bytes());
+            assert!(restored.get(key).await.is_err());
+        });
+    }
+
+    #[test]
+    fn change_stream_test() {
+        use super::super::change_stream::CHANGE_BUFFER;
+        use futures::StreamExt;
+
+        let base_dir = "/var/tmp/agilulf_change_stream_test";
+        let _ = std::fs::remove_dir_all(base_dir);
+        let database = open_database(base_dir, false);
+        let put = |prefix: &str, count: usize| {
+            futures::executor::block_on(async {
+                for index in 0..count {
+                    let key = Slice(format!("{}{:07}", prefix, index).into_bytes());
+                    database.put(key, Slice(b"VALUE".to_vec())).await.unwrap();
+                }
+            });
+        };
+
+        put("K", 10);
+        let mut changes = database.subscribe_changes(6).unwrap();
+        futures::executor::block_on(async {
+            database.delete(Slice(b"K0000000".to_vec())).await.unwrap();
+        });
+
+        futures::executor::block_on(async {
+            for sequence in 6..=10 {
+                match changes.next().await.unwrap().unwrap() {
+                    (seq, _, Command::PUT(command)) => {
+                        assert_eq!(seq, sequence);
+                        assert_eq!(command.key.0, format!("K{:07}", sequence - 1).into_bytes());
+                    }
+                    _ => panic!("unexpected change"),
+                }
+            }
+            match changes.next().await.unwrap().unwrap() {
+                (11, _, Command::DELETE(command)) => assert_eq!(&command.key.0[..], b"K0000000"),
+                _ => panic!("unexpected change"),
+            }
+        });
+
+        // `0` subscribes from the first write.
+        let mut changes = database.subscribe_changes(0).unwrap();
+        match futures::executor::block_on(changes.next())
+            .unwrap()
+            .unwrap()
+        {
+            (1, _, Command::PUT(command)) => assert_eq!(&command.key.0[..], b"K0000000"),
+            _ => panic!("unexpected change"),
+        }
+        drop(changes);
+
+        // A subscriber which doesn't read is dropped after it falls behind.
+        let mut lagging = database
+            .subscribe_changes(database.last_sequence() + 1)
+            .unwrap();
+
+        // Without an archive, `log.0` is removed after it's flushed.
+        put("F", 5000);
+        futures::executor::block_on(async {
+            let mut received = 0;
+            loop {
+                match lagging.next().await {
+                    Some(Ok(_)) => received += 1,
+                    Some(Err(StorageError::ChangesLagged)) => break,
+                    _ => panic!("subscriber should fall behind"),
+                }
+            }
+            assert!((CHANGE_BUFFER..5000).contains(&received));
+            assert!(lagging.next().await.is_none());
+        });
+        while Path::new(base_dir).join("log.0").exists() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        match database.subscribe_changes(1) {
+            Err(StorageError::ChangesTruncated) => {}
+            _ => panic!("changes should be truncated"),
+        }
+        assert!(database
+            .subscribe_changes(database.last_sequence() + 1)
+            .is_ok());
+    }
+
+    #[test]
+    fn delete_range_test() {
+        let base_dir = "/var/tmp/agilulf_delete_range_test";
+        let _ = std::fs::remove_dir_all(base_dir);
+        let key =
+            |prefix: &str, index: usize| Slice(format!("{}{:07}", prefix, index).into_bytes());
+        let put = |database: &Database, prefix: &str, count: usize| {
+            futures::executor::block_on(async {
+                for index in 0..count {
+                    database
+                        .put(key(prefix, index), Slice(b"VALUE".to_vec()))
+                        .await
+                        .unwrap();
+                }
+            });
+        };
+        let check = |database: &Database| {
+            futures::executor::block_on(async {
+                assert!(database.get(key("K", 999)).await.is_ok());
+                assert!(database.get(key("K", 1000)).await.is_err());
+                assert!(database.get(key("K", 2999)).await.is_err());
+                assert!(database.get(key("K", 3000)).await.is_ok());
+                assert!(database.get(key("K", 4000)).await.is_err());
+                assert_eq!(&database.get(key("K", 2000)).await.unwrap().0[0..3], b"NEW");
+
+                let keys: Vec<Slice> = database
+                    .scan(key("K", 990), key("K", 3010))
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|(key, _)| key)
+                    .collect();
+                assert_eq!(keys.len(), 21);
+                assert_eq!(keys[10], key("K", 2000));
+            });
+        };
+
+        // Most of keys are flushed into a table before they are deleted.
+        let database = open_database(base_dir, false);
+        put(&database, "K", 5000);
+        while Path::new(base_dir).join("log.0").exists() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        futures::executor::block_on(async {
+            database
+                .delete_range(key("K", 1000), key("K", 3000))
+                .await
+                .unwrap();
+            database.delete(key("K", 4000)).await.unwrap();
+            database
+                .put(key("K", 2000), Slice(b"NEW".to_vec()))
+                .await
+                .unwrap();
+        });
+        check(&database);
+        drop(database);
+
+        // The range tombstone is restored from log.
+        let database = open_database(base_dir, true);
+        check(&database);
+
+        // Level 0 is compacted after the fourth table is written. As there is no deeper level, deleted
+        // keys and range tombstones are dropped.
+        put(&database, "F", 4097 * 3);
+        let level_0_cleared = || {
+            std::fs::read_dir(base_dir).unwrap().all(|entry| {
+                let name = entry.unwrap().file_name();
+                !name.to_str().unwrap().starts_with("sstable_0_")
+            })
+        };
+        while !level_0_cleared() || Path::new(base_dir).join("log.3").exists() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        check(&database);
+
+        let tables = crate::inspect::table_summaries(base_dir, None).unwrap();
+        assert!(!tables.is_empty());
+        assert!(tables.iter().all(|table| table.range_tombstones == 0));
+        let mut keys = 0;
+        for table in tables {
+            let path = Path::new(base_dir).join(format!("sstable_1_{}", table.id));
+            let records = crate::inspect::read_table(&path, None).unwrap().records;
+            assert!(records.iter().all(|(_, value)| *value != Value::NotExist));
+            keys += records.iter().filter(|(key, _)| key.0[0] == b'K').count();
+        }
+        assert_eq!(keys, 1000 + 1 + 1999);
+    }
+
+    #[test]
+    fn expire_test() {
+        let base_dir = "/var/tmp/agilulf_expire_test";
+        let _ = std::fs::remove_dir_all(base_dir);
+        let key =
+            |prefix: &str, index: usize| Slice(format!("{}{:07}", prefix, index).into_bytes());
+        let later = super::super::mem_database::now_millis() + 3600 * 1000;
+        let put = |database: &Database, prefix: &str, count: usize, expire_at: Option<u64>| {
+            futures::executor::block_on(async {
+                for index in 0..count {
+                    let value = Slice(b"VALUE".to_vec());
+                    match expire_at {
+                        Some(expire_at) => {
+                            database.put_expire(key(prefix, index), value, expire_at)
+                        }
+                        None => database.put(key(prefix, index), value),
+                    }
+                    .await
+                    .unwrap();
+                }
+            });
+        };
+        let check = |database: &Database| {
+            futures::executor::block_on(async {
+                assert!(database.get(key("X", 0)).await.is_err());
+                assert!(database.get(key("O", 0)).await.is_err());
+                assert!(database.get(key("T", 0)).await.is_ok());
+                assert!(database
+                    .scan(key("O", 0), key("P", 0))
+                    .await
+                    .unwrap()
+                    .is_empty());
+                assert!(database
+                    .scan(key("X", 0), key("Y", 0))
+                    .await
+                    .unwrap()
+                    .is_empty());
+                assert_eq!(
+                    database.scan(key("T", 0), key("U", 0)).await.unwrap().len(),
+                    10
+                );
+            });
+        };
+
+        // An expired value hides the older one, even if that is in a table.
+        let database = open_database(base_dir, false);
+        put(&database, "O", 10, None);
+        put(&database, "X", 10, Some(1));
+        put(&database, "T", 10, Some(later));
+        put(&database, "K", 5000, None);
+        while Path::new(base_dir).join("log.0").exists() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        put(&database, "O", 10, Some(1));
+        check(&database);
+        drop(database);
+
+        // Expiration time is restored from log.
+        let database = open_database(base_dir, true);
+        check(&database);
+
+        // Expired keys are dropped by compaction, and others keep their expiration time.
+        put(&database, "F", 4097 * 3, None);
+        let level_0_cleared = || {
+            std::fs::read_dir(base_dir).unwrap().all(|entry| {
+                let name = entry.unwrap().file_name();
+                !name.to_str().unwrap().starts_with("sstable_0_")
+            })
+        };
+        while !level_0_cleared() || Path::new(base_dir).join("log.3").exists() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        check(&database);
+
+        let mut expiring = 0;
+        for table in crate::inspect::table_summaries(base_dir, None).unwrap() {
+            let path = Path::new(base_dir).join(format!("sstable_1_{}", table.id));
+            for (key, value) in crate::inspect::read_table(&path, None).unwrap().records {
+                assert!(key.0[0] != b'X' && key.0[0] != b'O');
+                if let Value::Expiring(_, expire_at) = value {
+                    assert_eq!(expire_at, later);
+                    expiring += 1;
+                }
+            }
+        }
+        assert_eq!(expiring, 10);
+    }
+
+    #[test]
+    fn merge_test() {
+        use super::super::U64AddOperator;
+
+        let base_dir = "/var/tmp/agilulf_merge_test";
+        let _ = std::fs::remove_dir_all(base_dir);
+        let key = |index: usize| Slice(format!("C{:07}", index).into_bytes());
+        let number = |value: u64| Slice(value.to_le_bytes().to_vec());
+        let open = |restore: bool| {
+            DatabaseBuilder::default()
+                .base_dir(base_dir.to_string())
+                .restore(restore)
+                .merge_operator(Arc::new(U64AddOperator))
+                .build()
+                .unwrap()
+        };
+        let flush = |database: &Database, log_id: usize| {
+            futures::executor::block_on(async {
+                for index in 0..5000 {
+                    let key = Slice(format!("F{:07}", index).into_bytes());
+                    database.put(key, Slice(b"VALUE".to_vec())).await.unwrap();
+                }
+            });
+            while Path::new(base_dir).join(format!("log.{}", log_id)).exists() {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+        };
+        let check = |database: &Database, expected: u64| {
+            futures::executor::block_on(async {
+                for index in 0..10 {
+                    let value = database.get(key(index)).await.unwrap();
+                    assert_eq!(&value.0[0..8], &(expected + index as u64).to_le_bytes());
+                }
+                let values = database.scan(key(0), key(10)).await.unwrap();
+                assert_eq!(values.len(), 10);
+                assert_eq!(&(values[9].1).0[0..8], &(expected + 9).to_le_bytes());
+            });
+        };
+        let merge = |database: &Database| {
+            futures::executor::block_on(async {
+                for index in 0..10 {
+                    database.merge(key(index), number(1)).await.unwrap();
+                }
+            });
+        };
+
+        // C0 to C9 start from 0 to 9. Operands without base value are folded while flushing.
+        let database = open(false);
+        futures::executor::block_on(async {
+            for index in 1..10 {
+                database
+                    .put(key(index), number(index as u64))
+                    .await
+                    .unwrap();
+            }
+        });
+        merge(&database);
+        check(&database, 1);
+        flush(&database, 0);
+        check(&database, 1);
+
+        // Operands in MemDatabase are applied on values in tables, also after restoring from log.
+        merge(&database);
+        check(&database, 2);
+        drop(database);
+        let database = open(true);
+        check(&database, 2);
+        flush(&database, 1);
+        check(&database, 2);
+        drop(database);
+
+        let database = open_database(base_dir, true);
+        futures::executor::block_on(async {
+            match database.merge(key(0), number(1)).await {
+                Err(DatabaseError::MergeOperatorNotSet) => {}
+                _ => panic!("merge should fail without merge operator"),
+            }
+        });
+    }
+
+    #[test]
+    fn compaction_filter_test() {
+        use super::super::{CompactionFilter, FilterDecision};
+
+        struct DropOld;
+        impl CompactionFilter for DropOld {
+            fn filter(&self, _: usize, _: &Slice, value: &Slice) -> FilterDecision {
+                match value.0.as_slice() {
+                    b"OLD" => FilterDecision::Remove,
+                    b"V1" => FilterDecision::Change(Slice(b"V2".to_vec())),
+                    // Values in blob files are read for the filter.
+                    value if value.len() == 200 && value.starts_with(b"BIG") => {
+                        FilterDecision::Change(Slice(vec![b'L'; 1000]))
+                    }
+                    _ => FilterDecision::Keep,
+                }
+            }
+        }
+
+        let base_dir = "/var/tmp/agilulf_compaction_filter_test";
+        let _ = std::fs::remove_dir_all(base_dir);
+        let key =
+            |prefix: &str, index: usize| Slice(format!("{}{:07}", prefix, index).into_bytes());
+        let database = DatabaseBuilder::default()
+            .base_dir(base_dir.to_string())
+            .restore(false)
+            .compaction_filter(Arc::new(DropOld))
+            .blob_threshold(100)
+            .build()
+            .unwrap();
+
+        futures::executor::block_on(async {
+            for index in 0..10 {
+                let old = Slice(b"OLD".to_vec());
+                database.put(key("O", index), old).await.unwrap();
+                let v1 = Slice(b"V1".to_vec());
+                database.put(key("V", index), v1).await.unwrap();
+                let mut big = b"BIG".to_vec();
+                big.resize(200, b'B');
+                database.put(key("B", index), Slice(big)).await.unwrap();
+            }
+            for index in 0..4097 * 4 {
+                let value = Slice(b"VALUE".to_vec());
+                database.put(key("F", index), value).await.unwrap();
+            }
+        });
+
+        // Level 0 is compacted after the fourth table is written.
+        let level_0_cleared = || {
+            std::fs::read_dir(base_dir).unwrap().all(|entry| {
+                let name = entry.unwrap().file_name();
+                !name.to_str().unwrap().starts_with("sstable_0_")
+            })
+        };
+        while !level_0_cleared() || Path::new(base_dir).join("log.3").exists() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        futures::executor::block_on(async {
+            assert!(database.get(key("O", 0)).await.is_err());
+            assert_eq!(&database.get(key("V", 0)).await.unwrap().0[0..3], b"V2\0");
+            assert!(database
+                .scan(key("O", 0), key("P", 0))
+                .await
+                .unwrap()
+                .is_empty());
+            assert!(database.get(key("F", 0)).await.is_ok());
+            // The changed value is too long for a table.
+            assert_eq!(
+                database.get(key("B", 0)).await.unwrap(),
+                Slice(vec![b'L'; 1000])
+            );
+        });
+
+        let stats = database.stats();
+        assert_eq!(stats.compaction_filter_removed, 10);
+        assert_eq!(stats.compaction_filter_changed, 20);
+        assert!(stats.compaction_filter_kept > 0);
+    }
+
+    #[test]
+    fn manual_compaction_test() {
+        let base_dir = "/var/tmp/agilulf_manual_compaction_test";
+        let _ = std::fs::remove_dir_all(base_dir);
+        let key =
+            |prefix: &str, index: usize| Slice(format!("{}{:07}", prefix, index).into_bytes());
+        let database = open_database(base_dir, false);
+
+        futures::executor::block_on(async {
+            for index in 0..100 {
+                let value = Slice(b"VALUE".to_vec());
+                database.put(key("F", index), value.clone()).await.unwrap();
+                database.put(key("K", index), value).await.unwrap();
+            }
+        });
+        let report = database.flush_memtable().unwrap();
+        assert_eq!(report.added, vec![(0, 0)]);
+        assert!(report.removed.is_empty());
+        assert!(report.bytes_written > 0);
+
+        let report = futures::executor::block_on(async {
+            database
+                .delete_range(key("K", 0), key("L", 0))
+                .await
+                .unwrap();
+            database.flush_memtable_async().await.unwrap()
+        });
+        assert_eq!(report.added, vec![(0, 1)]);
+
+        // Both tables in level 0 overlap the range. As there is no deeper level, the range tombstone
+        // and keys deleted by it are dropped.
+        let report = database
+            .compact_range(None, key("K", 0), key("L", 0))
+            .unwrap();
+        assert_eq!(report.removed, vec![(0, 1), (0, 0)]);
+        assert_eq!(report.added, vec![(1, 0)]);
+        assert!(report.bytes_read > report.bytes_written);
+
+        let tables = crate::inspect::table_summaries(base_dir, None).unwrap();
+        assert_eq!(tables.len(), 1);
+        assert_eq!(tables[0].range_tombstones, 0);
+        futures::executor::block_on(async {
+            assert!(database.get(key("K", 0)).await.is_err());
+            assert_eq!(
+                database.scan(key("F", 0), key("G", 0)).await.unwrap().len(),
+                100
+            );
+        });
+
+        // Nothing is left to compact.
+        let report = database
+            .compact_range(None, key("A", 0), key("B", 0))
+            .unwrap();
+        assert!(report.added.is_empty() && report.removed.is_empty());
     }
 
     #[test]
-    fn restore_after_big_request_test() {
-        let keys = generate_keys(1024 * 16);
-        let values = generate_values(1024 * 16);
+    fn universal_compaction_test() {
+        use super::super::UniversalCompaction;
 
-        let keys = &keys;
-        let values = &values;
+        let base_dir = "/var/tmp/agilulf_universal_compaction_test";
+        let database = DatabaseBuilder::default()
+            .base_dir(base_dir.to_string())
+            .restore(false)
+            .compaction_strategy(Arc::new(UniversalCompaction::default()))
+            .build()
+            .unwrap();
+        let key = |index: usize| Slice(format!("K{:07}", index).into_bytes());
+        let put_round = |count: usize, value: &str| {
+            futures::executor::block_on(async {
+                for index in 0..count {
+                    let value = Slice(value.as_bytes().to_vec());
+                    database.put(key(index), value).await.unwrap();
+                }
+            });
+            database.flush_memtable().unwrap();
+        };
+        // Compaction runs after the flush in background, and this waits until it finishes.
+        let wait_compaction = || {
+            database
+                .compact_range(
+                    None,
+                    Slice(b"A0000000".to_vec()),
+                    Slice(b"B0000000".to_vec()),
+                )
+                .unwrap();
+        };
 
-        let database = DatabaseBuilder::default().restore(false).build().unwrap();
-        futures::executor::block_on(async move {
-            for index in 0..(1024 * 16) {
-                let key = Slice(keys[index].clone());
-                let value = Slice(values[index].clone());
+        for round in 0..3 {
+            put_round(100, &format!("ROUND {}", round));
+        }
+        wait_compaction();
+        assert_eq!(database.stats().level_tables, vec![3, 0, 0, 0, 0, 0]);
+
+        // Four tables of the same size are merged into the bottommost level.
+        put_round(100, "ROUND 3");
+        wait_compaction();
+        assert_eq!(database.stats().level_tables, vec![0, 0, 0, 0, 0, 1]);
+
+        // Small tables are merged right above it, and newer records are still read first.
+        for round in 0..3 {
+            put_round(10, &format!("NEW {}", round));
+        }
+        wait_compaction();
+        assert_eq!(database.stats().level_tables, vec![0, 0, 0, 0, 1, 1]);
+
+        futures::executor::block_on(async {
+            for (index, expected) in
+                [(0, "NEW 2"), (9, "NEW 2"), (10, "ROUND 3"), (99, "ROUND 3")].iter()
+            {
+                let value = database.get(key(*index)).await.unwrap();
+                assert_eq!(&value.0[0..expected.len()], expected.as_bytes());
+            }
+        });
+    }
+
+    #[test]
+    fn ingest_test() {
+        use super::super::version::NUM_LEVELS;
+        use super::super::SSTableWriter;
+
+        let base_dir = "/var/tmp/agilulf_ingest_test";
+        let files_dir = "/var/tmp/agilulf_ingest_files";
+        let _ = std::fs::remove_dir_all(base_dir);
+        std::fs::create_dir_all(files_dir).unwrap();
+        let key =
+            |prefix: &str, index: usize| Slice(format!("{}{:07}", prefix, index).into_bytes());
+        let write_file = |name: &str, prefix: &str| {
+            let mut writer = SSTableWriter::new();
+            for index in 0..100 {
+                writer
+                    .put(key(prefix, index), Slice(b"NEW".to_vec()))
+                    .unwrap();
+            }
+            let path = format!("{}/{}", files_dir, name);
+            futures::executor::block_on(writer.finish(&path)).unwrap();
+            path
+        };
+
+        let mut writer = SSTableWriter::new();
+        writer.put(key("B", 0), Slice(b"VALUE".to_vec())).unwrap();
+        match writer.put(key("A", 0), Slice(b"VALUE".to_vec())) {
+            Err(StorageError::UnsortedKey) => {}
+            _ => panic!("keys should be ascending"),
+        }
+
+        let database = open_database(base_dir, false);
+        futures::executor::block_on(async {
+            database
+                .put(key("I", 50), Slice(b"OLD".to_vec()))
+                .await
+                .unwrap();
+        });
+
+        let overlapped = [
+            write_file("overlapped_0", "I"),
+            write_file("overlapped_1", "I"),
+        ];
+        match database.ingest(None, &[&overlapped[0], &overlapped[1]]) {
+            Err(StorageError::IngestFilesOverlap) => {}
+            _ => panic!("overlapped files should be rejected"),
+        }
+        assert!(Path::new(&overlapped[0]).exists() && Path::new(&overlapped[1]).exists());
+        assert_eq!(database.last_sequence(), 1);
+
+        // The file overlapping MemDatabase is put into level 0 after MemDatabase is flushed, and the
+        // other one goes to the bottom.
+        let i_file = write_file("i", "I");
+        let j_file = write_file("j", "J");
+        let report = database.ingest(None, &[&j_file, &i_file]).unwrap();
+        assert_eq!(report.added, vec![(0, 1), (NUM_LEVELS - 1, 0)]);
+        assert!(!Path::new(&i_file).exists() && !Path::new(&j_file).exists());
+        assert_eq!(database.last_sequence(), 2);
+
+        let check = |database: &Database| {
+            futures::executor::block_on(async {
+                assert_eq!(&database.get(key("I", 50)).await.unwrap().0[0..3], b"NEW");
+                assert_eq!(&database.get(key("J", 99)).await.unwrap().0[0..3], b"NEW");
+                assert_eq!(
+                    database.scan(key("I", 0), key("K", 0)).await.unwrap().len(),
+                    200
+                );
+            });
+        };
+        check(&database);
+        drop(database);
+
+        let database = open_database(base_dir, true);
+        check(&database);
+        assert_eq!(database.last_sequence(), 2);
+
+        // A table flushed into range of the file while it's written is older than the file, so the
+        // file is written again into level 0 above it.
+        let k_file = write_file("k", "K");
+        let files = vec![IngestFile::open(&k_file, None).unwrap().unwrap()];
+        let family = database.family_of(None).unwrap();
+        let mut locked = 0;
+        let (report, ()) = family
+            .background
+            .ingest(files, || {
+                locked += 1;
+                if locked == 1 {
+                    futures::executor::block_on(database.put(key("K", 10), Slice(b"OLD".to_vec())))
+                        .unwrap();
+                    database.flush_memtable().unwrap();
+                }
+                Ok(Some(((), database.last_sequence() + 1)))
+            })
+            .unwrap();
+        assert_eq!(locked, 2);
+        assert_eq!(report.added.len(), 1);
+        assert_eq!(report.added[0].0, 0);
+        futures::executor::block_on(async {
+            assert_eq!(&database.get(key("K", 10)).await.unwrap().0[0..3], b"NEW");
+        });
+
+        // The sequence number of ingested records is marked in log, so changes can be subscribed
+        // from it, while the marker itself is not sent.
+        let l_file = write_file("l", "L");
+        database.ingest(None, &[&l_file]).unwrap();
+        let ingested = database.last_sequence();
+        let mut changes = database.subscribe_changes(ingested).unwrap();
+        futures::executor::block_on(async {
+            use futures::StreamExt;
+
+            database
+                .put(key("M", 0), Slice(b"VALUE".to_vec()))
+                .await
+                .unwrap();
+            match changes.next().await.unwrap().unwrap() {
+                (sequence, _, Command::PUT(command)) => {
+                    assert_eq!(sequence, ingested + 1);
+                    assert_eq!(command.key, key("M", 0));
+                }
+                _ => panic!("unexpected change"),
+            }
+        });
+    }
+
+    #[test]
+    fn column_family_test() {
+        use super::super::U64AddOperator;
+
+        let base_dir = "/var/tmp/agilulf_column_family_test";
+        let target_dir = "/var/tmp/agilulf_column_family_test_target";
+        let _ = std::fs::remove_dir_all(base_dir);
+        let _ = std::fs::remove_dir_all(target_dir);
+
+        let open = |base_dir: &str, restore: bool| {
+            let mut options = ColumnFamilyOptions::default();
+            options.merge_operator(Arc::new(U64AddOperator));
+            DatabaseBuilder::default()
+                .base_dir(base_dir.to_string())
+                .restore(restore)
+                .column_family("counters".to_string(), options)
+                .build()
+                .unwrap()
+        };
+        let key = Slice(b"KEY00000".to_vec());
+        let one = Slice(1u64.to_le_bytes().to_vec());
+
+        let database = open(base_dir, false);
+        let users = database
+            .create_column_family("users", ColumnFamilyOptions::default())
+            .unwrap();
+        match database.create_column_family("users", ColumnFamilyOptions::default()) {
+            Err(StorageError::ColumnFamilyExists) => {}
+            _ => panic!("family should exist"),
+        }
+        match database.create_column_family("bad name", ColumnFamilyOptions::default()) {
+            Err(StorageError::InvalidColumnFamilyName) => {}
+            _ => panic!("name should be rejected"),
+        }
+        let counters = database.column_family("counters").unwrap();
+        let names: Vec<String> = database
+            .column_families()
+            .iter()
+            .map(|family| family.name().to_string())
+            .collect();
+        assert_eq!(names, vec!["default", "counters", "users"]);
+
+        futures::executor::block_on(async {
+            database
+                .put(key.clone(), Slice(b"DEFAULT".to_vec()))
+                .await
+                .unwrap();
+            database
+                .put_cf(Some(&users), key.clone(), Slice(b"USER".to_vec()))
+                .await
+                .unwrap();
+            database
+                .merge_cf(Some(&counters), key.clone(), one.clone())
+                .await
+                .unwrap();
+            match database.merge(key.clone(), one.clone()).await {
+                Err(DatabaseError::MergeOperatorNotSet) => {}
+                _ => panic!("default family has no merge operator"),
+            }
+        });
+
+        // Every family writes its own table.
+        let report = database.flush_memtable().unwrap();
+        assert_eq!(report.added.len(), 3);
+        futures::executor::block_on(async {
+            database
+                .merge_cf(Some(&counters), key.clone(), one.clone())
+                .await
+                .unwrap();
+            database.delete_cf(Some(&users), key.clone()).await.unwrap();
+        });
+
+        let check = |database: &Database| {
+            let users = database.column_family("users").unwrap();
+            let counters = database.column_family("counters").unwrap();
+            futures::executor::block_on(async {
+                assert_eq!(
+                    &database.get(key.clone()).await.unwrap().0[0..7],
+                    b"DEFAULT"
+                );
+                match database.get_cf(Some(&users), key.clone()).await {
+                    Err(DatabaseError::KeyNotFound) => {}
+                    _ => panic!("key should be deleted"),
+                }
+                let value = database.get_cf(Some(&counters), key.clone()).await.unwrap();
+                assert_eq!(&value.0[0..8], &2u64.to_le_bytes());
+                assert_eq!(
+                    database
+                        .scan_cf(
+                            Some(&counters),
+                            Slice(b"A\0\0\0\0\0\0\0".to_vec()),
+                            Slice(b"Z\0\0\0\0\0\0\0".to_vec())
+                        )
+                        .await
+                        .unwrap()
+                        .len(),
+                    1
+                );
+            });
+        };
+        check(&database);
+        database.checkpoint(target_dir).unwrap();
+        drop(database);
+
+        let database = open(base_dir, true);
+        check(&database);
+        drop(database);
+        check(&open(target_dir, true));
+    }
+
+    #[test]
+    fn write_batch_test() {
+        use super::super::{U64AddOperator, WriteBatch};
+
+        let base_dir = "/var/tmp/agilulf_write_batch_test";
+        let _ = std::fs::remove_dir_all(base_dir);
+
+        let open = |restore: bool| {
+            let mut options = ColumnFamilyOptions::default();
+            options.merge_operator(Arc::new(U64AddOperator));
+            DatabaseBuilder::default()
+                .base_dir(base_dir.to_string())
+                .restore(restore)
+                .column_family("counters".to_string(), options)
+                .build()
+                .unwrap()
+        };
+        let key = |index: usize| Slice(format!("K{:07}", index).into_bytes());
+        let one = Slice(1u64.to_le_bytes().to_vec());
+
+        let database = open(false);
+        let users = database
+            .create_column_family("users", ColumnFamilyOptions::default())
+            .unwrap();
+        let counters = database.column_family("counters").unwrap();
+        let mut batch = WriteBatch::new();
+        batch
+            .put(None, key(0), Slice(b"DEFAULT".to_vec()))
+            .put(Some(&users), key(0), Slice(b"USER".to_vec()))
+            .merge(Some(&counters), key(0), one.clone())
+            .merge(Some(&counters), key(0), one.clone());
+        database.write_batch(batch).unwrap();
+        assert_eq!(database.last_sequence(), 4);
+
+        // Nothing in a batch is written if any command is rejected.
+        let mut batch = WriteBatch::new();
+        batch
+            .put(None, key(1), Slice(b"DEFAULT".to_vec()))
+            .merge(None, key(1), one.clone());
+        match database.write_batch(batch) {
+            Err(DatabaseError::MergeOperatorNotSet) => {}
+            _ => panic!("default family has no merge operator"),
+        }
+        assert_eq!(database.last_sequence(), 4);
+        drop(database);
+
+        // A batch cut off by a crash is never replayed, and is erased before new records are appended.
+        let log_path = format!("{}/log", base_dir);
+        let log = DatabaseLog::open(&log_path, 4 * 1024 * 2, None).unwrap();
+        log.put(DEFAULT_FAMILY, &key(2), &Slice(b"LOST".to_vec()), 5, 1)
+            .unwrap();
+        drop(log);
+
+        let check = |database: &Database| {
+            let users = database.column_family("users").unwrap();
+            let counters = database.column_family("counters").unwrap();
+            futures::executor::block_on(async {
+                let value = database.get(key(0)).await.unwrap();
+                assert_eq!(&value.0[0..7], b"DEFAULT");
+                let value = database.get_cf(Some(&users), key(0)).await.unwrap();
+                assert_eq!(&value.0[0..4], b"USER");
+                let value = database.get_cf(Some(&counters), key(0)).await.unwrap();
+                assert_eq!(&value.0[0..8], &2u64.to_le_bytes());
+                assert!(database.get(key(1)).await.is_err());
+                assert!(database.get(key(2)).await.is_err());
+            });
+        };
+        let database = open(true);
+        check(&database);
+        assert_eq!(database.last_sequence(), 4);
+        futures::executor::block_on(database.put(key(3), Slice(b"NEW".to_vec()))).unwrap();
+        drop(database);
+
+        let database = open(true);
+        check(&database);
+        let value = futures::executor::block_on(database.get(key(3))).unwrap();
+        assert_eq!(&value.0[0..3], b"NEW");
+    }
+
+    #[test]
+    fn stats_test() {
+        use super::super::version::NUM_LEVELS;
+
+        let base_dir = "/var/tmp/agilulf_stats_test";
+        let database = open_database(base_dir, false);
+        let key = |index: usize| Slice(format!("K{:07}", index).into_bytes());
+
+        futures::executor::block_on(async {
+            for index in 0..10 {
+                database
+                    .put(key(index), Slice(b"VALUE".to_vec()))
+                    .await
+                    .unwrap();
+            }
+            database.get(key(0)).await.unwrap();
+            assert!(database.get(key(10)).await.is_err());
+            assert_eq!(database.scan(key(0), key(10)).await.unwrap().len(), 10);
+        });
+
+        let stats = database.stats();
+        assert_eq!(stats.write_count, 10);
+        // Every record is followed by its checksum.
+        assert_eq!(stats.wal_bytes_written, 10 * (RECORD_LENGTH + 4));
+        assert_eq!(stats.memtable_entries, 10);
+        assert_eq!(stats.memtable_bytes, 10 * (8 + 5));
+        assert_eq!((stats.get_hits_memtable, stats.get_misses), (1, 1));
+        assert_eq!(stats.scan_count, 1);
+        assert_eq!(stats.level_tables, vec![0; NUM_LEVELS]);
+
+        database.flush_memtable().unwrap();
+        futures::executor::block_on(async {
+            database.get(key(0)).await.unwrap();
+        });
+
+        let stats = database.stats();
+        assert_eq!(stats.memtable_entries, 0);
+        assert_eq!(stats.frozen_memtables, 0);
+        assert_eq!(stats.flush_count, 1);
+        assert_eq!(stats.level_tables[0], 1);
+        assert_eq!(stats.level_bytes[0], stats.flush_bytes);
+        assert_eq!(stats.get_hits_level[0], 1);
+
+        let report = database.compact_range(None, key(0), key(10)).unwrap();
+        let stats = database.stats();
+        assert_eq!(stats.compaction_count, 1);
+        assert_eq!(stats.compaction_bytes_written, report.bytes_written);
+        assert_eq!(stats.level_tables[0], 0);
+    }
+
+    #[test]
+    fn approximate_size_test() {
+        use super::super::sstable::{CHECKSUM_LENGTH, HEADER_LENGTH, PART_LENGTH};
+
+        let base_dir = "/var/tmp/agilulf_approximate_size_test";
+        let database = open_database(base_dir, false);
+        let key = |index: usize| Slice(format!("K{:07}", index).into_bytes());
+        let put_keys = |range: std::ops::Range<usize>| {
+            futures::executor::block_on(async {
+                for index in range {
+                    database
+                        .put(key(index), Slice(b"VALUE".to_vec()))
+                        .await
+                        .unwrap();
+                }
+            });
+        };
+
+        put_keys(0..100);
+        database.flush_memtable().unwrap();
+        put_keys(100..150);
+
+        // Half of the table and 20 keys in MemDatabase.
+        assert_eq!(database.approximate_count(&key(50), &key(120)), 70);
+        assert_eq!(
+            database.approximate_size(&key(50), &key(120)),
+            (HEADER_LENGTH + 100 * PART_LENGTH + CHECKSUM_LENGTH) as u64 / 2 + 20 * (8 + 5)
+        );
+
+        // The table is inside the range, so its file size is taken.
+        let (start, end) = (Slice(vec![0; 8]), Slice(vec![255; 8]));
+        assert_eq!(database.approximate_count(&start, &end), 150);
+        assert_eq!(
+            database.approximate_size(&start, &end),
+            database.stats().level_bytes[0] + 50 * (8 + 5)
+        );
+        assert_eq!(database.approximate_count(&key(200), &key(300)), 0);
+
+        futures::executor::block_on(async {
+            assert_eq!(database.approximate(key(0), key(10)).await.unwrap().1, 10);
+        });
 
+        let family = database
+            .create_column_family("approximate", ColumnFamilyOptions::default())
+            .unwrap();
+        futures::executor::block_on(async {
+            for index in 0..30 {
+                let value = Slice(b"VALUE".to_vec());
+                database
+                    .put_cf(Some(&family), key(index), value)
+                    .await
+                    .unwrap();
+            }
+            let (_, count) = database
+                .approximate_cf(Some(&family), key(0), key(10))
+                .await
+                .unwrap();
+            assert_eq!(count, 10);
+        });
+        let (_, count) = database
+            .approximate_range(Some(&family), &key(0), &key(100))
+            .unwrap();
+        assert_eq!(count, 30);
+        assert_eq!(database.approximate_count(&key(0), &key(100)), 100);
+    }
+
+    #[test]
+    fn rate_limit_test() {
+        let rate_limiter = Arc::new(RateLimiter::new(1024 * 1024, 256 * 1024));
+        let database = DatabaseBuilder::default()
+            .base_dir("/var/tmp/agilulf_rate_limit_test".to_string())
+            .restore(false)
+            .rate_limiter(rate_limiter.clone())
+            .build()
+            .unwrap();
+        futures::executor::block_on(async {
+            for index in 0..2000 {
+                let key = Slice(format!("K{:07}", index).into_bytes());
+                database.put(key, Slice(b"VALUE".to_vec())).await.unwrap();
+            }
+        });
+
+        // The whole table goes through the limiter, which is larger than the burst.
+        let report = database.flush_memtable().unwrap();
+        assert!(report.bytes_written > 256 * 1024);
+        assert_eq!(rate_limiter.requested_bytes(), report.bytes_written);
+
+        // The limit is changed by an admin request, and the shared limiter sees it.
+        futures::executor::block_on(async {
+            database.set_rate_limit(0).await.unwrap();
+            match MemDatabase::default().set_rate_limit(0).await {
+                Err(DatabaseError::NotSupported) => {}
+                _ => panic!("MemDatabase doesn't have background writes"),
+            }
+        });
+        assert_eq!(rate_limiter.bytes_per_second(), 0);
+        assert_eq!(database.rate_limiter().bytes_per_second(), 0);
+    }
+
+    #[test]
+    fn blob_test() {
+        let base_dir = "/var/tmp/agilulf_blob_test";
+        let invalid = DatabaseBuilder::default()
+            .base_dir(base_dir.to_string())
+            .blob_threshold(VALUE_LENGTH + 1)
+            .build();
+        match invalid {
+            Err(StorageError::InvalidBlobThreshold) => {}
+            _ => panic!("values longer than VALUE_LENGTH should go to blob files"),
+        }
+        let open = |restore: bool| {
+            DatabaseBuilder::default()
+                .base_dir(base_dir.to_string())
+                .restore(restore)
+                .blob_threshold(100)
+                .build()
+                .unwrap()
+        };
+        let key = |index: usize| Slice(format!("K{:07}", index).into_bytes());
+        let value = |index: usize, round: u8| Slice(vec![round + index as u8; 1000]);
+        let check = |database: &Database, round: u8, deleted: &[usize]| {
+            futures::executor::block_on(async {
+                for index in 0..20 {
+                    let found = database.get(key(index)).await;
+                    if deleted.contains(&index) {
+                        assert!(found.is_err());
+                    } else {
+                        assert_eq!(found.unwrap(), value(index, round));
+                    }
+                }
+                // Values in log and tables are padded.
+                let small = database.get(key(20)).await.unwrap();
+                assert_eq!(&small.0[0..5], b"SMALL");
+            });
+        };
+
+        let database = open(false);
+        futures::executor::block_on(async {
+            for index in 0..20 {
+                database.put(key(index), value(index, 0)).await.unwrap();
+            }
+            database
+                .put(key(20), Slice(b"SMALL".to_vec()))
+                .await
+                .unwrap();
+        });
+        check(&database, 0, &[]);
+        let scanned =
+            futures::executor::block_on(database.scan(Slice(vec![0; 8]), Slice(vec![255; 8])))
+                .unwrap();
+        assert_eq!(scanned.len(), 21);
+        assert_eq!(scanned[3], (key(3), value(3, 0)));
+        assert_eq!(blob_file_ids(base_dir).unwrap(), vec![0]);
+
+        // Values are restored from the log, and new values go to a new file.
+        drop(database);
+        let database = open(true);
+        check(&database, 0, &[]);
+        futures::executor::block_on(async {
+            for index in 0..20 {
+                database.put(key(index), value(index, 1)).await.unwrap();
+            }
+            database.delete(key(5)).await.unwrap();
+        });
+        database.flush_memtable().unwrap();
+        check(&database, 1, &[5]);
+        assert_eq!(blob_file_ids(base_dir).unwrap(), vec![0, 1]);
+
+        // Every value in the first file is overwritten, and the second one is still appended.
+        let report = database.collect_blob_garbage(0.5).unwrap();
+        assert_eq!((report.removed_files, report.rewritten), (1, 0));
+        assert_eq!(blob_file_ids(base_dir).unwrap(), vec![1]);
+        check(&database, 1, &[5]);
+
+        drop(database);
+        let database = open(true);
+        let report = database.collect_blob_garbage(0.5).unwrap();
+        assert_eq!(report.removed_files, 0);
+        let report = database.collect_blob_garbage(0.0).unwrap();
+        assert_eq!((report.removed_files, report.rewritten), (1, 19));
+        assert_eq!(blob_file_ids(base_dir).unwrap(), vec![2]);
+        check(&database, 1, &[5]);
+
+        drop(database);
+        let database = open(true);
+        check(&database, 1, &[5]);
+    }
+
+    #[test]
+    fn blob_short_key_test() {
+        let base_dir = "/var/tmp/agilulf_blob_short_key_test";
+        let open = |restore: bool| {
+            DatabaseBuilder::default()
+                .base_dir(base_dir.to_string())
+                .restore(restore)
+                .blob_threshold(100)
+                .build()
+                .unwrap()
+        };
+        let value = Slice(vec![7; 1000]);
+        let get = |database: &Database, key: &[u8]| {
+            futures::executor::block_on(database.get(Slice(key.to_vec())))
+        };
+
+        let database = open(false);
+        futures::executor::block_on(database.put(Slice(b"SHORT".to_vec()), value.clone())).unwrap();
+        // A short key is still found after it's flushed, and it's padded there.
+        database.flush_memtable().unwrap();
+        assert_eq!(get(&database, b"SHORT").unwrap(), value);
+
+        // The key is found in its padded form after restart, and the value is moved.
+        drop(database);
+        let database = open(true);
+        let report = database.collect_blob_garbage(0.0).unwrap();
+        assert_eq!((report.removed_files, report.rewritten), (1, 1));
+        assert_eq!(get(&database, b"SHORT\0\0\0").unwrap(), value);
+
+        drop(database);
+        let database = open(true);
+        assert_eq!(get(&database, b"SHORT\0\0\0").unwrap(), value);
+    }
+
+    #[test]
+    fn merge_blob_test() {
+        use super::super::AppendOperator;
+
+        let base_dir = "/var/tmp/agilulf_merge_blob_test";
+        let open = |restore: bool| {
+            DatabaseBuilder::default()
+                .base_dir(base_dir.to_string())
+                .restore(restore)
+                .blob_threshold(100)
+                .merge_operator(Arc::new(AppendOperator))
+                .build()
+                .unwrap()
+        };
+        let key = Slice(b"KEY\0\0\0\0\0".to_vec());
+        let merge = |database: &Database, operand: &[u8]| {
+            futures::executor::block_on(database.merge(key.clone(), Slice(operand.to_vec())))
+                .unwrap();
+        };
+        let check = |database: &Database, suffix: &[u8]| {
+            let mut expected = vec![b'A'; 200];
+            expected.extend_from_slice(suffix);
+            let value = futures::executor::block_on(database.get(key.clone())).unwrap();
+            assert_eq!(value, Slice(expected));
+        };
+
+        let database = open(false);
+        futures::executor::block_on(database.put(key.clone(), Slice(vec![b'A'; 200]))).unwrap();
+        database.flush_memtable().unwrap();
+
+        // The operand is kept above the value in blob files, and applied when it's read.
+        merge(&database, b"B");
+        check(&database, b"B");
+        // It's applied while flushing, and the new value goes back to blob files.
+        database.flush_memtable().unwrap();
+        check(&database, b"B");
+        assert_eq!(blob_file_ids(base_dir).unwrap(), vec![0]);
+
+        merge(&database, b"C");
+        drop(database);
+        let database = open(true);
+        check(&database, b"BC");
+        // The value is rewritten with operands above it.
+        let report = database.collect_blob_garbage(0.0).unwrap();
+        assert_eq!((report.removed_files, report.rewritten), (1, 1));
+        check(&database, b"BC");
+
+        drop(database);
+        let database = open(true);
+        check(&database, b"BC");
+    }
+
+    #[test]
+    fn compression_test() {
+        use super::super::sstable::PART_LENGTH;
+
+        let base_dir = "/var/tmp/agilulf_compression_test";
+        let open = |restore: bool, compression: Compression| {
+            DatabaseBuilder::default()
+                .base_dir(base_dir.to_string())
+                .restore(restore)
+                .compression(0, compression)
+                .build()
+                .unwrap()
+        };
+        let key = |index: usize| Slice(format!("K{:07}", index).into_bytes());
+        let put_keys = |database: &Database, range: std::ops::Range<usize>| {
+            futures::executor::block_on(async {
+                for index in range {
+                    let value = Slice(format!("VALUE {}", index).into_bytes());
+                    database.put(key(index), value).await.unwrap();
+                }
+            });
+        };
+
+        let database = open(false, Compression::Lz4);
+        put_keys(&database, 0..100);
+        database.flush_memtable().unwrap();
+        let compressed = database.stats().level_bytes[0];
+        assert!(compressed < 100 * PART_LENGTH as u64 / 4);
+
+        // Tables written with another codec are still read.
+        drop(database);
+        let database = open(true, Compression::None);
+        put_keys(&database, 100..200);
+        database.flush_memtable().unwrap();
+        assert!(database.stats().level_bytes[0] > compressed + 100 * PART_LENGTH as u64 - 1);
+        futures::executor::block_on(async {
+            for index in (0..200).step_by(7) {
+                let value = database.get(key(index)).await.unwrap();
+                let expected = format!("VALUE {}", index).into_bytes();
+                assert_eq!(&value.0[0..expected.len()], expected.as_slice());
+            }
+        });
+        assert_eq!(
+            database.approximate_count(&Slice(vec![0; 8]), &Slice(vec![255; 8])),
+            200
+        );
+    }
+
+    #[test]
+    fn encryption_test() {
+        use super::super::encryption::ChaCha20Poly1305Encryptor;
+
+        let base_dir = "/var/tmp/agilulf_encryption_test";
+        let open = |restore: bool, key: Option<u8>| {
+            let mut builder = DatabaseBuilder::default();
+            builder.base_dir(base_dir.to_string()).restore(restore);
+            if let Some(key) = key {
+                builder.encryptor(Arc::new(ChaCha20Poly1305Encryptor::new([key; 32]).unwrap()));
+            }
+            builder.build()
+        };
+
+        let database = open(false, Some(1)).unwrap();
+        futures::executor::block_on(async {
+            for index in 0..100 {
+                let key = Slice(format!("K{:07}", index).into_bytes());
+                let value = Slice(format!("SECRET {}", index).into_bytes());
                 database.put(key, value).await.unwrap();
             }
+        });
+        database.flush_memtable().unwrap();
+        drop(database);
 
-            for index in 0..(1024 * 16) {
-                let key = Slice(keys[index].clone());
-                let value = Slice(values[index].clone());
+        match open(true, None) {
+            Err(StorageError::EncryptionKeyRequired) => {}
+            _ => panic!("opened an encrypted database without a key"),
+        }
+        match open(true, Some(2)) {
+            Err(StorageError::WrongEncryptionKey) => {}
+            _ => panic!("opened an encrypted database with a wrong key"),
+        }
 
-                let ans = database.get(key).await.unwrap();
-                assert_eq!(ans, value);
+        let database = open(true, Some(1)).unwrap();
+        futures::executor::block_on(async {
+            for index in (0..100).step_by(7) {
+                let key = Slice(format!("K{:07}", index).into_bytes());
+                let value = database.get(key).await.unwrap();
+                let expected = format!("SECRET {}", index).into_bytes();
+                assert_eq!(&value.0[0..expected.len()], expected.as_slice());
             }
         });
+    }
 
-        let database = DatabaseBuilder::default().restore(true).build().unwrap();
-        futures::executor::block_on(async move {
-            for index in 0..(1024 * 16) {
-                let key = Slice(keys[index].clone());
-                let value = Slice(values[index].clone());
+    #[test]
+    fn data_path_test() {
+        let base_dir = "/var/tmp/agilulf_data_path_test";
+        let wal_dir = "/var/tmp/agilulf_data_path_test_wal";
+        let fast_dir = "/var/tmp/agilulf_data_path_test_fast";
+        let slow_dir = "/var/tmp/agilulf_data_path_test_slow";
+        let checkpoint_dir = "/var/tmp/agilulf_data_path_test_checkpoint";
+        for dir in [base_dir, wal_dir, fast_dir, slow_dir, checkpoint_dir].iter() {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+        let open = |restore: bool| {
+            DatabaseBuilder::default()
+                .base_dir(base_dir.to_string())
+                .wal_dir(wal_dir.to_string())
+                .data_path(fast_dir.to_string(), 20_000)
+                .data_path(slow_dir.to_string(), 1 << 30)
+                .restore(restore)
+                .build()
+                .unwrap()
+        };
+        let key = |index: usize| Slice(format!("K{:07}", index).into_bytes());
+        let put_keys = |database: &Database, range: std::ops::Range<usize>| {
+            futures::executor::block_on(async {
+                for index in range {
+                    database
+                        .put(key(index), Slice(b"VALUE".to_vec()))
+                        .await
+                        .unwrap();
+                }
+            });
+        };
+        let check = |database: &Database, count: usize| {
+            futures::executor::block_on(async {
+                assert_eq!(
+                    database.scan(key(0), key(count)).await.unwrap().len(),
+                    count
+                );
+            });
+        };
 
-                let ans = database.get(key).await.unwrap();
-                assert_eq!(ans, value);
+        let database = open(false);
+        put_keys(&database, 0..100);
+        assert!(Path::new(wal_dir).join("log").exists());
+        assert!(!Path::new(base_dir).join("log").exists());
+
+        // Level 0 fits in the first data path.
+        let report = database.flush_memtable().unwrap();
+        assert_eq!(report.added, vec![(0, 0)]);
+        assert!(Path::new(fast_dir).join("sstable_0_0").exists());
+
+        // Level 0 and level 1 don't fit in it together, so level 1 goes into the next one.
+        let report = database.compact_range(None, key(0), key(100)).unwrap();
+        assert_eq!(report.added, vec![(1, 0)]);
+        assert!(Path::new(slow_dir).join("sstable_1_0").exists());
+        assert!(!Path::new(fast_dir).join("sstable_0_0").exists());
+        assert!(crate::inspect::table_summaries(base_dir, None)
+            .unwrap()
+            .is_empty());
+
+        // Tables are found in data paths after reopening, and the log is replayed from wal_dir.
+        put_keys(&database, 100..150);
+        drop(database);
+        let database = open(true);
+        check(&database, 150);
+        drop(database);
+
+        // Other paths would miss the log and tables recorded in MANIFEST.
+        let reopen = |wal_dir: Option<&str>, data_paths: &[&str]| {
+            let mut builder = DatabaseBuilder::default();
+            builder.base_dir(base_dir.to_string());
+            if let Some(wal_dir) = wal_dir {
+                builder.wal_dir(wal_dir.to_string());
+            }
+            for path in data_paths {
+                builder.data_path(path.to_string(), 1 << 30);
+            }
+            builder.build()
+        };
+        for (wal_dir, data_paths) in [
+            (None, vec![fast_dir, slow_dir]),
+            (Some(wal_dir), vec![slow_dir, fast_dir]),
+            (Some(wal_dir), vec![fast_dir]),
+        ]
+        .iter()
+        {
+            match reopen(*wal_dir, data_paths) {
+                Err(StorageError::PathsChanged) => {}
+                _ => panic!("Database should not be opened with other paths"),
+            }
+        }
+        let database = reopen(Some(wal_dir), &[fast_dir, slow_dir]).unwrap();
+        check(&database, 150);
+
+        // A checkpoint holds every table and log in its own directory.
+        database.checkpoint(checkpoint_dir).unwrap();
+        drop(database);
+        assert!(Path::new(checkpoint_dir).join("sstable_1_0").exists());
+        let database = DatabaseBuilder::default()
+            .base_dir(checkpoint_dir.to_string())
+            .build()
+            .unwrap();
+        check(&database, 150);
+        drop(database);
+
+        // Without PATHS (written before paths were recorded), logs in base directory are still found.
+        std::fs::remove_file(Path::new(checkpoint_dir).join("PATHS")).unwrap();
+        match DatabaseBuilder::default()
+            .base_dir(checkpoint_dir.to_string())
+            .wal_dir(wal_dir.to_string())
+            .build()
+        {
+            Err(StorageError::PathsChanged) => {}
+            _ => panic!("Logs in base directory should not be left behind"),
+        }
+    }
+
+    #[test]
+    fn incompatible_format_test() {
+        use crate::log::LogError;
+
+        let base_dir = "/var/tmp/agilulf_format_test";
+        let database = open_database(base_dir, false);
+        futures::executor::block_on(async {
+            database
+                .put(Slice(b"KEY\0\0\0\0\0".to_vec()), Slice(b"VALUE".to_vec()))
+                .await
+                .unwrap();
+        });
+        drop(database);
+
+        // Files written before formats were versioned start with their first record.
+        for name in ["log", "MANIFEST"].iter() {
+            let path = Path::new(base_dir).join(name);
+            let original = std::fs::read(&path).unwrap();
+            let mut content = original.clone();
+            content[0..12].copy_from_slice(&[1; 12]);
+            std::fs::write(&path, content).unwrap();
+            match DatabaseBuilder::default()
+                .base_dir(base_dir.to_string())
+                .build()
+            {
+                Err(StorageError::LogManagerError(LogError::IncompatibleFormat)) => {}
+                _ => panic!("{} in an old format should be rejected", name),
             }
+            std::fs::write(&path, original).unwrap();
+        }
+
+        let database = open_database(base_dir, true);
+        futures::executor::block_on(async {
+            database
+                .get(Slice(b"KEY\0\0\0\0\0".to_vec()))
+                .await
+                .unwrap();
         });
     }
 }
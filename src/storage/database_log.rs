@@ -1,17 +1,105 @@
+use super::blob::{BlobIndex, BLOB_INDEX_LENGTH};
+use super::encryption::Encryptor;
 use super::Result as DatabaseResult;
-use super::SyncDatabase;
-use crate::log::{JudgeReal, Result};
-use crate::log::{LogIterator, LogManager};
+use crate::log::{JudgeReal, LogFormat, Result};
+use crate::log::{LogIterator, LogManager, LogReader};
 
-use agilulf_protocol::{Command, DeleteCommand, PutCommand, Slice};
+use agilulf_protocol::{
+    Command, DeleteCommand, DeleteRangeCommand, MergeCommand, PutCommand, PutExpireCommand, Slice,
+};
 
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A record in log. Every record carries the sequence number of the write and the time (in microseconds
+/// since UNIX epoch) it was written, so archived logs can be replayed up to a point. `family` is the id of
+/// the column family written by it, as every family shares the same log.
+///
+/// `delete_flag` is `PUT`, `DELETE`, `DELETE_RANGE`, `MERGE`, `PUT_BLOB` or `INGEST`. A range
+/// deletion stores the start of the range in `key` and the end in `value`, and a merge stores its
+/// operand in `value`. A `PUT_BLOB` is a PUT whose value is in blob files, and stores the index of
+/// it in `value`. `expire_at` is the expiration time of a PUT (in milliseconds since UNIX epoch),
+/// and `0` if the key never expires. An `INGEST` only takes up the sequence number of ingested
+/// tables, so sequence numbers in logs have no gaps. It's written to `INGEST_FAMILY` and changes
+/// nothing.
+///
+/// Records of a `WriteBatch` are appended together with consecutive sequence numbers, and `remaining` is
+/// the number of records of the same batch after this one (`0` for a single write). A batch is read only
+/// if its last record is found, so a batch cut off by a crash is never replayed in part.
+///
+/// Logs start with `LogFormat::MAGIC` and `LogFormat::VERSION` of it, so a log written before sequence
+/// numbers, column families, checksums or batches were added is rejected instead of being replayed as garbage.
 #[repr(packed)]
 #[derive(Clone)]
 struct RawRecord {
     pub real_flag: u8,
+    pub sequence: u64,
+    pub timestamp: u64,
+    pub family: u32,
+    pub expire_at: u64,
     pub key: [u8; 8],
     pub delete_flag: u8,
     pub value: [u8; 256],
+    pub remaining: u32,
+}
+
+/// Bytes appended to log by every write.
+pub const RECORD_LENGTH: u64 = std::mem::size_of::<RawRecord>() as u64;
+
+/// A command read from log, with its sequence number, timestamp and column family.
+///
+/// If `blob` is `true`, the command is a PUT or PUT_EXPIRE whose value is the encoded index of the real
+/// value in blob files.
+pub struct LogRecord {
+    pub sequence: u64,
+    pub timestamp: u64,
+    pub family: u32,
+    pub command: Command,
+    pub blob: bool,
+}
+
+impl LogRecord {
+    /// Whether this record marks the sequence number of ingested tables instead of a write.
+    pub fn is_ingest(&self) -> bool {
+        self.family == INGEST_FAMILY
+    }
+
+    /// The key, blob index and expiration time (`0` if never) of a PUT whose value is in blob files.
+    pub fn blob_index(&self) -> Option<(Slice, BlobIndex, u64)> {
+        if !self.blob {
+            return None;
+        }
+        let (key, value, expire_at) = match &self.command {
+            Command::PUT(command) => (&command.key, &command.value, 0),
+            Command::PUT_EXPIRE(command) => (&command.key, &command.value, command.expire_at),
+            _ => return None,
+        };
+        BlobIndex::decode(&value.0).map(|index| (key.clone(), index, expire_at))
+    }
+}
+
+/// Microseconds since UNIX epoch.
+pub fn now_micros() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_micros() as u64,
+        Err(_) => 0,
+    }
+}
+
+const PUT: u8 = 0;
+const DELETE: u8 = 1;
+const DELETE_RANGE: u8 = 2;
+const MERGE: u8 = 3;
+const PUT_BLOB: u8 = 4;
+const INGEST: u8 = 5;
+
+/// The column family of `INGEST` records, which is never the id of a real column family.
+pub const INGEST_FAMILY: u32 = std::u32::MAX;
+
+impl LogFormat for RawRecord {
+    const MAGIC: [u8; 8] = *b"AGLFWAL\0";
+    const VERSION: u32 = 3;
 }
 
 impl JudgeReal for RawRecord {
@@ -28,29 +116,81 @@ impl JudgeReal for RawRecord {
     }
 }
 
+/// Records of a log. Records of a batch are kept back until its last record is read.
 pub struct DatabaseLogIter<'a> {
     log_iter: LogIterator<'a, RawRecord>,
+    batch: Vec<LogRecord>,
+    ready: VecDeque<LogRecord>,
+}
+
+impl<'a> DatabaseLogIter<'a> {
+    fn new(log_iter: LogIterator<'a, RawRecord>) -> DatabaseLogIter<'a> {
+        DatabaseLogIter {
+            log_iter,
+            batch: Vec::new(),
+            ready: VecDeque::new(),
+        }
+    }
 }
 
 impl<'a> Iterator for DatabaseLogIter<'a> {
-    type Item = Command;
+    type Item = LogRecord;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let next_entry = match self.log_iter.next() {
-            Some(entry) => entry,
-            None => return None,
-        };
+        while self.ready.is_empty() {
+            let entry = self.log_iter.next()?;
+            let remaining = entry.remaining;
+            self.batch.push(to_record(entry));
+            if remaining == 0 {
+                self.ready.extend(self.batch.drain(..));
+            }
+        }
 
-        Some(match next_entry.delete_flag {
-            0 => Command::PUT(PutCommand {
-                key: Slice(next_entry.key.to_vec()),
-                value: Slice(next_entry.value.to_vec()),
-            }),
-            1 => Command::DELETE(DeleteCommand {
-                key: Slice(next_entry.key.to_vec()),
-            }),
-            _ => unreachable!(),
-        })
+        self.ready.pop_front()
+    }
+}
+
+/// The command of a record read from log.
+fn to_record(next_entry: RawRecord) -> LogRecord {
+    let value_length = match next_entry.delete_flag {
+        PUT_BLOB => BLOB_INDEX_LENGTH,
+        _ => next_entry.value.len(),
+    };
+    let command = match next_entry.delete_flag {
+        PUT | PUT_BLOB if next_entry.expire_at != 0 => Command::PUT_EXPIRE(PutExpireCommand {
+            key: Slice(next_entry.key.to_vec()),
+            value: Slice(next_entry.value[0..value_length].to_vec()),
+            expire_at: next_entry.expire_at,
+        }),
+        PUT | PUT_BLOB => Command::PUT(PutCommand {
+            key: Slice(next_entry.key.to_vec()),
+            value: Slice(next_entry.value[0..value_length].to_vec()),
+        }),
+        DELETE => Command::DELETE(DeleteCommand {
+            key: Slice(next_entry.key.to_vec()),
+        }),
+        DELETE_RANGE => Command::DELETE_RANGE(DeleteRangeCommand {
+            start: Slice(next_entry.key.to_vec()),
+            end: Slice(next_entry.value[0..8].to_vec()),
+        }),
+        MERGE => Command::MERGE(MergeCommand {
+            key: Slice(next_entry.key.to_vec()),
+            operand: Slice(next_entry.value.to_vec()),
+        }),
+        // An empty range, so the marker deletes nothing even if it's replayed.
+        INGEST => Command::DELETE_RANGE(DeleteRangeCommand {
+            start: Slice(next_entry.key.to_vec()),
+            end: Slice(next_entry.key.to_vec()),
+        }),
+        _ => unreachable!(),
+    };
+
+    LogRecord {
+        sequence: next_entry.sequence,
+        timestamp: next_entry.timestamp,
+        family: next_entry.family,
+        command,
+        blob: next_entry.delete_flag == PUT_BLOB,
     }
 }
 
@@ -59,65 +199,263 @@ pub struct DatabaseLog {
 }
 
 impl DatabaseLog {
-    pub fn create_new(path: &str, length: usize) -> Result<DatabaseLog> {
-        let log_manager = LogManager::create_new(path, length)?;
+    pub fn create_new(
+        path: &str,
+        length: usize,
+        encryptor: Option<&Arc<dyn Encryptor>>,
+    ) -> Result<DatabaseLog> {
+        let log_manager = LogManager::create_new(path, length, encryptor)?;
         Ok(DatabaseLog { log_manager })
     }
-    pub fn open(path: &str, length: usize) -> Result<DatabaseLog> {
-        let log_manager = LogManager::open(path, length)?;
+    /// Open a log. Every record is encrypted separately if `encryptor` is given. Records of a batch
+    /// cut off by a crash are erased, so they're never taken as a part of records appended later.
+    pub fn open(
+        path: &str,
+        length: usize,
+        encryptor: Option<&Arc<dyn Encryptor>>,
+    ) -> Result<DatabaseLog> {
+        let mut log_manager: LogManager<RawRecord> = LogManager::open(path, length, encryptor)?;
+
+        let mut batch_start = None;
+        for (index, record) in log_manager.iter().enumerate() {
+            match (record.remaining, batch_start) {
+                (0, _) => batch_start = None,
+                (_, None) => batch_start = Some(index),
+                _ => {}
+            }
+        }
+        if let Some(index) = batch_start {
+            log::warn!(
+                "Erasing an incomplete batch from record {} of {}",
+                index,
+                path
+            );
+            log_manager.truncate(index);
+        }
+
         Ok(DatabaseLog { log_manager })
     }
 
-    pub fn iter(&self) -> DatabaseLogIter {
-        DatabaseLogIter {
-            log_iter: self.log_manager.iter(),
-        }
+    /// Records of a column family in this log.
+    pub fn iter(&self, family: u32) -> impl Iterator<Item = LogRecord> + '_ {
+        self.records().filter(move |record| record.family == family)
+    }
+
+    pub fn records(&self) -> DatabaseLogIter {
+        DatabaseLogIter::new(self.log_manager.iter())
+    }
+
+    /// How many records have been appended into this log.
+    pub fn len(&self) -> usize {
+        self.log_manager.len()
+    }
+
+    /// How many records this log can hold.
+    pub fn capacity(&self) -> usize {
+        self.log_manager.capacity()
+    }
+
+    /// The largest sequence number in this log. It's 0 if the log is empty.
+    pub fn last_sequence(&self) -> u64 {
+        self.log_manager
+            .iter()
+            .map(|record| record.sequence)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Bytes appended to this log by every write, which is `RECORD_LENGTH` with the checksum or the
+    /// encryption overhead of a record.
+    pub fn record_length(&self) -> u64 {
+        RECORD_LENGTH + self.log_manager.overhead() as u64
     }
 
     pub fn rename(&self, new_path: &str) -> Result<()> {
         self.log_manager.rename(new_path)
     }
-}
 
-impl SyncDatabase for DatabaseLog {
-    fn get_sync(&self, _key: Slice) -> DatabaseResult<Slice> {
-        panic!("Cannot read from log directly")
+    /// Write records appended before to disk.
+    pub fn sync(&self) -> Result<()> {
+        self.log_manager.sync()
     }
 
-    fn put_sync(&self, key: Slice, value: Slice) -> DatabaseResult<()> {
+    #[allow(clippy::too_many_arguments)]
+    fn append(
+        &self,
+        family: u32,
+        key: &Slice,
+        value: Option<&Slice>,
+        delete_flag: u8,
+        expire_at: u64,
+        sequence: u64,
+        remaining: u32,
+    ) {
         let mut key_slice = [0u8; 8];
         key_slice[0..key.0.len()].clone_from_slice(key.0.as_slice());
         let mut value_slice = [0u8; 256];
-        value_slice[0..value.0.len()].clone_from_slice(value.0.as_slice());
+        if let Some(value) = value {
+            value_slice[0..value.0.len()].clone_from_slice(value.0.as_slice());
+        }
 
         let record = RawRecord {
             real_flag: 1,
+            sequence,
+            timestamp: now_micros(),
+            family,
+            expire_at,
             key: key_slice,
-            delete_flag: 0,
+            delete_flag,
             value: value_slice,
+            remaining,
         };
         self.log_manager.add_entry(record);
+    }
+
+    pub fn put(
+        &self,
+        family: u32,
+        key: &Slice,
+        value: &Slice,
+        sequence: u64,
+        remaining: u32,
+    ) -> DatabaseResult<()> {
+        self.append(family, key, Some(value), PUT, 0, sequence, remaining);
 
         Ok(())
     }
 
-    fn scan_sync(&self, _start: Slice, _end: Slice) -> Vec<(Slice, Slice)> {
-        panic!("Cannot read from log directly")
+    pub fn put_expire(
+        &self,
+        family: u32,
+        key: &Slice,
+        value: &Slice,
+        expire_at: u64,
+        sequence: u64,
+        remaining: u32,
+    ) -> DatabaseResult<()> {
+        self.append(
+            family,
+            key,
+            Some(value),
+            PUT,
+            expire_at,
+            sequence,
+            remaining,
+        );
+
+        Ok(())
     }
 
-    fn delete_sync(&self, key: Slice) -> DatabaseResult<()> {
-        let mut key_slice = [0u8; 8];
-        key_slice[0..key.0.len()].clone_from_slice(key.0.as_slice());
-        let value_slice = [0u8; 256];
+    /// A PUT whose value is in blob files.
+    pub fn put_blob(
+        &self,
+        family: u32,
+        key: &Slice,
+        index: &BlobIndex,
+        expire_at: u64,
+        sequence: u64,
+        remaining: u32,
+    ) -> DatabaseResult<()> {
+        self.append(
+            family,
+            key,
+            Some(&index.encode()),
+            PUT_BLOB,
+            expire_at,
+            sequence,
+            remaining,
+        );
 
-        let record = RawRecord {
-            real_flag: 1,
-            key: key_slice,
-            delete_flag: 1,
-            value: value_slice,
-        };
-        self.log_manager.add_entry(record);
+        Ok(())
+    }
+
+    pub fn delete(
+        &self,
+        family: u32,
+        key: &Slice,
+        sequence: u64,
+        remaining: u32,
+    ) -> DatabaseResult<()> {
+        self.append(family, key, None, DELETE, 0, sequence, remaining);
+
+        Ok(())
+    }
+
+    pub fn delete_range(
+        &self,
+        family: u32,
+        start: &Slice,
+        end: &Slice,
+        sequence: u64,
+        remaining: u32,
+    ) -> DatabaseResult<()> {
+        self.append(
+            family,
+            start,
+            Some(end),
+            DELETE_RANGE,
+            0,
+            sequence,
+            remaining,
+        );
+
+        Ok(())
+    }
+
+    pub fn merge(
+        &self,
+        family: u32,
+        key: &Slice,
+        operand: &Slice,
+        sequence: u64,
+        remaining: u32,
+    ) -> DatabaseResult<()> {
+        self.append(family, key, Some(operand), MERGE, 0, sequence, remaining);
 
         Ok(())
     }
+
+    /// Mark `sequence` as taken by ingested tables.
+    pub fn ingest(&self, sequence: u64) -> DatabaseResult<()> {
+        self.append(
+            INGEST_FAMILY,
+            &Slice(Vec::new()),
+            None,
+            INGEST,
+            0,
+            sequence,
+            0,
+        );
+
+        Ok(())
+    }
+}
+
+/// A log opened read-only, e.g. by `inspect` and `Database::repair`. The file is never written, so it
+/// can be read while the database is running.
+pub struct DatabaseLogReader {
+    log_reader: LogReader<RawRecord>,
+}
+
+impl DatabaseLogReader {
+    pub fn open(path: &str, encryptor: Option<&Arc<dyn Encryptor>>) -> Result<DatabaseLogReader> {
+        let log_reader = LogReader::open(path, encryptor)?;
+        Ok(DatabaseLogReader { log_reader })
+    }
+
+    pub fn records(&self) -> DatabaseLogIter {
+        DatabaseLogIter::new(self.log_reader.iter())
+    }
+
+    /// Index of the first record which is written but cannot be read. See `LogReader::corrupted_entry`.
+    pub fn corrupted_record(&self) -> Option<usize> {
+        self.log_reader.corrupted_entry()
+    }
+
+    /// Check whether every record in this log can be understood.
+    pub fn is_valid(&self) -> bool {
+        self.log_reader
+            .iter()
+            .all(|record| record.delete_flag <= INGEST)
+    }
 }
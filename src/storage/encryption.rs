@@ -0,0 +1,243 @@
+use super::error::{StorageError, StorageResult};
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Encrypts data of a database before it's written to disk. Records of log and MANIFEST, blocks of
+/// tables and records of blob files are encrypted one by one.
+///
+/// Encryption must be authenticated: `decrypt` should fail instead of returning garbage if the block
+/// is encrypted with another key or it has been modified. Every block is also bound to `aad`, which
+/// tells where the block is (e.g. the header of its file and its index), and is not stored in the
+/// block. A block moved to another place cannot be decrypted.
+pub trait Encryptor: Send + Sync {
+    /// Bytes added to every block by `encrypt`. Records of log and MANIFEST have fixed length, so it
+    /// must be the same for every block.
+    fn overhead(&self) -> usize;
+
+    fn encrypt(&self, data: &[u8], aad: &[u8]) -> Vec<u8>;
+
+    /// `None` if `data` is not encrypted with the same key and `aad`, or it's corrupted.
+    fn decrypt(&self, data: &[u8], aad: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// Bytes of a key of ChaCha20-Poly1305.
+pub const ENCRYPTION_KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+
+/// ChaCha20-Poly1305 (RFC 8439) of the `chacha20poly1305` crate. Every encrypted block is the nonce,
+/// the ciphertext and the tag.
+///
+/// A nonce must never be used twice with the same key. Every encryptor starts from 12 random bytes,
+/// and takes the last 8 bytes as a counter which is increased for every block.
+pub struct ChaCha20Poly1305Encryptor {
+    cipher: ChaCha20Poly1305,
+    nonce_prefix: [u8; 4],
+    counter: AtomicU64,
+}
+
+fn random_bytes(buf: &mut [u8]) -> std::io::Result<()> {
+    File::open("/dev/urandom")?.read_exact(buf)
+}
+
+fn parse_hex(text: &[u8]) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    let digit = |byte: u8| (byte as char).to_digit(16);
+    text.chunks(2)
+        .map(|pair| Some((digit(pair[0])? * 16 + digit(pair[1])?) as u8))
+        .collect()
+}
+
+impl ChaCha20Poly1305Encryptor {
+    pub fn new(key: [u8; ENCRYPTION_KEY_LENGTH]) -> StorageResult<ChaCha20Poly1305Encryptor> {
+        let mut nonce = [0u8; NONCE_LENGTH];
+        random_bytes(&mut nonce)?;
+        let mut nonce_prefix = [0u8; 4];
+        nonce_prefix.copy_from_slice(&nonce[0..4]);
+        let mut counter = [0u8; 8];
+        counter.copy_from_slice(&nonce[4..12]);
+
+        Ok(ChaCha20Poly1305Encryptor {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            nonce_prefix,
+            counter: AtomicU64::new(u64::from_le_bytes(counter)),
+        })
+    }
+
+    /// Read the key from a file, which holds 32 bytes, or 64 hex digits followed by an optional
+    /// newline.
+    pub fn from_key_file(path: &str) -> StorageResult<ChaCha20Poly1305Encryptor> {
+        let content = std::fs::read(path)?;
+        let key = if content.len() == ENCRYPTION_KEY_LENGTH {
+            Some(content)
+        } else {
+            let text = match content.iter().rposition(|byte| !byte.is_ascii_whitespace()) {
+                Some(end) => &content[0..=end],
+                None => &content[0..0],
+            };
+            parse_hex(text).filter(|key| key.len() == ENCRYPTION_KEY_LENGTH)
+        };
+
+        match key {
+            Some(key) => {
+                let mut array = [0u8; ENCRYPTION_KEY_LENGTH];
+                array.copy_from_slice(&key);
+                ChaCha20Poly1305Encryptor::new(array)
+            }
+            None => {
+                log::error!("{} is not a key of 32 bytes or 64 hex digits", path);
+                Err(StorageError::InvalidEncryptionKey)
+            }
+        }
+    }
+
+    /// Write a random key into a new file, which can only be read by its owner. It fails if the file
+    /// exists, so a key in use is never replaced.
+    pub fn generate_key_file(path: &str) -> StorageResult<()> {
+        let mut key = [0u8; ENCRYPTION_KEY_LENGTH];
+        random_bytes(&mut key)?;
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?
+            .write_all(&key)?;
+
+        Ok(())
+    }
+
+    fn next_nonce(&self) -> [u8; NONCE_LENGTH] {
+        let mut nonce = [0u8; NONCE_LENGTH];
+        nonce[0..4].copy_from_slice(&self.nonce_prefix);
+        let counter = self.counter.fetch_add(1, Ordering::SeqCst);
+        nonce[4..12].copy_from_slice(&counter.to_le_bytes());
+        nonce
+    }
+}
+
+impl Encryptor for ChaCha20Poly1305Encryptor {
+    fn overhead(&self) -> usize {
+        NONCE_LENGTH + TAG_LENGTH
+    }
+
+    fn encrypt(&self, data: &[u8], aad: &[u8]) -> Vec<u8> {
+        let nonce = self.next_nonce();
+        let sealed = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: data, aad })
+            .expect("ChaCha20-Poly1305 fails only on blocks larger than 256 GiB");
+        let mut output = Vec::with_capacity(data.len() + self.overhead());
+        output.extend_from_slice(&nonce);
+        output.extend_from_slice(&sealed);
+        output
+    }
+
+    fn decrypt(&self, data: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        if data.len() < self.overhead() {
+            return None;
+        }
+        let nonce = Nonce::from_slice(&data[0..NONCE_LENGTH]);
+        let payload = Payload {
+            msg: &data[NONCE_LENGTH..],
+            aad,
+        };
+        self.cipher.decrypt(nonce, payload).ok()
+    }
+}
+
+/// A file in base directory holding `KEY_CHECK` encrypted by the key of the database, so opening it with
+/// another key fails at once.
+const KEY_CHECK_FILE: &str = "ENCRYPTION";
+const KEY_CHECK: &[u8] = b"AGILULF ENCRYPTION KEY CHECK";
+
+/// Check the key of the database in `base_dir` before opening it. A new database (or one whose files
+/// are removed as `restore` is `false`) records the key check if it's encrypted.
+///
+/// An encrypted database cannot be opened without `encryptor` or with another key, and a database
+/// written without encryption cannot be opened with `encryptor`.
+pub fn check_key(
+    base_dir: &str,
+    encryptor: Option<&Arc<dyn Encryptor>>,
+    restore: bool,
+) -> StorageResult<()> {
+    let base_path = Path::new(base_dir);
+    let path = base_path.join(KEY_CHECK_FILE);
+    if !restore && path.exists() {
+        std::fs::remove_file(&path)?;
+    }
+
+    match (path.exists(), encryptor) {
+        (true, Some(encryptor)) => {
+            match encryptor.decrypt(&std::fs::read(&path)?, KEY_CHECK_FILE.as_bytes()) {
+                Some(ref check) if check.as_slice() == KEY_CHECK => Ok(()),
+                _ => {
+                    log::error!("The key cannot decrypt database in {}", base_dir);
+                    Err(StorageError::WrongEncryptionKey)
+                }
+            }
+        }
+        (true, None) => {
+            log::error!("Database in {} is encrypted, but no key is given", base_dir);
+            Err(StorageError::EncryptionKeyRequired)
+        }
+        (false, Some(_)) if restore && base_path.join("MANIFEST").exists() => {
+            log::error!("Database in {} is not encrypted", base_dir);
+            Err(StorageError::DatabaseNotEncrypted)
+        }
+        (false, Some(encryptor)) => {
+            std::fs::write(
+                &path,
+                encryptor.encrypt(KEY_CHECK, KEY_CHECK_FILE.as_bytes()),
+            )?;
+            Ok(())
+        }
+        (false, None) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reject_wrong_key() {
+        let encryptor = ChaCha20Poly1305Encryptor::new([1; ENCRYPTION_KEY_LENGTH]).unwrap();
+        let other = ChaCha20Poly1305Encryptor::new([2; ENCRYPTION_KEY_LENGTH]).unwrap();
+
+        let data = vec![7u8; 1000];
+        let first = encryptor.encrypt(&data, b"AAD");
+        let second = encryptor.encrypt(&data, b"AAD");
+        assert_eq!(first.len(), data.len() + encryptor.overhead());
+        // Every block takes a new nonce.
+        assert_ne!(first, second);
+        assert_eq!(encryptor.decrypt(&first, b"AAD"), Some(data.clone()));
+        assert_eq!(other.decrypt(&first, b"AAD"), None);
+
+        let mut modified = first.clone();
+        modified[100] ^= 1;
+        assert_eq!(encryptor.decrypt(&modified, b"AAD"), None);
+        assert_eq!(encryptor.decrypt(&first[0..20], b"AAD"), None);
+    }
+
+    #[test]
+    fn reject_moved_block() {
+        let encryptor = ChaCha20Poly1305Encryptor::new([1; ENCRYPTION_KEY_LENGTH]).unwrap();
+
+        let data = vec![7u8; 100];
+        let block = encryptor.encrypt(&data, b"file 1, block 1");
+        assert_eq!(encryptor.decrypt(&block, b"file 1, block 2"), None);
+        assert_eq!(encryptor.decrypt(&block, b"file 2, block 1"), None);
+        assert_eq!(encryptor.decrypt(&block, b""), None);
+        assert_eq!(encryptor.decrypt(&block, b"file 1, block 1"), Some(data));
+    }
+}
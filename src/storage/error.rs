@@ -7,6 +7,30 @@ quick_error! {
     pub enum StorageError {
         UnicodeError
         ManifestLogFormatError
+        DatabaseLogFormatError
+        DatabaseLocked
+        RestorePointTooEarly
+        ChangesTruncated
+        ChangesLagged
+        UnsortedKey
+        RecordTooLarge
+        IngestFilesOverlap
+        FamiliesFormatError
+        InvalidColumnFamilyName
+        ColumnFamilyExists
+        ColumnFamilyNotFound
+        BlobFileInUse
+        InvalidEncryptionKey
+        WrongEncryptionKey
+        EncryptionKeyRequired
+        DatabaseNotEncrypted
+        DecryptionFailed
+        DataPathNotFound
+        TableNotFound
+        FrozenDatabaseNotFound
+        PathsFormatError
+        PathsChanged
+        InvalidBlobThreshold
         IOError(err: std::io::Error) {
             from()
         }
@@ -16,7 +40,7 @@ quick_error! {
         SSTableError(err: SSTableError) {
             from()
         }
-        BackgroundWorkerChannelSendError(err: futures::channel::mpsc::TrySendError<usize>) {
+        BackgroundWorkerCanceled(err: futures::channel::oneshot::Canceled) {
             from()
         }
         RestoreError(err:DatabaseError ) {
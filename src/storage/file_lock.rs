@@ -0,0 +1,65 @@
+use super::error::{StorageError, StorageResult};
+
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// An exclusive lock on `LOCK` file in base directory.
+///
+/// It's held by a `Database` during its whole lifetime, so another process (or another `Database` in
+/// this process) cannot open the same directory at the same time. The lock is released by `flock`
+/// automatically if the process exits.
+pub struct FileLock {
+    file: File,
+}
+
+impl FileLock {
+    pub fn lock(base_dir: &str) -> StorageResult<FileLock> {
+        let path = Path::new(base_dir).join("LOCK");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)?;
+
+        let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        if ret != 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                log::error!("{:#?} is held by another process", path);
+                return Err(StorageError::DatabaseLocked);
+            }
+            return Err(err.into());
+        }
+
+        Ok(FileLock { file })
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        unsafe {
+            libc::flock(self.file.as_raw_fd(), libc::LOCK_UN);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exclusive_lock() {
+        let base_dir = "/var/tmp/agilulf_lock_test";
+        std::fs::create_dir_all(base_dir).unwrap();
+
+        let lock = FileLock::lock(base_dir).unwrap();
+        match FileLock::lock(base_dir) {
+            Err(StorageError::DatabaseLocked) => {}
+            _ => panic!("LOCK should be held"),
+        }
+
+        drop(lock);
+        FileLock::lock(base_dir).unwrap();
+    }
+}
@@ -0,0 +1,301 @@
+use super::compaction::{overlapping, CompactionReport};
+use super::compression::Compression;
+use super::encryption::Encryptor;
+use super::error::{StorageError, StorageResult};
+use super::manifest_manager::VersionSet;
+use super::mem_database::Value;
+use super::rate_limiter::RateLimiter;
+use super::sstable::{SSTable, KEY_LENGTH, VALUE_LENGTH};
+use super::table_cache::{sync_parent, TableCache};
+use super::version::{Version, VersionEdit, NUM_LEVELS};
+
+use agilulf_protocol::Slice;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Build a table file outside of a database (e.g. in an offline job), which can be loaded by
+/// `Database::ingest` without going through log and MemDatabase.
+///
+/// Keys should be added in strictly ascending order. Keys are padded with zero to 8 bytes before
+/// comparing, so `A` and `A\0` are the same key.
+///
+/// # Example
+///
+/// ```
+/// # use crate::agilulf::SSTableWriter;
+/// # use agilulf_protocol::Slice;
+/// let mut writer = SSTableWriter::new();
+/// writer.put(Slice(b"A".to_vec()), Slice(b"VALUE".to_vec())).unwrap();
+/// writer.delete(Slice(b"B".to_vec())).unwrap();
+/// futures::executor::block_on(writer.finish("/tmp/agilulf_writer_example")).unwrap();
+/// ```
+#[derive(Default)]
+pub struct SSTableWriter {
+    entries: Vec<(Slice, Value)>,
+    compression: Compression,
+}
+
+impl SSTableWriter {
+    pub fn new() -> SSTableWriter {
+        SSTableWriter::default()
+    }
+
+    /// Compress blocks of the table with `compression`. It's not compressed by default.
+    pub fn compression(&mut self, compression: Compression) -> &mut Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn put(&mut self, key: Slice, value: Slice) -> StorageResult<()> {
+        self.add(key, Value::Slice(value))
+    }
+
+    /// Add a key which expires at `expire_at` (in milliseconds since UNIX epoch).
+    pub fn put_expire(&mut self, key: Slice, value: Slice, expire_at: u64) -> StorageResult<()> {
+        self.add(key, Value::Expiring(value, expire_at))
+    }
+
+    /// Add a tombstone, which hides the key in older data after ingested.
+    pub fn delete(&mut self, key: Slice) -> StorageResult<()> {
+        self.add(key, Value::NotExist)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn add(&mut self, mut key: Slice, value: Value) -> StorageResult<()> {
+        let value_length = match &value {
+            Value::Slice(value) | Value::Expiring(value, _) => value.0.len(),
+            Value::NotExist | Value::Merge(_) | Value::Blob(..) => 0,
+        };
+        if key.0.len() > KEY_LENGTH || value_length > VALUE_LENGTH {
+            return Err(StorageError::RecordTooLarge);
+        }
+
+        key.0.resize(KEY_LENGTH, 0);
+        if let Some((last, _)) = self.entries.last() {
+            if last >= &key {
+                return Err(StorageError::UnsortedKey);
+            }
+        }
+        self.entries.push((key, value));
+
+        Ok(())
+    }
+
+    /// Write the table into `path`.
+    pub async fn finish<'a>(self, path: &'a str) -> StorageResult<()> {
+        SSTable::new(self.entries, Vec::new())
+            .save(path, self.compression, None, None)
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// A verified table file waiting to be ingested, with its key range.
+pub struct IngestFile {
+    pub path: String,
+    pub smallest: Slice,
+    pub largest: Slice,
+}
+
+impl IngestFile {
+    /// Open and verify a table file, which may be encrypted by `encryptor`. `None` if it's empty.
+    pub fn open(
+        path: &str,
+        encryptor: Option<&Arc<dyn Encryptor>>,
+    ) -> StorageResult<Option<IngestFile>> {
+        let table = SSTable::open(std::fs::File::open(path)?, encryptor, None)?;
+        table.verify()?;
+
+        Ok(table.key_range().map(|(smallest, largest)| IngestFile {
+            path: path.to_string(),
+            smallest,
+            largest,
+        }))
+    }
+}
+
+/// Put a file into a table without touching the original one: it's linked if possible, and copied
+/// otherwise (e.g. on another file system), after waiting for `rate_limiter`. The table is written to
+/// disk before returning.
+async fn link_file<'a>(
+    source: &'a Path,
+    target: &'a Path,
+    rate_limiter: &'a RateLimiter,
+) -> StorageResult<()> {
+    if std::fs::hard_link(source, target).is_err() {
+        rate_limiter.request(std::fs::metadata(source)?.len()).await;
+        std::fs::copy(source, target)?;
+    }
+    std::fs::File::open(target)?.sync_all()?;
+
+    Ok(())
+}
+
+/// The deepest level above which no table overlaps with the file, where its records are older than
+/// every record above them.
+fn ingest_level(version: &Version, file: &IngestFile) -> usize {
+    let first_overlapped = (0..NUM_LEVELS)
+        .find(|level| !overlapping(version.level(*level), &file.smallest, &file.largest).is_empty())
+        .unwrap_or(NUM_LEVELS);
+    first_overlapped.saturating_sub(1)
+}
+
+/// Put every file into the deepest level, above which no table overlaps with it, and record them in
+/// a single edit. Files should not overlap with each other.
+///
+/// Ingested records are newer than every record in tables. A file overlapping level 0 is put into
+/// level 0 with the largest id, so it's read before older tables.
+///
+/// If tables of the database are encrypted, every file is written again with encryption (compressed by
+/// the codec of its level in `compression`). Files written again or copied wait for `rate_limiter` like
+/// flushes and compactions.
+///
+/// Tables are written without blocking writes. After that, `lock` is called to block writes and
+/// give the sequence number of ingested records, or `None` if keys have been written into range of
+/// the files, which should be flushed first. Tables are written again if they are not in the right
+/// level any more when writes are blocked (e.g. a flush has added an overlapping table into level
+/// 0). The returned guard of `lock` is held until the edit is recorded. `lock` is called outside of
+/// any executor, so it may block on futures.
+///
+/// Files are linked (or copied) into tables, which are written to disk before the edit is recorded in
+/// MANIFEST, and the original files are removed only after MANIFEST is written to disk. If anything
+/// fails before that, tables created here are removed and the original files are kept.
+pub fn ingest_tables<G, F>(
+    version_set: &VersionSet,
+    table_cache: &TableCache,
+    files: Vec<IngestFile>,
+    compression: &[Compression],
+    rate_limiter: &RateLimiter,
+    mut lock: F,
+) -> StorageResult<(CompactionReport, G)>
+where
+    F: FnMut() -> StorageResult<Option<(G, u64)>>,
+{
+    loop {
+        let mut created = Vec::new();
+        let prepared = futures::executor::block_on(add_tables(
+            version_set,
+            table_cache,
+            &files,
+            compression,
+            rate_limiter,
+            &mut created,
+        ));
+        let locked = prepared.and_then(|prepared| Ok((prepared, lock()?)));
+        let (mut edit, report, guard, last_sequence) = match locked {
+            Ok(((edit, report), Some((guard, last_sequence)))) => {
+                (edit, report, guard, last_sequence)
+            }
+            Ok((_, None)) => {
+                remove_tables(&created);
+                continue;
+            }
+            Err(err) => {
+                remove_tables(&created);
+                return Err(err);
+            }
+        };
+        if !in_right_levels(&version_set.current(), &files, &edit) {
+            log::info!("Tables changed while ingesting, writing ingested tables again");
+            drop(guard);
+            remove_tables(&created);
+            continue;
+        }
+
+        edit.set_last_sequence(last_sequence);
+        if let Err(err) = version_set.log_and_apply(&edit) {
+            drop(guard);
+            remove_tables(&created);
+            return Err(err);
+        }
+
+        for file in files.iter() {
+            if let Err(err) = std::fs::remove_file(&file.path) {
+                log::warn!("Cannot remove ingested {}: {}", file.path, err);
+            }
+        }
+        return Ok((report, guard));
+    }
+}
+
+fn remove_tables(created: &[PathBuf]) {
+    for path in created.iter() {
+        if let Err(err) = std::fs::remove_file(path) {
+            log::error!("Cannot remove {:#?} of failed ingestion: {}", path, err);
+        }
+    }
+}
+
+/// Whether every table added by `edit` for `files` is still in its level in `version`. A table in
+/// level 0 should be newer than every table overlapping it.
+fn in_right_levels(version: &Version, files: &[IngestFile], edit: &VersionEdit) -> bool {
+    files
+        .iter()
+        .zip(edit.added.iter())
+        .all(|(file, (level, id, _, _, _))| {
+            *level == ingest_level(version, file)
+                && (*level > 0
+                    || overlapping(version.level(0), &file.smallest, &file.largest)
+                        .iter()
+                        .all(|table| table.id < *id))
+        })
+}
+
+/// Create a table for every file, and return the edit recording them. Paths of created tables are
+/// pushed to `created`.
+async fn add_tables<'a>(
+    version_set: &'a VersionSet,
+    table_cache: &'a TableCache,
+    files: &'a [IngestFile],
+    compression: &'a [Compression],
+    rate_limiter: &'a RateLimiter,
+    created: &'a mut Vec<PathBuf>,
+) -> StorageResult<(VersionEdit, CompactionReport)> {
+    let version = version_set.current();
+    let mut report = CompactionReport::default();
+    let mut edit = VersionEdit::default();
+
+    for file in files {
+        let level = ingest_level(&version, file);
+        let id = version_set.new_table_id(level);
+        let path_id = version_set.path_for_level(level);
+        let path = table_cache.table_path(path_id, level, id);
+        log::info!("Ingesting {} as {:#?}", file.path, path);
+        created.push(path.clone());
+        match table_cache.encryptor() {
+            Some(encryptor) => {
+                let table = SSTable::open(std::fs::File::open(&file.path)?, Some(encryptor), None)?;
+                let target = match path.to_str() {
+                    Some(target) => target,
+                    None => return Err(StorageError::UnicodeError),
+                };
+                let compression = Compression::for_level(compression, level);
+                table
+                    .save(target, compression, Some(encryptor), Some(rate_limiter))
+                    .await?;
+            }
+            None => link_file(Path::new(&file.path), &path, rate_limiter).await?,
+        }
+        sync_parent(&path)?;
+
+        report.bytes_written += std::fs::metadata(&path)?.len();
+        report.added.push((level, id));
+        edit.add_table(
+            level,
+            id,
+            path_id,
+            file.smallest.clone(),
+            file.largest.clone(),
+        );
+    }
+
+    Ok((edit, report))
+}
@@ -0,0 +1,495 @@
+//! Read files of a `Database` offline, for debugging.
+//!
+//! Functions here map tables, logs and MANIFEST read-only without opening the database or taking its
+//! lock, and never write into them. A directory which is being written can be read, but files may be
+//! changed (or removed) while they are read, so problems found in it may be false. Files of an
+//! encrypted database can only be read with its encryptor.
+
+use super::column_family::{family_dir, read_families, DEFAULT_FAMILY};
+use super::database_log::DatabaseLogReader;
+pub use super::database_log::LogRecord;
+use super::encryption::Encryptor;
+use super::error::{StorageError, StorageResult};
+use super::manifest_manager::{
+    RawManifestLogEntry, ADD_TABLE, EDIT_ABORT, EDIT_END, LAST_SEQUENCE, LOG_NUMBER, REMOVE_TABLE,
+    TABLE_PATH,
+};
+pub use super::mem_database::Value;
+pub use super::range_tombstone::RangeTombstone;
+use super::sstable::{verify_checksum, SSTable, SSTableError};
+use super::table_cache::parse_table_name;
+use crate::log::LogReader;
+
+use agilulf_protocol::Slice;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Summary of a table file. A table which cannot be opened is marked as corrupted, with no record.
+/// `family` is the id of the column family of the table.
+#[derive(Debug)]
+pub struct TableSummary {
+    pub family: u32,
+    pub level: usize,
+    pub id: usize,
+    pub file_size: u64,
+    pub records: usize,
+    pub range_tombstones: usize,
+    pub key_range: Option<(Slice, Slice)>,
+    pub corrupted: bool,
+}
+
+/// A record in MANIFEST.
+#[derive(Debug, Clone)]
+pub enum ManifestRecord {
+    AddTable {
+        level: usize,
+        id: usize,
+        smallest: Slice,
+        largest: Slice,
+    },
+    RemoveTable {
+        level: usize,
+        id: usize,
+    },
+    /// The data path of the table added by the last record.
+    TablePath(usize),
+    LogNumber(usize),
+    LastSequence(u64),
+    EditEnd,
+    EditAbort,
+    Unknown(u8),
+}
+
+/// Every key (including deleted ones) and range tombstone in a table file.
+#[derive(Debug)]
+pub struct TableContents {
+    pub records: Vec<(Slice, Value)>,
+    pub range_tombstones: Vec<RangeTombstone>,
+}
+
+/// Something wrong found by `verify`.
+#[derive(Debug)]
+pub struct Problem {
+    pub path: PathBuf,
+    pub message: String,
+}
+
+fn path_to_str(path: &Path) -> StorageResult<&str> {
+    match path.to_str() {
+        Some(str) => Ok(str),
+        None => {
+            log::error!("Path is not UTF-8: {:#?}", path);
+            Err(StorageError::UnicodeError)
+        }
+    }
+}
+
+fn open_table(path: &Path, encryptor: Option<&Arc<dyn Encryptor>>) -> StorageResult<SSTable> {
+    Ok(SSTable::open(std::fs::File::open(path)?, encryptor, None)?)
+}
+
+pub fn read_table(
+    path: &Path,
+    encryptor: Option<&Arc<dyn Encryptor>>,
+) -> StorageResult<TableContents> {
+    let table = open_table(path, encryptor)?;
+    Ok(TableContents {
+        records: table.records()?,
+        range_tombstones: table.range_tombstones().to_vec(),
+    })
+}
+
+/// Summaries of every table in base directory and `family_<id>` directories of column families,
+/// sorted by column family, level and id.
+pub fn table_summaries(
+    base_dir: &str,
+    encryptor: Option<&Arc<dyn Encryptor>>,
+) -> StorageResult<Vec<TableSummary>> {
+    let mut summaries = Vec::new();
+    add_table_summaries(DEFAULT_FAMILY, base_dir, encryptor, &mut summaries)?;
+    for (id, _) in read_families(base_dir)? {
+        add_table_summaries(id, &family_dir(base_dir, id), encryptor, &mut summaries)?;
+    }
+    summaries.sort_by_key(|summary| (summary.family, summary.level, summary.id));
+
+    Ok(summaries)
+}
+
+fn add_table_summaries(
+    family: u32,
+    dir: &str,
+    encryptor: Option<&Arc<dyn Encryptor>>,
+    summaries: &mut Vec<TableSummary>,
+) -> StorageResult<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let (level, id) = match path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(parse_table_name)
+        {
+            Some(table) => table,
+            None => continue,
+        };
+
+        let file_size = std::fs::metadata(&path)?.len();
+        summaries.push(match open_table(&path, encryptor) {
+            Ok(table) => TableSummary {
+                family,
+                level,
+                id,
+                file_size,
+                records: table.len(),
+                range_tombstones: table.range_tombstones().len(),
+                key_range: table.key_range(),
+                corrupted: false,
+            },
+            Err(_) => TableSummary {
+                family,
+                level,
+                id,
+                file_size,
+                records: 0,
+                range_tombstones: 0,
+                key_range: None,
+                corrupted: true,
+            },
+        });
+    }
+
+    Ok(())
+}
+
+/// Every record in a log file (`log`, `log.<id>` or an archived log).
+pub fn read_log(
+    path: &Path,
+    encryptor: Option<&Arc<dyn Encryptor>>,
+) -> StorageResult<Vec<LogRecord>> {
+    let log = DatabaseLogReader::open(path_to_str(path)?, encryptor)?;
+    if !log.is_valid() {
+        return Err(StorageError::DatabaseLogFormatError);
+    }
+
+    Ok(log.records().collect())
+}
+
+/// Index of the first record of a log which is written but cannot be read. Records after it are never
+/// replayed.
+fn corrupted_log_record(
+    path: &Path,
+    encryptor: Option<&Arc<dyn Encryptor>>,
+) -> StorageResult<Option<usize>> {
+    Ok(DatabaseLogReader::open(path_to_str(path)?, encryptor)?.corrupted_record())
+}
+
+/// Every record in MANIFEST, in the order they were written.
+pub fn read_manifest(
+    path: &Path,
+    encryptor: Option<&Arc<dyn Encryptor>>,
+) -> StorageResult<Vec<ManifestRecord>> {
+    let log_reader: LogReader<RawManifestLogEntry> =
+        LogReader::open(path_to_str(path)?, encryptor)?;
+
+    Ok(log_reader
+        .iter()
+        .map(|entry| {
+            let (level, id) = (entry.level as usize, entry.id as usize);
+            let (smallest, largest) = (entry.smallest, entry.largest);
+            match entry.kind {
+                ADD_TABLE => ManifestRecord::AddTable {
+                    level,
+                    id,
+                    smallest: Slice(smallest.to_vec()),
+                    largest: Slice(largest.to_vec()),
+                },
+                REMOVE_TABLE => ManifestRecord::RemoveTable { level, id },
+                TABLE_PATH => ManifestRecord::TablePath(id),
+                LOG_NUMBER => ManifestRecord::LogNumber(id),
+                LAST_SEQUENCE => ManifestRecord::LastSequence(u64::from_le_bytes(smallest)),
+                EDIT_END => ManifestRecord::EditEnd,
+                EDIT_ABORT => ManifestRecord::EditAbort,
+                kind => ManifestRecord::Unknown(kind),
+            }
+        })
+        .collect())
+}
+
+/// Check every file in base directory, and tables and MANIFEST of every column family in its
+/// `family_<id>` directory.
+///
+/// Tables must match their checksums and have whole records with strictly ascending keys. Logs and
+/// MANIFEST must only contain known records, and every written entry of them must match its checksum
+/// (or be decrypted). Every table in MANIFEST must exist with the same key range as recorded, except
+/// tables in other data paths, which are not checked. Tables which are not in MANIFEST are reported
+/// too. Blob files are not checked.
+pub fn verify(
+    base_dir: &str,
+    encryptor: Option<&Arc<dyn Encryptor>>,
+) -> StorageResult<Vec<Problem>> {
+    let base_path = Path::new(base_dir);
+    let mut problems = Vec::new();
+
+    for entry in std::fs::read_dir(base_path)? {
+        let path = entry?.path();
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+
+        if name == "log" || name.starts_with("log.") || name.starts_with("wal_") {
+            match read_log(&path, encryptor) {
+                Ok(_) => {
+                    if let Some(index) = corrupted_log_record(&path, encryptor)? {
+                        problems.push(Problem {
+                            path,
+                            message: format!("record {} is corrupted or half written", index),
+                        });
+                    }
+                }
+                Err(err) => problems.push(Problem {
+                    path,
+                    message: format!("cannot read log: {}", err),
+                }),
+            }
+        }
+    }
+
+    verify_family(base_path, encryptor, &mut problems)?;
+    let families = match read_families(base_dir) {
+        Ok(families) => families,
+        Err(err) => {
+            problems.push(Problem {
+                path: base_path.join("FAMILIES"),
+                message: format!("cannot read column families: {}", err),
+            });
+            Vec::new()
+        }
+    };
+    for (id, _) in families {
+        let dir = family_dir(base_dir, id);
+        let path = Path::new(&dir);
+        if !path.is_dir() {
+            problems.push(Problem {
+                path: path.to_path_buf(),
+                message: "directory of column family is missing".to_string(),
+            });
+            continue;
+        }
+        verify_family(path, encryptor, &mut problems)?;
+    }
+
+    Ok(problems)
+}
+
+/// Check tables and MANIFEST of a column family in `dir`.
+fn verify_family(
+    dir: &Path,
+    encryptor: Option<&Arc<dyn Encryptor>>,
+    problems: &mut Vec<Problem>,
+) -> StorageResult<()> {
+    let mut tables = BTreeMap::new();
+
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+
+        if let Some(table) = parse_table_name(&name) {
+            let checked = std::fs::File::open(&path)
+                .map_err(SSTableError::from)
+                .and_then(|file| verify_checksum(&file));
+            if let Err(err) = checked {
+                problems.push(Problem {
+                    path,
+                    message: format!("checksum mismatch: {}", err),
+                });
+                continue;
+            }
+            match open_table(&path, encryptor) {
+                Ok(sstable) => match sstable.verify() {
+                    Ok(()) => {
+                        tables.insert(table, sstable.key_range());
+                    }
+                    Err(_) => problems.push(Problem {
+                        path,
+                        message: "keys are not strictly ascending".to_string(),
+                    }),
+                },
+                Err(err) => problems.push(Problem {
+                    path,
+                    message: format!("cannot open table: {}", err),
+                }),
+            }
+        }
+    }
+
+    let manifest_path = dir.join("MANIFEST");
+    let records = match read_manifest(&manifest_path, encryptor) {
+        Ok(records) => records,
+        Err(err) => {
+            problems.push(Problem {
+                path: manifest_path,
+                message: format!("cannot read MANIFEST: {}", err),
+            });
+            return Ok(());
+        }
+    };
+
+    let reader: LogReader<RawManifestLogEntry> =
+        LogReader::open(path_to_str(&manifest_path)?, encryptor)?;
+    if let Some(index) = reader.corrupted_entry() {
+        problems.push(Problem {
+            path: manifest_path.clone(),
+            message: format!("record {} is corrupted or half written", index),
+        });
+    }
+
+    let mut current = BTreeMap::new();
+    let mut pending = Vec::new();
+    for record in records {
+        match record {
+            ManifestRecord::EditEnd => {
+                let mut added = None;
+                for record in pending.drain(..) {
+                    match record {
+                        ManifestRecord::AddTable {
+                            level,
+                            id,
+                            smallest,
+                            largest,
+                        } => {
+                            current.insert((level, id), (smallest, largest));
+                            added = Some((level, id));
+                        }
+                        ManifestRecord::TablePath(_) => {
+                            if let Some(table) = &added {
+                                current.remove(table);
+                            }
+                        }
+                        ManifestRecord::RemoveTable { level, id } => {
+                            current.remove(&(level, id));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            ManifestRecord::EditAbort => pending.clear(),
+            ManifestRecord::Unknown(kind) => problems.push(Problem {
+                path: manifest_path.clone(),
+                message: format!("unknown record kind {}", kind),
+            }),
+            record => pending.push(record),
+        }
+    }
+    if !pending.is_empty() {
+        problems.push(Problem {
+            path: manifest_path.clone(),
+            message: "the last edit is incomplete".to_string(),
+        });
+    }
+
+    for ((level, id), range) in current.iter() {
+        let path = dir.join(format!("sstable_{}_{}", level, id));
+        match tables.get(&(*level, *id)) {
+            Some(Some((smallest, largest))) => {
+                if (smallest, largest) != (&range.0, &range.1) {
+                    problems.push(Problem {
+                        path,
+                        message: "key range differs from MANIFEST".to_string(),
+                    });
+                }
+            }
+            Some(None) => problems.push(Problem {
+                path,
+                message: "table is empty".to_string(),
+            }),
+            None => problems.push(Problem {
+                path,
+                message: "table in MANIFEST is missing or corrupted".to_string(),
+            }),
+        }
+    }
+    for (level, id) in tables.keys() {
+        if !current.contains_key(&(*level, *id)) {
+            problems.push(Problem {
+                path: dir.join(format!("sstable_{}_{}", level, id)),
+                message: "table is not in MANIFEST".to_string(),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{AsyncDatabase, DatabaseBuilder};
+
+    #[test]
+    fn verify_database() {
+        let base_dir = "/var/tmp/agilulf_inspect_test";
+        let _ = std::fs::remove_dir_all(base_dir);
+
+        let database = DatabaseBuilder::default()
+            .base_dir(base_dir.to_string())
+            .restore(false)
+            .build()
+            .unwrap();
+        futures::executor::block_on(async {
+            for index in 0..(5 * 1024) {
+                let key = Slice(format!("{:08}", index).into_bytes());
+                database.put(key, Slice(b"VALUE".to_vec())).await.unwrap();
+            }
+        });
+        drop(database);
+
+        let table = Path::new(base_dir).join("sstable_0_0");
+        while !table.exists() || Path::new(base_dir).join("log.0").exists() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        assert!(verify(base_dir, None).unwrap().is_empty());
+        let summaries = table_summaries(base_dir, None).unwrap();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].records, 4 * 1024 + 1);
+
+        // Files are only read, so an empty log is not filled with a header.
+        let empty_log = Path::new(base_dir).join("log.9");
+        std::fs::write(&empty_log, b"").unwrap();
+        assert!(read_log(&empty_log, None).is_err());
+        assert_eq!(std::fs::metadata(&empty_log).unwrap().len(), 0);
+        std::fs::remove_file(&empty_log).unwrap();
+
+        std::fs::write(Path::new(base_dir).join("sstable_0_1"), b"BROKEN").unwrap();
+        let problems = verify(base_dir, None).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].path, Path::new(base_dir).join("sstable_0_1"));
+        std::fs::remove_file(Path::new(base_dir).join("sstable_0_1")).unwrap();
+
+        // A flipped bit in a value or a log record is found by checksums.
+        let flip = |name: &str, offset: usize| {
+            let path = Path::new(base_dir).join(name);
+            let mut content = std::fs::read(&path).unwrap();
+            content[offset] ^= 1;
+            std::fs::write(&path, content).unwrap();
+            path
+        };
+        let table = flip(
+            "sstable_0_0",
+            crate::storage::sstable::HEADER_LENGTH + 8 + 100,
+        );
+        let log = flip("log", crate::log::HEADER_LENGTH + 10);
+        let problems = verify(base_dir, None).unwrap();
+        assert!(problems
+            .iter()
+            .any(|problem| problem.path == table && problem.message.starts_with("checksum")));
+        assert!(problems
+            .iter()
+            .any(|problem| problem.path == log && problem.message.starts_with("record 0")));
+    }
+}
@@ -1,31 +1,90 @@
+use super::background::{Priority, Scheduler};
+use super::blob::BlobStore;
+use super::block_cache::BlockCache;
+use super::compaction::{
+    compact, compact_range, level_bytes, Compaction, CompactionReport, FilterContext,
+};
+use super::compaction_filter::CompactionFilter;
+use super::compaction_strategy::CompactionStrategy;
+use super::compression::Compression;
+use super::database_log::DatabaseLog;
+use super::encryption::Encryptor;
 use super::error::{StorageError, StorageResult};
-use super::merge::merge_iter;
-use super::sstable::SSTable;
-use crate::log::{JudgeReal, LogManager};
-use crate::storage::SyncDatabase;
+use super::ingest::{ingest_tables, IngestFile};
+use super::mem_database::Value;
+use super::merge::Source;
+use super::merge_operator::fold;
+use super::rate_limiter::RateLimiter;
+use super::sstable::{key_to_array, SSTable, KEY_LENGTH};
+use super::statistics::Statistics;
+use super::table_cache::{parse_table_name, sync_parent, TableCache};
+use super::version::{TableMeta, Version, VersionEdit, NUM_LEVELS};
+use super::wal_archive::PendingLogs;
+use crate::log::{JudgeReal, LogFormat, LogManager};
 use crate::MemDatabase;
 
 use agilulf_protocol::Slice;
 use crossbeam::sync::ShardedLock;
-use futures::channel::mpsc::{unbounded, UnboundedSender};
-use futures::executor::LocalPool;
-use futures::stream::StreamExt;
-use futures::task::LocalSpawnExt;
+use futures::channel::oneshot;
 
-use std::collections::{BTreeMap, VecDeque};
+use std::collections::VecDeque;
 use std::path::Path;
 
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
-use std::sync::Arc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
+/// A record in MANIFEST. Every record adds a table into a level, removes a table from a level or
+/// records the log number (in `id`) or the last sequence (in `smallest`).
+///
+/// Records of a `VersionEdit` are followed by an `EDIT_END` record. An edit without `EDIT_END` (e.g.
+/// the process crashed while writing it) is ignored while opening, and an `EDIT_ABORT` record is
+/// appended to discard it.
+///
+/// The smallest and the largest key of the added table are recorded with it, so tables whose key
+/// range cannot contain the key can be skipped without opening them. A table outside `base_dir` is
+/// followed by a `TABLE_PATH` record with the id of its data path (in `id`).
+///
+/// MANIFEST starts with `LogFormat::MAGIC` and `LogFormat::VERSION` of it, so a MANIFEST written before
+/// key ranges or checksums were recorded is rejected instead of being read as garbage.
 #[repr(packed)]
 #[derive(Clone)]
 pub struct RawManifestLogEntry {
     pub real_flag: u8,
-    pub add_flag: u8,
+    pub kind: u8,
     pub level: u8,
-    pub id: u8,
+    pub id: u32,
+    pub smallest: [u8; KEY_LENGTH],
+    pub largest: [u8; KEY_LENGTH],
+}
+
+pub const REMOVE_TABLE: u8 = 0;
+pub const ADD_TABLE: u8 = 1;
+pub const EDIT_END: u8 = 2;
+pub const EDIT_ABORT: u8 = 3;
+pub const LOG_NUMBER: u8 = 4;
+pub const LAST_SEQUENCE: u8 = 5;
+pub const TABLE_PATH: u8 = 6;
+
+pub const MANIFEST_LENGTH: usize = 4 * 1024;
+
+impl RawManifestLogEntry {
+    fn marker(kind: u8) -> RawManifestLogEntry {
+        RawManifestLogEntry {
+            real_flag: 1,
+            kind,
+            level: 0,
+            id: 0,
+            smallest: [0; KEY_LENGTH],
+            largest: [0; KEY_LENGTH],
+        }
+    }
+}
+
+impl LogFormat for RawManifestLogEntry {
+    const MAGIC: [u8; 8] = *b"AGLFMANI";
+    const VERSION: u32 = 2;
 }
 
 impl JudgeReal for RawManifestLogEntry {
@@ -42,247 +101,1211 @@ impl JudgeReal for RawManifestLogEntry {
     }
 }
 
-pub struct ManifestManager {
+fn manifest_path(base_dir: &str) -> StorageResult<String> {
+    let manifest_path = Path::new(base_dir).join("MANIFEST");
+
+    match manifest_path.to_str() {
+        Some(str) => Ok(str.to_string()),
+        None => {
+            log::error!("Manifest path is not UTF-8: {:#?}", manifest_path);
+            Err(StorageError::UnicodeError)
+        }
+    }
+}
+
+/// Number of records written by `write_edit`.
+fn edit_records(edit: &VersionEdit) -> usize {
+    edit.added.len()
+        + edit.added.iter().filter(|added| added.2 != 0).count()
+        + edit.deleted.len()
+        + edit.log_number.iter().count()
+        + edit.last_sequence.iter().count()
+        + 1
+}
+
+/// Write records of an edit into MANIFEST. Keys are checked before anything is written, so an edit
+/// with a key which cannot be stored is rejected as a whole.
+fn write_edit(
+    log_manager: &LogManager<RawManifestLogEntry>,
+    edit: &VersionEdit,
+) -> StorageResult<()> {
+    let mut ranges = Vec::with_capacity(edit.added.len());
+    for (_, _, _, smallest, largest) in edit.added.iter() {
+        ranges.push((key_to_array(smallest)?, key_to_array(largest)?));
+    }
+
+    for (level, id) in edit.deleted.iter() {
+        log_manager.add_entry(RawManifestLogEntry {
+            level: *level as u8,
+            id: *id as u32,
+            ..RawManifestLogEntry::marker(REMOVE_TABLE)
+        });
+    }
+    for ((level, id, path_id, _, _), (smallest, largest)) in edit.added.iter().zip(ranges) {
+        log_manager.add_entry(RawManifestLogEntry {
+            level: *level as u8,
+            id: *id as u32,
+            smallest,
+            largest,
+            ..RawManifestLogEntry::marker(ADD_TABLE)
+        });
+        if *path_id != 0 {
+            log_manager.add_entry(RawManifestLogEntry {
+                id: *path_id as u32,
+                ..RawManifestLogEntry::marker(TABLE_PATH)
+            });
+        }
+    }
+    if let Some(log_number) = edit.log_number {
+        log_manager.add_entry(RawManifestLogEntry {
+            id: log_number as u32,
+            ..RawManifestLogEntry::marker(LOG_NUMBER)
+        });
+    }
+    if let Some(last_sequence) = edit.last_sequence {
+        log_manager.add_entry(RawManifestLogEntry {
+            smallest: last_sequence.to_le_bytes(),
+            ..RawManifestLogEntry::marker(LAST_SEQUENCE)
+        });
+    }
+    log_manager.add_entry(RawManifestLogEntry::marker(EDIT_END));
+
+    Ok(())
+}
+
+/// The set of tables in every level, persisted by MANIFEST (like VersionSet in LevelDB).
+///
+/// Every modification is described by a `VersionEdit`. `log_and_apply` writes the edit into MANIFEST
+/// and then installs a new `Version` as current. Readers hold an `Arc<Version>`, so tables in it will
+/// not be removed until they have finished.
+pub struct VersionSet {
     base_dir: String,
-    log_manager: Arc<LogManager<RawManifestLogEntry>>,
-    frozen_databases: Arc<ShardedLock<VecDeque<Arc<MemDatabase>>>>,
-    sstables: Arc<[ShardedLock<BTreeMap<usize, SSTable>>; 6]>, // TODO: a concurrent RwLock may be better
-    level_counter: Arc<[AtomicUsize; 6]>,
+    log_manager: Mutex<LogManager<RawManifestLogEntry>>,
+    current: ShardedLock<Arc<Version>>,
+    table_cache: Arc<TableCache>,
+    level_counter: [AtomicUsize; NUM_LEVELS],
+}
+
+impl VersionSet {
+    pub fn create_new(base_dir: &str, table_cache: Arc<TableCache>) -> StorageResult<VersionSet> {
+        let manifest_path = manifest_path(base_dir)?;
+
+        Ok(VersionSet {
+            base_dir: base_dir.to_string(),
+            log_manager: Mutex::new(LogManager::create_new(
+                &manifest_path,
+                MANIFEST_LENGTH,
+                table_cache.encryptor(),
+            )?),
+            current: ShardedLock::new(Arc::new(Version::default())),
+            table_cache,
+            level_counter: Default::default(),
+        })
+    }
+
+    pub fn open(base_dir: &str, table_cache: Arc<TableCache>) -> StorageResult<VersionSet> {
+        let manifest_path = manifest_path(base_dir)?;
+        let log_manager: LogManager<RawManifestLogEntry> =
+            LogManager::open(&manifest_path, MANIFEST_LENGTH, table_cache.encryptor())?;
+
+        let level_counter: [AtomicUsize; NUM_LEVELS] = Default::default();
+        let mut version = Version::default();
+        let mut pending = VersionEdit::default();
+
+        for log in log_manager.iter() {
+            let (level, id) = (log.level as usize, log.id as usize);
+            match log.kind {
+                ADD_TABLE | REMOVE_TABLE if level >= NUM_LEVELS => {
+                    return Err(StorageError::ManifestLogFormatError);
+                }
+                ADD_TABLE => {
+                    let (smallest, largest) = (log.smallest, log.largest);
+                    let (smallest, largest) = (Slice(smallest.to_vec()), Slice(largest.to_vec()));
+                    pending.add_table(level, id, 0, smallest, largest);
+                    level_counter[level].fetch_max(id + 1, Ordering::SeqCst);
+                }
+                TABLE_PATH => match pending.added.last_mut() {
+                    Some(added) => added.2 = id,
+                    None => return Err(StorageError::ManifestLogFormatError),
+                },
+                REMOVE_TABLE => {
+                    pending.delete_table(level, id);
+                }
+                LOG_NUMBER => {
+                    pending.set_log_number(id);
+                }
+                LAST_SEQUENCE => {
+                    pending.set_last_sequence(u64::from_le_bytes(log.smallest));
+                }
+                EDIT_END => {
+                    for (_, _, path_id, _, _) in pending.added.iter() {
+                        if !table_cache.has_path(*path_id) {
+                            log::error!("Data path {} in MANIFEST is not configured", path_id);
+                            return Err(StorageError::DataPathNotFound);
+                        }
+                    }
+
+                    let next = version.apply(&pending, &table_cache);
+                    version.mark_obsolete(&pending);
+                    version = next;
+                    pending = VersionEdit::default();
+                }
+                EDIT_ABORT => {
+                    pending = VersionEdit::default();
+                }
+                _ => return Err(StorageError::ManifestLogFormatError),
+            }
+        }
+
+        // SSTables are opened lazily by table cache. Only check tables of the last version exist here,
+        // as tables removed by later edits may have been removed from disk.
+        for level in 0..NUM_LEVELS {
+            for table in version.level(level) {
+                let table_path = table.path();
+                log::info!("Restoring sstable {:#?}", table_path);
+                if !table_path.exists() {
+                    log::error!("SSTable {:#?} in MANIFEST doesn't exist", table_path);
+                    return Err(StorageError::TableNotFound);
+                }
+            }
+        }
+
+        if !pending.is_empty() {
+            log::warn!("Discard an incomplete edit in MANIFEST");
+            log_manager.add_entry(RawManifestLogEntry::marker(EDIT_ABORT));
+        }
+
+        Ok(VersionSet {
+            base_dir: base_dir.to_string(),
+            log_manager: Mutex::new(log_manager),
+            current: ShardedLock::new(Arc::new(version)),
+            table_cache,
+            level_counter,
+        })
+    }
+
+    pub fn current(&self) -> Arc<Version> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn new_table_id(&self, level: usize) -> usize {
+        self.level_counter[level].fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Write the edit into MANIFEST and install the new version. MANIFEST is synced before tables
+    /// removed by this edit are marked obsolete, so tables added by it should be synced before calling
+    /// it. Removed tables will be deleted from disk after every version containing them is dropped.
+    pub fn log_and_apply(&self, edit: &VersionEdit) -> StorageResult<()> {
+        let mut log_manager = self.log_manager.lock().unwrap();
+
+        let current = self.current();
+        let next = current.apply(edit, &self.table_cache);
+
+        if log_manager.len() + edit_records(edit) > log_manager.capacity() {
+            *log_manager =
+                write_manifest(&self.base_dir, &next, true, self.table_cache.encryptor())?;
+        } else {
+            write_edit(&log_manager, edit)?;
+            log_manager.sync()?;
+        }
+
+        current.mark_obsolete(edit);
+        *self.current.write().unwrap() = Arc::new(next);
+
+        Ok(())
+    }
+
+    /// The data path for a new table in `level`. Data paths are filled in order from the upper levels,
+    /// so it's the first one whose target size, with every data path before it, holds this level and
+    /// all levels above it. The last data path takes what the others can't hold. Without data paths,
+    /// every table is put in `base_dir`.
+    pub fn path_for_level(&self, level: usize) -> usize {
+        let target_sizes = self.table_cache.target_sizes();
+        if target_sizes.is_empty() {
+            return 0;
+        }
+
+        let version = self.current();
+        let bytes: u64 = (0..=level).map(|level| level_bytes(&version, level)).sum();
+        let mut capacity = 0;
+        for (index, target_size) in target_sizes.iter().enumerate() {
+            capacity += target_size;
+            if bytes < capacity {
+                return index + 1;
+            }
+        }
+        target_sizes.len()
+    }
+}
+
+/// Write a new MANIFEST containing only the snapshot of a version into `base_dir`. It's written into a
+/// temporary file, which is synced and then renamed, so the old MANIFEST is replaced atomically. The new
+/// MANIFEST is large enough for the snapshot however many tables there are.
+///
+/// It's used when MANIFEST is full, and for writing MANIFEST of a checkpoint, which has every table in
+/// its own directory, so data paths of tables are not kept. Records are encrypted if `encryptor` is
+/// given.
+pub fn write_manifest(
+    base_dir: &str,
+    version: &Version,
+    keep_paths: bool,
+    encryptor: Option<&Arc<dyn Encryptor>>,
+) -> StorageResult<LogManager<RawManifestLogEntry>> {
+    let manifest_path = manifest_path(base_dir)?;
+    let tmp_path = format!("{}.tmp", manifest_path);
+    log::info!("Writing MANIFEST with a snapshot into {}", manifest_path);
+
+    let mut snapshot = VersionEdit::default();
+    snapshot.set_log_number(version.log_number);
+    snapshot.set_last_sequence(version.last_sequence);
+    for level in 0..NUM_LEVELS {
+        for table in version.level(level) {
+            snapshot.add_table(
+                level,
+                table.id,
+                if keep_paths { table.path_id } else { 0 },
+                table.smallest.clone(),
+                table.largest.clone(),
+            );
+        }
+    }
+
+    // The snapshot takes at most half of the new MANIFEST, so edits after it have room too.
+    let length = std::cmp::max(MANIFEST_LENGTH, edit_records(&snapshot) * 2);
+    let log_manager = LogManager::create_new(&tmp_path, length, encryptor)?;
+    write_edit(&log_manager, &snapshot)?;
+    log_manager.sync()?;
+    log_manager.rename(&manifest_path)?;
+    std::fs::File::open(base_dir)?.sync_all()?;
+
+    Ok(log_manager)
+}
+
+/// Find key in SSTables level by level. Tables whose key range cannot contain the key are skipped
+/// without being opened. Searching stops at the first table which has the key or deletes it.
+///
+/// In level 0, tables are searched from the newest one. In other levels, tables don't overlap with
+/// each other, so binary search gives the only table which may contain the key.
+fn find_key(
+    version_set: &VersionSet,
+    table_cache: &TableCache,
+    key: &Slice,
+) -> StorageResult<Option<(usize, Value)>> {
+    let version = version_set.current();
+    // Keys in tables are padded, so a short key written before flushing is still found.
+    let mut padded = key.clone();
+    if padded.0.len() < KEY_LENGTH {
+        padded.0.resize(KEY_LENGTH, 0);
+    }
+    let key = &padded;
+
+    for level in 0..NUM_LEVELS {
+        let candidates: Vec<&Arc<TableMeta>> = if level == 0 {
+            version
+                .level(level)
+                .iter()
+                .rev()
+                .filter(|table| table.may_contain(key))
+                .collect()
+        } else {
+            version.find_table(level, key).into_iter().collect()
+        };
+
+        for table in candidates {
+            let table = table_cache.get(table.path_id, level, table.id)?;
+            if let Some(value) = table.lookup(key)? {
+                return Ok(Some((level, value)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Convert the oldest frozen database into a table. Operands of merge are applied on the value found
+/// in tables, which are older than it, so tables never contain operands. A value in blob files under
+/// operands is read, and the new value is put into blob files again.
+fn flush_table(
+    db: &MemDatabase,
+    version_set: &VersionSet,
+    table_cache: &TableCache,
+    family: u32,
+    blob_store: &BlobStore,
+) -> StorageResult<SSTable> {
+    let source = db.source(&Slice(Vec::new()), &Slice(vec![255; KEY_LENGTH]));
+
+    let mut entries = Vec::with_capacity(source.entries.len());
+    for (key, value) in source.entries {
+        let value = match (value, db.merge_operator()) {
+            (Value::Merge(operands), Some(operator)) => {
+                let base = find_key(version_set, table_cache, &key)?.map(|(_, value)| value);
+                let base = blob_store.resolve(base.unwrap_or(Value::NotExist))?;
+                let value = fold(operator.as_ref(), &key, base, &operands);
+                blob_store.separate(family, &key, value)?
+            }
+            (value, _) => value,
+        };
+        entries.push((key, value));
+    }
+
+    Ok(SSTable::new(entries, db.range_tombstones()))
+}
+
+/// Frozen databases of a family with ids of their logs, the newest one in front.
+pub type FrozenDatabases = ShardedLock<VecDeque<(usize, Arc<MemDatabase>)>>;
+
+/// Write the frozen database of `log.<log_id>` into a table in level 0, compressed by `compression`.
+/// It's removed from `frozen_databases` and the log is discarded (after every column family has
+/// written it) only after the table and MANIFEST are written to disk. Frozen databases should be
+/// flushed in order of their logs, as the log number recorded in MANIFEST skips every older log.
+#[allow(clippy::too_many_arguments)]
+async fn flush(
+    log_id: usize,
+    frozen_databases: &FrozenDatabases,
+    version_set: &VersionSet,
+    table_cache: &TableCache,
+    family: u32,
+    blob_store: &BlobStore,
+    pending_logs: &PendingLogs,
+    compression: Compression,
+    rate_limiter: &RateLimiter,
+) -> StorageResult<CompactionReport> {
+    let mut report = CompactionReport::default();
+    let log_path = pending_logs.log_path(log_id);
+
+    let db = frozen_databases
+        .read()
+        .unwrap()
+        .iter()
+        .find(|(id, _)| *id == log_id)
+        .map(|(_, db)| db.clone());
+    let db = match db {
+        Some(db) => db,
+        None => {
+            log::error!("Frozen database of log.{} is not found", log_id);
+            return Err(StorageError::FrozenDatabaseNotFound);
+        }
+    };
+    let sstable = flush_table(&db, version_set, table_cache, family, blob_store)?;
+
+    let (smallest, largest) = match sstable.key_range() {
+        Some(range) => range,
+        None => {
+            // Nothing to write.
+            remove_frozen(frozen_databases, log_id);
+            pending_logs.done(log_id)?;
+            return Ok(report);
+        }
+    };
+
+    // The last sequence in this log is recorded in MANIFEST, so sequence numbers will not go back
+    // after restart when the log is removed.
+    let last_sequence = match log_path.to_str() {
+        Some(path) => {
+            DatabaseLog::open(path, 4 * 1024 * 2, table_cache.encryptor())?.last_sequence()
+        }
+        None => {
+            log::error!("Log path is not UTF-8: {:#?}", log_path);
+            return Err(StorageError::UnicodeError);
+        }
+    };
+
+    let id = version_set.new_table_id(0);
+    let path_id = version_set.path_for_level(0);
+    let table_path = table_cache.table_path(path_id, 0, id);
+    let table_path = match table_path.to_str() {
+        Some(str) => str,
+        None => {
+            log::error!("Table path is not UTF-8: {:#?}", table_path);
+            return Err(StorageError::UnicodeError);
+        }
+    };
+    sstable
+        .save(
+            table_path,
+            compression,
+            table_cache.encryptor(),
+            Some(rate_limiter),
+        )
+        .await?;
+    sync_parent(Path::new(table_path))?;
+    report.bytes_written = std::fs::metadata(table_path)?.len();
+    report.added.push((0, id));
+
+    let mut edit = VersionEdit::default();
+    edit.add_table(0, id, path_id, smallest, largest);
+    edit.set_log_number(log_id + 1);
+    edit.set_last_sequence(last_sequence);
+    version_set.log_and_apply(&edit)?;
+
+    table_cache.insert(0, id, sstable);
+    remove_frozen(frozen_databases, log_id);
+
+    pending_logs.done(log_id)?;
+
+    Ok(report)
+}
+
+fn remove_frozen(frozen_databases: &FrozenDatabases, log_id: usize) {
+    frozen_databases
+        .write()
+        .unwrap()
+        .retain(|(id, _)| *id != log_id);
+}
+
+type FlushReply = oneshot::Sender<StorageResult<CompactionReport>>;
+
+/// Flushes of a family waiting for the running one, in order of their logs. A failed flush is put
+/// back in front, so no newer log is flushed before it.
+#[derive(Default)]
+struct FlushQueue {
+    logs: VecDeque<(usize, Option<FlushReply>)>,
+    running: bool,
+    /// Flushes failed in a row, which decides how long to wait before retrying.
+    failures: u32,
+}
+
+/// Key ranges of running (or queued) compactions of a family. `exclusive` counts `compact_range` and
+/// ingestion waiting or running, and no compaction is started while it's not `0`.
+#[derive(Default)]
+struct RunningCompactions {
+    ranges: Vec<(Slice, Slice)>,
+    exclusive: usize,
+    /// Compactions failed in a row, which decides how long to wait before retrying.
+    failures: u32,
+    retry_scheduled: bool,
+}
+
+/// How long to wait before retrying after the first failed flush or compaction. It's doubled after
+/// every failure in a row, up to `MAX_RETRY_DELAY`.
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+fn retry_delay(failures: u32) -> Duration {
+    std::cmp::min(
+        RETRY_DELAY * (1 << std::cmp::min(failures, 16)),
+        MAX_RETRY_DELAY,
+    )
+}
+
+struct WorkerState {
+    frozen_databases: Arc<FrozenDatabases>,
+    version_set: Arc<VersionSet>,
+    table_cache: Arc<TableCache>,
+    family: u32,
+    blob_store: Arc<BlobStore>,
+    pending_logs: Arc<PendingLogs>,
+    compaction_strategy: Arc<dyn CompactionStrategy>,
+    compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    compression: Vec<Compression>,
+    statistics: Arc<Statistics>,
+    rate_limiter: Arc<RateLimiter>,
+    scheduler: Scheduler,
+    flushes: Mutex<FlushQueue>,
+    compactions: Mutex<RunningCompactions>,
+    compaction_done: Condvar,
+}
+
+impl WorkerState {
+    fn filter_context(&self) -> Option<FilterContext> {
+        self.compaction_filter.as_ref().map(|filter| FilterContext {
+            filter: filter.as_ref(),
+            blob_store: &self.blob_store,
+            family: self.family,
+        })
+    }
+}
+
+/// Background work of a column family, which runs as jobs in the `BackgroundPool` shared by every
+/// family.
+///
+/// Frozen databases of a family are flushed one by one in order of their logs, with `High` priority.
+/// After every flush and compaction, compactions picked by the compaction strategy are queued with
+/// `Low` priority, until it picks nothing or a compaction overlapping a running one. So compactions of
+/// non-overlapping key ranges run in parallel. A failed flush is retried before any newer log, and
+/// compactions are picked again after a compaction fails, both waiting longer after every failure in
+/// a row (see `RETRY_DELAY`). A running compaction is never interrupted by a flush, as flushes have
+/// threads of their own.
+///
+/// `compact_range` and `ingest` run in the calling thread. They wait until running compactions finish,
+/// and no compaction starts before they finish.
+#[derive(Clone)]
+pub struct BackgroundWorker {
+    state: Arc<WorkerState>,
+}
+
+impl BackgroundWorker {
+    /// `log.<id>` is frozen, and its frozen database should be written into a table. The result is sent
+    /// back if a sender is given.
+    pub fn flush(&self, log_id: usize, reply: Option<FlushReply>) {
+        let mut flushes = self.state.flushes.lock().unwrap();
+        flushes.logs.push_back((log_id, reply));
+        if !flushes.running {
+            flushes.running = true;
+            let state = self.state.clone();
+            self.state
+                .scheduler
+                .schedule(Priority::High, move || run_flushes(&state));
+        }
+    }
+
+    /// Compact tables overlapping `[start, end)` down to the bottom.
+    pub fn compact_range(&self, start: &Slice, end: &Slice) -> StorageResult<CompactionReport> {
+        let state = &self.state;
+        let result = exclusive(state, || {
+            futures::executor::block_on(compact_range(
+                &state.version_set,
+                &state.table_cache,
+                start,
+                end,
+                state.filter_context(),
+                &state.compression,
+                &state.statistics,
+                &state.rate_limiter,
+            ))
+        });
+        schedule_compactions(state);
+        result
+    }
+
+    /// Move verified files into tables. `lock` blocks writes and gives the sequence number of
+    /// ingested records after tables are written (see `ingest_tables`), and its guard is returned.
+    pub fn ingest<G, F>(
+        &self,
+        files: Vec<IngestFile>,
+        lock: F,
+    ) -> StorageResult<(CompactionReport, G)>
+    where
+        F: FnMut() -> StorageResult<Option<(G, u64)>>,
+    {
+        let state = &self.state;
+        let result = exclusive(state, || {
+            ingest_tables(
+                &state.version_set,
+                &state.table_cache,
+                files,
+                &state.compression,
+                &state.rate_limiter,
+                lock,
+            )
+        });
+        schedule_compactions(state);
+        result
+    }
+}
+
+fn run_flushes(state: &Arc<WorkerState>) {
+    loop {
+        let (log_id, reply) = {
+            let mut flushes = state.flushes.lock().unwrap();
+            match flushes.logs.pop_front() {
+                Some(flush) => flush,
+                None => {
+                    flushes.running = false;
+                    return;
+                }
+            }
+        };
+
+        let started = Instant::now();
+        let result = futures::executor::block_on(flush(
+            log_id,
+            &state.frozen_databases,
+            &state.version_set,
+            &state.table_cache,
+            state.family,
+            &state.blob_store,
+            &state.pending_logs,
+            Compression::for_level(&state.compression, 0),
+            &state.rate_limiter,
+        ));
+        match &result {
+            Ok(report) if !report.added.is_empty() => {
+                state.statistics.record_flush(started.elapsed(), report)
+            }
+            Ok(_) => {}
+            Err(err) => log::error!("Error while flushing log.{}: {}", log_id, err),
+        }
+        // Compactions are queued before replying, so `compact_range` called after the flush always
+        // waits for them.
+        let failed = result.is_err();
+        if failed {
+            schedule_flush_retry(state, log_id);
+        } else {
+            state.flushes.lock().unwrap().failures = 0;
+            schedule_compactions(state);
+        }
+        if let Some(reply) = reply {
+            // The caller may have stopped waiting.
+            let _ = reply.send(result);
+        }
+        if failed {
+            return;
+        }
+    }
+}
+
+/// Put a failed flush back in front of the queue, and run the queue again later. Its frozen database
+/// and log are kept until it succeeds, and newer logs wait for it. The error has been sent to the
+/// caller (if any), so nobody waits for the retry.
+fn schedule_flush_retry(state: &Arc<WorkerState>, log_id: usize) {
+    let mut flushes = state.flushes.lock().unwrap();
+    flushes.logs.push_front((log_id, None));
+    let delay = retry_delay(flushes.failures);
+    flushes.failures = flushes.failures.saturating_add(1);
+
+    log::info!("Retrying flush of log.{} in {:?}", log_id, delay);
+    let job_state = state.clone();
+    state
+        .scheduler
+        .schedule_after(Priority::High, delay, move || run_flushes(&job_state));
+}
+
+/// Queue every compaction picked by the strategy which doesn't overlap a running one.
+fn schedule_compactions(state: &Arc<WorkerState>) {
+    let mut compactions = state.compactions.lock().unwrap();
+    if compactions.exclusive > 0 {
+        return;
+    }
+
+    let version = state.version_set.current();
+    while let Some(compaction) = state.compaction_strategy.pick_compaction(&version) {
+        let range = match compaction.key_range() {
+            Some(range) => range,
+            None => break,
+        };
+        let overlaps = compactions
+            .ranges
+            .iter()
+            .any(|(smallest, largest)| smallest <= &range.1 && largest >= &range.0);
+        if overlaps {
+            break;
+        }
+
+        compaction.set_being_compacted(true);
+        compactions.ranges.push(range.clone());
+        let job_state = state.clone();
+        state.scheduler.schedule(Priority::Low, move || {
+            run_compaction(&job_state, compaction, range)
+        });
+    }
+}
+
+fn run_compaction(state: &Arc<WorkerState>, compaction: Compaction, range: (Slice, Slice)) {
+    let result = futures::executor::block_on(compact(
+        &state.version_set,
+        &state.table_cache,
+        &compaction,
+        state.filter_context(),
+        &state.compression,
+        &state.statistics,
+        &state.rate_limiter,
+    ));
+    compaction.set_being_compacted(false);
+    finish_compaction(state, &range);
+
+    match result {
+        Ok(_) => {
+            state.compactions.lock().unwrap().failures = 0;
+            schedule_compactions(state);
+        }
+        Err(err) => {
+            log::error!("Error while compacting: {}", err);
+            schedule_retry(state);
+        }
+    }
+}
+
+/// Pick compactions again after a failure. It may fail again at once (e.g. the disk is full), so it
+/// waits longer after every failure in a row.
+fn schedule_retry(state: &Arc<WorkerState>) {
+    let mut compactions = state.compactions.lock().unwrap();
+    let failures = compactions.failures;
+    compactions.failures = failures.saturating_add(1);
+    if compactions.retry_scheduled {
+        return;
+    }
+    compactions.retry_scheduled = true;
+
+    let delay = retry_delay(failures);
+    log::info!("Retrying compaction in {:?}", delay);
+    let job_state = state.clone();
+    state
+        .scheduler
+        .schedule_after(Priority::Low, delay, move || {
+            job_state.compactions.lock().unwrap().retry_scheduled = false;
+            schedule_compactions(&job_state);
+        });
+}
+
+fn finish_compaction(state: &WorkerState, range: &(Slice, Slice)) {
+    let mut compactions = state.compactions.lock().unwrap();
+    if let Some(index) = compactions
+        .ranges
+        .iter()
+        .position(|running| running == range)
+    {
+        compactions.ranges.remove(index);
+    }
+    state.compaction_done.notify_all();
+}
+
+/// Run `work` after every running compaction finishes, and start no compaction until it finishes.
+fn exclusive<T, F: FnOnce() -> StorageResult<T>>(state: &WorkerState, work: F) -> StorageResult<T> {
+    let everything = (Slice(Vec::new()), Slice(vec![255; KEY_LENGTH]));
+    {
+        let mut compactions = state.compactions.lock().unwrap();
+        compactions.exclusive += 1;
+        while !compactions.ranges.is_empty() {
+            compactions = state.compaction_done.wait(compactions).unwrap();
+        }
+        compactions.ranges.push(everything.clone());
+    }
+
+    let result = work();
+
+    state.compactions.lock().unwrap().exclusive -= 1;
+    finish_compaction(state, &everything);
+    result
+}
+
+pub struct ManifestManager {
+    frozen_databases: Arc<FrozenDatabases>,
+    version_set: Arc<VersionSet>,
+    table_cache: Arc<TableCache>,
 }
 
 impl ManifestManager {
     pub fn create_new(
         base_dir: &str,
-        frozen_databases: Arc<ShardedLock<VecDeque<Arc<MemDatabase>>>>,
+        data_paths: Vec<(String, u64)>,
+        frozen_databases: Arc<FrozenDatabases>,
+        max_open_files: usize,
+        block_cache: Arc<BlockCache>,
+        encryptor: Option<Arc<dyn Encryptor>>,
     ) -> StorageResult<ManifestManager> {
-        let base_path = Path::new(base_dir);
-        let manifest_path = base_path.join("MANIFEST");
-
-        let manifest_path = match manifest_path.to_str() {
-            Some(str) => str,
-            None => {
-                log::error!("Manifest path is not UTF-8: {:#?}", manifest_path);
-                return Err(StorageError::UnicodeError);
-            }
-        };
+        let table_cache = Arc::new(TableCache::new(
+            base_dir,
+            data_paths,
+            max_open_files,
+            block_cache,
+            encryptor,
+        ));
 
         Ok(ManifestManager {
-            base_dir: base_dir.to_string(),
-            log_manager: Arc::new(LogManager::create_new(manifest_path, 4 * 1024)?),
             frozen_databases,
-            sstables: Arc::new([
-                ShardedLock::new(BTreeMap::new()),
-                ShardedLock::new(BTreeMap::new()),
-                ShardedLock::new(BTreeMap::new()),
-                ShardedLock::new(BTreeMap::new()),
-                ShardedLock::new(BTreeMap::new()),
-                ShardedLock::new(BTreeMap::new()),
-            ]),
-            level_counter: Arc::new([
-                AtomicUsize::new(0),
-                AtomicUsize::new(0),
-                AtomicUsize::new(0),
-                AtomicUsize::new(0),
-                AtomicUsize::new(0),
-                AtomicUsize::new(0),
-            ]), // TODO: use macro to avoid these redundant codes
+            version_set: Arc::new(VersionSet::create_new(base_dir, table_cache.clone())?),
+            table_cache,
         })
     }
 
     pub fn open(
         base_dir: &str,
-        frozen_databases: Arc<ShardedLock<VecDeque<Arc<MemDatabase>>>>,
+        data_paths: Vec<(String, u64)>,
+        frozen_databases: Arc<FrozenDatabases>,
+        max_open_files: usize,
+        block_cache: Arc<BlockCache>,
+        encryptor: Option<Arc<dyn Encryptor>>,
     ) -> StorageResult<ManifestManager> {
-        let base_path = Path::new(base_dir);
-        let manifest_path = base_path.join("MANIFEST");
-
-        let manifest_path = match manifest_path.to_str() {
-            Some(str) => str,
-            None => {
-                log::error!("Manifest path is not UTF-8: {:#?}", manifest_path);
-                return Err(StorageError::UnicodeError);
-            }
-        };
-        let log_manager: Arc<LogManager<RawManifestLogEntry>> =
-            Arc::new(LogManager::open(manifest_path, 4 * 1024)?);
-
-        let sstables = Arc::new([
-            ShardedLock::new(BTreeMap::new()),
-            ShardedLock::new(BTreeMap::new()),
-            ShardedLock::new(BTreeMap::new()),
-            ShardedLock::new(BTreeMap::new()),
-            ShardedLock::new(BTreeMap::new()),
-            ShardedLock::new(BTreeMap::new()),
-        ]);
-
-        let level_counter = Arc::new([
-            AtomicUsize::new(0),
-            AtomicUsize::new(0),
-            AtomicUsize::new(0),
-            AtomicUsize::new(0),
-            AtomicUsize::new(0),
-            AtomicUsize::new(0),
-        ]);
+        let table_cache = Arc::new(TableCache::new(
+            base_dir,
+            data_paths,
+            max_open_files,
+            block_cache,
+            encryptor,
+        ));
 
-        for log in log_manager.iter() {
-            match log.add_flag {
-                1 => {
-                    if log.level >= 6 {
-                        return Err(StorageError::ManifestLogFormatError);
-                    }
+        Ok(ManifestManager {
+            frozen_databases,
+            version_set: Arc::new(VersionSet::open(base_dir, table_cache.clone())?),
+            table_cache,
+        })
+    }
 
-                    let table_path = base_path.join(format!("sstable_{}_{}", log.level, log.id));
-                    log::info!("Restoring sstable from {:#?}", table_path);
-                    let sstable_file = std::fs::OpenOptions::new()
-                        .read(true)
-                        .write(true)
-                        .open(table_path)?;
-                    let sstable = SSTable::open(sstable_file)?;
-                    sstables
-                        .get(log.level as usize)
-                        .unwrap() // unwrap here is totally safe
-                        .write()
-                        .unwrap()
-                        .insert(log.id as usize, sstable);
-                    level_counter
-                        .get(log.level as usize)
-                        .unwrap() // unwrap here is totally safe
-                        .fetch_max(log.id as usize, Ordering::SeqCst);
-                }
-                0 => {
-                    if log.level >= 6 {
-                        return Err(StorageError::ManifestLogFormatError);
-                    }
+    pub fn current(&self) -> Arc<Version> {
+        self.version_set.current()
+    }
+
+    /// Remove tables which are not referenced by current version from every data path. They are left by
+    /// a crash after writing a table but before recording it in MANIFEST, or after removing a table from
+    /// MANIFEST but before unlinking it.
+    pub fn remove_obsolete_files(&self) -> StorageResult<()> {
+        let version = self.version_set.current();
 
-                    sstables
-                        .get(log.level as usize)
-                        .unwrap() // unwrap here is totally safe
-                        .write()
-                        .unwrap()
-                        .remove(&(log.id as usize));
+        for (path_id, dir) in self.table_cache.dirs().into_iter().enumerate() {
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                let name = match path.file_name().and_then(|name| name.to_str()) {
+                    Some(name) => name,
+                    None => continue,
+                };
+
+                let obsolete = if name == "MANIFEST.tmp" {
+                    true
+                } else if let Some((level, id)) = parse_table_name(name) {
+                    level >= NUM_LEVELS
+                        || !version
+                            .level(level)
+                            .iter()
+                            .any(|table| table.id == id && table.path_id == path_id)
+                } else {
+                    false
+                };
+
+                if obsolete {
+                    log::info!("Removing obsolete file {:#?}", path);
+                    std::fs::remove_file(&path)?;
                 }
-                _ => unreachable!(),
             }
         }
 
-        Ok(ManifestManager {
-            base_dir: base_dir.to_string(),
-            log_manager,
-            frozen_databases,
-            sstables,
-            level_counter,
-        })
+        Ok(())
     }
 
-    //    fn compact<S: Spawn>(&self, _spawner: S) {
-    //        unimplemented!()
-    //    }
-
-    pub fn background_work(&self) -> StorageResult<UnboundedSender<usize>> {
-        let frozen_databases = self.frozen_databases.clone();
-
-        let (freeze_sender, freeze_receiver) = unbounded::<usize>();
-        let mut freeze_receiver = freeze_receiver.fuse();
-
-        let base_dir = self.base_dir.clone();
-        let level_counter = self.level_counter.clone();
-        let sstables = self.sstables.clone();
-        let log_manager = self.log_manager.clone();
-
-        std::thread::Builder::new()
-            .name("background_worker".to_string())
-            .spawn(move || {
-                let mut local_pool = LocalPool::new();
-                let spawn_result = local_pool.spawner().spawn_local(async move {
-                    let base_path = Path::new(&base_dir);
-                    loop {
-                        let newest_log_id = match freeze_receiver.next().await {
-                            Some(id) => id,
-                            None => {
-                                break;
-                            }
-                        };
-                        let new_log_path = base_path.join(format!("log.{}", newest_log_id));
-
-                        let db_guard = frozen_databases.read().unwrap();
-                        match db_guard.back() {
-                            Some(db) => {
-                                let sstable = SSTable::from(db.clone());
-                                drop(db_guard);
-
-                                let id = level_counter[0].fetch_add(1, Ordering::SeqCst);
-
-                                let table_path = base_path.join(format! {"sstable_0_{}", id});
-                                let table_path = match table_path.to_str() {
-                                    Some(str) => str,
-                                    None => {
-                                        log::error!("Table path is not UTF-8: {:#?}", table_path);
-                                        continue;
-                                    }
-                                };
-                                match sstable.save(table_path).await {
-                                    Ok(()) => {}
-                                    Err(err) => {
-                                        log::error!("Error while storing SSTable: {}", err);
-                                        continue;
-                                    }
-                                }
-
-                                log_manager.add_entry(RawManifestLogEntry {
-                                    real_flag: 1,
-                                    add_flag: 1,
-                                    level: 0,
-                                    id: id as u8,
-                                });
-
-                                sstables[0].write().unwrap().insert(id, sstable);
-                                frozen_databases.write().unwrap().pop_back();
-
-                                match std::fs::remove_file(new_log_path) {
-                                    Ok(()) => {}
-                                    Err(err) => {
-                                        log::error!("Error while remove log: {}", err);
-                                        continue;
-                                    }
-                                };
-                            }
-                            None => {}
-                        }
+    /// Create the background worker of this family, whose jobs are run by `scheduler`. Tables are
+    /// compacted with `compaction_filter` applied on rewritten records. New tables of every level are
+    /// compressed with its codec in `compression`. Flushes and compactions wait for `rate_limiter`
+    /// before writing tables. Values merged while flushing are put into `blob_store` as values of
+    /// `family` if they are long.
+    #[allow(clippy::too_many_arguments)]
+    pub fn background_work(
+        &self,
+        scheduler: Scheduler,
+        family: u32,
+        blob_store: Arc<BlobStore>,
+        pending_logs: Arc<PendingLogs>,
+        compaction_strategy: Arc<dyn CompactionStrategy>,
+        compaction_filter: Option<Arc<dyn CompactionFilter>>,
+        compression: Vec<Compression>,
+        statistics: Arc<Statistics>,
+        rate_limiter: Arc<RateLimiter>,
+    ) -> BackgroundWorker {
+        BackgroundWorker {
+            state: Arc::new(WorkerState {
+                frozen_databases: self.frozen_databases.clone(),
+                version_set: self.version_set.clone(),
+                table_cache: self.table_cache.clone(),
+                family,
+                blob_store,
+                pending_logs,
+                compaction_strategy,
+                compaction_filter,
+                compression,
+                statistics,
+                rate_limiter,
+                scheduler,
+                flushes: Mutex::new(FlushQueue::default()),
+                compactions: Mutex::new(RunningCompactions::default()),
+                compaction_done: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Find key in SSTables, with the level where it's found. `Some((_, Value::NotExist))` means it's
+    /// deleted or expired, and `None` means it's not in any table.
+    pub fn find_key(&self, key: &Slice) -> StorageResult<Option<(usize, Value)>> {
+        find_key(&self.version_set, &self.table_cache, key)
+    }
+
+    /// Number and size of tables in every level.
+    pub fn level_stats(&self) -> Vec<(u64, u64)> {
+        let version = self.version_set.current();
+        (0..NUM_LEVELS)
+            .map(|level| {
+                let tables = version.level(level).len() as u64;
+                (tables, level_bytes(&version, level))
+            })
+            .collect()
+    }
+
+    /// Estimated size and number of records in tables within `[start, end)`. A table inside the range is
+    /// counted by its file size, and others by the part of its file size taken by records in the range,
+    /// so compressed tables are counted by their compressed size. Deleted and overwritten records are
+    /// counted too.
+    pub fn approximate_range(&self, start: &Slice, end: &Slice) -> (u64, u64) {
+        let version = self.version_set.current();
+
+        let (mut bytes, mut count) = (0, 0);
+        for level in 0..NUM_LEVELS {
+            for meta in version.level(level) {
+                if !meta.overlaps(start, end) {
+                    continue;
+                }
+                let table = self.table_cache.get(meta.path_id, level, meta.id);
+                let size = std::fs::metadata(meta.path());
+                let (table, size) = match (table, size) {
+                    (Ok(table), Ok(metadata)) => (table, metadata.len()),
+                    (Err(err), _) => {
+                        log::error!("Error while opening sstable_{}_{}: {}", level, meta.id, err);
+                        continue;
+                    }
+                    (_, Err(err)) => {
+                        log::error!("Error while reading sstable_{}_{}: {}", level, meta.id, err);
+                        continue;
                     }
-                });
+                };
 
-                match spawn_result {
-                    Ok(()) => {}
-                    Err(err) => log::error!("Error while spawning: {}", err),
+                let total = table.len() as u64;
+                if &meta.smallest >= start && &meta.largest < end {
+                    bytes += size;
+                    count += total;
+                } else if total > 0 {
+                    let records = table.approximate_count(start, end);
+                    bytes += size * records / total;
+                    count += records;
                 }
+            }
+        }
+        (bytes, count)
+    }
 
-                local_pool.run();
-            })?;
+    /// Sources of every table overlapping with `[start, end)`. Newer tables are put in front of older
+    /// ones. A table which cannot be read fails the whole read, instead of leaving its keys out.
+    pub fn sources(&self, start: &Slice, end: &Slice) -> StorageResult<Vec<Source>> {
+        let version = self.version_set.current();
 
-        Ok(freeze_sender)
-    }
+        let mut sources = Vec::new();
+        for level in 0..NUM_LEVELS {
+            let tables: Vec<&Arc<TableMeta>> = if level == 0 {
+                version.level(level).iter().rev().collect()
+            } else {
+                version.level(level).iter().collect()
+            };
 
-    pub fn find_key(&self, key: Slice) -> Option<Slice> {
-        for level in 0..6 {
-            let level = self.sstables[level].read().unwrap();
-            for (_id, table) in level.iter() {
-                match table.get_sync(key.clone()) {
-                    Ok(value) => return Some(value),
-                    Err(_) => {}
+            for table in tables {
+                if !table.overlaps(start, end) {
+                    continue;
+                }
+                let source = self
+                    .table_cache
+                    .get(table.path_id, level, table.id)
+                    .and_then(|table| Ok(table.source(start, end)?));
+                match source {
+                    Ok(source) => sources.push(source),
+                    Err(err) => {
+                        log::error!(
+                            "Error while reading sstable_{}_{}: {}",
+                            level,
+                            table.id,
+                            err
+                        );
+                        return Err(err);
+                    }
                 }
             }
         }
-        None
+        Ok(sources)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::table_cache::table_path;
+    use agilulf_protocol::{Command, PutCommand};
+
+    #[test]
+    fn obsolete_table_outlives_old_version() {
+        let base_dir = "/var/tmp/agilulf_version_test";
+        std::fs::create_dir_all(base_dir).unwrap();
+        let table_cache = Arc::new(TableCache::new(
+            base_dir,
+            Vec::new(),
+            16,
+            Arc::new(BlockCache::new(0)),
+            None,
+        ));
+        let version_set = VersionSet::create_new(base_dir, table_cache.clone()).unwrap();
+
+        let id = version_set.new_table_id(0);
+        let path = table_path(base_dir, 0, id);
+        std::fs::write(&path, b"").unwrap();
+
+        let mut edit = VersionEdit::default();
+        edit.add_table(0, id, 0, Slice(b"A".to_vec()), Slice(b"Z".to_vec()));
+        version_set.log_and_apply(&edit).unwrap();
+
+        let old_version = version_set.current();
+        let mut edit = VersionEdit::default();
+        edit.delete_table(0, id);
+        version_set.log_and_apply(&edit).unwrap();
+
+        assert_eq!(version_set.current().level(0).len(), 0);
+        assert_eq!(old_version.level(0).len(), 1);
+        assert!(path.exists());
+
+        drop(old_version);
+        assert!(!path.exists());
 
-    pub fn scan(&self, start: Slice, end: Slice) -> impl Iterator<Item = (Slice, Slice)> {
-        let mut merge_vec = Vec::new();
-        for level in 0..6 {
-            let level = self.sstables[level].read().unwrap();
-            for (_id, table) in level.iter() {
-                merge_vec.push(table.scan_sync(start.clone(), end.clone()).into_iter())
+        drop(version_set);
+        let version_set = VersionSet::open(base_dir, table_cache).unwrap();
+        assert_eq!(version_set.current().level(0).len(), 0);
+        assert_eq!(version_set.new_table_id(0), id + 1);
+    }
+
+    #[test]
+    fn rewrite_full_manifest() {
+        let base_dir = "/var/tmp/agilulf_manifest_rewrite_test";
+        std::fs::create_dir_all(base_dir).unwrap();
+        let table_cache = Arc::new(TableCache::new(
+            base_dir,
+            Vec::new(),
+            16,
+            Arc::new(BlockCache::new(0)),
+            None,
+        ));
+        let version_set = VersionSet::create_new(base_dir, table_cache.clone()).unwrap();
+
+        // Every edit takes two records, so MANIFEST is rewritten several times.
+        for _ in 0..MANIFEST_LENGTH * 2 {
+            let id = version_set.new_table_id(1);
+            let mut edit = VersionEdit::default();
+            edit.add_table(1, id, 0, Slice(b"A".to_vec()), Slice(b"Z".to_vec()));
+            std::fs::write(table_path(base_dir, 1, id), b"").unwrap();
+            if id > 0 {
+                edit.delete_table(1, id - 1);
             }
+            version_set.log_and_apply(&edit).unwrap();
+        }
+
+        drop(version_set);
+        let version_set = VersionSet::open(base_dir, table_cache).unwrap();
+        let version = version_set.current();
+        assert_eq!(version.level(1).len(), 1);
+        assert_eq!(version.level(1)[0].id, MANIFEST_LENGTH * 2 - 1);
+    }
+
+    #[test]
+    fn snapshot_many_tables() {
+        let base_dir = "/var/tmp/agilulf_manifest_snapshot_test";
+        let _ = std::fs::remove_dir_all(base_dir);
+        std::fs::create_dir_all(base_dir).unwrap();
+        let table_cache = Arc::new(TableCache::new(
+            base_dir,
+            Vec::new(),
+            16,
+            Arc::new(BlockCache::new(0)),
+            None,
+        ));
+        let version_set = VersionSet::create_new(base_dir, table_cache.clone()).unwrap();
+
+        // The snapshot of these tables doesn't fit into a MANIFEST of `MANIFEST_LENGTH` records.
+        let tables = MANIFEST_LENGTH * 3 / 2;
+        let mut edit = VersionEdit::default();
+        for _ in 0..tables {
+            let id = version_set.new_table_id(0);
+            edit.add_table(0, id, 0, Slice(b"A".to_vec()), Slice(b"Z".to_vec()));
+            std::fs::write(table_path(base_dir, 0, id), b"").unwrap();
+        }
+        version_set.log_and_apply(&edit).unwrap();
+        let mut edit = VersionEdit::default();
+        edit.delete_table(0, 0);
+        version_set.log_and_apply(&edit).unwrap();
+
+        drop(version_set);
+        let version_set = VersionSet::open(base_dir, table_cache).unwrap();
+        assert_eq!(version_set.current().level(0).len(), tables - 1);
+        assert!(!Path::new(&format!("{}/MANIFEST.tmp", base_dir)).exists());
+    }
+
+    #[test]
+    fn flush_frozen_database_of_log() {
+        let base_dir = "/var/tmp/agilulf_manifest_flush_test";
+        let _ = std::fs::remove_dir_all(base_dir);
+        std::fs::create_dir_all(base_dir).unwrap();
+        let table_cache = Arc::new(TableCache::new(
+            base_dir,
+            Vec::new(),
+            16,
+            Arc::new(BlockCache::new(0)),
+            None,
+        ));
+        let version_set = VersionSet::create_new(base_dir, table_cache.clone()).unwrap();
+        let blob_store = BlobStore::open(base_dir, None, None).unwrap();
+        let pending_logs = PendingLogs::new(base_dir, None, None);
+        let rate_limiter = RateLimiter::unlimited();
+
+        let frozen_databases = FrozenDatabases::new(VecDeque::new());
+        for (log_id, key) in [(1, b"A"), (2, b"B")].iter() {
+            let path = pending_logs.log_path(*log_id);
+            DatabaseLog::create_new(path.to_str().unwrap(), 4 * 1024 * 2, None).unwrap();
+            pending_logs.add(*log_id, 1).unwrap();
+
+            let db = MemDatabase::new(None);
+            db.apply(Command::PUT(PutCommand {
+                key: Slice(key.to_vec()),
+                value: Slice(b"VALUE".to_vec()),
+            }))
+            .unwrap();
+            frozen_databases
+                .write()
+                .unwrap()
+                .push_front((*log_id, Arc::new(db)));
+        }
+
+        let flush_log = |log_id| {
+            futures::executor::block_on(flush(
+                log_id,
+                &frozen_databases,
+                &version_set,
+                &table_cache,
+                0,
+                &blob_store,
+                &pending_logs,
+                Compression::None,
+                &rate_limiter,
+            ))
+        };
+
+        // The frozen database of the log is flushed, wherever it is in the queue.
+        flush_log(2).unwrap();
+        let frozen: Vec<usize> = frozen_databases
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(id, _)| *id)
+            .collect();
+        assert_eq!(frozen, vec![1]);
+        let found = find_key(&version_set, &table_cache, &Slice(b"B".to_vec())).unwrap();
+        assert!(found.is_some());
+        assert!(find_key(&version_set, &table_cache, &Slice(b"A".to_vec()))
+            .unwrap()
+            .is_none());
+        assert!(!pending_logs.log_path(2).exists());
+        assert!(pending_logs.log_path(1).exists());
+
+        match flush_log(3) {
+            Err(StorageError::FrozenDatabaseNotFound) => {}
+            _ => panic!("a log without frozen database should not be flushed"),
+        }
+        assert!(pending_logs.log_path(1).exists());
+    }
+
+    #[test]
+    fn missing_table_fails_open() {
+        let base_dir = "/var/tmp/agilulf_manifest_missing_test";
+        let _ = std::fs::remove_dir_all(base_dir);
+        std::fs::create_dir_all(base_dir).unwrap();
+        let table_cache = Arc::new(TableCache::new(
+            base_dir,
+            Vec::new(),
+            16,
+            Arc::new(BlockCache::new(0)),
+            None,
+        ));
+        let version_set = VersionSet::create_new(base_dir, table_cache.clone()).unwrap();
+
+        let id = version_set.new_table_id(0);
+        let path = table_path(base_dir, 0, id);
+        std::fs::write(&path, b"").unwrap();
+        let mut edit = VersionEdit::default();
+        edit.add_table(0, id, 0, Slice(b"A".to_vec()), Slice(b"Z".to_vec()));
+        version_set.log_and_apply(&edit).unwrap();
+
+        drop(version_set);
+        std::fs::remove_file(&path).unwrap();
+        match VersionSet::open(base_dir, table_cache) {
+            Err(StorageError::TableNotFound) => {}
+            _ => panic!("open should fail on a missing table"),
         }
-        merge_iter(merge_vec)
     }
 }
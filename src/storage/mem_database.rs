@@ -1,16 +1,78 @@
+use super::blob::{BlobIndex, BLOB_INDEX_LENGTH};
+use super::database_log::LogRecord;
+use super::merge::Source;
+use super::merge_operator::{fold, MergeOperator};
+use super::range_tombstone::RangeTombstone;
+use super::statistics::{DatabaseStats, ReadSource, Statistics};
 use super::{Slice, SyncDatabase};
 use agilulf_protocol::{DatabaseError, DatabaseResult as Result};
 
 use crate::storage::error::StorageResult;
 use agilulf_protocol::Command;
 use agilulf_skiplist::SkipMap;
-use std::sync::atomic::AtomicPtr;
+use crossbeam::sync::ShardedLock;
 use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicPtr, AtomicU64};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-#[derive(Clone)]
-enum Value {
+/// Milliseconds since UNIX epoch, which is the unit of expiration time.
+pub fn now_millis() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_millis() as u64,
+        Err(_) => 0,
+    }
+}
+
+/// Value of a key in MemDatabase or SSTable. `NotExist` is the tombstone of a deleted key, which hides
+/// the key in older MemDatabases and SSTables. `Expiring` is a value with its expiration time (in
+/// milliseconds since UNIX epoch).
+///
+/// `Merge` holds operands of `merge` (the oldest first) whose base value is in older MemDatabases or
+/// SSTables. It only exists in MemDatabase, as operands are folded into the base value while flushing.
+///
+/// `Blob` is a value put into blob files, with its expiration time (`0` if it never expires). It has to
+/// be read from there by `Family`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
     NotExist,
     Slice(Slice),
+    Expiring(Slice, u64),
+    Merge(Vec<Slice>),
+    Blob(BlobIndex, u64),
+}
+
+impl Value {
+    /// An expired value becomes `NotExist`, as it should still hide the key in older databases.
+    pub fn live(self, now: u64) -> Value {
+        match self {
+            Value::Expiring(_, expire_at) if expire_at <= now => Value::NotExist,
+            Value::Blob(_, expire_at) if expire_at != 0 && expire_at <= now => Value::NotExist,
+            value => value,
+        }
+    }
+
+    pub fn into_slice(self) -> Option<Slice> {
+        match self {
+            Value::Slice(value) | Value::Expiring(value, _) => Some(value),
+            Value::NotExist | Value::Merge(_) | Value::Blob(..) => None,
+        }
+    }
+
+    /// Bytes of values or operands. A value in blob files only takes its index here.
+    pub fn len(&self) -> usize {
+        match self {
+            Value::Slice(value) | Value::Expiring(value, _) => value.0.len(),
+            Value::NotExist => 0,
+            Value::Blob(..) => BLOB_INDEX_LENGTH,
+            Value::Merge(operands) => operands.iter().map(|operand| operand.0.len()).sum(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 impl Default for Value {
@@ -21,44 +83,256 @@ impl Default for Value {
 
 /// A simple RAM only database with skiplist as kernel.
 ///
-/// The type of Value in skiplist is either NotExist (used for deleting element) or Slice.
+/// The type of Value in skiplist is either NotExist (used for deleting element) or Slice. Range
+/// tombstones are kept in a list beside the skiplist.
+///
+/// Expired keys are hidden as soon as they expire, but the skiplist never removes anything. When it's
+/// used alone (e.g. memory only server), `sweep` rebuilds the skiplist to free them, and
+/// `spawn_sweeper` does it periodically.
+///
+/// `merge` needs a merge operator. If the key has a value here, operands are applied on it at once.
+/// Otherwise they are kept as `Value::Merge` until the base value is found.
+///
+/// Every access to the skiplist holds `map_lock` for read. `sweep` holds it for write while replacing
+/// the skiplist, and so does `merge` while reading and updating the value.
+///
+/// `bytes` counts keys and values inserted into the skiplist (and bounds of range tombstones), as
+/// overwritten values are kept until swept.
 pub struct MemDatabase {
     inner: AtomicPtr<SkipMap<Value>>,
+    range_tombstones: ShardedLock<Vec<RangeTombstone>>,
+    map_lock: ShardedLock<()>,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    bytes: AtomicU64,
+    statistics: Statistics,
 }
 
 impl MemDatabase {
+    pub fn new(merge_operator: Option<Arc<dyn MergeOperator>>) -> MemDatabase {
+        MemDatabase {
+            inner: AtomicPtr::new(Box::into_raw(box SkipMap::default())),
+            range_tombstones: ShardedLock::new(Vec::new()),
+            map_lock: ShardedLock::new(()),
+            merge_operator,
+            bytes: AtomicU64::new(0),
+            statistics: Statistics::default(),
+        }
+    }
+
+    pub fn with_merge_operator(merge_operator: Arc<dyn MergeOperator>) -> MemDatabase {
+        MemDatabase::new(Some(merge_operator))
+    }
+
+    pub fn merge_operator(&self) -> Option<&Arc<dyn MergeOperator>> {
+        self.merge_operator.as_ref()
+    }
+
     /// It can read from command iterator and run every command on MemDatabase. It is very useful for
     /// restoring data from log.
     pub fn restore_from_iterator<I: Iterator<Item = Command>>(
         iter: I,
+        merge_operator: Option<Arc<dyn MergeOperator>>,
     ) -> StorageResult<MemDatabase> {
-        let mem_db = MemDatabase::default();
+        let mem_db = MemDatabase::new(merge_operator);
         for command in iter {
-            match command {
-                Command::PUT(command) => {
-                    SyncDatabase::put_sync(&mem_db, command.key, command.value)?;
-                }
-                Command::DELETE(command) => {
-                    SyncDatabase::delete_sync(&mem_db, command.key)?;
-                }
-                _ => unreachable!(),
+            mem_db.apply(command)?;
+        }
+        Ok(mem_db)
+    }
+
+    /// Same as `restore_from_iterator`, but reads records of log, where a value in blob files is kept
+    /// as its index.
+    pub fn restore_from_records<I: Iterator<Item = LogRecord>>(
+        iter: I,
+        merge_operator: Option<Arc<dyn MergeOperator>>,
+    ) -> StorageResult<MemDatabase> {
+        let mem_db = MemDatabase::new(merge_operator);
+        for record in iter {
+            match record.blob_index() {
+                Some((key, index, expire_at)) => mem_db.put_blob(key, index, expire_at),
+                None => mem_db.apply(record.command)?,
             }
         }
         Ok(mem_db)
     }
 
+    /// Run a PUT, PUT_EXPIRE, DELETE, DELETE_RANGE or MERGE command.
+    pub fn apply(&self, command: Command) -> Result<()> {
+        match command {
+            Command::PUT(command) => self.put_sync(command.key, command.value),
+            Command::PUT_EXPIRE(command) => {
+                self.put_expire_sync(command.key, command.value, command.expire_at)
+            }
+            Command::DELETE(command) => self.delete_sync(command.key),
+            Command::DELETE_RANGE(command) => self.delete_range_sync(command.start, command.end),
+            Command::MERGE(command) => self.merge_sync(command.key, command.operand),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Put the index of a value in blob files. `expire_at` is `0` if it never expires.
+    pub fn put_blob(&self, key: Slice, index: BlobIndex, expire_at: u64) {
+        self.with_map(|map| self.insert(map, &key, &Value::Blob(index, expire_at)));
+        self.statistics.record_write(0);
+    }
+
     /// This function decide whether MemDatabase is too large. As every key and value is (256 + 8) bytes,
     /// nearly 1MB is the threshold.
     pub fn large_enough(&self) -> bool {
-        unsafe { (*self.inner.load(Ordering::SeqCst)).len() > 4 * 1024 }
+        let range_tombstones = self.range_tombstones.read().unwrap().len() as u64;
+        self.with_map(|map| map.len()) + range_tombstones > 4 * 1024
+    }
+
+    /// Sizes of this database, and counters of GET, SCAN and writes called on it directly.
+    pub fn stats(&self) -> DatabaseStats {
+        let mut stats = self.statistics.snapshot();
+        stats.memtable_bytes = self.bytes.load(Ordering::Relaxed);
+        stats.memtable_entries = self.with_map(|map| map.len());
+        stats
+    }
+
+    /// Estimated size and number of records in `[start, end)`, including overwritten and deleted ones.
+    /// Only keys are counted, and every record is taken as large as the average one.
+    pub fn approximate_range(&self, start: &Slice, end: &Slice) -> (u64, u64) {
+        let (count, entries) =
+            self.with_map(|map| (map.count(start.clone()..end.clone()), map.len()));
+        if count == 0 {
+            return (0, 0);
+        }
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        (bytes * count / entries, count)
+    }
+
+    fn with_map<R>(&self, f: impl FnOnce(&SkipMap<Value>) -> R) -> R {
+        let _guard = self.map_lock.read().unwrap();
+        unsafe { f(&*self.inner.load(Ordering::SeqCst)) }
+    }
+
+    fn insert(&self, map: &SkipMap<Value>, key: &Slice, value: &Value) {
+        map.insert(key, value);
+        self.bytes
+            .fetch_add((key.0.len() + value.len()) as u64, Ordering::Relaxed);
+    }
+
+    /// Find a key in this database only. `Some(Value::NotExist)` means the key is deleted here (by a
+    /// tombstone, a range tombstone or expiration), and `None` means older databases should be searched.
+    /// `Some(Value::Merge(_))` has to be applied on the value found in older databases.
+    pub fn lookup(&self, key: &Slice) -> Option<Value> {
+        self.with_map(|map| self.lookup_in(map, key))
+    }
+
+    fn lookup_in(&self, map: &SkipMap<Value>, key: &Slice) -> Option<Value> {
+        match map.find(key) {
+            Some(value) => Some(value.live(now_millis())),
+            None => {
+                let range_tombstones = self.range_tombstones.read().unwrap();
+                if range_tombstones.iter().any(|tombstone| tombstone.covers(key)) {
+                    Some(Value::NotExist)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Keys (including deleted ones) in `[start, end)` and range tombstones overlapping it.
+    pub fn source(&self, start: &Slice, end: &Slice) -> Source {
+        let entries = self.with_map(|map| map.scan(start.clone()..end.clone()));
+        let range_tombstones = self
+            .range_tombstones
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|tombstone| tombstone.overlaps(start, end))
+            .cloned()
+            .collect();
+
+        Source {
+            entries,
+            range_tombstones,
+        }
+    }
+
+    /// Every range tombstone in this database.
+    pub fn range_tombstones(&self) -> Vec<RangeTombstone> {
+        self.range_tombstones.read().unwrap().clone()
+    }
+
+    /// Rebuild the skiplist with only live keys, which frees expired keys, deleted keys and overwritten
+    /// values. Range tombstones are dropped too, as keys they cover are already deleted here. Returns
+    /// how many records are freed.
+    ///
+    /// It must not be called on a MemDatabase inside `Database`, whose tombstones hide keys in SSTables.
+    pub fn sweep(&self) -> u64 {
+        let now = now_millis();
+        let _guard = self.map_lock.write().unwrap();
+        let mut range_tombstones = self.range_tombstones.write().unwrap();
+
+        let old_map = unsafe { &*self.inner.load(Ordering::SeqCst) };
+        let entries: Vec<(Slice, Value)> = old_map
+            .scan(..)
+            .into_iter()
+            .map(|(key, value)| {
+                let value = self.resolve(&key, value.live(now));
+                (key, value)
+            })
+            .filter(|(_, value)| *value != Value::NotExist)
+            .collect();
+        let freed = old_map.len() - entries.len() as u64;
+        if freed == 0 && range_tombstones.is_empty() {
+            return 0;
+        }
+
+        let new_map = SkipMap::default();
+        for (key, value) in entries.iter() {
+            new_map.insert(key, value);
+        }
+        let bytes = entries
+            .iter()
+            .map(|(key, value)| key.0.len() + value.len())
+            .sum::<usize>();
+        self.bytes.store(bytes as u64, Ordering::Relaxed);
+        let old_map = self
+            .inner
+            .swap(Box::into_raw(box new_map), Ordering::SeqCst);
+        unsafe { drop(Box::from_raw(old_map)) }
+        range_tombstones.clear();
+
+        freed
+    }
+
+    /// Operands without base value are applied on nothing, as there is nothing older than a MemDatabase
+    /// used alone.
+    fn resolve(&self, key: &Slice, value: Value) -> Value {
+        match (value, &self.merge_operator) {
+            (Value::Merge(operands), Some(operator)) => {
+                fold(operator.as_ref(), key, Value::NotExist, &operands)
+            }
+            (value, _) => value,
+        }
+    }
+
+    /// Sweep `database` every `interval` in a background thread, until the database is dropped.
+    pub fn spawn_sweeper(database: &Arc<MemDatabase>, interval: Duration) -> JoinHandle<()> {
+        let database = Arc::downgrade(database);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            match database.upgrade() {
+                Some(database) => {
+                    let freed = database.sweep();
+                    if freed > 0 {
+                        log::debug!("Swept {} records from MemDatabase", freed);
+                    }
+                }
+                None => break,
+            }
+        })
     }
 }
 
 impl Default for MemDatabase {
     fn default() -> Self {
-        MemDatabase {
-            inner: AtomicPtr::new(Box::into_raw(box SkipMap::default())),
-        }
+        MemDatabase::new(None)
     }
 }
 
@@ -71,41 +345,121 @@ impl Drop for MemDatabase {
 
 impl SyncDatabase for MemDatabase {
     fn get_sync(&self, key: Slice) -> Result<Slice> {
-        unsafe {
-            match (*self.inner.load(Ordering::SeqCst)).find(&key) {
-                Some(value) => match value {
-                    Value::NotExist => Err(DatabaseError::KeyNotFound),
-                    Value::Slice(value) => Ok(value),
-                },
-                None => Err(DatabaseError::KeyNotFound),
-            }
+        let value = self.lookup(&key).map(|value| self.resolve(&key, value));
+        self.statistics.record_get(match &value {
+            Some(_) => ReadSource::MemTable,
+            None => ReadSource::Miss,
+        });
+        match value.and_then(Value::into_slice) {
+            Some(value) => Ok(value),
+            None => Err(DatabaseError::KeyNotFound),
         }
     }
 
     fn put_sync(&self, key: Slice, value: Slice) -> Result<()> {
-        unsafe {
-            (*self.inner.load(Ordering::SeqCst)).insert(&key, &Value::Slice(value));
-        }
+        self.with_map(|map| self.insert(map, &key, &Value::Slice(value)));
+        self.statistics.record_write(0);
         Ok(())
     }
 
-    fn scan_sync(&self, start: Slice, end: Slice) -> Vec<(Slice, Slice)> {
-        unsafe {
-            (*self.inner.load(Ordering::SeqCst))
-                .scan(start..end)
-                .into_iter()
-                .filter_map(|(key, value)| match value {
-                    Value::Slice(value) => Some((key, value)),
-                    Value::NotExist => None,
-                })
-                .collect()
-        }
+    fn put_expire_sync(&self, key: Slice, value: Slice, expire_at: u64) -> Result<()> {
+        self.with_map(|map| self.insert(map, &key, &Value::Expiring(value, expire_at)));
+        self.statistics.record_write(0);
+        Ok(())
+    }
+
+    fn scan_sync(&self, start: Slice, end: Slice) -> Result<Vec<(Slice, Slice)>> {
+        self.statistics.record_scan();
+        let now = now_millis();
+        Ok(self
+            .with_map(|map| map.scan(start..end))
+            .into_iter()
+            .filter_map(|(key, value)| {
+                let value = self.resolve(&key, value.live(now)).into_slice()?;
+                Some((key, value))
+            })
+            .collect())
     }
 
     fn delete_sync(&self, key: Slice) -> Result<()> {
-        unsafe {
-            (*self.inner.load(Ordering::SeqCst)).insert(&key, &Value::NotExist);
-        }
+        self.with_map(|map| self.insert(map, &key, &Value::NotExist));
+        self.statistics.record_write(0);
+        Ok(())
+    }
+
+    fn merge_sync(&self, key: Slice, operand: Slice) -> Result<()> {
+        let operator = match &self.merge_operator {
+            Some(operator) => operator,
+            None => return Err(DatabaseError::MergeOperatorNotSet),
+        };
+
+        let _guard = self.map_lock.write().unwrap();
+        let map = unsafe { &*self.inner.load(Ordering::SeqCst) };
+        let value = match self.lookup_in(map, &key) {
+            Some(Value::Merge(mut operands)) => {
+                operands.push(operand);
+                Value::Merge(operands)
+            }
+            Some(value) => fold(operator.as_ref(), &key, value, &[operand]),
+            None => Value::Merge(vec![operand]),
+        };
+        self.insert(map, &key, &value);
+        self.statistics.record_write(0);
         Ok(())
     }
+
+    /// Add a range tombstone, and then delete keys in this range which already exist here, so keys
+    /// found in this database are always newer than its range tombstones.
+    fn delete_range_sync(&self, start: Slice, end: Slice) -> Result<()> {
+        self.bytes
+            .fetch_add((start.0.len() + end.0.len()) as u64, Ordering::Relaxed);
+        self.range_tombstones
+            .write()
+            .unwrap()
+            .push(RangeTombstone::new(start.clone(), end.clone()));
+
+        self.with_map(|map| {
+            for (key, value) in map.scan(start..end) {
+                if value != Value::NotExist {
+                    self.insert(map, &key, &Value::NotExist);
+                }
+            }
+        });
+        self.statistics.record_write(0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sweep_expired() {
+        let db = MemDatabase::default();
+        let slice = |key: &[u8]| Slice(key.to_vec());
+        db.put_sync(slice(b"A"), slice(b"OLD")).unwrap();
+        db.put_sync(slice(b"A"), slice(b"NEW")).unwrap();
+        db.put_expire_sync(slice(b"B"), slice(b"VALUE"), 1).unwrap();
+        db.put_expire_sync(slice(b"C"), slice(b"VALUE"), std::u64::MAX)
+            .unwrap();
+        db.put_sync(slice(b"D"), slice(b"VALUE")).unwrap();
+        db.delete_range_sync(slice(b"D"), slice(b"E")).unwrap();
+
+        assert!(db.get_sync(slice(b"B")).is_err());
+        assert_eq!(db.scan_sync(slice(b"A"), slice(b"Z")).unwrap().len(), 2);
+
+        // The old A, expired B and deleted D (two versions) are freed.
+        assert_eq!(db.sweep(), 4);
+        assert_eq!(db.sweep(), 0);
+        assert!(db.range_tombstones().is_empty());
+        assert_eq!(db.get_sync(slice(b"A")).unwrap(), slice(b"NEW"));
+        assert!(db.get_sync(slice(b"C")).is_ok());
+        assert_eq!(db.scan_sync(slice(b"A"), slice(b"Z")).unwrap().len(), 2);
+
+        let stats = db.stats();
+        assert_eq!((stats.memtable_entries, stats.memtable_bytes), (2, (1 + 3) + (1 + 5)));
+        assert_eq!((stats.get_hits_memtable, stats.get_misses), (3, 0));
+        assert_eq!((stats.write_count, stats.scan_count), (6, 2));
+    }
 }
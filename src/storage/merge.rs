@@ -1,72 +1,126 @@
+use super::mem_database::{now_millis, Value};
+use super::range_tombstone::RangeTombstone;
+
 use agilulf_protocol::Slice;
-use std::cmp::Ordering;
 use std::iter::Peekable;
 
-pub struct MergeIter<T: Iterator<Item = (Slice, Slice)>> {
+/// Keys (including deleted ones) of a MemDatabase or an SSTable in a range, sorted by key, and range
+/// tombstones of it overlapping the range.
+pub struct Source {
+    pub entries: Vec<(Slice, Value)>,
+    pub range_tombstones: Vec<RangeTombstone>,
+}
+
+/// Merge sorted iterators. Iterators in front are newer, so if a key appears in several iterators, the
+/// one in the first iterator is returned (with the index of that iterator) and others are skipped.
+pub struct MergeIter<T: Iterator> {
     iters: Vec<Peekable<T>>,
 }
 
-impl<T: Iterator<Item = (Slice, Slice)>> Iterator for MergeIter<T> {
-    type Item = (Slice, Slice);
+impl<V, T: Iterator<Item = (Slice, V)>> Iterator for MergeIter<T> {
+    type Item = (usize, Slice, V);
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut tmp_ret: Option<(Slice, Slice)> = None;
-        let mut min_index = 0;
+        let mut min: Option<(usize, Slice)> = None;
         for (index, iter) in self.iters.iter_mut().enumerate() {
-            let item = iter.peek();
-            match item {
-                Some(item) => match &tmp_ret {
-                    None => {
-                        tmp_ret = Some((item.0.clone(), item.1.clone()));
-                        min_index = index;
-                    }
-                    Some(ret) => {
-                        let cmp = ret.cmp(item);
-                        match cmp {
-                            Ordering::Less => {}
-                            Ordering::Equal => {
-                                while let Some(content) = iter.peek() {
-                                    if content == ret {
-                                        iter.next();
-                                    } else {
-                                        break;
-                                    }
-                                }
-                                continue;
-                            }
-                            Ordering::Greater => {
-                                tmp_ret = Some((item.0.clone(), item.1.clone()));
-                                min_index = index;
-                            }
-                        }
-                    }
-                },
-                None => {}
+            if let Some((key, _)) = iter.peek() {
+                match &min {
+                    Some((_, min_key)) if min_key <= key => {}
+                    _ => min = Some((index, key.clone())),
+                }
             }
         }
 
-        while let Some(content) = self.iters[min_index].peek() {
-            match tmp_ret.as_ref() {
-                Some(ret) => {
-                    if ret == content {
-                        self.iters[min_index].next();
-                    }
-                }
-                None => {
+        let (index, key) = min?;
+        let (_, value) = self.iters[index].next()?;
+        for iter in self.iters.iter_mut() {
+            while let Some((next_key, _)) = iter.peek() {
+                if next_key == &key {
+                    iter.next();
+                } else {
                     break;
                 }
             }
         }
 
-        tmp_ret
+        Some((index, key, value))
     }
 }
 
 pub fn merge_iter<T>(iters: Vec<T>) -> MergeIter<T>
 where
-    T: Iterator<Item = (Slice, Slice)>,
+    T: Iterator,
 {
     MergeIter {
         iters: iters.into_iter().map(|item| item.peekable()).collect(),
     }
 }
+
+/// Merge sources (newer ones in front) into sorted keys. A key is taken from the newest source which
+/// contains it, and is dropped if a range tombstone in a newer source covers it.
+///
+/// Deleted and expired keys are kept as `Value::NotExist` only if `keep_deleted` is true, which is
+/// needed when the result will be put above older data (e.g. compaction into a level which is not the
+/// last one).
+pub fn merge_sources(sources: Vec<Source>, keep_deleted: bool) -> Vec<(Slice, Value)> {
+    let now = now_millis();
+    let mut range_tombstones = Vec::new();
+    let mut iters = Vec::new();
+    for source in sources {
+        range_tombstones.push(source.range_tombstones);
+        iters.push(source.entries.into_iter());
+    }
+
+    merge_iter(iters)
+        .map(|(index, key, value)| (index, key, value.live(now)))
+        .filter(|(index, key, value)| {
+            if !keep_deleted && *value == Value::NotExist {
+                return false;
+            }
+            !range_tombstones[0..*index]
+                .iter()
+                .any(|tombstones| tombstones.iter().any(|tombstone| tombstone.covers(key)))
+        })
+        .map(|(_, key, value)| (key, value))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slice(key: &[u8]) -> Slice {
+        Slice(key.to_vec())
+    }
+
+    #[test]
+    fn newer_source_wins() {
+        let newer = Source {
+            entries: vec![
+                (slice(b"A"), Value::Slice(slice(b"NEW"))),
+                (slice(b"B"), Value::NotExist),
+                (slice(b"F"), Value::Expiring(slice(b"NEW"), 1)),
+            ],
+            range_tombstones: vec![RangeTombstone::new(slice(b"C"), slice(b"E"))],
+        };
+        let older = Source {
+            entries: vec![
+                (slice(b"A"), Value::Slice(slice(b"OLD"))),
+                (slice(b"B"), Value::Slice(slice(b"OLD"))),
+                (slice(b"D"), Value::Slice(slice(b"OLD"))),
+                (slice(b"E"), Value::Slice(slice(b"OLD"))),
+                (slice(b"F"), Value::Slice(slice(b"OLD"))),
+            ],
+            range_tombstones: vec![RangeTombstone::new(slice(b"A"), slice(b"Z"))],
+        };
+
+        let merged = merge_sources(vec![newer, older], false);
+        assert_eq!(
+            merged,
+            vec![
+                (slice(b"A"), Value::Slice(slice(b"NEW"))),
+                (slice(b"E"), Value::Slice(slice(b"OLD"))),
+            ]
+        );
+    }
+}
@@ -0,0 +1,124 @@
+use super::mem_database::Value;
+use super::sstable::VALUE_LENGTH;
+
+use agilulf_protocol::Slice;
+
+/// Combine the value of a key with operands written by `merge`, so counters and lists can be updated
+/// without reading them first.
+///
+/// Operands are applied one by one from the oldest. `existing` is `None` if the key doesn't exist or
+/// is deleted. Values and operands read from log or SSTables are padded with zero to 256 bytes, so
+/// operators should not depend on their length.
+pub trait MergeOperator: Send + Sync {
+    fn merge(&self, key: &Slice, existing: Option<&Slice>, operand: &Slice) -> Slice;
+}
+
+/// Read the first 8 bytes as a little endian u64. Shorter slices are padded with zero.
+fn to_u64(slice: &Slice) -> u64 {
+    let mut bytes = [0u8; 8];
+    let length = std::cmp::min(slice.0.len(), 8);
+    bytes[0..length].clone_from_slice(&slice.0[0..length]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Drop zero bytes padded to the end of a value read from log or tables. Trailing zero bytes of the
+/// value itself are dropped too, as they cannot be told apart from padding.
+pub fn trim_zero(slice: &[u8]) -> &[u8] {
+    match slice.iter().rposition(|byte| *byte != 0) {
+        Some(index) => &slice[0..=index],
+        None => &[],
+    }
+}
+
+/// Values and operands are little endian u64. Operands are added to the value (wrapping on overflow).
+pub struct U64AddOperator;
+
+impl MergeOperator for U64AddOperator {
+    fn merge(&self, _: &Slice, existing: Option<&Slice>, operand: &Slice) -> Slice {
+        let sum = existing.map_or(0, to_u64).wrapping_add(to_u64(operand));
+        Slice(sum.to_le_bytes().to_vec())
+    }
+}
+
+/// Values and operands are little endian u64. The value is the largest one of them.
+pub struct MaxOperator;
+
+impl MergeOperator for MaxOperator {
+    fn merge(&self, _: &Slice, existing: Option<&Slice>, operand: &Slice) -> Slice {
+        let max = std::cmp::max(existing.map_or(0, to_u64), to_u64(operand));
+        Slice(max.to_le_bytes().to_vec())
+    }
+}
+
+/// Operands are appended to the value. As padding is trimmed, trailing zero of operands is lost, and
+/// anything after the 256th byte is dropped.
+pub struct AppendOperator;
+
+impl MergeOperator for AppendOperator {
+    fn merge(&self, _: &Slice, existing: Option<&Slice>, operand: &Slice) -> Slice {
+        let mut value = existing.map_or(Vec::new(), |value| trim_zero(&value.0).to_vec());
+        value.extend_from_slice(trim_zero(&operand.0));
+        value.truncate(VALUE_LENGTH);
+        Slice(value)
+    }
+}
+
+/// Apply operands (the oldest first) on `base`, which is the value found under them. The expiration
+/// time of base is kept.
+///
+/// A value in blob files should be read before. Operands above such a value are applied when the key is
+/// read, or when they are flushed into a table.
+pub fn fold(operator: &dyn MergeOperator, key: &Slice, base: Value, operands: &[Slice]) -> Value {
+    let (mut value, expire_at) = match base {
+        Value::Slice(value) => (Some(value), None),
+        Value::Expiring(value, expire_at) => (Some(value), Some(expire_at)),
+        Value::NotExist | Value::Merge(_) | Value::Blob(..) => (None, None),
+    };
+    for operand in operands {
+        value = Some(operator.merge(key, value.as_ref(), operand));
+    }
+
+    match (value, expire_at) {
+        (Some(value), Some(expire_at)) => Value::Expiring(value, expire_at),
+        (Some(value), None) => Value::Slice(value),
+        (None, _) => Value::NotExist,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slice(value: &[u8]) -> Slice {
+        Slice(value.to_vec())
+    }
+
+    #[test]
+    fn builtin_operators() {
+        let key = slice(b"KEY");
+        let mut padded = 1u64.to_le_bytes().to_vec();
+        padded.resize(VALUE_LENGTH, 0);
+        let operands = vec![Slice(padded), slice(&41u64.to_le_bytes())];
+
+        let sum = fold(&U64AddOperator, &key, Value::NotExist, &operands);
+        assert_eq!(sum, Value::Slice(slice(&42u64.to_le_bytes())));
+        let sum = fold(&U64AddOperator, &key, sum, &operands);
+        assert_eq!(sum, Value::Slice(slice(&84u64.to_le_bytes())));
+
+        let max = fold(
+            &MaxOperator,
+            &key,
+            Value::Expiring(slice(&7u64.to_le_bytes()), 9),
+            &operands,
+        );
+        assert_eq!(max, Value::Expiring(slice(&41u64.to_le_bytes()), 9));
+
+        let list = fold(
+            &AppendOperator,
+            &key,
+            Value::Slice(slice(b"A\0\0")),
+            &[slice(b"B\0"), slice(b"C")],
+        );
+        assert_eq!(list, Value::Slice(slice(b"ABC")));
+    }
+}
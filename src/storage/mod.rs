@@ -1,34 +1,90 @@
+mod background;
+mod blob;
+mod block_cache;
+mod change_stream;
+mod checkpoint;
+mod column_family;
+mod compaction;
+mod compaction_filter;
+mod compaction_strategy;
+mod compression;
 pub mod database;
 mod database_log;
+mod encryption;
 pub mod error;
+mod file_lock;
+mod ingest;
+pub mod inspect;
 mod manifest_manager;
 pub mod mem_database;
 mod merge;
+mod merge_operator;
+mod range_tombstone;
+mod rate_limiter;
+mod repair;
 mod sstable;
+mod statistics;
+mod table_cache;
+mod version;
+mod wal_archive;
+mod write_batch;
 
-use agilulf_protocol::Slice;
+use agilulf_protocol::{DatabaseError, Slice};
 
 use agilulf_protocol::DatabaseResult as Result;
 use futures::Future;
 use std::pin::Pin;
+use std::sync::Arc;
 
+pub use blob::BlobGcReport;
+pub use change_stream::{Change, ChangeStream};
+pub use checkpoint::CheckpointReport;
+pub use column_family::{ColumnFamily, ColumnFamilyOptions};
+pub use compaction::CompactionReport;
+pub use compaction_filter::{CompactionFilter, FilterDecision};
+pub use compaction_strategy::{CompactionStrategy, LeveledCompaction, UniversalCompaction};
+pub use compression::Compression;
 pub use database::{Database, DatabaseBuilder};
+pub use encryption::{ChaCha20Poly1305Encryptor, Encryptor};
+pub use ingest::SSTableWriter;
+pub use merge_operator::{AppendOperator, MaxOperator, MergeOperator, U64AddOperator};
+pub use rate_limiter::RateLimiter;
+pub use repair::RepairReport;
+pub use statistics::DatabaseStats;
+pub use wal_archive::RestorePoint;
+pub use write_batch::WriteBatch;
 
 /// Abstraction layer for a SyncDatabase. Every method should return directly.
+///
+/// Operations added after GET, PUT, SCAN and DELETE have default implementations returning
+/// `DatabaseError::NotSupported`, so databases written before them still build.
 pub trait SyncDatabase: Send + Sync {
     fn get_sync(&self, key: Slice) -> Result<Slice>;
 
     fn put_sync(&self, key: Slice, value: Slice) -> Result<()>;
 
-    fn scan_sync(&self, start: Slice, end: Slice) -> Vec<(Slice, Slice)>;
+    fn put_expire_sync(&self, _key: Slice, _value: Slice, _expire_at: u64) -> Result<()> {
+        Err(DatabaseError::NotSupported)
+    }
+
+    fn scan_sync(&self, start: Slice, end: Slice) -> Result<Vec<(Slice, Slice)>>;
 
     fn delete_sync(&self, key: Slice) -> Result<()>;
+
+    fn delete_range_sync(&self, _start: Slice, _end: Slice) -> Result<()> {
+        Err(DatabaseError::NotSupported)
+    }
+
+    fn merge_sync(&self, _key: Slice, _operand: Slice) -> Result<()> {
+        Err(DatabaseError::NotSupported)
+    }
 }
 
 /// Abstraction layer for a AsyncDatabase. Every method return a Future.
 ///
 /// The return type of these function are fixed as `Pin<Box<dyn Future<Output = Result<_>> + Send + '_>>`
-/// rather than a generic type for convenience.
+/// rather than a generic type for convenience. As in `SyncDatabase`, operations added after GET, PUT,
+/// SCAN and DELETE return `DatabaseError::NotSupported` by default.
 pub trait AsyncDatabase: Send + Sync {
     fn get(&self, key: Slice) -> Pin<Box<dyn Future<Output = Result<Slice>> + Send + '_>>;
 
@@ -38,14 +94,169 @@ pub trait AsyncDatabase: Send + Sync {
         value: Slice,
     ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>;
 
-    /// SCAN don't return a `Result` because if nothing is found, an empty vector will be returned.
+    /// PUT a key which expires at `expire_at` (in milliseconds since UNIX epoch). After that, it can't
+    /// be read any more.
+    fn put_expire(
+        &self,
+        _key: Slice,
+        _value: Slice,
+        _expire_at: u64,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(async { Err(DatabaseError::NotSupported) })
+    }
+
+    /// An empty vector is returned if nothing is found. An error means some keys in range could not be
+    /// read, so a partial result is never returned.
     fn scan(
         &self,
         start: Slice,
         end: Slice,
-    ) -> Pin<Box<dyn Future<Output = Vec<(Slice, Slice)>> + Send + '_>>;
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<(Slice, Slice)>>> + Send + '_>>;
 
     fn delete(&self, key: Slice) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>;
+
+    /// Delete every key in `[start, end)`.
+    fn delete_range(
+        &self,
+        _start: Slice,
+        _end: Slice,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(async { Err(DatabaseError::NotSupported) })
+    }
+
+    /// Apply `operand` on the value of `key` with the merge operator, without reading it first.
+    fn merge(
+        &self,
+        _key: Slice,
+        _operand: Slice,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(async { Err(DatabaseError::NotSupported) })
+    }
+
+    /// Estimated size and number of keys in `[start, end)`, which is served as `APPROXIMATE_SIZE`.
+    /// Databases which can't estimate it count keys and values returned by `scan`. Like other commands,
+    /// `APPROXIMATE_SIZE` has no column family, so it's served from the default one. Other families are
+    /// estimated by `approximate_cf`.
+    fn approximate(
+        &self,
+        start: Slice,
+        end: Slice,
+    ) -> Pin<Box<dyn Future<Output = Result<(u64, u64)>> + Send + '_>> {
+        Box::pin(async move {
+            let pairs = self.scan(start, end).await?;
+            let bytes = pairs
+                .iter()
+                .map(|(key, value)| (key.0.len() + value.0.len()) as u64)
+                .sum();
+            Ok((bytes, pairs.len() as u64))
+        })
+    }
+
+    /// Change the limit of background writes to `bytes_per_second` (`0` for no limit), which is served
+    /// as `SET_RATE_LIMIT`. Databases without background writes don't support it.
+    fn set_rate_limit(
+        &self,
+        _bytes_per_second: u64,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(async { Err(DatabaseError::NotSupported) })
+    }
+
+    /// Methods with `_cf` suffix work on a column family, or the default one if `family` is `None`.
+    /// Databases without column families only have the default one, and other families are not found.
+    fn get_cf<'a>(
+        &'a self,
+        family: Option<&'a ColumnFamily>,
+        key: Slice,
+    ) -> Pin<Box<dyn Future<Output = Result<Slice>> + Send + 'a>> {
+        match family {
+            None => self.get(key),
+            Some(_) => Box::pin(async { Err(DatabaseError::ColumnFamilyNotFound) }),
+        }
+    }
+
+    fn put_cf<'a>(
+        &'a self,
+        family: Option<&'a ColumnFamily>,
+        key: Slice,
+        value: Slice,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>> {
+        match family {
+            None => self.put(key, value),
+            Some(_) => Box::pin(async { Err(DatabaseError::ColumnFamilyNotFound) }),
+        }
+    }
+
+    fn put_expire_cf<'a>(
+        &'a self,
+        family: Option<&'a ColumnFamily>,
+        key: Slice,
+        value: Slice,
+        expire_at: u64,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>> {
+        match family {
+            None => self.put_expire(key, value, expire_at),
+            Some(_) => Box::pin(async { Err(DatabaseError::ColumnFamilyNotFound) }),
+        }
+    }
+
+    fn scan_cf<'a>(
+        &'a self,
+        family: Option<&'a ColumnFamily>,
+        start: Slice,
+        end: Slice,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<(Slice, Slice)>>> + Send + 'a>> {
+        match family {
+            None => self.scan(start, end),
+            Some(_) => Box::pin(async { Err(DatabaseError::ColumnFamilyNotFound) }),
+        }
+    }
+
+    fn delete_cf<'a>(
+        &'a self,
+        family: Option<&'a ColumnFamily>,
+        key: Slice,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>> {
+        match family {
+            None => self.delete(key),
+            Some(_) => Box::pin(async { Err(DatabaseError::ColumnFamilyNotFound) }),
+        }
+    }
+
+    fn delete_range_cf<'a>(
+        &'a self,
+        family: Option<&'a ColumnFamily>,
+        start: Slice,
+        end: Slice,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>> {
+        match family {
+            None => self.delete_range(start, end),
+            Some(_) => Box::pin(async { Err(DatabaseError::ColumnFamilyNotFound) }),
+        }
+    }
+
+    fn merge_cf<'a>(
+        &'a self,
+        family: Option<&'a ColumnFamily>,
+        key: Slice,
+        operand: Slice,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>> {
+        match family {
+            None => self.merge(key, operand),
+            Some(_) => Box::pin(async { Err(DatabaseError::ColumnFamilyNotFound) }),
+        }
+    }
+
+    fn approximate_cf<'a>(
+        &'a self,
+        family: Option<&'a ColumnFamily>,
+        start: Slice,
+        end: Slice,
+    ) -> Pin<Box<dyn Future<Output = Result<(u64, u64)>> + Send + 'a>> {
+        match family {
+            None => self.approximate(start, end),
+            Some(_) => Box::pin(async { Err(DatabaseError::ColumnFamilyNotFound) }),
+        }
+    }
 }
 
 /// Every sync database can be wrapped as an async database easily. With this wrapper, MemDatabase can
@@ -63,15 +274,119 @@ impl<T: SyncDatabase> AsyncDatabase for T {
         Box::pin(async move { self.put_sync(key, value) })
     }
 
+    fn put_expire(
+        &self,
+        key: Slice,
+        value: Slice,
+        expire_at: u64,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(async move { self.put_expire_sync(key, value, expire_at) })
+    }
+
     fn scan(
         &self,
         start: Slice,
         end: Slice,
-    ) -> Pin<Box<dyn Future<Output = Vec<(Slice, Slice)>> + Send + '_>> {
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<(Slice, Slice)>>> + Send + '_>> {
         Box::pin(async move { self.scan_sync(start, end) })
     }
 
     fn delete(&self, key: Slice) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
         Box::pin(async move { self.delete_sync(key) })
     }
+
+    fn delete_range(
+        &self,
+        start: Slice,
+        end: Slice,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(async move { self.delete_range_sync(start, end) })
+    }
+
+    fn merge(
+        &self,
+        key: Slice,
+        operand: Slice,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(async move { self.merge_sync(key, operand) })
+    }
+}
+
+/// A shared SyncDatabase is still a SyncDatabase, so a MemDatabase can be served while another thread
+/// (e.g. the sweeper) holds it.
+impl<T: SyncDatabase> SyncDatabase for Arc<T> {
+    fn get_sync(&self, key: Slice) -> Result<Slice> {
+        (**self).get_sync(key)
+    }
+
+    fn put_sync(&self, key: Slice, value: Slice) -> Result<()> {
+        (**self).put_sync(key, value)
+    }
+
+    fn put_expire_sync(&self, key: Slice, value: Slice, expire_at: u64) -> Result<()> {
+        (**self).put_expire_sync(key, value, expire_at)
+    }
+
+    fn scan_sync(&self, start: Slice, end: Slice) -> Result<Vec<(Slice, Slice)>> {
+        (**self).scan_sync(start, end)
+    }
+
+    fn delete_sync(&self, key: Slice) -> Result<()> {
+        (**self).delete_sync(key)
+    }
+
+    fn delete_range_sync(&self, start: Slice, end: Slice) -> Result<()> {
+        (**self).delete_range_sync(start, end)
+    }
+
+    fn merge_sync(&self, key: Slice, operand: Slice) -> Result<()> {
+        (**self).merge_sync(key, operand)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A database written before PUT_EXPIRE, DELETE_RANGE and MERGE were added.
+    struct OldDatabase;
+
+    impl SyncDatabase for OldDatabase {
+        fn get_sync(&self, _key: Slice) -> Result<Slice> {
+            Err(DatabaseError::KeyNotFound)
+        }
+
+        fn put_sync(&self, _key: Slice, _value: Slice) -> Result<()> {
+            Ok(())
+        }
+
+        fn scan_sync(&self, _start: Slice, _end: Slice) -> Result<Vec<(Slice, Slice)>> {
+            Ok(Vec::new())
+        }
+
+        fn delete_sync(&self, _key: Slice) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn default_operations() {
+        let database = OldDatabase;
+        let key = || Slice(b"KEY".to_vec());
+        futures::executor::block_on(async {
+            database.put(key(), key()).await.unwrap();
+            for result in [
+                database.put_expire(key(), key(), 1).await,
+                database.delete_range(key(), key()).await,
+                database.merge(key(), key()).await,
+            ]
+            .iter()
+            {
+                match result {
+                    Err(DatabaseError::NotSupported) => {}
+                    _ => panic!("New operations should not be supported by default"),
+                }
+            }
+        });
+    }
 }
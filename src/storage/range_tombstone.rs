@@ -0,0 +1,28 @@
+use agilulf_protocol::Slice;
+
+/// Deletion of every key in `[start, end)`, written by `delete_range`.
+///
+/// A range tombstone in a MemDatabase or an SSTable hides keys in older MemDatabases and SSTables. It
+/// never hides keys in the same one: older keys in the same MemDatabase are deleted one by one when the
+/// tombstone is added, and compaction drops keys covered by a newer tombstone. So a key found in the
+/// same place as a tombstone is always newer than it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeTombstone {
+    pub start: Slice,
+    pub end: Slice,
+}
+
+impl RangeTombstone {
+    pub fn new(start: Slice, end: Slice) -> RangeTombstone {
+        RangeTombstone { start, end }
+    }
+
+    pub fn covers(&self, key: &Slice) -> bool {
+        &self.start <= key && key < &self.end
+    }
+
+    /// Whether this tombstone deletes any key in `[start, end)`.
+    pub fn overlaps(&self, start: &Slice, end: &Slice) -> bool {
+        &self.start < end && start < &self.end && self.start < self.end
+    }
+}
@@ -0,0 +1,169 @@
+use futures_timer::Delay;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The longest sleep of a request before the bucket is checked again, so a new limit set by
+/// `set_bytes_per_second` is used soon by requests already waiting.
+const MAX_WAIT: Duration = Duration::from_millis(100);
+
+/// Burst of a limiter created by `unlimited`, which is kept if a limit is set later.
+const DEFAULT_BURST_BYTES: u64 = 4 * 1024 * 1024;
+
+struct Bucket {
+    bytes_per_second: u64,
+    burst_bytes: u64,
+    available: u64,
+    refilled_at: Instant,
+}
+
+impl Bucket {
+    /// Add tokens for the time since the last refill. Time of fractional tokens is not counted, so it
+    /// is added by the next refill.
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.refilled_at).as_nanos();
+        let tokens = elapsed * self.bytes_per_second as u128 / 1_000_000_000;
+        if tokens == 0 {
+            return;
+        }
+        if self.available as u128 + tokens >= self.burst_bytes as u128 {
+            self.available = self.burst_bytes;
+            self.refilled_at = now;
+        } else {
+            self.available += tokens as u64;
+            let nanos = tokens * 1_000_000_000 / self.bytes_per_second as u128;
+            self.refilled_at += Duration::from_nanos(nanos as u64);
+        }
+    }
+}
+
+/// A token bucket shared by flushes and compactions to limit how fast they write tables, so they
+/// don't take all bandwidth of a disk shared with foreground reads.
+///
+/// Tokens are added at `bytes_per_second`, and at most `burst_bytes` of them are kept, so writes
+/// after an idle period can go faster for a while. A limit of `0` means writes are not limited. The
+/// limit can be changed at any time, and it's used by writes already waiting.
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+    requested: AtomicU64,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64, burst_bytes: u64) -> RateLimiter {
+        RateLimiter {
+            bucket: Mutex::new(Bucket {
+                bytes_per_second,
+                burst_bytes: std::cmp::max(burst_bytes, 1),
+                available: std::cmp::max(burst_bytes, 1),
+                refilled_at: Instant::now(),
+            }),
+            requested: AtomicU64::new(0),
+        }
+    }
+
+    /// A limiter which never blocks until a limit is set, with a burst of 4 MB.
+    pub fn unlimited() -> RateLimiter {
+        RateLimiter::new(0, DEFAULT_BURST_BYTES)
+    }
+
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bucket.lock().unwrap().bytes_per_second
+    }
+
+    pub fn set_bytes_per_second(&self, bytes_per_second: u64) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(Instant::now());
+        bucket.bytes_per_second = bytes_per_second;
+    }
+
+    pub fn burst_bytes(&self) -> u64 {
+        self.bucket.lock().unwrap().burst_bytes
+    }
+
+    pub fn set_burst_bytes(&self, burst_bytes: u64) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.burst_bytes = std::cmp::max(burst_bytes, 1);
+        bucket.available = std::cmp::min(bucket.available, bucket.burst_bytes);
+    }
+
+    /// Bytes requested since the limiter is created, whether they waited or not.
+    pub fn requested_bytes(&self) -> u64 {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Wait until `bytes` can be written. Requests larger than `burst_bytes` take tokens in several
+    /// parts. The task waits on a timer, so the thread running it is not blocked.
+    pub async fn request(&self, mut bytes: u64) {
+        self.requested.fetch_add(bytes, Ordering::SeqCst);
+        while bytes > 0 {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                if bucket.bytes_per_second == 0 {
+                    return;
+                }
+                let now = Instant::now();
+                bucket.refill(now);
+
+                let wanted = std::cmp::min(bytes, bucket.burst_bytes);
+                if bucket.available >= wanted {
+                    bucket.available -= wanted;
+                    bytes -= wanted;
+                    continue;
+                }
+                let missing = (wanted - bucket.available) as u128;
+                let nanos = missing * 1_000_000_000 / bucket.bytes_per_second as u128;
+                Duration::from_nanos(nanos as u64 + 1)
+            };
+            Delay::new(std::cmp::min(wait, MAX_WAIT)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use std::sync::Arc;
+
+    #[test]
+    fn limit_writes() {
+        let limiter = RateLimiter::new(100_000, 10_000);
+
+        // The bucket is full at first, so a burst doesn't wait.
+        let started = Instant::now();
+        block_on(limiter.request(10_000));
+        assert!(started.elapsed() < Duration::from_millis(50));
+
+        // Later requests wait for tokens.
+        let started = Instant::now();
+        for _ in 0..4 {
+            block_on(limiter.request(5_000));
+        }
+        assert!(started.elapsed() >= Duration::from_millis(180));
+
+        // Without a limit, nothing waits.
+        limiter.set_bytes_per_second(0);
+        let started = Instant::now();
+        block_on(limiter.request(1 << 30));
+        assert!(started.elapsed() < Duration::from_millis(50));
+        assert_eq!(limiter.requested_bytes(), 30_000 + (1 << 30));
+    }
+
+    #[test]
+    fn raise_limit_while_waiting() {
+        let limiter = Arc::new(RateLimiter::new(1_000, 1_000));
+        block_on(limiter.request(1_000));
+
+        // It takes 10 seconds with the old limit.
+        let started = Instant::now();
+        let waiting = {
+            let limiter = limiter.clone();
+            std::thread::spawn(move || block_on(limiter.request(10_000)))
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        limiter.set_bytes_per_second(1_000_000);
+        waiting.join().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(limiter.bytes_per_second(), 1_000_000);
+    }
+}
@@ -0,0 +1,396 @@
+use super::block_cache::BlockCache;
+use super::column_family::{family_dir, read_families};
+use super::database::{frozen_log_ids, Database};
+use super::database_log::DatabaseLogReader;
+use super::encryption::{check_key, Encryptor};
+use super::error::StorageResult;
+use super::file_lock::FileLock;
+use super::manifest_manager::VersionSet;
+use super::sstable::{verify_checksum, SSTable, SSTableError};
+use super::table_cache::{parse_table_name, table_path, TableCache};
+use super::version::{VersionEdit, NUM_LEVELS};
+
+use agilulf_protocol::Slice;
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// What `Database::repair` has done.
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// Tables recorded in the new MANIFEST of the default column family, as `(level, id)`.
+    pub tables: Vec<(usize, usize)>,
+    /// Tables recorded in new MANIFESTs of other column families, as `(family, level, id)`.
+    pub family_tables: Vec<(u32, usize, usize)>,
+    /// Frozen logs which will be replayed by next open.
+    pub logs: Vec<usize>,
+    /// Files which cannot be read. They are moved into `lost` under base directory.
+    pub lost: Vec<PathBuf>,
+}
+
+fn check_table(
+    path: &Path,
+    encryptor: Option<&Arc<dyn Encryptor>>,
+) -> StorageResult<(Slice, Slice)> {
+    let file = std::fs::File::open(path)?;
+    verify_checksum(&file)?;
+    let table = SSTable::open(file, encryptor, None)?;
+    table.verify()?;
+
+    match table.key_range() {
+        Some(range) => Ok(range),
+        None => Err(SSTableError::Corrupted.into()),
+    }
+}
+
+fn check_log(path: &Path, encryptor: Option<&Arc<dyn Encryptor>>) -> bool {
+    let path = match path.to_str() {
+        Some(str) => str,
+        None => return false,
+    };
+
+    match DatabaseLogReader::open(path, encryptor) {
+        Ok(log) => log.is_valid(),
+        Err(err) => {
+            log::warn!("Cannot open log {}: {}", path, err);
+            false
+        }
+    }
+}
+
+/// Names of files in a directory, sorted.
+fn file_names(path: &Path) -> StorageResult<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        if let Some(name) = entry.file_name().to_str() {
+            names.push(name.to_string());
+        }
+    }
+    names.sort();
+
+    Ok(names)
+}
+
+fn move_to_lost(path: &Path, lost_path: &Path, lost: &mut Vec<PathBuf>) -> StorageResult<()> {
+    log::warn!("Moving {:#?} into {:#?}", path, lost_path);
+    std::fs::create_dir_all(lost_path)?;
+    let new_path = lost_path.join(path.file_name().unwrap_or_default());
+    std::fs::rename(path, &new_path)?;
+    lost.push(new_path);
+
+    Ok(())
+}
+
+/// Rebuild MANIFEST of a column family from tables in `dir`, and return tables recorded in it as
+/// `(level, id)`. Corrupted tables and the old MANIFEST are moved into `lost_path`.
+fn repair_family(
+    dir: &str,
+    lost_path: &Path,
+    encryptor: Option<&Arc<dyn Encryptor>>,
+    lost: &mut Vec<PathBuf>,
+) -> StorageResult<Vec<(usize, usize)>> {
+    let path = Path::new(dir);
+    let mut tables = Vec::new();
+    for name in file_names(path)? {
+        let valid = if name == "MANIFEST" || name == "MANIFEST.tmp" {
+            false
+        } else if let Some((level, id)) = parse_table_name(&name) {
+            match check_table(&path.join(&name), encryptor) {
+                Ok((smallest, largest)) if level < NUM_LEVELS => {
+                    tables.push((level, id, smallest, largest));
+                    true
+                }
+                Ok(_) => false,
+                Err(err) => {
+                    log::warn!("SSTable {:#?} is corrupted: {}", path.join(&name), err);
+                    false
+                }
+            }
+        } else {
+            continue;
+        };
+
+        if !valid {
+            move_to_lost(&path.join(&name), lost_path, lost)?;
+        }
+    }
+
+    // From the oldest table to the newest one. Tables already in level 0 are kept if there is no
+    // other table, otherwise every table takes a new id after them.
+    tables.sort_by_key(|(level, id, _, _)| (std::cmp::Reverse(*level), *id));
+    let mut edit = VersionEdit::default();
+    if tables.iter().any(|(level, _, _, _)| *level > 0) {
+        let first_id = tables
+            .iter()
+            .filter(|(level, _, _, _)| *level == 0)
+            .map(|(_, id, _, _)| id + 1)
+            .max()
+            .unwrap_or(0);
+        for (index, (level, id, _, _)) in tables.iter_mut().enumerate().rev() {
+            let new_id = first_id + index;
+            let old_path = table_path(dir, *level, *id);
+            log::info!("Moving {:#?} into level 0 as {}", old_path, new_id);
+            std::fs::rename(&old_path, table_path(dir, 0, new_id))?;
+            *level = 0;
+            *id = new_id;
+        }
+        std::fs::File::open(path)?.sync_all()?;
+    }
+    let mut recorded = Vec::new();
+    for (level, id, smallest, largest) in tables {
+        edit.add_table(level, id, 0, smallest, largest);
+        recorded.push((level, id));
+    }
+
+    // Every remaining frozen log will be replayed, as the log number in new MANIFEST is zero.
+    let table_cache = Arc::new(TableCache::new(
+        dir,
+        Vec::new(),
+        1,
+        Arc::new(BlockCache::new(0)),
+        encryptor.cloned(),
+    ));
+    let version_set = VersionSet::create_new(dir, table_cache)?;
+    version_set.log_and_apply(&edit)?;
+
+    Ok(recorded)
+}
+
+impl Database {
+    /// Rebuild MANIFEST from files in `base_dir`, so a database whose MANIFEST is lost or corrupted can
+    /// be opened again. MANIFEST of every column family registered in `FAMILIES` is rebuilt from its
+    /// `family_<id>` directory in the same way.
+    ///
+    /// Every SSTable is validated (with its checksum) and its key range is read from the file. Tables
+    /// of a level may overlap (e.g. inputs and outputs of an interrupted compaction), so every table
+    /// is put into level 0, and compacted down again later. Their order is recovered from levels and ids: deeper
+    /// tables are older, and ids grow in the order tables of a level are written. Tables are renamed
+    /// from the newest one, so an interrupted repair leaves them in the same order and can run again.
+    ///
+    /// Frozen logs are kept and will be replayed by next open. Files which cannot be read (and the old
+    /// MANIFEST) are moved into `lost` directory instead of being removed, and files of a column
+    /// family into `family_<id>` under it.
+    ///
+    /// The database must not be opened while repairing. An encrypted database must be repaired with
+    /// its key, which is checked before anything is moved. Only files in `base_dir` are found, so it
+    /// should not be used on a database with another `wal_dir` or data paths, whose files would be lost.
+    pub fn repair(
+        base_dir: &str,
+        encryptor: Option<Arc<dyn Encryptor>>,
+    ) -> StorageResult<RepairReport> {
+        let _file_lock = FileLock::lock(base_dir)?;
+        check_key(base_dir, encryptor.as_ref(), true)?;
+
+        let base_path = Path::new(base_dir);
+        let lost_path = base_path.join("lost");
+
+        let mut report = RepairReport::default();
+        let frozen_logs = frozen_log_ids(base_path)?;
+        for name in file_names(base_path)? {
+            let path = base_path.join(&name);
+
+            let valid = if name == "log" {
+                check_log(&path, encryptor.as_ref())
+            } else if name.starts_with("log.") {
+                let id = frozen_logs
+                    .iter()
+                    .find(|id| format!("log.{}", id) == name)
+                    .cloned();
+                match id {
+                    Some(id) if check_log(&path, encryptor.as_ref()) => {
+                        report.logs.push(id);
+                        true
+                    }
+                    _ => false,
+                }
+            } else {
+                continue;
+            };
+
+            if !valid {
+                move_to_lost(&path, &lost_path, &mut report.lost)?;
+            }
+        }
+        report.logs.sort();
+
+        report.tables = repair_family(base_dir, &lost_path, encryptor.as_ref(), &mut report.lost)?;
+        for (id, _) in read_families(base_dir)? {
+            let dir = family_dir(base_dir, id);
+            std::fs::create_dir_all(&dir)?;
+            let family_lost_path = lost_path.join(format!("family_{}", id));
+            let tables = repair_family(
+                &dir,
+                &family_lost_path,
+                encryptor.as_ref(),
+                &mut report.lost,
+            )?;
+            for (level, table) in tables {
+                report.family_tables.push((id, level, table));
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{AsyncDatabase, DatabaseBuilder};
+
+    #[test]
+    fn repair_lost_manifest() {
+        let base_dir = "/var/tmp/agilulf_repair_test";
+        let _ = std::fs::remove_dir_all(base_dir);
+
+        let database = DatabaseBuilder::default()
+            .base_dir(base_dir.to_string())
+            .restore(false)
+            .build()
+            .unwrap();
+        futures::executor::block_on(async {
+            for index in 0..(5 * 1024) {
+                let key = Slice(format!("{:08}", index).into_bytes());
+                database.put(key, Slice(b"VALUE".to_vec())).await.unwrap();
+            }
+        });
+        drop(database);
+
+        // Wait for the frozen database to be written into a table.
+        let table = Path::new(base_dir).join("sstable_0_0");
+        while !table.exists() || Path::new(base_dir).join("log.0").exists() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        std::fs::write(Path::new(base_dir).join("MANIFEST"), b"BROKEN").unwrap();
+        std::fs::write(Path::new(base_dir).join("sstable_0_7"), b"BROKEN").unwrap();
+
+        let report = Database::repair(base_dir, None).unwrap();
+        assert_eq!(report.tables, vec![(0, 0)]);
+        assert_eq!(report.lost.len(), 2);
+
+        let database = DatabaseBuilder::default()
+            .base_dir(base_dir.to_string())
+            .build()
+            .unwrap();
+        futures::executor::block_on(async {
+            for index in 0..(5 * 1024) {
+                let key = Slice(format!("{:08}", index).into_bytes());
+                let value = database.get(key).await.unwrap();
+                assert_eq!(&value.0[0..5], b"VALUE");
+            }
+        });
+    }
+
+    #[test]
+    fn repair_overlapping_levels() {
+        let base_dir = "/var/tmp/agilulf_repair_levels_test";
+        let _ = std::fs::remove_dir_all(base_dir);
+
+        let database = DatabaseBuilder::default()
+            .base_dir(base_dir.to_string())
+            .restore(false)
+            .build()
+            .unwrap();
+        let key = |index: usize| Slice(format!("K{:07}", index).into_bytes());
+        let put_keys = |value: &[u8]| {
+            futures::executor::block_on(async {
+                for index in 0..100 {
+                    database
+                        .put(key(index), Slice(value.to_vec()))
+                        .await
+                        .unwrap();
+                }
+            });
+        };
+        put_keys(b"OLD");
+        database.flush_memtable().unwrap();
+        database.compact_range(None, key(0), key(100)).unwrap();
+        put_keys(b"NEW");
+        database.flush_memtable().unwrap();
+        drop(database);
+
+        // An interrupted compaction leaves an overlapping table in level 1.
+        let base_path = Path::new(base_dir);
+        std::fs::copy(base_path.join("sstable_1_0"), base_path.join("sstable_1_5")).unwrap();
+        std::fs::write(base_path.join("MANIFEST"), b"BROKEN").unwrap();
+
+        let report = Database::repair(base_dir, None).unwrap();
+        assert_eq!(report.tables, vec![(0, 2), (0, 3), (0, 4)]);
+        assert!(!base_path.join("sstable_1_0").exists());
+
+        let database = DatabaseBuilder::default()
+            .base_dir(base_dir.to_string())
+            .build()
+            .unwrap();
+        futures::executor::block_on(async {
+            for index in 0..100 {
+                let value = database.get(key(index)).await.unwrap();
+                assert_eq!(&value.0[0..3], b"NEW");
+            }
+        });
+    }
+
+    #[test]
+    fn repair_column_family() {
+        use crate::storage::ColumnFamilyOptions;
+
+        let base_dir = "/var/tmp/agilulf_repair_family_test";
+        let _ = std::fs::remove_dir_all(base_dir);
+
+        let database = DatabaseBuilder::default()
+            .base_dir(base_dir.to_string())
+            .restore(false)
+            .build()
+            .unwrap();
+        let users = database
+            .create_column_family("users", ColumnFamilyOptions::default())
+            .unwrap();
+        let key = |index: usize| Slice(format!("K{:07}", index).into_bytes());
+        futures::executor::block_on(async {
+            for index in 0..100 {
+                database
+                    .put_cf(Some(&users), key(index), Slice(b"USER".to_vec()))
+                    .await
+                    .unwrap();
+            }
+        });
+        database.flush_memtable().unwrap();
+        drop(database);
+
+        let family_path = Path::new(base_dir).join(format!("family_{}", users.id()));
+        std::fs::write(family_path.join("MANIFEST"), b"BROKEN").unwrap();
+        assert!(!crate::storage::inspect::verify(base_dir, None)
+            .unwrap()
+            .is_empty());
+
+        let report = Database::repair(base_dir, None).unwrap();
+        assert_eq!(report.family_tables, vec![(users.id(), 0, 0)]);
+        assert_eq!(
+            report.lost,
+            vec![
+                Path::new(base_dir).join("lost/MANIFEST"),
+                Path::new(base_dir).join("lost/family_1/MANIFEST"),
+            ]
+        );
+        assert!(crate::storage::inspect::verify(base_dir, None)
+            .unwrap()
+            .is_empty());
+
+        let database = DatabaseBuilder::default()
+            .base_dir(base_dir.to_string())
+            .build()
+            .unwrap();
+        let users = database.column_family("users").unwrap();
+        futures::executor::block_on(async {
+            for index in 0..100 {
+                let value = database.get_cf(Some(&users), key(index)).await.unwrap();
+                assert_eq!(&value.0[0..4], b"USER");
+            }
+        });
+    }
+}
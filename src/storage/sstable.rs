@@ -1,19 +1,47 @@
-use super::{mem_database::MemDatabase, SyncDatabase};
+use super::blob::BlobIndex;
+use super::block_cache::{Block, BlockCache};
+use super::compression::Compression;
+use super::encryption::Encryptor;
+use super::mem_database::{now_millis, MemDatabase, Value};
+use super::merge::Source;
+use super::rate_limiter::RateLimiter;
+use super::range_tombstone::RangeTombstone;
+use super::SyncDatabase;
 use agilulf_protocol::Slice;
 use agilulf_protocol::{DatabaseError, DatabaseResult as Result};
 use memmap::MmapOptions;
 use std::borrow::Borrow;
 use std::cmp::Ordering;
 use std::ops::{Index, Range};
+use std::sync::Arc;
 
 pub trait SearchIndex:
-    Index<usize, Output = (Slice, Slice)>
-    + Index<std::ops::Range<usize>, Output = [(Slice, Slice)]>
+    Index<usize, Output = (Slice, Value)>
+    + Index<std::ops::Range<usize>, Output = [(Slice, Value)]>
     + Sync
     + Send
 {
     fn len(&self) -> usize;
 
+    /// Index of the first pair whose key is not less than `key`. It's `len()` if every key is less.
+    fn lower_bound(&self, key: &Slice) -> usize {
+        let mut base = 0usize;
+        let mut size = self.len();
+        while size > 0 {
+            let half = size / 2;
+            let mid = base + half;
+
+            if &self[mid].0 < key {
+                base = mid + 1;
+                size -= half + 1;
+            } else {
+                size = half;
+            }
+        }
+
+        base
+    }
+
     fn binary_search_by_key(&self, key: &Slice) -> usize {
         let mut size = self.len();
         if size == 0 {
@@ -32,31 +60,37 @@ pub trait SearchIndex:
         base
     }
 
-    fn first(&self) -> &(Slice, Slice) {
+    fn first(&self) -> &(Slice, Value) {
         &self[0]
     }
 
-    fn last(&self) -> &(Slice, Slice) {
+    fn last(&self) -> &(Slice, Value) {
         &self[self.len() - 1]
     }
 }
 
-#[repr(packed)]
+/// An immutable sorted table. Deleted keys are kept as `Value::NotExist`, and range tombstones are kept
+/// beside keys, so they can hide keys in older tables.
 pub struct SSTable {
-    kv_pairs: Box<dyn SearchIndex>,
+    records: Records,
+    range_tombstones: Vec<RangeTombstone>,
+}
+
+/// Keys of a table, kept in memory or mapped from a plain table, or in blocks which are read only when
+/// they are searched.
+enum Records {
+    Sorted(Box<dyn SearchIndex>),
+    Blocks(BlockTable),
 }
 
 impl SyncDatabase for SSTable {
     fn get_sync(&self, key: Slice) -> Result<Slice> {
-        if &self.kv_pairs.first().0 > &key || &self.kv_pairs.last().0 < &key {
-            return Err(DatabaseError::KeyNotFound);
-        }
-        let index = self.kv_pairs.binary_search_by_key(&key);
-
-        if self.kv_pairs[index].0.cmp(&key) == Ordering::Equal {
-            Ok(self.kv_pairs[index].1.clone())
-        } else {
-            Err(DatabaseError::KeyNotFound)
+        let value = self
+            .lookup(&key)
+            .map_err(|err| DatabaseError::InternalError(err.to_string()))?;
+        match value.and_then(Value::into_slice) {
+            Some(value) => Ok(value),
+            None => Err(DatabaseError::KeyNotFound),
         }
     }
 
@@ -64,40 +98,252 @@ impl SyncDatabase for SSTable {
         panic!("Cannot modify SSTable")
     }
 
-    fn scan_sync(&self, start: Slice, end: Slice) -> Vec<(Slice, Slice)> {
-        let start_index = self.kv_pairs.binary_search_by_key(&start);
-        let end_index = self.kv_pairs.binary_search_by_key(&end);
-        self.kv_pairs[start_index..end_index].to_vec()
+    fn put_expire_sync(&self, _: Slice, _: Slice, _: u64) -> Result<()> {
+        panic!("Cannot modify SSTable")
+    }
+
+    fn scan_sync(&self, start: Slice, end: Slice) -> Result<Vec<(Slice, Slice)>> {
+        let now = now_millis();
+        let entries = self
+            .entries(&start, &end)
+            .map_err(|err| DatabaseError::InternalError(err.to_string()))?;
+        Ok(entries
+            .into_iter()
+            .filter_map(|(key, value)| {
+                let value = value.live(now).into_slice()?;
+                Some((key, value))
+            })
+            .collect())
     }
 
     fn delete_sync(&self, _: Slice) -> Result<()> {
         panic!("Cannot modify SSTable")
     }
+
+    fn delete_range_sync(&self, _: Slice, _: Slice) -> Result<()> {
+        panic!("Cannot modify SSTable")
+    }
+
+    fn merge_sync(&self, _: Slice, _: Slice) -> Result<()> {
+        panic!("Cannot modify SSTable")
+    }
 }
 
-impl SearchIndex for Vec<(Slice, Slice)> {
+impl SearchIndex for Vec<(Slice, Value)> {
     fn len(&self) -> usize {
         Vec::len(self)
     }
 }
 
+/// Every key (including deleted ones) and range tombstone in MemDatabase is written into SSTable. The
+/// MemDatabase should not have operands of merge, which are folded by `flush_table` in
+/// `manifest_manager`.
 impl<T: Borrow<MemDatabase>> From<T> for SSTable {
     fn from(mem_database: T) -> Self {
-        let kv_pairs: Box<Vec<(Slice, Slice)>> = box mem_database
-            .borrow()
-            .scan_sync(Slice(Vec::new()), Slice(vec![255; 8]))
-            .into_iter()
-            .collect();
+        let mem_database = mem_database.borrow();
+        let source = mem_database.source(&Slice(Vec::new()), &Slice(vec![255; KEY_LENGTH]));
 
-        Self { kv_pairs }
+        SSTable::new(source.entries, mem_database.range_tombstones())
     }
 }
 
-pub const PART_LENGTH: usize = 256 + 8;
+pub const KEY_LENGTH: usize = 8;
+pub const VALUE_LENGTH: usize = 256;
+/// Every record is a key, a value, the kind of it and the expiration time (in little endian, `0` if the
+/// key never expires).
+pub const PART_LENGTH: usize = VALUE_LENGTH + KEY_LENGTH + 1 + 8;
+
+/// Kinds of records in SSTable. A `DELETE_RANGE` record is a range tombstone, whose end is stored in
+/// value. Range tombstones are written after every key. A `BLOB` record stores the index of its value in
+/// blob files.
+pub const PUT: u8 = 0;
+pub const DELETE: u8 = 1;
+pub const DELETE_RANGE: u8 = 2;
+pub const BLOB: u8 = 3;
+
+/// Every table starts with a header: `TABLE_MAGIC`, the version of its format and its flags, followed
+/// by the number of key blocks, the number of range tombstone blocks and the length of the block index
+/// (all in little endian, and `0` in a plain table), and a random id of the file. A file without the
+/// magic, or written in another version, is rejected with `SSTableError::IncompatibleFormat`.
+///
+/// A plain table (neither compressed nor encrypted) is the header followed by records, which are mapped
+/// and read without copying.
+///
+/// Records of a table with `FLAG_BLOCKS` are split into blocks of `BLOCK_RECORDS` records, and keys and
+/// range tombstones never share a block. The block index follows the header, with the codec, the length
+/// of records, the length of the stored (maybe compressed) bytes, and the first and the last key of
+/// every block. Blocks follow the index in the same order. With `FLAG_ENCRYPTED`, the index and the
+/// stored bytes of every block are encrypted (after compression). The index is bound to the header,
+/// and every block to the header, its position and its entry in the index, so blocks cannot be
+/// reordered or moved between tables.
+///
+/// Every table ends with a CRC32 of the bytes before it (in little endian). It's only checked by
+/// `verify_checksum`, as reading the whole table on every open would be too slow.
+pub const TABLE_FORMAT_VERSION: u32 = 2;
+const TABLE_MAGIC: &[u8; 8] = b"AGLFSST\0";
+pub const HEADER_LENGTH: usize = 8 + 4 + 1 + 4 + 4 + 4 + 8;
+const FLAG_BLOCKS: u8 = 1;
+const FLAG_ENCRYPTED: u8 = 2;
+pub const BLOCK_RECORDS: usize = 16;
+const INDEX_ENTRY_LENGTH: usize = 1 + 4 + 4 + KEY_LENGTH * 2;
+pub const CHECKSUM_LENGTH: usize = 4;
+
+/// A table written with a rate limiter is written in parts of this length.
+const WRITE_CHUNK_LENGTH: usize = 256 * 1024;
+
+fn pad(mut slice: Slice, length: usize) -> Slice {
+    slice.0.resize(length, 0);
+    slice
+}
+
+fn read_u32(data: &[u8], offset: usize) -> usize {
+    let mut array = [0u8; 4];
+    array.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(array) as usize
+}
+
+/// Convert a key into the fixed length form stored in SSTable and MANIFEST. A key longer than
+/// `KEY_LENGTH` cannot be stored, and is rejected instead of being cut.
+pub fn key_to_array(key: &Slice) -> SSTableResult<[u8; KEY_LENGTH]> {
+    if key.0.len() > KEY_LENGTH {
+        return Err(SSTableError::KeyTooLong(key.0.len()));
+    }
+
+    let mut array = [0u8; KEY_LENGTH];
+    array[0..key.0.len()].clone_from_slice(key.0.as_slice());
+    Ok(array)
+}
+
+fn append_record(buf: &mut Vec<u8>, key: &Slice, value: &[u8], kind: u8, expire_at: u64) {
+    buf.extend_from_slice(&key.0);
+    buf.extend(vec![0; KEY_LENGTH - key.0.len()].iter());
+    buf.extend_from_slice(value);
+    buf.extend(vec![0; VALUE_LENGTH - value.len()].iter());
+    buf.push(kind);
+    buf.extend_from_slice(&expire_at.to_le_bytes());
+}
+
+fn append_checksum(buf: &mut Vec<u8>) {
+    let checksum = crc32fast::hash(buf);
+    buf.extend_from_slice(&checksum.to_le_bytes());
+}
+
+/// Check the checksum at the end of a table file, which covers every byte of it, including values
+/// which `SSTable::verify` cannot check.
+pub fn verify_checksum(file: &std::fs::File) -> SSTableResult<()> {
+    let length = file.metadata()?.len() as usize;
+    if length < HEADER_LENGTH + CHECKSUM_LENGTH {
+        return Err(SSTableError::Corrupted);
+    }
+    let mmap = unsafe { MmapOptions::new().map(file)? };
+    let (data, checksum) = mmap.split_at(length - CHECKSUM_LENGTH);
+    if crc32fast::hash(data).to_le_bytes() != checksum {
+        return Err(SSTableError::ChecksumMismatch);
+    }
+
+    Ok(())
+}
+
+/// The random id written into the header of a table when it's created. It's kept when the table is
+/// linked or copied, so two files with the same id are the same table.
+pub fn table_file_id(path: &std::path::Path) -> SSTableResult<u64> {
+    let mut header = [0u8; HEADER_LENGTH];
+    std::io::Read::read_exact(&mut std::fs::File::open(path)?, &mut header)?;
+    Ok(Header::decode(&header)?.file_id)
+}
+
+struct Header {
+    flags: u8,
+    key_blocks: usize,
+    tombstone_blocks: usize,
+    index_length: usize,
+    file_id: u64,
+}
+
+impl Header {
+    fn encode(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_LENGTH);
+        header.extend_from_slice(TABLE_MAGIC);
+        header.extend_from_slice(&TABLE_FORMAT_VERSION.to_le_bytes());
+        header.push(self.flags);
+        header.extend_from_slice(&(self.key_blocks as u32).to_le_bytes());
+        header.extend_from_slice(&(self.tombstone_blocks as u32).to_le_bytes());
+        header.extend_from_slice(&(self.index_length as u32).to_le_bytes());
+        header.extend_from_slice(&self.file_id.to_le_bytes());
+        header
+    }
+
+    fn decode(data: &[u8]) -> SSTableResult<Header> {
+        if data.len() < HEADER_LENGTH {
+            return Err(SSTableError::Corrupted);
+        }
+        if &data[0..8] != TABLE_MAGIC || read_u32(data, 8) != TABLE_FORMAT_VERSION as usize {
+            return Err(SSTableError::IncompatibleFormat);
+        }
+
+        Ok(Header {
+            flags: data[12],
+            key_blocks: read_u32(data, 13),
+            tombstone_blocks: read_u32(data, 17),
+            index_length: read_u32(data, 21),
+            file_id: {
+                let mut file_id = [0u8; 8];
+                file_id.copy_from_slice(&data[25..33]);
+                u64::from_le_bytes(file_id)
+            },
+        })
+    }
+}
+
+/// The kind and the expiration time of a record.
+fn record_kind(part: &[u8]) -> (u8, u64) {
+    let mut expire_at = [0u8; 8];
+    expire_at.copy_from_slice(&part[KEY_LENGTH + VALUE_LENGTH + 1..PART_LENGTH]);
+    (part[KEY_LENGTH + VALUE_LENGTH], u64::from_le_bytes(expire_at))
+}
+
+/// Copy keys out of a decoded block. A range tombstone is not allowed in it.
+fn decode_keys(block: &[u8]) -> SSTableResult<Block> {
+    let mut records = Vec::with_capacity(block.len() / PART_LENGTH);
+    for part in block.chunks(PART_LENGTH) {
+        let (kind, expire_at) = record_kind(part);
+        let key = Slice(part[0..KEY_LENGTH].to_vec());
+        let value = &part[KEY_LENGTH..KEY_LENGTH + VALUE_LENGTH];
+        let value = match kind {
+            PUT if expire_at == 0 => Value::Slice(Slice(value.to_vec())),
+            PUT => Value::Expiring(Slice(value.to_vec()), expire_at),
+            DELETE => Value::NotExist,
+            BLOB => match BlobIndex::decode(value) {
+                Some(index) => Value::Blob(index, expire_at),
+                None => return Err(SSTableError::Corrupted),
+            },
+            _ => return Err(SSTableError::Corrupted),
+        };
+        records.push((key, value));
+    }
+
+    Ok(records)
+}
+
+/// Copy range tombstones out of a decoded block, which should only contain range tombstones.
+fn decode_tombstones(block: &[u8]) -> SSTableResult<Vec<RangeTombstone>> {
+    let mut range_tombstones = Vec::with_capacity(block.len() / PART_LENGTH);
+    for part in block.chunks(PART_LENGTH) {
+        if record_kind(part).0 != DELETE_RANGE {
+            return Err(SSTableError::Corrupted);
+        }
+        range_tombstones.push(RangeTombstone::new(
+            Slice(part[0..KEY_LENGTH].to_vec()),
+            Slice(part[KEY_LENGTH..KEY_LENGTH * 2].to_vec()),
+        ));
+    }
+
+    Ok(range_tombstones)
+}
 
 struct SliceMmap {
     _inner_mmap: memmap::Mmap,
-    inner_vec: Option<Vec<(Slice, Slice)>>,
+    inner_vec: Option<Vec<(Slice, Value)>>,
 }
 
 impl Drop for SliceMmap {
@@ -108,30 +354,71 @@ impl Drop for SliceMmap {
 }
 
 impl SliceMmap {
-    fn from_mmap(mmap: memmap::Mmap) -> Self {
-        unsafe {
-            let length = mmap.len() / PART_LENGTH;
-            let mut inner_vec = Vec::new();
+    /// Keys (following the header) are read from mmap directly. Range tombstones are few, so they are
+    /// copied out.
+    fn from_mmap(mmap: memmap::Mmap) -> SSTableResult<(Self, Vec<RangeTombstone>)> {
+        let length = (mmap.len() - HEADER_LENGTH - CHECKSUM_LENGTH) / PART_LENGTH;
+        let mut inner_vec = Vec::new();
+        let mut range_tombstones = Vec::new();
 
-            for index in 0..length {
+        for index in 0..length {
+            let offset = HEADER_LENGTH + PART_LENGTH * index;
+            let (kind, expire_at) = record_kind(&mmap[offset..offset + PART_LENGTH]);
+            if kind == DELETE_RANGE {
+                range_tombstones.push(RangeTombstone::new(
+                    Slice(mmap[offset..offset + KEY_LENGTH].to_vec()),
+                    Slice(mmap[offset + KEY_LENGTH..offset + KEY_LENGTH * 2].to_vec()),
+                ));
+                continue;
+            }
+
+            unsafe {
                 let key = Vec::from_raw_parts(
-                    mmap.index(PART_LENGTH * index) as *const u8 as *mut u8, // Note: don't write to this vector
-                    8,
-                    8,
-                );
-                let value = Vec::from_raw_parts(
-                    mmap.index(PART_LENGTH * index + 8) as *const u8 as *mut u8, // Note: don't write to this vector
-                    256,
-                    256,
+                    mmap.index(offset) as *const u8 as *mut u8, // Note: don't write to this vector
+                    KEY_LENGTH,
+                    KEY_LENGTH,
                 );
-                inner_vec.push((Slice(key), Slice(value)));
+                let value = match kind {
+                    PUT => {
+                        let value = Slice(Vec::from_raw_parts(
+                            mmap.index(offset + KEY_LENGTH) as *const u8 as *mut u8, // Note: don't write to this vector
+                            VALUE_LENGTH,
+                            VALUE_LENGTH,
+                        ));
+                        if expire_at == 0 {
+                            Value::Slice(value)
+                        } else {
+                            Value::Expiring(value, expire_at)
+                        }
+                    }
+                    DELETE => Value::NotExist,
+                    BLOB => match BlobIndex::decode(
+                        &mmap[offset + KEY_LENGTH..offset + KEY_LENGTH + VALUE_LENGTH],
+                    ) {
+                        Some(index) => Value::Blob(index, expire_at),
+                        None => {
+                            std::mem::forget(key);
+                            std::mem::forget(inner_vec);
+                            return Err(SSTableError::Corrupted);
+                        }
+                    },
+                    _ => {
+                        std::mem::forget(key);
+                        std::mem::forget(inner_vec);
+                        return Err(SSTableError::Corrupted);
+                    }
+                };
+                inner_vec.push((Slice(key), value));
             }
+        }
 
+        Ok((
             SliceMmap {
                 _inner_mmap: mmap,
                 inner_vec: Some(inner_vec),
-            }
-        }
+            },
+            range_tombstones,
+        ))
     }
 }
 
@@ -145,7 +432,7 @@ impl SearchIndex for SliceMmap {
 }
 
 impl Index<std::ops::Range<usize>> for SliceMmap {
-    type Output = [(Slice, Slice)];
+    type Output = [(Slice, Value)];
 
     fn index(&self, index: Range<usize>) -> &Self::Output {
         match &self.inner_vec {
@@ -156,7 +443,7 @@ impl Index<std::ops::Range<usize>> for SliceMmap {
 }
 
 impl Index<usize> for SliceMmap {
-    type Output = (Slice, Slice);
+    type Output = (Slice, Value);
 
     fn index(&self, index: usize) -> &Self::Output {
         match &self.inner_vec {
@@ -166,6 +453,115 @@ impl Index<usize> for SliceMmap {
     }
 }
 
+/// A block in the block index.
+struct BlockHandle {
+    offset: usize,
+    codec: u8,
+    raw_length: usize,
+    stored_length: usize,
+    first_key: Slice,
+    last_key: Slice,
+    /// Number of keys in blocks before it.
+    start: usize,
+    /// Data authenticated with the block if it's encrypted.
+    aad: Vec<u8>,
+}
+
+/// Data authenticated with an encrypted block: the header of its table, its index and its entry in
+/// the block index.
+fn block_aad(header: &[u8], index: usize, entry: &[u8]) -> Vec<u8> {
+    let mut aad = header.to_vec();
+    aad.extend_from_slice(&(index as u64).to_le_bytes());
+    aad.extend_from_slice(entry);
+    aad
+}
+
+/// Read a block from `data` (the mapped file), decrypt it with `encryptor` and decompress it.
+fn read_block(
+    data: &[u8],
+    handle: &BlockHandle,
+    encryptor: Option<&dyn Encryptor>,
+) -> SSTableResult<Vec<u8>> {
+    let stored = match data.get(handle.offset..handle.offset + handle.stored_length) {
+        Some(stored) => stored,
+        None => return Err(SSTableError::Corrupted),
+    };
+    let decrypted;
+    let stored = match encryptor {
+        Some(encryptor) => {
+            decrypted = encryptor
+                .decrypt(stored, &handle.aad)
+                .ok_or(SSTableError::DecryptionFailed)?;
+            decrypted.as_slice()
+        }
+        None => stored,
+    };
+
+    let block = Compression::from_code(handle.codec)
+        .and_then(|compression| compression.decompress(stored, handle.raw_length));
+    match block {
+        Some(block) if !block.is_empty() && block.len() % PART_LENGTH == 0 => Ok(block),
+        _ => Err(SSTableError::Corrupted),
+    }
+}
+
+/// Keys of a compressed or encrypted table. Only the block index is read while opening, and a block is
+/// read, decrypted and decompressed when it's searched. Decoded blocks are kept in `block_cache`, so
+/// compression and encryption sit below the cache.
+struct BlockTable {
+    mmap: memmap::Mmap,
+    handles: Vec<BlockHandle>,
+    len: usize,
+    encryptor: Option<Arc<dyn Encryptor>>,
+    block_cache: Option<Arc<BlockCache>>,
+    cache_id: u64,
+}
+
+impl BlockTable {
+    /// Decoded keys of a block. A block read for a full scan (e.g. by compaction) is not put into
+    /// cache if `fill_cache` is `false`, so it doesn't evict blocks which are read often.
+    fn block(&self, index: usize, fill_cache: bool) -> SSTableResult<Arc<Block>> {
+        if let Some(block_cache) = &self.block_cache {
+            if let Some(block) = block_cache.get(self.cache_id, index) {
+                return Ok(block);
+            }
+        }
+
+        let handle = &self.handles[index];
+        let data = read_block(&self.mmap, handle, self.encryptor.as_ref().map(Arc::as_ref))?;
+        let block = Arc::new(decode_keys(&data)?);
+        if let (Some(block_cache), true) = (&self.block_cache, fill_cache) {
+            block_cache.insert(self.cache_id, index, block.clone(), handle.raw_length);
+        }
+
+        Ok(block)
+    }
+
+    /// Index of the first block whose last key is not less than `key`. It's the number of blocks if
+    /// every key is less.
+    fn find_block(&self, key: &Slice) -> usize {
+        match self
+            .handles
+            .binary_search_by(|handle| handle.last_key.cmp(key))
+        {
+            Ok(index) | Err(index) => index,
+        }
+    }
+
+    /// Index of the first key which is not less than `key`, or the start of its block if the block
+    /// cannot be read.
+    fn lower_bound(&self, key: &Slice) -> usize {
+        let index = self.find_block(key);
+        match self.handles.get(index) {
+            Some(handle) => match self.block(index, true) {
+                Ok(block) => handle.start + block.lower_bound(key),
+                Err(_) => handle.start,
+            },
+            None => self.len,
+        }
+    }
+}
+
 quick_error! {
     #[derive(Debug)]
     pub enum SSTableError {
@@ -175,39 +571,443 @@ quick_error! {
         IoError(err: std::io::Error) {
             from()
         }
+        Corrupted
+        KeyTooLong(length: usize) {
+            display("key of {} bytes is longer than {}", length, KEY_LENGTH)
+        }
+        ChecksumMismatch
+        IncompatibleFormat
+        Encrypted
+        DecryptionFailed
     }
 }
 pub type SSTableResult<T> = std::result::Result<T, SSTableError>;
 
 impl SSTable {
-    fn freeze(&self) -> Vec<u8> {
-        let mut buf = Vec::new();
-        let len = self.kv_pairs.len();
+    /// Build a table from keys sorted by key and range tombstones. Keys, values and bounds of range
+    /// tombstones are padded with zero, so it behaves the same with the one reopened from disk.
+    pub fn new(entries: Vec<(Slice, Value)>, mut range_tombstones: Vec<RangeTombstone>) -> SSTable {
+        let kv_pairs: Box<Vec<(Slice, Value)>> = box entries
+            .into_iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    Value::Slice(value) => Value::Slice(pad(value, VALUE_LENGTH)),
+                    Value::Expiring(value, expire_at) => {
+                        Value::Expiring(pad(value, VALUE_LENGTH), expire_at)
+                    }
+                    value => value,
+                };
+                (pad(key, KEY_LENGTH), value)
+            })
+            .collect();
+
+        for tombstone in range_tombstones.iter_mut() {
+            tombstone.start = pad(tombstone.start.clone(), KEY_LENGTH);
+            tombstone.end = pad(tombstone.end.clone(), KEY_LENGTH);
+        }
+        range_tombstones.sort_by(|a, b| a.start.cmp(&b.start));
+
+        SSTable {
+            records: Records::Sorted(kv_pairs),
+            range_tombstones,
+        }
+    }
+
+    /// Number of keys (including deleted ones) in this table.
+    pub fn len(&self) -> usize {
+        match &self.records {
+            Records::Sorted(kv_pairs) => kv_pairs.len(),
+            Records::Blocks(table) => table.len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The smallest and the largest key in this table, including bounds of range tombstones. `None` if
+    /// the table is empty.
+    pub fn key_range(&self) -> Option<(Slice, Slice)> {
+        let mut range = match &self.records {
+            Records::Sorted(kv_pairs) if kv_pairs.len() > 0 => {
+                Some((kv_pairs.first().0.clone(), kv_pairs.last().0.clone()))
+            }
+            Records::Blocks(table) if !table.handles.is_empty() => Some((
+                table.handles[0].first_key.clone(),
+                table.handles[table.handles.len() - 1].last_key.clone(),
+            )),
+            _ => None,
+        };
+
+        for tombstone in self.range_tombstones.iter() {
+            range = match range {
+                None => Some((tombstone.start.clone(), tombstone.end.clone())),
+                Some((smallest, largest)) => Some((
+                    std::cmp::min(smallest, tombstone.start.clone()),
+                    std::cmp::max(largest, tombstone.end.clone()),
+                )),
+            };
+        }
+
+        range
+    }
+
+    /// Every key in this table (including deleted ones), sorted by key. Blocks read for it are not put
+    /// into cache.
+    pub fn records(&self) -> SSTableResult<Vec<(Slice, Value)>> {
+        match &self.records {
+            Records::Sorted(kv_pairs) => Ok(kv_pairs[0..kv_pairs.len()].to_vec()),
+            Records::Blocks(table) => {
+                let mut records = Vec::with_capacity(table.len);
+                for index in 0..table.handles.len() {
+                    records.extend(table.block(index, false)?.iter().cloned());
+                }
+                Ok(records)
+            }
+        }
+    }
+
+    pub fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.range_tombstones
+    }
+
+    /// Keys (including deleted ones) in `[start, end)`.
+    fn entries(&self, start: &Slice, end: &Slice) -> SSTableResult<Vec<(Slice, Value)>> {
+        match &self.records {
+            Records::Sorted(kv_pairs) => {
+                let start_index = kv_pairs.lower_bound(start);
+                let end_index = kv_pairs.lower_bound(end);
+                if start_index >= end_index {
+                    return Ok(Vec::new());
+                }
+                Ok(kv_pairs[start_index..end_index].to_vec())
+            }
+            Records::Blocks(table) => {
+                let mut entries = Vec::new();
+                for index in table.find_block(start)..table.handles.len() {
+                    if &table.handles[index].first_key >= end {
+                        break;
+                    }
+                    let block = table.block(index, true)?;
+                    entries.extend(
+                        block
+                            .iter()
+                            .filter(|(key, _)| key >= start && key < end)
+                            .cloned(),
+                    );
+                }
+                Ok(entries)
+            }
+        }
+    }
 
-        for (key, value) in self.kv_pairs[0..len].iter() {
-            buf.extend_from_slice(&key.0);
-            buf.extend(vec![0; 8 - key.0.len()].iter());
-            buf.extend_from_slice(&value.0);
-            buf.extend(vec![0; 256 - value.0.len()].iter());
+    /// Number of records (including deleted ones) in `[start, end)`, found by their offsets in index.
+    /// In a table of blocks, only the blocks at both bounds are read.
+    pub fn approximate_count(&self, start: &Slice, end: &Slice) -> u64 {
+        let (start_index, end_index) = match &self.records {
+            Records::Sorted(kv_pairs) => (kv_pairs.lower_bound(start), kv_pairs.lower_bound(end)),
+            Records::Blocks(table) => (table.lower_bound(start), table.lower_bound(end)),
+        };
+        end_index.saturating_sub(start_index) as u64
+    }
+
+    /// Find a key in this table only. `Some(Value::NotExist)` means the key is deleted (or expired) in
+    /// this table, and `None` means older tables should be searched.
+    pub fn lookup(&self, key: &Slice) -> SSTableResult<Option<Value>> {
+        let found = match &self.records {
+            Records::Sorted(kv_pairs) => {
+                if kv_pairs.len() > 0 && &kv_pairs.first().0 <= key && &kv_pairs.last().0 >= key {
+                    let index = kv_pairs.binary_search_by_key(key);
+                    if kv_pairs[index].0.cmp(key) == Ordering::Equal {
+                        Some(kv_pairs[index].1.clone())
+                    } else {
+                        None
+                    }
+                } else {
+                    None
+                }
+            }
+            Records::Blocks(table) => {
+                let index = table.find_block(key);
+                match table.handles.get(index) {
+                    Some(handle) if &handle.first_key <= key => {
+                        let block = table.block(index, true)?;
+                        match block.binary_search_by(|(record, _)| record.cmp(key)) {
+                            Ok(position) => Some(block[position].1.clone()),
+                            Err(_) => None,
+                        }
+                    }
+                    _ => None,
+                }
+            }
+        };
+        if let Some(value) = found {
+            return Ok(Some(value.live(now_millis())));
+        }
+
+        if self
+            .range_tombstones
+            .iter()
+            .any(|tombstone| tombstone.covers(key))
+        {
+            Ok(Some(Value::NotExist))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Keys (including deleted ones) in `[start, end)` and range tombstones overlapping it.
+    pub fn source(&self, start: &Slice, end: &Slice) -> SSTableResult<Source> {
+        Ok(Source {
+            entries: self.entries(start, end)?,
+            range_tombstones: self
+                .range_tombstones
+                .iter()
+                .filter(|tombstone| tombstone.overlaps(start, end))
+                .cloned()
+                .collect(),
+        })
+    }
+
+    /// Check whether keys in this table are strictly ascending, and every block of it can be read and
+    /// matches the block index. A table which is truncated or partly overwritten will usually break it.
+    pub fn verify(&self) -> SSTableResult<()> {
+        if let Records::Blocks(table) = &self.records {
+            for (index, handle) in table.handles.iter().enumerate() {
+                let block = table.block(index, false)?;
+                if block[0].0 != handle.first_key || block[block.len() - 1].0 != handle.last_key {
+                    return Err(SSTableError::Corrupted);
+                }
+            }
+        }
+
+        let records = self.records()?;
+        for index in 1..records.len() {
+            if records[index - 1].0 >= records[index].0 {
+                return Err(SSTableError::Corrupted);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The header and plain records, or blocks of them if `compression` is not `Compression::None` or
+    /// `encryptor` is given. A block which doesn't get smaller is stored without compression.
+    fn freeze(
+        &self,
+        compression: Compression,
+        encryptor: Option<&Arc<dyn Encryptor>>,
+    ) -> SSTableResult<Vec<u8>> {
+        let mut keys = Vec::new();
+        for (key, value) in self.records()? {
+            match value {
+                Value::Slice(value) => append_record(&mut keys, &key, &value.0, PUT, 0),
+                Value::Expiring(value, expire_at) => {
+                    append_record(&mut keys, &key, &value.0, PUT, expire_at)
+                }
+                Value::NotExist => append_record(&mut keys, &key, &[], DELETE, 0),
+                Value::Blob(index, expire_at) => {
+                    append_record(&mut keys, &key, &index.encode().0, BLOB, expire_at)
+                }
+                Value::Merge(_) => unreachable!(),
+            }
         }
+        let mut tombstones = Vec::new();
+        for tombstone in self.range_tombstones.iter() {
+            append_record(&mut tombstones, &tombstone.start, &tombstone.end.0, DELETE_RANGE, 0);
+        }
+
+        let file_id = crate::log::random_id()?;
+        if compression == Compression::None && encryptor.is_none() {
+            let header = Header {
+                flags: 0,
+                key_blocks: 0,
+                tombstone_blocks: 0,
+                index_length: 0,
+                file_id,
+            };
+            let mut buf = header.encode();
+            buf.extend_from_slice(&keys);
+            buf.extend_from_slice(&tombstones);
+            append_checksum(&mut buf);
+            return Ok(buf);
+        }
+
+        let key_blocks: Vec<&[u8]> = keys.chunks(BLOCK_RECORDS * PART_LENGTH).collect();
+        let tombstone_blocks: Vec<&[u8]> = tombstones.chunks(BLOCK_RECORDS * PART_LENGTH).collect();
+        let block_count = key_blocks.len() + tombstone_blocks.len();
+        let overhead = encryptor.map_or(0, |encryptor| encryptor.overhead());
+        let header = Header {
+            flags: FLAG_BLOCKS | encryptor.map_or(0, |_| FLAG_ENCRYPTED),
+            key_blocks: key_blocks.len(),
+            tombstone_blocks: tombstone_blocks.len(),
+            index_length: block_count * INDEX_ENTRY_LENGTH + overhead,
+            file_id,
+        };
+        let header = header.encode();
 
-        buf
+        let mut index = Vec::new();
+        let mut blocks = Vec::new();
+        for (position, block) in key_blocks.iter().chain(tombstone_blocks.iter()).enumerate() {
+            let mut codec = compression;
+            let mut stored = compression.compress(block);
+            if stored.len() >= block.len() {
+                codec = Compression::None;
+                stored = block.to_vec();
+            }
+            let stored_length = stored.len() + overhead;
+
+            let mut entry = Vec::with_capacity(INDEX_ENTRY_LENGTH);
+            entry.push(codec.code());
+            entry.extend_from_slice(&(block.len() as u32).to_le_bytes());
+            entry.extend_from_slice(&(stored_length as u32).to_le_bytes());
+            entry.extend_from_slice(&block[0..KEY_LENGTH]);
+            let last = block.len() - PART_LENGTH;
+            entry.extend_from_slice(&block[last..last + KEY_LENGTH]);
+
+            if let Some(encryptor) = encryptor {
+                stored = encryptor.encrypt(&stored, &block_aad(&header, position, &entry));
+            }
+            index.extend_from_slice(&entry);
+            blocks.extend_from_slice(&stored);
+        }
+        if let Some(encryptor) = encryptor {
+            index = encryptor.encrypt(&index, &header);
+        }
+
+        let mut buf = header;
+        buf.extend_from_slice(&index);
+        buf.extend_from_slice(&blocks);
+        append_checksum(&mut buf);
+        Ok(buf)
     }
 
-    pub async fn save<'a>(&'a self, path: &'a str) -> SSTableResult<()> {
+    /// Write this table into `path`, replacing anything in it, with blocks compressed by `compression`
+    /// and encrypted by `encryptor`. With `rate_limiter`, it's written in parts of `WRITE_CHUNK_LENGTH`
+    /// bytes, each of which waits for the limiter. The file is synced before returning, but its
+    /// directory entry is not (see `sync_parent`).
+    pub async fn save<'a>(
+        &'a self,
+        path: &'a str,
+        compression: Compression,
+        encryptor: Option<&'a Arc<dyn Encryptor>>,
+        rate_limiter: Option<&'a RateLimiter>,
+    ) -> SSTableResult<()> {
         use agilulf_fs::File;
+        std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
         let file = File::open(path)?;
 
-        let buf = self.freeze();
-        file.write(0, buf.as_slice()).await?;
+        let buf = self.freeze(compression, encryptor)?;
+        match rate_limiter {
+            Some(rate_limiter) => {
+                for (index, chunk) in buf.chunks(WRITE_CHUNK_LENGTH).enumerate() {
+                    rate_limiter.request(chunk.len() as u64).await;
+                    file.write((index * WRITE_CHUNK_LENGTH) as i64, chunk)
+                        .await?;
+                }
+            }
+            None => file.write(0, buf.as_slice()).await?,
+        }
+        std::fs::File::open(path)?.sync_all()?;
 
         Ok(())
     }
 
-    pub fn open(file: std::fs::File) -> SSTableResult<Self> {
-        let mmap = box SliceMmap::from_mmap(unsafe { MmapOptions::new().map(&file)? });
+    /// Open a table file. Only the block index of a compressed (or encrypted) table is read here, and
+    /// its blocks are read when they are searched and kept in `block_cache` (if it's given). An encrypted
+    /// table can only be opened with `encryptor`, but tables without encryption can be opened with it
+    /// too.
+    pub fn open(
+        file: std::fs::File,
+        encryptor: Option<&Arc<dyn Encryptor>>,
+        block_cache: Option<&Arc<BlockCache>>,
+    ) -> SSTableResult<Self> {
+        let length = file.metadata()?.len() as usize;
+        if length < HEADER_LENGTH + CHECKSUM_LENGTH {
+            return Err(SSTableError::Corrupted);
+        }
+        let mmap = unsafe { MmapOptions::new().map(&file)? };
+        let header = Header::decode(&mmap[0..HEADER_LENGTH])?;
+
+        if header.flags & FLAG_BLOCKS == 0 {
+            if header.flags != 0 || (length - HEADER_LENGTH - CHECKSUM_LENGTH) % PART_LENGTH != 0 {
+                return Err(SSTableError::Corrupted);
+            }
+            let (mmap, range_tombstones) = SliceMmap::from_mmap(mmap)?;
+            return Ok(Self {
+                records: Records::Sorted(box mmap),
+                range_tombstones,
+            });
+        }
 
-        Ok(Self { kv_pairs: mmap })
+        let encryptor = match (header.flags & FLAG_ENCRYPTED != 0, encryptor) {
+            (true, Some(encryptor)) => Some(encryptor.clone()),
+            (true, None) => return Err(SSTableError::Encrypted),
+            (false, _) => None,
+        };
+        let index_end = HEADER_LENGTH + header.index_length;
+        let index = match mmap.get(HEADER_LENGTH..index_end) {
+            Some(index) => index,
+            None => return Err(SSTableError::Corrupted),
+        };
+        let index = match &encryptor {
+            Some(encryptor) => encryptor
+                .decrypt(index, &mmap[0..HEADER_LENGTH])
+                .ok_or(SSTableError::DecryptionFailed)?,
+            None => index.to_vec(),
+        };
+        let blocks = header.key_blocks + header.tombstone_blocks;
+        if index.len() != blocks * INDEX_ENTRY_LENGTH {
+            return Err(SSTableError::Corrupted);
+        }
+
+        let (mut handles, mut offset, mut start) = (Vec::with_capacity(blocks), index_end, 0);
+        for (position, entry) in index.chunks(INDEX_ENTRY_LENGTH).enumerate() {
+            let handle = BlockHandle {
+                offset,
+                codec: entry[0],
+                raw_length: read_u32(entry, 1),
+                stored_length: read_u32(entry, 5),
+                first_key: Slice(entry[9..9 + KEY_LENGTH].to_vec()),
+                last_key: Slice(entry[9 + KEY_LENGTH..9 + KEY_LENGTH * 2].to_vec()),
+                start,
+                aad: match encryptor {
+                    Some(_) => block_aad(&mmap[0..HEADER_LENGTH], position, entry),
+                    None => Vec::new(),
+                },
+            };
+            offset += handle.stored_length;
+            start += handle.raw_length / PART_LENGTH;
+            handles.push(handle);
+        }
+        if offset + CHECKSUM_LENGTH != length {
+            return Err(SSTableError::Corrupted);
+        }
+
+        let mut range_tombstones = Vec::new();
+        for handle in handles.split_off(header.key_blocks) {
+            let block = read_block(&mmap, &handle, encryptor.as_ref().map(Arc::as_ref))?;
+            range_tombstones.extend(decode_tombstones(&block)?);
+        }
+        let len = handles.last().map_or(0, |handle| {
+            handle.start + handle.raw_length / PART_LENGTH
+        });
+
+        Ok(Self {
+            records: Records::Blocks(BlockTable {
+                mmap,
+                handles,
+                len,
+                encryptor,
+                block_cache: block_cache.cloned(),
+                cache_id: block_cache.map_or(0, |block_cache| block_cache.new_id()),
+            }),
+            range_tombstones,
+        })
     }
 }
 
@@ -223,19 +1023,30 @@ mod tests {
 
         let sstable: SSTable = db.into();
         futures::executor::block_on(async move {
-            sstable.save("/tmp/test_table").await.unwrap();
+            sstable.save("/tmp/test_table", Compression::None, None, None).await.unwrap();
         });
 
         let mut reader = std::fs::File::open("/tmp/test_table").unwrap();
         let mut buf = Vec::new();
         reader.read_to_end(&mut buf).unwrap();
 
+        let buf = &buf[HEADER_LENGTH..];
         assert_eq!(&buf[0..5], b"HELLO");
         assert_eq!(&buf[5..8], vec![0; 3].as_slice());
         assert_eq!(&buf[8..13], b"WORLD");
         assert_eq!(&buf[13..(256 + 8)], vec![0; 251].as_slice());
     }
 
+    #[test]
+    fn key_to_array_test() {
+        let array = key_to_array(&Slice(b"HELLO".to_vec())).unwrap();
+        assert_eq!(&array, b"HELLO\0\0\0");
+        match key_to_array(&Slice(b"TOO LONG KEY".to_vec())) {
+            Err(SSTableError::KeyTooLong(12)) => {}
+            _ => panic!("a long key should be rejected"),
+        }
+    }
+
     #[test]
     fn read_sstable() {
         let db = MemDatabase::default();
@@ -243,13 +1054,298 @@ mod tests {
 
         let sstable: SSTable = db.into();
         futures::executor::block_on(async move {
-            sstable.save("/tmp/test_table").await.unwrap();
+            sstable.save("/tmp/test_table", Compression::None, None, None).await.unwrap();
         });
 
         let file = std::fs::File::open("/tmp/test_table").unwrap();
-        let sstable = SSTable::open(file).unwrap();
+        let sstable = SSTable::open(file, None, None).unwrap();
         let value = SyncDatabase::get_sync(&sstable, Slice(b"HELLO\0\0\0".to_vec())).unwrap();
 
         assert_eq!(&value.0[0..5], b"WORLD");
     }
+
+    #[test]
+    fn scan_sstable() {
+        let db = MemDatabase::default();
+        for key in [b"A", b"C", b"E"].iter() {
+            SyncDatabase::put_sync(&db, Slice(key.to_vec()), Slice(b"VALUE".to_vec())).unwrap();
+        }
+        let sstable: SSTable = db.into();
+
+        let keys = |start: &[u8], end: &[u8]| -> Vec<u8> {
+            sstable
+                .scan_sync(Slice(start.to_vec()), Slice(end.to_vec()))
+                .unwrap()
+                .into_iter()
+                .map(|(key, _)| key.0[0])
+                .collect()
+        };
+        assert_eq!(keys(b"B\0\0\0\0\0\0\0", b"D\0\0\0\0\0\0\0"), b"C".to_vec());
+        assert_eq!(keys(b"A\0\0\0\0\0\0\0", b"E\0\0\0\0\0\0\0"), b"AC".to_vec());
+        assert_eq!(keys(b"F\0\0\0\0\0\0\0", b"G\0\0\0\0\0\0\0"), b"".to_vec());
+
+        let (smallest, largest) = sstable.key_range().unwrap();
+        assert_eq!(smallest, Slice(b"A\0\0\0\0\0\0\0".to_vec()));
+        assert_eq!(largest, Slice(b"E\0\0\0\0\0\0\0".to_vec()));
+    }
+
+    #[test]
+    fn save_tombstones() {
+        let db = MemDatabase::default();
+        for key in [b"A", b"B", b"C", b"D"].iter() {
+            SyncDatabase::put_sync(&db, Slice(key.to_vec()), Slice(b"VALUE".to_vec())).unwrap();
+        }
+        SyncDatabase::delete_sync(&db, Slice(b"A".to_vec())).unwrap();
+        SyncDatabase::delete_range_sync(&db, Slice(b"B".to_vec()), Slice(b"D".to_vec())).unwrap();
+        SyncDatabase::put_sync(&db, Slice(b"C".to_vec()), Slice(b"NEW".to_vec())).unwrap();
+
+        let sstable: SSTable = db.into();
+        futures::executor::block_on(async move {
+            sstable.save("/tmp/test_tombstone_table", Compression::None, None, None).await.unwrap();
+        });
+
+        let file = std::fs::File::open("/tmp/test_tombstone_table").unwrap();
+        let sstable = SSTable::open(file, None, None).unwrap();
+        let key = |key: &[u8]| Slice(format!("{}\0\0\0\0\0\0\0", key[0] as char).into_bytes());
+
+        assert_eq!(sstable.lookup(&key(b"A")).unwrap(), Some(Value::NotExist));
+        assert_eq!(sstable.lookup(&key(b"B")).unwrap(), Some(Value::NotExist));
+        match sstable.lookup(&key(b"C")).unwrap() {
+            Some(Value::Slice(value)) => assert_eq!(&value.0[0..3], b"NEW"),
+            _ => panic!("C should be found"),
+        }
+        assert!(sstable.get_sync(key(b"D")).is_ok());
+        assert_eq!(sstable.lookup(&key(b"E")).unwrap(), None);
+
+        assert_eq!(sstable.range_tombstones().len(), 1);
+        assert_eq!(sstable.key_range(), Some((key(b"A"), key(b"D"))));
+    }
+
+    #[test]
+    fn save_expiring() {
+        let db = MemDatabase::default();
+        SyncDatabase::put_expire_sync(&db, Slice(b"A".to_vec()), Slice(b"VALUE".to_vec()), 1)
+            .unwrap();
+        SyncDatabase::put_expire_sync(
+            &db,
+            Slice(b"B".to_vec()),
+            Slice(b"VALUE".to_vec()),
+            std::u64::MAX,
+        )
+        .unwrap();
+
+        let sstable: SSTable = db.into();
+        futures::executor::block_on(async move {
+            sstable.save("/tmp/test_expiring_table", Compression::None, None, None).await.unwrap();
+        });
+
+        let file = std::fs::File::open("/tmp/test_expiring_table").unwrap();
+        let sstable = SSTable::open(file, None, None).unwrap();
+        let key = |key: &[u8]| Slice(format!("{}\0\0\0\0\0\0\0", key[0] as char).into_bytes());
+
+        assert_eq!(sstable.lookup(&key(b"A")).unwrap(), Some(Value::NotExist));
+        match sstable.lookup(&key(b"B")).unwrap() {
+            Some(Value::Expiring(value, expire_at)) => {
+                assert_eq!(&value.0[0..5], b"VALUE");
+                assert_eq!(expire_at, std::u64::MAX);
+            }
+            _ => panic!("B should be found"),
+        }
+        assert_eq!(sstable.len(), 2);
+        assert_eq!(sstable.scan_sync(key(b"A"), key(b"C")).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn save_compressed() {
+        use rand::{thread_rng, Rng};
+
+        let db = MemDatabase::default();
+        for index in 0..100 {
+            let value = if index % 10 == 0 {
+                // Random values cannot be compressed, so their blocks are stored as they are.
+                (0..VALUE_LENGTH).map(|_| thread_rng().gen::<u8>()).collect()
+            } else {
+                format!("VALUE {}", index).into_bytes()
+            };
+            let key = Slice(format!("K{:07}", index).into_bytes());
+            SyncDatabase::put_sync(&db, key, Slice(value)).unwrap();
+        }
+        let key = |index: usize| Slice(format!("K{:07}", index).into_bytes());
+        SyncDatabase::delete_sync(&db, key(3)).unwrap();
+        SyncDatabase::delete_range_sync(&db, key(50), key(60)).unwrap();
+        let sstable: SSTable = db.into();
+
+        futures::executor::block_on(async {
+            sstable
+                .save("/tmp/test_compressed_table", Compression::Lz4, None, None)
+                .await
+                .unwrap();
+            sstable
+                .save("/tmp/test_plain_table", Compression::None, None, None)
+                .await
+                .unwrap();
+        });
+        let compressed = std::fs::metadata("/tmp/test_compressed_table").unwrap();
+        let plain = std::fs::metadata("/tmp/test_plain_table").unwrap();
+        assert!(compressed.len() * 2 < plain.len());
+
+        let file = std::fs::File::open("/tmp/test_compressed_table").unwrap();
+        let block_cache = Arc::new(BlockCache::new(1024 * 1024));
+        let reopened = SSTable::open(file, None, Some(&block_cache)).unwrap();
+        assert_eq!(reopened.records().unwrap(), sstable.records().unwrap());
+        assert_eq!(reopened.range_tombstones(), sstable.range_tombstones());
+        assert_eq!(block_cache.usage(), 0);
+        assert_eq!(reopened.lookup(&key(3)).unwrap(), Some(Value::NotExist));
+        assert_eq!(reopened.lookup(&key(55)).unwrap(), Some(Value::NotExist));
+        assert_eq!(&reopened.get_sync(key(7)).unwrap().0[0..7], b"VALUE 7");
+        // Only the blocks which have been searched are decoded.
+        assert_eq!(block_cache.usage(), 2 * BLOCK_RECORDS * PART_LENGTH);
+        assert_eq!(reopened.approximate_count(&key(20), &key(40)), 20);
+        assert_eq!(reopened.scan_sync(key(40), key(70)).unwrap().len(), 20);
+        reopened.verify().unwrap();
+
+        let mut content = std::fs::read("/tmp/test_compressed_table").unwrap();
+        // An unknown codec of the first block.
+        content[HEADER_LENGTH] = 7;
+        std::fs::write("/tmp/test_compressed_table", content).unwrap();
+        let file = std::fs::File::open("/tmp/test_compressed_table").unwrap();
+        let reopened = SSTable::open(file, None, None).unwrap();
+        assert!(reopened.lookup(&key(3)).is_err());
+        assert!(reopened.verify().is_err());
+    }
+
+    #[test]
+    fn reject_other_formats() {
+        let db = MemDatabase::default();
+        for index in 0..40 {
+            let key = Slice(format!("K{:07}", index).into_bytes());
+            SyncDatabase::put_sync(&db, key, Slice(b"VALUE".to_vec())).unwrap();
+        }
+        let sstable: SSTable = db.into();
+        let path = "/tmp/test_format_table";
+        let open = || SSTable::open(std::fs::File::open(path).unwrap(), None, None);
+
+        // A table without header, whose last record looks like the end of an old compressed table.
+        let mut content = Vec::new();
+        for (key, _) in sstable.records().unwrap() {
+            append_record(&mut content, &key, b"VALUE", PUT, 0);
+        }
+        let length = content.len();
+        content[length - 8..].copy_from_slice(b"AGLFBLK\x01");
+        std::fs::write(path, &content).unwrap();
+        match open() {
+            Err(SSTableError::IncompatibleFormat) => {}
+            _ => panic!("A table without header should be rejected"),
+        }
+
+        // A larger table is replaced completely, and a truncated table is never read.
+        std::fs::write(path, vec![1; 100_000]).unwrap();
+        futures::executor::block_on(async {
+            sstable.save(path, Compression::Lz4, None, None).await.unwrap();
+        });
+        let saved = open().unwrap();
+        assert_eq!(saved.records().unwrap(), sstable.records().unwrap());
+        let content = std::fs::read(path).unwrap();
+        std::fs::write(path, &content[0..content.len() - 1]).unwrap();
+        match open() {
+            Err(SSTableError::Corrupted) => {}
+            _ => panic!("A truncated table should be rejected"),
+        }
+    }
+
+    #[test]
+    fn save_rate_limited() {
+        let db = MemDatabase::default();
+        for index in 0..2000 {
+            let key = Slice(format!("K{:07}", index).into_bytes());
+            SyncDatabase::put_sync(&db, key, Slice(b"VALUE".to_vec())).unwrap();
+        }
+        let sstable: SSTable = db.into();
+
+        let rate_limiter = RateLimiter::new(1024 * 1024, WRITE_CHUNK_LENGTH as u64);
+        futures::executor::block_on(async {
+            sstable
+                .save("/tmp/test_limited_table", Compression::None, None, Some(&rate_limiter))
+                .await
+                .unwrap();
+            sstable
+                .save("/tmp/test_unlimited_table", Compression::None, None, None)
+                .await
+                .unwrap();
+        });
+
+        let limited = std::fs::read("/tmp/test_limited_table").unwrap();
+        let unlimited = std::fs::read("/tmp/test_unlimited_table").unwrap();
+        assert_eq!(limited.len(), HEADER_LENGTH + 2000 * PART_LENGTH + CHECKSUM_LENGTH);
+        // Every byte of the limited table went through the limiter.
+        assert_eq!(rate_limiter.requested_bytes(), limited.len() as u64);
+        // Every file gets its own id in header.
+        let records = HEADER_LENGTH..limited.len() - CHECKSUM_LENGTH;
+        assert!(limited[records.clone()] == unlimited[records]);
+    }
+
+    #[test]
+    fn save_encrypted() {
+        use crate::storage::ChaCha20Poly1305Encryptor;
+
+        let db = MemDatabase::default();
+        for index in 0..40 {
+            let key = Slice(format!("K{:07}", index).into_bytes());
+            SyncDatabase::put_sync(&db, key, Slice(b"SECRET".to_vec())).unwrap();
+        }
+        let sstable: SSTable = db.into();
+        let encryptor: Arc<dyn Encryptor> =
+            Arc::new(ChaCha20Poly1305Encryptor::new([1; 32]).unwrap());
+        let other: Arc<dyn Encryptor> = Arc::new(ChaCha20Poly1305Encryptor::new([2; 32]).unwrap());
+
+        let path = "/tmp/test_encrypted_table";
+        let _ = std::fs::remove_file(path);
+        futures::executor::block_on(async {
+            sstable
+                .save(path, Compression::Lz4, Some(&encryptor), None)
+                .await
+                .unwrap();
+        });
+        let content = std::fs::read(path).unwrap();
+        assert!(!content.windows(6).any(|window| window == b"SECRET"));
+
+        let open = |encryptor| SSTable::open(std::fs::File::open(path).unwrap(), encryptor, None);
+        let reopened = open(Some(&encryptor)).unwrap();
+        assert_eq!(reopened.records().unwrap(), sstable.records().unwrap());
+        match open(None) {
+            Err(SSTableError::Encrypted) => {}
+            _ => panic!("An encrypted table should not be opened without key"),
+        }
+        match open(Some(&other)) {
+            Err(SSTableError::DecryptionFailed) => {}
+            _ => panic!("An encrypted table should not be opened with another key"),
+        }
+
+        // Blocks are bound to their table, and the index to the header.
+        let other_path = "/tmp/test_encrypted_table_copy";
+        futures::executor::block_on(async {
+            sstable
+                .save(other_path, Compression::Lz4, Some(&encryptor), None)
+                .await
+                .unwrap();
+        });
+        let mut moved = std::fs::read(other_path).unwrap();
+        assert_eq!(moved.len(), content.len());
+        let blocks_start = moved.len() - 100;
+        moved[0..blocks_start].copy_from_slice(&content[0..blocks_start]);
+        std::fs::write(other_path, &moved).unwrap();
+        let table = SSTable::open(std::fs::File::open(other_path).unwrap(), Some(&encryptor), None);
+        match table.unwrap().records() {
+            Err(SSTableError::DecryptionFailed) => {}
+            _ => panic!("A block moved from another table should be rejected"),
+        }
+
+        let mut modified = content.clone();
+        modified[HEADER_LENGTH - 1] ^= 1;
+        std::fs::write(path, &modified).unwrap();
+        match open(Some(&encryptor)) {
+            Err(SSTableError::DecryptionFailed) => {}
+            _ => panic!("A table with modified header should be rejected"),
+        }
+    }
 }
@@ -0,0 +1,172 @@
+use super::compaction::CompactionReport;
+use super::compaction_filter::FilterDecision;
+use super::version::NUM_LEVELS;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Where a GET stopped, which is the newest place having the key or its tombstone.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReadSource {
+    MemTable,
+    Frozen,
+    Level(usize),
+    Miss,
+}
+
+/// Counters of a database, shared by the database and the background worker. They are only
+/// increased and read separately, so relaxed ordering is enough.
+#[derive(Default)]
+pub struct Statistics {
+    filter_kept: AtomicU64,
+    filter_removed: AtomicU64,
+    filter_changed: AtomicU64,
+    flush_count: AtomicU64,
+    flush_micros: AtomicU64,
+    flush_bytes: AtomicU64,
+    compaction_count: AtomicU64,
+    compaction_micros: AtomicU64,
+    compaction_bytes_read: AtomicU64,
+    compaction_bytes_written: AtomicU64,
+    write_count: AtomicU64,
+    wal_bytes_written: AtomicU64,
+    stall_micros: AtomicU64,
+    scan_count: AtomicU64,
+    get_hits_memtable: AtomicU64,
+    get_hits_frozen: AtomicU64,
+    get_hits_level: [AtomicU64; NUM_LEVELS],
+    get_misses: AtomicU64,
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_micros() as u64
+}
+
+impl Statistics {
+    pub fn record_filter_decision(&self, decision: &FilterDecision) {
+        let counter = match decision {
+            FilterDecision::Keep => &self.filter_kept,
+            FilterDecision::Remove => &self.filter_removed,
+            FilterDecision::Change(_) => &self.filter_changed,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_flush(&self, duration: Duration, report: &CompactionReport) {
+        self.flush_count.fetch_add(1, Ordering::Relaxed);
+        self.flush_micros
+            .fetch_add(micros(duration), Ordering::Relaxed);
+        self.flush_bytes
+            .fetch_add(report.bytes_written, Ordering::Relaxed);
+    }
+
+    pub fn record_compaction(&self, duration: Duration, report: &CompactionReport) {
+        self.compaction_count.fetch_add(1, Ordering::Relaxed);
+        self.compaction_micros
+            .fetch_add(micros(duration), Ordering::Relaxed);
+        self.compaction_bytes_read
+            .fetch_add(report.bytes_read, Ordering::Relaxed);
+        self.compaction_bytes_written
+            .fetch_add(report.bytes_written, Ordering::Relaxed);
+    }
+
+    /// A write is committed, and `wal_bytes` are appended to log for it.
+    pub fn record_write(&self, wal_bytes: u64) {
+        self.write_count.fetch_add(1, Ordering::Relaxed);
+        self.wal_bytes_written
+            .fetch_add(wal_bytes, Ordering::Relaxed);
+    }
+
+    /// Writes were blocked for `duration`.
+    pub fn record_stall(&self, duration: Duration) {
+        self.stall_micros
+            .fetch_add(micros(duration), Ordering::Relaxed);
+    }
+
+    pub fn record_scan(&self) {
+        self.scan_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_get(&self, source: ReadSource) {
+        let counter = match source {
+            ReadSource::MemTable => &self.get_hits_memtable,
+            ReadSource::Frozen => &self.get_hits_frozen,
+            ReadSource::Level(level) => &self.get_hits_level[level],
+            ReadSource::Miss => &self.get_misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counters only. Sizes of MemDatabases and tables are filled by the owner.
+    pub fn snapshot(&self) -> DatabaseStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        DatabaseStats {
+            flush_count: load(&self.flush_count),
+            flush_micros: load(&self.flush_micros),
+            flush_bytes: load(&self.flush_bytes),
+            compaction_count: load(&self.compaction_count),
+            compaction_micros: load(&self.compaction_micros),
+            compaction_bytes_read: load(&self.compaction_bytes_read),
+            compaction_bytes_written: load(&self.compaction_bytes_written),
+            write_count: load(&self.write_count),
+            wal_bytes_written: load(&self.wal_bytes_written),
+            stall_micros: load(&self.stall_micros),
+            scan_count: load(&self.scan_count),
+            get_hits_memtable: load(&self.get_hits_memtable),
+            get_hits_frozen: load(&self.get_hits_frozen),
+            get_hits_level: self.get_hits_level.iter().map(load).collect(),
+            get_misses: load(&self.get_misses),
+            compaction_filter_kept: load(&self.filter_kept),
+            compaction_filter_removed: load(&self.filter_removed),
+            compaction_filter_changed: load(&self.filter_changed),
+            ..DatabaseStats::default()
+        }
+    }
+}
+
+/// A snapshot of statistics since the database is opened, returned by `Database::stats`.
+///
+/// `MemDatabase::stats` returns the same struct, with only MemTable sizes, write, scan and GET counters
+/// filled. Sizes are summed over every column family.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DatabaseStats {
+    /// Bytes of keys and values in MemDatabase, including overwritten ones.
+    pub memtable_bytes: u64,
+    /// Records in MemDatabase, including overwritten ones.
+    pub memtable_entries: u64,
+    /// Frozen databases waiting to be written into tables.
+    pub frozen_memtables: u64,
+    /// Number of tables in every level.
+    pub level_tables: Vec<u64>,
+    /// Size of tables in every level.
+    pub level_bytes: Vec<u64>,
+    /// Frozen databases written into tables.
+    pub flush_count: u64,
+    pub flush_micros: u64,
+    /// Size of tables written by flushes.
+    pub flush_bytes: u64,
+    pub compaction_count: u64,
+    pub compaction_micros: u64,
+    pub compaction_bytes_read: u64,
+    pub compaction_bytes_written: u64,
+    /// PUT, PUT_EXPIRE, DELETE, DELETE_RANGE and MERGE committed.
+    pub write_count: u64,
+    pub wal_bytes_written: u64,
+    /// Time writes were blocked by freezing MemDatabase, `flush_memtable` and `ingest`.
+    pub stall_micros: u64,
+    pub scan_count: u64,
+    /// GETs which stopped at MemDatabase, i.e. found the key or its tombstone there.
+    pub get_hits_memtable: u64,
+    /// GETs which stopped at a frozen database.
+    pub get_hits_frozen: u64,
+    /// GETs which stopped at a table of every level.
+    pub get_hits_level: Vec<u64>,
+    /// GETs which found nothing about the key.
+    pub get_misses: u64,
+    /// Records kept as they are by the compaction filter.
+    pub compaction_filter_kept: u64,
+    /// Records removed by the compaction filter.
+    pub compaction_filter_removed: u64,
+    /// Records whose value is changed by the compaction filter.
+    pub compaction_filter_changed: u64,
+}
@@ -0,0 +1,245 @@
+use super::block_cache::BlockCache;
+use super::encryption::Encryptor;
+use super::error::StorageResult;
+use super::sstable::SSTable;
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Path of the table file `sstable_<level>_<id>` inside `base_dir`.
+pub fn table_path(base_dir: &str, level: usize, id: usize) -> PathBuf {
+    Path::new(base_dir).join(format!("sstable_{}_{}", level, id))
+}
+
+/// Write the directory entry of a new table to disk. Tables are synced and then their directory
+/// entries, before they are recorded in MANIFEST.
+pub fn sync_parent(path: &Path) -> StorageResult<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::File::open(parent)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// Parse `(level, id)` from the name of a table file. `None` if it's not a table.
+pub fn parse_table_name(name: &str) -> Option<(usize, usize)> {
+    if !name.starts_with("sstable_") {
+        return None;
+    }
+
+    let mut parts = name["sstable_".len()..].splitn(2, '_');
+    let level = parts.next()?.parse().ok()?;
+    let id = parts.next()?.parse().ok()?;
+    Some((level, id))
+}
+
+struct CachedTable {
+    table: Arc<SSTable>,
+    last_used: u64,
+}
+
+struct TableCacheInner {
+    tables: HashMap<(usize, usize), CachedTable>,
+    lru: BTreeMap<u64, (usize, usize)>,
+    tick: u64,
+}
+
+impl TableCacheInner {
+    fn touch(&mut self, key: (usize, usize)) -> Option<Arc<SSTable>> {
+        self.tick += 1;
+        let tick = self.tick;
+
+        let cached = self.tables.get_mut(&key)?;
+        self.lru.remove(&cached.last_used);
+        cached.last_used = tick;
+        self.lru.insert(tick, key);
+
+        Some(cached.table.clone())
+    }
+
+    fn remove(&mut self, key: (usize, usize)) {
+        if let Some(cached) = self.tables.remove(&key) {
+            self.lru.remove(&cached.last_used);
+        }
+    }
+}
+
+/// An LRU cache of opened SSTables.
+///
+/// Every SSTable is a mmap of `sstable_<level>_<id>`. Blocks of compressed or encrypted tables are
+/// decoded when they are searched, and kept in `block_cache`, which is shared by every table of the
+/// database. Keeping all of them mapped forever will exhaust file descriptors and VMAs after many
+/// tables are created, so only `capacity` tables are kept open. The least recently used one is
+/// dropped when a new table is opened and the cache is full.
+///
+/// Tables are handed out as `Arc<SSTable>`, so an evicted table is still valid for anyone who is
+/// reading it. It will be unmapped after the last reader drops it.
+///
+/// A table is found by its data path id. `0` is `base_dir`, and `i` is the `i`th (counted from `1`)
+/// of `data_paths`, which are directories of this column family on other disks with their target
+/// sizes.
+pub struct TableCache {
+    base_dir: String,
+    data_paths: Vec<(String, u64)>,
+    capacity: usize,
+    block_cache: Arc<BlockCache>,
+    encryptor: Option<Arc<dyn Encryptor>>,
+    inner: Mutex<TableCacheInner>,
+}
+
+impl TableCache {
+    pub fn new(
+        base_dir: &str,
+        data_paths: Vec<(String, u64)>,
+        capacity: usize,
+        block_cache: Arc<BlockCache>,
+        encryptor: Option<Arc<dyn Encryptor>>,
+    ) -> TableCache {
+        TableCache {
+            base_dir: base_dir.to_string(),
+            data_paths,
+            capacity: std::cmp::max(capacity, 1),
+            block_cache,
+            encryptor,
+            inner: Mutex::new(TableCacheInner {
+                tables: HashMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+            }),
+        }
+    }
+
+    /// Get a table from cache. If it's not opened, it will be opened from disk and may evict the
+    /// least recently used table.
+    pub fn get(&self, path_id: usize, level: usize, id: usize) -> StorageResult<Arc<SSTable>> {
+        if let Some(table) = self.inner.lock().unwrap().touch((level, id)) {
+            return Ok(table);
+        }
+
+        let path = self.table_path(path_id, level, id);
+        log::debug!("Opening sstable from {:#?}", path);
+        let file = std::fs::OpenOptions::new().read(true).open(path)?;
+        let table = SSTable::open(file, self.encryptor.as_ref(), Some(&self.block_cache))?;
+
+        Ok(self.insert(level, id, table))
+    }
+
+    pub fn base_dir(&self) -> &str {
+        &self.base_dir
+    }
+
+    /// Directories of every data path, indexed by path id.
+    pub fn dirs(&self) -> Vec<&str> {
+        std::iter::once(self.base_dir.as_str())
+            .chain(self.data_paths.iter().map(|(dir, _)| dir.as_str()))
+            .collect()
+    }
+
+    /// Target sizes of `data_paths`, whose path ids start from `1`.
+    pub fn target_sizes(&self) -> Vec<u64> {
+        self.data_paths.iter().map(|(_, size)| *size).collect()
+    }
+
+    /// Path of a table in the data path `path_id`, which should be checked by `has_path` first.
+    pub fn table_path(&self, path_id: usize, level: usize, id: usize) -> PathBuf {
+        match path_id {
+            0 => table_path(&self.base_dir, level, id),
+            _ => table_path(&self.data_paths[path_id - 1].0, level, id),
+        }
+    }
+
+    pub fn has_path(&self, path_id: usize) -> bool {
+        path_id <= self.data_paths.len()
+    }
+
+    /// The encryptor of tables, MANIFEST and logs of the database.
+    pub fn encryptor(&self) -> Option<&Arc<dyn Encryptor>> {
+        self.encryptor.as_ref()
+    }
+
+    /// Put a table which has already been loaded (e.g. just flushed from a MemDatabase) into cache.
+    pub fn insert(&self, level: usize, id: usize, table: SSTable) -> Arc<SSTable> {
+        let mut inner = self.inner.lock().unwrap();
+
+        // Another thread may have opened the same table at the same time.
+        if let Some(table) = inner.touch((level, id)) {
+            return table;
+        }
+
+        while inner.tables.len() >= self.capacity {
+            let oldest = match inner.lru.iter().next() {
+                Some((_, key)) => *key,
+                None => break,
+            };
+            inner.remove(oldest);
+        }
+
+        inner.tick += 1;
+        let tick = inner.tick;
+        let table = Arc::new(table);
+        inner.tables.insert(
+            (level, id),
+            CachedTable {
+                table: table.clone(),
+                last_used: tick,
+            },
+        );
+        inner.lru.insert(tick, (level, id));
+
+        table
+    }
+
+    /// Drop a table from cache. It's used when the table is removed from database.
+    pub fn evict(&self, level: usize, id: usize) {
+        self.inner.lock().unwrap().remove((level, id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::compression::Compression;
+    use crate::storage::SyncDatabase;
+    use crate::MemDatabase;
+    use agilulf_protocol::Slice;
+
+    #[test]
+    fn lru_eviction() {
+        let base_dir = "/tmp/agilulf_table_cache_test";
+        std::fs::create_dir_all(base_dir).unwrap();
+
+        for id in 0..3 {
+            let db = MemDatabase::default();
+            db.put_sync(
+                Slice(format!("KEY{}", id).into_bytes()),
+                Slice(format!("VALUE{}", id).into_bytes()),
+            )
+            .unwrap();
+            let table = SSTable::from(db);
+            let path = table_path(base_dir, 0, id);
+            futures::executor::block_on(async {
+                table
+                    .save(path.to_str().unwrap(), Compression::None, None, None)
+                    .await
+                    .unwrap();
+            });
+        }
+
+        let cache = TableCache::new(base_dir, Vec::new(), 2, Arc::new(BlockCache::new(0)), None);
+        for id in 0..3 {
+            let table = cache.get(0, 0, id).unwrap();
+            let value = table
+                .get_sync(Slice(format!("KEY{}\0\0\0\0", id).into_bytes()))
+                .unwrap();
+            assert_eq!(&value.0[0..6], format!("VALUE{}", id).as_bytes());
+            assert!(cache.inner.lock().unwrap().tables.len() <= 2);
+        }
+
+        // The first table has been evicted, and will be reloaded transparently.
+        let table = cache.get(0, 0, 0).unwrap();
+        let value = table.get_sync(Slice(b"KEY0\0\0\0\0".to_vec())).unwrap();
+        assert_eq!(&value.0[0..6], b"VALUE0");
+        assert_eq!(cache.inner.lock().unwrap().tables.len(), 2);
+    }
+}
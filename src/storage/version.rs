@@ -0,0 +1,219 @@
+use super::table_cache::TableCache;
+
+use agilulf_protocol::Slice;
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+pub const NUM_LEVELS: usize = 6;
+
+/// Metadata of a table held in memory. The table itself is loaded by `TableCache`.
+///
+/// It's shared by every `Version` which contains this table. When a table is removed by an edit, it's
+/// marked as obsolete. Then the file will be unlinked after the last `Version` referencing it is
+/// dropped, so a reader holding an old `Version` can always open every table in it.
+///
+/// A table is marked as being compacted while a background compaction is merging it, so compaction
+/// strategies can pick other tables to compact at the same time.
+///
+/// `path_id` is the data path holding the file, see `TableCache`.
+pub struct TableMeta {
+    pub level: usize,
+    pub id: usize,
+    pub path_id: usize,
+    pub smallest: Slice,
+    pub largest: Slice,
+    obsolete: AtomicBool,
+    being_compacted: AtomicBool,
+    table_cache: Arc<TableCache>,
+}
+
+impl TableMeta {
+    pub fn new(
+        level: usize,
+        id: usize,
+        path_id: usize,
+        smallest: Slice,
+        largest: Slice,
+        table_cache: Arc<TableCache>,
+    ) -> TableMeta {
+        TableMeta {
+            level,
+            id,
+            path_id,
+            smallest,
+            largest,
+            obsolete: AtomicBool::new(false),
+            being_compacted: AtomicBool::new(false),
+            table_cache,
+        }
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.table_cache
+            .table_path(self.path_id, self.level, self.id)
+    }
+
+    pub fn may_contain(&self, key: &Slice) -> bool {
+        &self.smallest <= key && key <= &self.largest
+    }
+
+    pub fn overlaps(&self, start: &Slice, end: &Slice) -> bool {
+        &self.largest >= start && &self.smallest < end
+    }
+
+    pub fn is_being_compacted(&self) -> bool {
+        self.being_compacted.load(Ordering::SeqCst)
+    }
+
+    pub fn set_being_compacted(&self, being_compacted: bool) {
+        self.being_compacted
+            .store(being_compacted, Ordering::SeqCst);
+    }
+
+    fn mark_obsolete(&self) {
+        self.obsolete.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Drop for TableMeta {
+    fn drop(&mut self) {
+        if self.obsolete.load(Ordering::SeqCst) {
+            self.table_cache.evict(self.level, self.id);
+
+            let path = self.path();
+            log::info!("Removing obsolete sstable {:#?}", path);
+            match std::fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => log::error!("Error while removing sstable {:#?}: {}", path, err),
+            }
+        }
+    }
+}
+
+/// A change of tables. It's recorded in MANIFEST as a group, and turns a `Version` into the next one.
+///
+/// `log_number` means every `log.<id>` with `id < log_number` has been written into tables, so these
+/// logs will not be replayed after restart. `last_sequence` is the largest sequence number written
+/// into tables. It never goes back, as tables may be ingested with a sequence number larger than
+/// records in frozen logs.
+///
+/// An added table is `(level, id, path_id, smallest, largest)`.
+#[derive(Default)]
+pub struct VersionEdit {
+    pub added: Vec<(usize, usize, usize, Slice, Slice)>,
+    pub deleted: Vec<(usize, usize)>,
+    pub log_number: Option<usize>,
+    pub last_sequence: Option<u64>,
+}
+
+impl VersionEdit {
+    pub fn add_table(
+        &mut self,
+        level: usize,
+        id: usize,
+        path_id: usize,
+        smallest: Slice,
+        largest: Slice,
+    ) {
+        self.added.push((level, id, path_id, smallest, largest));
+    }
+
+    pub fn delete_table(&mut self, level: usize, id: usize) {
+        self.deleted.push((level, id));
+    }
+
+    pub fn set_log_number(&mut self, log_number: usize) {
+        self.log_number = Some(log_number);
+    }
+
+    pub fn set_last_sequence(&mut self, last_sequence: u64) {
+        self.last_sequence = Some(last_sequence);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.deleted.is_empty()
+            && self.log_number.is_none()
+            && self.last_sequence.is_none()
+    }
+}
+
+/// An immutable snapshot of tables in every level.
+///
+/// Tables in level 0 are sorted by id, as they may overlap with each other and newer tables should
+/// be read first. Tables in other levels don't overlap, so they are sorted by key.
+///
+/// A `Version` is never modified after it's created. Readers clone the `Arc<Version>` and read tables
+/// from it without any lock.
+#[derive(Default)]
+pub struct Version {
+    levels: [Vec<Arc<TableMeta>>; NUM_LEVELS],
+    pub log_number: usize,
+    pub last_sequence: u64,
+}
+
+impl Version {
+    pub fn level(&self, level: usize) -> &[Arc<TableMeta>] {
+        &self.levels[level]
+    }
+
+    /// Create the next version with an edit applied.
+    pub fn apply(&self, edit: &VersionEdit, table_cache: &Arc<TableCache>) -> Version {
+        let mut levels = self.levels.clone();
+
+        for (level, id) in edit.deleted.iter() {
+            levels[*level].retain(|table| table.id != *id);
+        }
+
+        for (level, id, path_id, smallest, largest) in edit.added.iter() {
+            levels[*level].push(Arc::new(TableMeta::new(
+                *level,
+                *id,
+                *path_id,
+                smallest.clone(),
+                largest.clone(),
+                table_cache.clone(),
+            )));
+        }
+
+        for (level, tables) in levels.iter_mut().enumerate() {
+            if level == 0 {
+                tables.sort_by_key(|table| table.id);
+            } else {
+                tables.sort_by(|a, b| a.smallest.cmp(&b.smallest));
+            }
+        }
+
+        Version {
+            levels,
+            log_number: edit.log_number.unwrap_or(self.log_number),
+            last_sequence: edit.last_sequence.map_or(self.last_sequence, |last| {
+                std::cmp::max(last, self.last_sequence)
+            }),
+        }
+    }
+
+    /// Mark tables removed by the edit as obsolete. It should be called after the edit is persisted.
+    pub fn mark_obsolete(&self, edit: &VersionEdit) {
+        for (level, id) in edit.deleted.iter() {
+            for table in self.levels[*level].iter() {
+                if table.id == *id {
+                    table.mark_obsolete();
+                }
+            }
+        }
+    }
+
+    /// Find the only table in a level (except level 0) which may contain the key.
+    pub fn find_table(&self, level: usize, key: &Slice) -> Option<&Arc<TableMeta>> {
+        let tables = &self.levels[level];
+        let index = match tables.binary_search_by(|table| table.largest.cmp(key)) {
+            Ok(index) => index,
+            Err(index) => index,
+        };
+        tables.get(index).filter(|table| table.may_contain(key))
+    }
+}
@@ -0,0 +1,238 @@
+use super::database_log::{DatabaseLog, DatabaseLogReader, LogRecord};
+use super::encryption::Encryptor;
+use super::error::{StorageError, StorageResult};
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Where point-in-time restore stops.
+#[derive(Debug, Clone, Copy)]
+pub enum RestorePoint {
+    /// Replay every write whose sequence number is not larger than it.
+    Sequence(u64),
+    /// Replay every write before this time (in microseconds since UNIX epoch, inclusive).
+    Timestamp(u64),
+}
+
+impl RestorePoint {
+    pub fn includes(&self, record: &LogRecord) -> bool {
+        match *self {
+            RestorePoint::Sequence(sequence) => record.sequence <= sequence,
+            RestorePoint::Timestamp(timestamp) => record.timestamp <= timestamp,
+        }
+    }
+}
+
+fn path_to_str(path: &Path) -> StorageResult<&str> {
+    match path.to_str() {
+        Some(str) => Ok(str),
+        None => {
+            log::error!("log path {:#?} is not UTF-8", path);
+            Err(StorageError::UnicodeError)
+        }
+    }
+}
+
+/// Get rid of a log which has been written into tables. If `archive_dir` is set, it's moved into the
+/// archive as `wal_<first sequence>` instead of being removed. Archived logs stay encrypted.
+pub fn discard_log(
+    log_path: &Path,
+    archive_dir: Option<&str>,
+    encryptor: Option<&Arc<dyn Encryptor>>,
+) -> StorageResult<()> {
+    let archive_dir = match archive_dir {
+        Some(archive_dir) => archive_dir,
+        None => {
+            std::fs::remove_file(log_path)?;
+            return Ok(());
+        }
+    };
+
+    let first_sequence = DatabaseLog::open(path_to_str(log_path)?, 4 * 1024 * 2, encryptor)?
+        .records()
+        .next()
+        .map(|record| record.sequence);
+    let first_sequence = match first_sequence {
+        Some(sequence) => sequence,
+        None => {
+            std::fs::remove_file(log_path)?;
+            return Ok(());
+        }
+    };
+
+    std::fs::create_dir_all(archive_dir)?;
+    let archive_path = Path::new(archive_dir).join(format!("wal_{:020}", first_sequence));
+    log::info!("Archiving {:#?} to {:#?}", log_path, archive_path);
+    if std::fs::rename(log_path, &archive_path).is_err() {
+        // Archive may be on another file system.
+        std::fs::copy(log_path, &archive_path)?;
+        std::fs::remove_file(log_path)?;
+    }
+
+    Ok(())
+}
+
+/// Frozen logs which are not written into tables by every column family yet. As column families share
+/// logs, a log is discarded only after the last family has written it.
+pub struct PendingLogs {
+    wal_dir: String,
+    archive_dir: Option<String>,
+    encryptor: Option<Arc<dyn Encryptor>>,
+    pending: Mutex<HashMap<usize, usize>>,
+}
+
+impl PendingLogs {
+    pub fn new(
+        wal_dir: &str,
+        archive_dir: Option<String>,
+        encryptor: Option<Arc<dyn Encryptor>>,
+    ) -> PendingLogs {
+        PendingLogs {
+            wal_dir: wal_dir.to_string(),
+            archive_dir,
+            encryptor,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// `log.<log_id>` will be written into tables by `families` column families. It's discarded at once
+    /// if no family needs it.
+    pub fn add(&self, log_id: usize, families: usize) -> StorageResult<()> {
+        if families == 0 {
+            return self.discard(log_id);
+        }
+        self.pending.lock().unwrap().insert(log_id, families);
+
+        Ok(())
+    }
+
+    /// A column family has written `log.<log_id>` into tables.
+    pub fn done(&self, log_id: usize) -> StorageResult<()> {
+        let mut pending = self.pending.lock().unwrap();
+        let remaining = match pending.get_mut(&log_id) {
+            Some(remaining) => {
+                *remaining -= 1;
+                *remaining
+            }
+            None => 0,
+        };
+
+        if remaining == 0 {
+            pending.remove(&log_id);
+            self.discard(log_id)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn log_path(&self, log_id: usize) -> PathBuf {
+        Path::new(&self.wal_dir).join(format!("log.{}", log_id))
+    }
+
+    fn discard(&self, log_id: usize) -> StorageResult<()> {
+        discard_log(
+            &self.log_path(log_id),
+            self.archive_dir.as_ref().map(String::as_str),
+            self.encryptor.as_ref(),
+        )
+    }
+}
+
+/// Archived logs which may hold records with sequence number larger than `after`, with their first
+/// sequence numbers, sorted by them.
+pub fn archived_logs(archive_dir: &str, after: u64) -> StorageResult<Vec<(u64, PathBuf)>> {
+    let mut logs = Vec::new();
+    for entry in std::fs::read_dir(archive_dir)? {
+        let path = entry?.path();
+        let first_sequence = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) if name.starts_with("wal_") => name[4..].parse::<u64>(),
+            _ => continue,
+        };
+        match first_sequence {
+            Ok(first_sequence) => logs.push((first_sequence, path)),
+            Err(_) => {
+                log::error!("{:#?} is not named after its first sequence number", path);
+                return Err(StorageError::DatabaseLogFormatError);
+            }
+        }
+    }
+    logs.sort_by_key(|(first_sequence, _)| *first_sequence);
+
+    // Every record of a log is before the first one of the next log.
+    let skipped = logs
+        .iter()
+        .skip(1)
+        .take_while(|(first_sequence, _)| *first_sequence <= after + 1)
+        .count();
+    logs.drain(..skipped);
+
+    Ok(logs)
+}
+
+/// Open an archived log, which is rejected if some of its records cannot be understood.
+pub fn open_archived_log(
+    path: &Path,
+    encryptor: Option<&Arc<dyn Encryptor>>,
+) -> StorageResult<DatabaseLogReader> {
+    let log = DatabaseLogReader::open(path_to_str(path)?, encryptor)?;
+    if !log.is_valid() {
+        return Err(StorageError::DatabaseLogFormatError);
+    }
+
+    Ok(log)
+}
+
+/// Call `f` with every record in archived logs with sequence number larger than `after`, in order of
+/// sequence number. Logs are read one by one, so only records of one log are kept in memory.
+pub fn for_each_archived_record<F>(
+    archive_dir: &str,
+    after: u64,
+    encryptor: Option<&Arc<dyn Encryptor>>,
+    mut f: F,
+) -> StorageResult<()>
+where
+    F: FnMut(LogRecord) -> StorageResult<()>,
+{
+    for (_, path) in archived_logs(archive_dir, after)? {
+        let log = open_archived_log(&path, encryptor)?;
+        let mut records: Vec<LogRecord> = log
+            .records()
+            .filter(|record| record.sequence > after)
+            .collect();
+        records.sort_by_key(|record| record.sequence);
+        for record in records {
+            f(record)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skip_archived_logs() {
+        let archive_dir = "/var/tmp/agilulf_skip_archived_logs_test";
+        let _ = std::fs::remove_dir_all(archive_dir);
+        std::fs::create_dir_all(archive_dir).unwrap();
+        for first_sequence in [1, 100, 200].iter() {
+            let path = Path::new(archive_dir).join(format!("wal_{:020}", first_sequence));
+            std::fs::File::create(path).unwrap();
+        }
+
+        let first_sequences = |after| -> Vec<u64> {
+            archived_logs(archive_dir, after)
+                .unwrap()
+                .into_iter()
+                .map(|(first_sequence, _)| first_sequence)
+                .collect()
+        };
+        assert_eq!(first_sequences(0), vec![1, 100, 200]);
+        assert_eq!(first_sequences(98), vec![1, 100, 200]);
+        assert_eq!(first_sequences(99), vec![100, 200]);
+        assert_eq!(first_sequences(500), vec![200]);
+    }
+}
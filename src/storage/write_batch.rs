@@ -0,0 +1,87 @@
+use super::column_family::{ColumnFamily, DEFAULT_FAMILY};
+
+use agilulf_protocol::{
+    Command, DeleteCommand, DeleteRangeCommand, MergeCommand, PutCommand, PutExpireCommand, Slice,
+};
+
+/// Commands written together by `Database::write_batch`. Every command can be in a different column
+/// family (the default one if it's `None`), as all families share the same log. Methods can be chained
+/// on it like `DatabaseBuilder`.
+#[derive(Default)]
+pub struct WriteBatch {
+    commands: Vec<(u32, Command)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    pub fn put(&mut self, family: Option<&ColumnFamily>, key: Slice, value: Slice) -> &mut Self {
+        self.push(family_id(family), Command::PUT(PutCommand { key, value }))
+    }
+
+    pub fn put_expire(
+        &mut self,
+        family: Option<&ColumnFamily>,
+        key: Slice,
+        value: Slice,
+        expire_at: u64,
+    ) -> &mut Self {
+        let command = Command::PUT_EXPIRE(PutExpireCommand {
+            key,
+            value,
+            expire_at,
+        });
+        self.push(family_id(family), command)
+    }
+
+    pub fn delete(&mut self, family: Option<&ColumnFamily>, key: Slice) -> &mut Self {
+        self.push(family_id(family), Command::DELETE(DeleteCommand { key }))
+    }
+
+    pub fn delete_range(
+        &mut self,
+        family: Option<&ColumnFamily>,
+        start: Slice,
+        end: Slice,
+    ) -> &mut Self {
+        let command = Command::DELETE_RANGE(DeleteRangeCommand { start, end });
+        self.push(family_id(family), command)
+    }
+
+    pub fn merge(
+        &mut self,
+        family: Option<&ColumnFamily>,
+        key: Slice,
+        operand: Slice,
+    ) -> &mut Self {
+        self.push(
+            family_id(family),
+            Command::MERGE(MergeCommand { key, operand }),
+        )
+    }
+
+    /// Add a command of the column family `family` (an id).
+    pub fn push(&mut self, family: u32, command: Command) -> &mut Self {
+        self.commands.push((family, command));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// Commands in the order they were added, with ids of their column families.
+    pub fn into_commands(self) -> Vec<(u32, Command)> {
+        self.commands
+    }
+}
+
+fn family_id(family: Option<&ColumnFamily>) -> u32 {
+    family.map_or(DEFAULT_FAMILY, ColumnFamily::id)
+}